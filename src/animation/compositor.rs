@@ -0,0 +1,251 @@
+use super::{Animation, AnimationFrame, BlendMode, DisposeMode};
+use crate::error::{WkError, WkResult};
+use image::RgbaImage;
+
+struct Disposal {
+    mode: DisposeMode,
+    rect: (usize, usize, usize, usize),
+    saved: Vec<u8>,
+}
+
+// Renders frames onto a full RGBA canvas with APNG/GIF semantics: each
+// frame's disposal is applied just before the next frame is drawn.
+pub struct Compositor {
+    width: usize,
+    height: usize,
+    background: [u8; 4],
+    canvas: Vec<u8>,
+    pending: Option<Disposal>,
+    drawn: usize,
+}
+
+impl Compositor {
+    pub fn new(width: u32, height: u32, background: [u8; 4]) -> Self {
+        Self {
+            width: width as usize,
+            height: height as usize,
+            background,
+            canvas: background.repeat(width as usize * height as usize),
+            pending: None,
+            drawn: 0,
+        }
+    }
+
+    pub fn for_animation(animation: &Animation) -> WkResult<Self> {
+        let (width, height) = animation.canvas_size()?;
+        Ok(Self::new(width, height, animation.config.background_color))
+    }
+
+    pub fn width(&self) -> u32 {
+        self.width as u32
+    }
+
+    pub fn height(&self) -> u32 {
+        self.height as u32
+    }
+
+    pub fn canvas(&self) -> &[u8] {
+        &self.canvas
+    }
+
+    pub fn to_image(&self) -> RgbaImage {
+        RgbaImage::from_raw(self.width as u32, self.height as u32, self.canvas.clone())
+            .expect("canvas matches its dimensions")
+    }
+
+    pub fn reset(&mut self) {
+        self.canvas = self.background.repeat(self.width * self.height);
+        self.pending = None;
+        self.drawn = 0;
+    }
+
+    // Composites an RGBA frame and returns the canvas as displayed.
+    pub fn compose(&mut self, frame: &AnimationFrame) -> WkResult<RgbaImage> {
+        if frame.data.len() != frame.width as usize * frame.height as usize * 4 {
+            return Err(WkError::InvalidFormat(format!(
+                "Frame data does not match {}x{} RGBA",
+                frame.width, frame.height
+            )));
+        }
+        if !self.contains(frame) {
+            return Err(WkError::InvalidFormat(
+                "Frame lies outside the canvas".into(),
+            ));
+        }
+        self.dispose_previous();
+        self.draw(frame, &frame.data, 4);
+        Ok(self.to_image())
+    }
+
+    pub fn contains(&self, frame: &AnimationFrame) -> bool {
+        frame.extent().is_some_and(|(right, bottom)| {
+            right as usize <= self.width && bottom as usize <= self.height
+        })
+    }
+
+    pub(crate) fn dispose_previous(&mut self) {
+        let Some(disposal) = self.pending.take() else {
+            return;
+        };
+        let (x0, y0, w, h) = disposal.rect;
+        for y in 0..h {
+            let start = ((y0 + y) * self.width + x0) * 4;
+            let row = &mut self.canvas[start..start + w * 4];
+            match disposal.mode {
+                DisposeMode::None => return,
+                DisposeMode::Background => {
+                    for px in row.chunks_exact_mut(4) {
+                        px.copy_from_slice(&self.background);
+                    }
+                }
+                DisposeMode::Previous => {
+                    row.copy_from_slice(&disposal.saved[y * w * 4..(y + 1) * w * 4])
+                }
+            }
+        }
+    }
+
+    // The canvas under the frame rectangle, reduced to the first `channels`.
+    pub(crate) fn region(&self, frame: &AnimationFrame, channels: usize) -> Vec<u8> {
+        let (x0, y0) = (frame.x_offset as usize, frame.y_offset as usize);
+        let (w, h) = (frame.width as usize, frame.height as usize);
+        let mut out = Vec::with_capacity(w * h * channels);
+        for y in y0..y0 + h {
+            let start = (y * self.width + x0) * 4;
+            for px in self.canvas[start..start + w * 4].chunks_exact(4) {
+                out.extend_from_slice(&px[..channels]);
+            }
+        }
+        out
+    }
+
+    // Draws RGB or RGBA pixels for the frame rectangle; the caller applies
+    // the previous frame's disposal first.
+    pub(crate) fn draw(&mut self, frame: &AnimationFrame, pixels: &[u8], channels: usize) {
+        // APNG treats "restore previous" on the first frame as "restore background".
+        let mode = match frame.dispose_mode {
+            DisposeMode::Previous if self.drawn == 0 => DisposeMode::Background,
+            mode => mode,
+        };
+        let saved = if mode == DisposeMode::Previous {
+            self.region(frame, 4)
+        } else {
+            Vec::new()
+        };
+
+        let (x0, y0) = (frame.x_offset as usize, frame.y_offset as usize);
+        let w = frame.width as usize;
+        for (y, row) in pixels.chunks_exact(w * channels).enumerate() {
+            let start = ((y0 + y) * self.width + x0) * 4;
+            let dst = &mut self.canvas[start..start + w * 4];
+            for (out, src) in dst.chunks_exact_mut(4).zip(row.chunks_exact(channels)) {
+                let src = [
+                    src[0],
+                    src[1],
+                    src[2],
+                    if channels == 4 { src[3] } else { 255 },
+                ];
+                let px = match frame.blend_mode {
+                    BlendMode::Source => src,
+                    BlendMode::Over => blend_over(src, [out[0], out[1], out[2], out[3]]),
+                };
+                out.copy_from_slice(&px);
+            }
+        }
+
+        self.pending = Some(Disposal {
+            mode,
+            rect: (x0, y0, w, frame.height as usize),
+            saved,
+        });
+        self.drawn += 1;
+    }
+}
+
+// Non-premultiplied alpha-over, following the integer formulation in the
+// APNG specification.
+fn blend_over(src: [u8; 4], dst: [u8; 4]) -> [u8; 4] {
+    match src[3] {
+        255 => src,
+        0 => dst,
+        sa => {
+            let u = sa as u32 * 255;
+            let v = (255 - sa as u32) * dst[3] as u32;
+            let al = u + v;
+            let c = |i: usize| ((src[i] as u32 * u + dst[i] as u32 * v) / al) as u8;
+            [c(0), c(1), c(2), (al / 255) as u8]
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(w: u32, h: u32, px: [u8; 4]) -> AnimationFrame {
+        AnimationFrame::new(w, h, px.repeat((w * h) as usize))
+    }
+
+    #[test]
+    fn test_blend_over_matches_apng_formula() {
+        assert_eq!(
+            blend_over([10, 20, 30, 255], [1, 2, 3, 4]),
+            [10, 20, 30, 255]
+        );
+        assert_eq!(blend_over([10, 20, 30, 0], [1, 2, 3, 4]), [1, 2, 3, 4]);
+        assert_eq!(
+            blend_over([255, 0, 0, 128], [0, 0, 255, 255]),
+            [128, 0, 127, 255]
+        );
+        assert_eq!(
+            blend_over([200, 100, 0, 128], [0, 0, 0, 0]),
+            [200, 100, 0, 128]
+        );
+    }
+
+    #[test]
+    fn test_dispose_background_and_previous() {
+        let mut compositor = Compositor::new(4, 4, [0, 0, 0, 0]);
+        let base = compositor.compose(&solid(4, 4, [9, 9, 9, 255])).unwrap();
+
+        let mut patch = solid(2, 2, [200, 0, 0, 255]).with_offset(1, 1);
+        patch.dispose_mode = DisposeMode::Previous;
+        let shown = compositor.compose(&patch).unwrap();
+        assert_eq!(shown.get_pixel(1, 1).0, [200, 0, 0, 255]);
+
+        let mut cleared = solid(1, 1, [5, 5, 5, 255]).with_offset(3, 3);
+        cleared.dispose_mode = DisposeMode::Background;
+        let shown = compositor.compose(&cleared).unwrap();
+        assert_eq!(shown.get_pixel(1, 1), base.get_pixel(1, 1));
+        assert_eq!(shown.get_pixel(3, 3).0, [5, 5, 5, 255]);
+
+        let shown = compositor.compose(&solid(1, 1, [7, 7, 7, 255])).unwrap();
+        assert_eq!(shown.get_pixel(3, 3).0, [0, 0, 0, 0]);
+        assert_eq!(shown.get_pixel(0, 0).0, [7, 7, 7, 255]);
+    }
+
+    #[test]
+    fn test_rejects_overflowing_offsets() {
+        let compositor = Compositor::new(8, 8, [0, 0, 0, 0]);
+        let frame = solid(4, 4, [1, 2, 3, 255]).with_offset(u32::MAX - 1, 0);
+        assert!(!compositor.contains(&frame));
+
+        let mut animation = Animation::new();
+        animation.add_frame(frame);
+        assert!(animation.canvas_size().is_err());
+        assert!(Compositor::for_animation(&animation).is_err());
+    }
+
+    #[test]
+    fn test_over_blends_onto_canvas() {
+        let mut compositor = Compositor::new(2, 1, [0, 0, 255, 255]);
+        let mut frame = solid(2, 1, [255, 0, 0, 128]);
+        frame.blend_mode = BlendMode::Over;
+        let shown = compositor.compose(&frame).unwrap();
+        assert_eq!(shown.get_pixel(0, 0).0, [128, 0, 127, 255]);
+
+        frame.blend_mode = BlendMode::Source;
+        let shown = compositor.compose(&frame).unwrap();
+        assert_eq!(shown.get_pixel(1, 0).0, [255, 0, 0, 128]);
+    }
+}
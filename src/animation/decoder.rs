@@ -0,0 +1,242 @@
+use super::compositor::Compositor;
+use super::frame::FRAME_HEADER_LEN;
+use super::index::FrameIndex;
+use super::inter::InterFrameCoder;
+use super::{AnimationConfig, AnimationFrame, FrameType};
+use crate::compression::{CompressionConfig, CompressionEngine};
+use crate::decoder::read_metadata_chunk;
+use crate::error::{WkError, WkResult};
+use crate::format::header::{CompressionMode, WkHeader};
+use crate::format::{Chunk, ChunkReader, ChunkType};
+use crate::metadata::WkMetadata;
+use image::RgbaImage;
+use std::io::Read;
+
+#[derive(Debug, Clone)]
+pub struct DecodedFrame {
+    pub index: usize,
+    pub image: RgbaImage,
+    pub timestamp_ms: u32,
+    pub delay_ms: u32,
+    pub is_keyframe: bool,
+}
+
+// Yields full-canvas RGBA frames in display order.
+pub struct WkAnimationDecoder {
+    header: WkHeader,
+    config: AnimationConfig,
+    metadata: WkMetadata,
+    frames: Vec<Vec<u8>>,
+    index: FrameIndex,
+    engine: CompressionEngine,
+    inter: InterFrameCoder,
+    compositor: Compositor,
+    next: usize,
+}
+
+impl WkAnimationDecoder {
+    pub fn new<R: Read>(reader: R) -> WkResult<Self> {
+        let chunks = ChunkReader::new(reader).read_all_chunks()?;
+        Self::from_chunks(chunks)
+    }
+
+    pub(crate) fn from_chunks(chunks: Vec<Chunk>) -> WkResult<Self> {
+        let header_chunk = chunks
+            .iter()
+            .find(|c| c.chunk_type == ChunkType::ImageHeader)
+            .ok_or_else(|| WkError::MissingChunk("IHDR".into()))?;
+        let header = WkHeader::decode(&header_chunk.data)?;
+        if !header.has_animation {
+            return Err(WkError::InvalidFormat("Not an animated WK file".into()));
+        }
+        let anim_chunk = chunks
+            .iter()
+            .find(|c| c.chunk_type == ChunkType::Animation)
+            .ok_or_else(|| WkError::MissingChunk("ANIM".into()))?;
+        let (config, frame_count) = AnimationConfig::decode(&anim_chunk.data)?;
+
+        let mut metadata = WkMetadata::new();
+        let mut gain_map = None;
+        for chunk in &chunks {
+            read_metadata_chunk(chunk, &mut metadata, &mut gain_map)?;
+        }
+
+        // Files written before the FIDX chunk get their index rebuilt from the frame headers.
+        let stored_index = chunks
+            .iter()
+            .find(|c| c.chunk_type == ChunkType::FrameIndex)
+            .map(|c| FrameIndex::decode(&c.data))
+            .transpose()?;
+        let frame_chunks: Vec<Chunk> = chunks
+            .into_iter()
+            .filter(|c| c.chunk_type == ChunkType::FrameData)
+            .collect();
+        if frame_chunks.len() != frame_count as usize {
+            return Err(WkError::MissingChunk("FRMD".into()));
+        }
+        let index = match stored_index {
+            Some(index) if index.len() == frame_chunks.len() => {
+                index.check_keyframes(&frame_chunks, header.width, header.height)?;
+                index
+            }
+            Some(_) => {
+                return Err(WkError::InvalidChunk(
+                    "Frame index does not match the frame count".into(),
+                ))
+            }
+            None => FrameIndex::from_frame_chunks(&frame_chunks, header.width, header.height)?,
+        };
+        let frames: Vec<Vec<u8>> = frame_chunks.into_iter().map(|c| c.data).collect();
+
+        let engine_config = match header.compression_mode {
+            CompressionMode::Lossless => CompressionConfig::lossless(),
+            _ => CompressionConfig::lossy(header.quality),
+        };
+        let compositor = Compositor::new(header.width, header.height, config.background_color);
+        let inter = InterFrameCoder::new(
+            header.compression_mode == CompressionMode::Lossless,
+            header.quality,
+        );
+        Ok(Self {
+            header,
+            config,
+            metadata,
+            frames,
+            index,
+            engine: CompressionEngine::new(engine_config),
+            inter,
+            compositor,
+            next: 0,
+        })
+    }
+
+    pub fn header(&self) -> &WkHeader {
+        &self.header
+    }
+
+    pub fn metadata(&self) -> &WkMetadata {
+        &self.metadata
+    }
+
+    pub fn config(&self) -> &AnimationConfig {
+        &self.config
+    }
+
+    pub fn loop_count(&self) -> u32 {
+        self.config.loop_count
+    }
+
+    pub fn frame_count(&self) -> usize {
+        self.frames.len()
+    }
+
+    pub fn frame_index(&self) -> &FrameIndex {
+        &self.index
+    }
+
+    pub fn duration_ms(&self) -> u32 {
+        match (self.index.entries.last(), self.frames.last()) {
+            (Some(entry), Some(data)) => AnimationFrame::decode_header(data)
+                .map_or(entry.timestamp_ms, |(frame, _)| {
+                    entry.timestamp_ms.saturating_add(frame.delay_ms)
+                }),
+            _ => 0,
+        }
+    }
+
+    // Decodes frame `n`, starting from the nearest keyframe at or before it
+    // unless the frames already decoded lead up to it. Iteration continues
+    // from the frame after `n`.
+    pub fn seek_to_frame(&mut self, n: usize) -> WkResult<DecodedFrame> {
+        if n >= self.frames.len() {
+            return Err(WkError::DecodingError(format!(
+                "Frame {} is past the last frame ({})",
+                n,
+                self.frames.len()
+            )));
+        }
+        let keyframe = self.index.keyframe_before(n);
+        if self.next <= keyframe || self.next > n {
+            self.compositor.reset();
+            self.next = keyframe;
+        }
+        while self.next < n {
+            self.decode_frame(self.next)?;
+            self.next += 1;
+        }
+        let frame = self.decode_frame(n)?;
+        self.next = n + 1;
+        Ok(frame)
+    }
+
+    // Decodes the frame on screen at `time_ms`; later times give the last frame.
+    pub fn seek_to_time(&mut self, time_ms: u32) -> WkResult<DecodedFrame> {
+        let n = self
+            .index
+            .frame_at_time(time_ms)
+            .ok_or_else(|| WkError::MissingChunk("FRMD".into()))?;
+        self.seek_to_frame(n)
+    }
+
+    fn decode_frame(&mut self, index: usize) -> WkResult<DecodedFrame> {
+        let data = &self.frames[index];
+        let (frame, frame_type) = AnimationFrame::decode_header(data)?;
+        let (w, h) = (frame.width as usize, frame.height as usize);
+        if !self.compositor.contains(&frame) {
+            return Err(WkError::DecodingError(format!(
+                "Frame {} lies outside the canvas",
+                index
+            )));
+        }
+
+        let channels = self.header.color_type.channels() as usize;
+        let payload = &data[FRAME_HEADER_LEN..];
+        self.compositor.dispose_previous();
+        // Rate-controlled lossy frames carry their own quality.
+        let quality = AnimationFrame::header_quality(data).filter(|&q| {
+            q != self.header.quality && self.header.compression_mode != CompressionMode::Lossless
+        });
+        let pixels = match (frame_type, quality) {
+            (FrameType::IFrame, None) => {
+                self.engine
+                    .decompress(payload, w, h, channels, self.header.compression_mode)?
+            }
+            (FrameType::IFrame, Some(q)) => CompressionEngine::new(CompressionConfig::lossy(q))
+                .decompress(payload, w, h, channels, self.header.compression_mode)?,
+            (FrameType::PFrame, quality) => {
+                let reference = self.compositor.region(&frame, channels);
+                match quality {
+                    Some(q) => InterFrameCoder::new(false, q)
+                        .decode(payload, &reference, w, h, channels)?,
+                    None => self.inter.decode(payload, &reference, w, h, channels)?,
+                }
+            }
+        };
+        if pixels.len() != w * h * channels {
+            return Err(WkError::DecodingError("Frame size mismatch".into()));
+        }
+        self.compositor.draw(&frame, &pixels, channels);
+
+        let image = self.compositor.to_image();
+        Ok(DecodedFrame {
+            index,
+            image,
+            timestamp_ms: self.index.entries[index].timestamp_ms,
+            delay_ms: frame.delay_ms,
+            is_keyframe: frame.is_keyframe,
+        })
+    }
+}
+
+impl Iterator for WkAnimationDecoder {
+    type Item = WkResult<DecodedFrame>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.next >= self.frames.len() {
+            return None;
+        }
+        let frame = self.decode_frame(self.next);
+        self.next += 1;
+        Some(frame)
+    }
+}
@@ -0,0 +1,465 @@
+use super::compositor::Compositor;
+use super::frame::FRAME_HEADER_LEN;
+use super::index::{FrameIndex, FrameIndexEntry};
+use super::inter::InterFrameCoder;
+use super::motion::{MotionEstimator, MotionVector};
+use super::optimize::FrameOptimizer;
+use super::rate::{RateControl, RateController};
+use super::scene::{FramePlan, KeyframePlacement, KeyframeReason, Lookahead, SceneMetrics};
+use super::{Animation, AnimationFrame, FrameType};
+use crate::compression::{
+    CompressionConfig, CompressionEngine, EffortProfile, LosslessEngine, MAX_EFFORT,
+};
+use crate::encoder::metadata_chunks;
+use crate::error::{WkError, WkResult};
+use crate::format::header::{ColorType, CompressionMode, WkHeader};
+use crate::format::{Chunk, ChunkType, ChunkWriter};
+use crate::metadata::WkMetadata;
+use std::io::Write;
+
+// Codings per frame before rate control settles for the last one. A frame
+// over the cap keeps being re-coded at lower qualities regardless.
+const MAX_RATE_ATTEMPTS: usize = 4;
+// Whole-animation passes that miss the size target before every frame drops
+// to the lowest quality.
+const MAX_BUDGET_PASSES: usize = 3;
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct FrameStats {
+    pub frame_type: FrameType,
+    pub keyframe_reason: Option<KeyframeReason>,
+    // Present when the look-ahead measured the frame: under automatic
+    // placement or rate control.
+    pub scene: Option<SceneMetrics>,
+    pub quality: u8,
+    // x, y, width and height of the coded rectangle.
+    pub rect: (u32, u32, u32, u32),
+    // Identical source frames folded into this one.
+    pub merged_frames: usize,
+    pub bytes: usize,
+}
+
+#[derive(Debug, Clone, Default, PartialEq)]
+pub struct AnimationEncodeStats {
+    pub frames: Vec<FrameStats>,
+}
+
+impl AnimationEncodeStats {
+    pub fn keyframes(&self) -> Vec<usize> {
+        (0..self.frames.len())
+            .filter(|&i| self.frames[i].frame_type == FrameType::IFrame)
+            .collect()
+    }
+
+    pub fn total_frame_bytes(&self) -> usize {
+        self.frames.iter().map(|f| f.bytes).sum()
+    }
+}
+
+pub struct WkAnimationEncoder {
+    config: CompressionConfig,
+    metadata: WkMetadata,
+    keyframes: KeyframePlacement,
+    optimize_frames: bool,
+    rate_control: Option<RateControl>,
+}
+
+impl WkAnimationEncoder {
+    pub fn new() -> Self {
+        Self {
+            config: CompressionConfig::default(),
+            metadata: WkMetadata::new(),
+            keyframes: KeyframePlacement::default(),
+            optimize_frames: true,
+            rate_control: None,
+        }
+    }
+
+    pub fn lossless() -> Self {
+        Self {
+            config: CompressionConfig::lossless(),
+            metadata: WkMetadata::new(),
+            keyframes: KeyframePlacement::default(),
+            optimize_frames: true,
+            rate_control: None,
+        }
+    }
+
+    pub fn lossy(quality: u8) -> Self {
+        Self {
+            config: CompressionConfig::lossy(quality),
+            metadata: WkMetadata::new(),
+            keyframes: KeyframePlacement::default(),
+            optimize_frames: true,
+            rate_control: None,
+        }
+    }
+
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.config.quality = quality.clamp(1, 100);
+        if quality == 100 {
+            self.config.mode = CompressionMode::Lossless;
+        }
+        self
+    }
+
+    pub fn with_metadata(mut self, metadata: WkMetadata) -> Self {
+        self.metadata = metadata;
+        self
+    }
+
+    pub fn with_lossless_engine(mut self, engine: LosslessEngine) -> Self {
+        self.config.lossless_engine = Some(engine);
+        self
+    }
+
+    pub fn with_effort(mut self, effort: u8) -> Self {
+        self.config.effort = effort.min(MAX_EFFORT);
+        self
+    }
+
+    // Automatic placement (the default) adds scene cuts and interval
+    // keyframes to the frames marked `is_keyframe`.
+    pub fn with_keyframe_placement(mut self, placement: KeyframePlacement) -> Self {
+        self.keyframes = placement;
+        self
+    }
+
+    // On by default: frames are cropped to what changed and duplicates merged,
+    // so the decoder may return fewer frames than the animation holds.
+    pub fn with_frame_optimization(mut self, optimize: bool) -> Self {
+        self.optimize_frames = optimize;
+        self
+    }
+
+    // Lossy only: each frame's quality is chosen to meet the target, and the
+    // encoder quality becomes the one recorded in the header.
+    pub fn with_rate_control(mut self, rate_control: RateControl) -> Self {
+        self.rate_control = Some(rate_control);
+        self
+    }
+
+    // Frames hold RGBA pixels; the canvas covers every frame rectangle.
+    pub fn encode<W: Write>(&self, animation: &Animation, writer: W) -> WkResult<()> {
+        self.encode_with_stats(animation, writer).map(|_| ())
+    }
+
+    pub fn encode_with_stats<W: Write>(
+        &self,
+        animation: &Animation,
+        mut writer: W,
+    ) -> WkResult<AnimationEncodeStats> {
+        if animation.frames.is_empty() {
+            return Err(WkError::EncodingError("Animation has no frames".into()));
+        }
+        for frame in &animation.frames {
+            if frame.width == 0
+                || frame.height == 0
+                || frame.data.len() != frame.width as usize * frame.height as usize * 4
+            {
+                return Err(WkError::EncodingError(format!(
+                    "Frame data does not match {}x{} RGBA",
+                    frame.width, frame.height
+                )));
+            }
+        }
+        if self.rate_control.is_some() && self.config.mode == CompressionMode::Lossless {
+            return Err(WkError::EncodingError(
+                "Rate control needs lossy compression".into(),
+            ));
+        }
+        let rate_control = self.rate_control.map(RateControl::validated).transpose()?;
+
+        let (width, height) = animation.canvas_size()?;
+        let analysis = MotionEstimator::for_effort(self.config.effort).with_subpixel(false);
+        let lookahead = Lookahead::run(
+            animation,
+            self.optimize_frames,
+            self.keyframes,
+            &analysis,
+            rate_control.is_some(),
+        )?;
+        let opaque = if self.optimize_frames {
+            lookahead.opaque
+        } else {
+            animation
+                .frames
+                .iter()
+                .all(|f| f.data.chunks_exact(4).all(|p| p[3] == 255))
+        };
+        let color_type = if opaque {
+            ColorType::Rgb
+        } else {
+            ColorType::Rgba
+        };
+
+        let header = WkHeader {
+            width,
+            height,
+            color_type,
+            compression_mode: self.config.mode,
+            quality: self.config.quality,
+            has_alpha: !opaque,
+            has_animation: true,
+            bit_depth: 8,
+        };
+        let metadata = metadata_chunks(&self.metadata)?;
+
+        let Some(mut control) = rate_control else {
+            let (frame_chunks, stats) =
+                self.code_frames(animation, &header, &lookahead.plans, None)?;
+            self.write_file(writer, &header, &metadata, animation, &frame_chunks)?;
+            return Ok(stats);
+        };
+
+        // Everything but the frame payloads has a known size up front.
+        let frame_count = lookahead.plans.len();
+        let index_len = FrameIndex {
+            entries: vec![FrameIndexEntry::default(); frame_count],
+        }
+        .encode()
+        .len();
+        let overhead = (8
+            + Chunk::new(ChunkType::ImageHeader, header.encode()).encoded_len()
+            + metadata.iter().map(Chunk::encoded_len).sum::<usize>()
+            + Chunk::new(ChunkType::Animation, animation.config.encode(0)).encoded_len()
+            + 12
+            + index_len
+            + 12
+            + frame_count * 12) as u64;
+        let target = control.target_bytes(animation.total_duration_ms() as u64);
+        let room = target.saturating_sub(overhead);
+
+        // The target is a hard limit. A pass that overshoots is coded again
+        // with its budget shrunk by the overshoot, and the last pass codes
+        // every frame at the lowest quality allowed.
+        let mut budget = room;
+        let mut pass = 1;
+        loop {
+            let rate = RateController::new(control, &lookahead.plans, width, height, budget)?;
+            let (frame_chunks, stats) =
+                self.code_frames(animation, &header, &lookahead.plans, Some(rate))?;
+            let mut file = Vec::new();
+            self.write_file(&mut file, &header, &metadata, animation, &frame_chunks)?;
+            if file.len() as u64 <= target {
+                writer.write_all(&file)?;
+                return Ok(stats);
+            }
+            if control.max_quality == control.min_quality {
+                return Err(WkError::EncodingError(format!(
+                    "Rate target of {} bytes is too small: {} bytes at quality {}",
+                    target,
+                    file.len(),
+                    control.min_quality
+                )));
+            }
+            if pass == MAX_BUDGET_PASSES {
+                control.max_quality = control.min_quality;
+            } else {
+                let payload = (file.len() as u64).saturating_sub(overhead).max(1);
+                budget = (budget as f64 * room as f64 / payload as f64 * 0.95).max(1.0) as u64;
+            }
+            pass += 1;
+        }
+    }
+
+    // Codes every frame in order: each P-frame predicts from the composited
+    // canvas as the decoder will have reconstructed it.
+    fn code_frames(
+        &self,
+        animation: &Animation,
+        header: &WkHeader,
+        plans: &[FramePlan],
+        mut rate: Option<RateController>,
+    ) -> WkResult<(Vec<Chunk>, AnimationEncodeStats)> {
+        let (width, height) = (header.width, header.height);
+        let opaque = header.color_type == ColorType::Rgb;
+        let channels = header.color_type.channels() as usize;
+        let lossless = self.config.mode == CompressionMode::Lossless;
+        let estimator = MotionEstimator::for_effort(self.config.effort);
+        let deflate_level = EffortProfile::from_effort(self.config.effort).deflate_level;
+        let mut compositor = Compositor::new(width, height, animation.config.background_color);
+        let mut optimizer = if self.optimize_frames {
+            Some(FrameOptimizer::new(animation)?)
+        } else {
+            None
+        };
+        let mut stats = AnimationEncodeStats::default();
+
+        let code = |pixels: &[u8],
+                    reference: &[u8],
+                    w: usize,
+                    h: usize,
+                    keyframe: bool,
+                    quality: u8,
+                    searched: &[MotionVector]|
+         -> WkResult<(FrameType, Vec<u8>, Vec<u8>)> {
+            if keyframe {
+                let engine = CompressionEngine::new(CompressionConfig {
+                    quality,
+                    ..self.config.clone()
+                });
+                let payload = engine.compress(pixels, w, h, channels)?;
+                let recon = if lossless {
+                    pixels.to_vec()
+                } else {
+                    engine.decompress(&payload, w, h, channels, self.config.mode)?
+                };
+                Ok((FrameType::IFrame, payload, recon))
+            } else {
+                let (payload, recon) = InterFrameCoder::new(lossless, quality).encode(
+                    pixels,
+                    reference,
+                    w,
+                    h,
+                    channels,
+                    &estimator,
+                    deflate_level,
+                    searched,
+                );
+                Ok((FrameType::PFrame, payload, recon))
+            }
+        };
+        let to_pixels = |frame: &AnimationFrame| -> Vec<u8> {
+            if opaque {
+                frame
+                    .data
+                    .chunks_exact(4)
+                    .flat_map(|p| [p[0], p[1], p[2]])
+                    .collect()
+            } else {
+                frame.data.clone()
+            }
+        };
+
+        let mut frame_chunks = Vec::with_capacity(plans.len());
+        for (index, plan) in plans.iter().enumerate() {
+            let mut frame = match optimizer {
+                Some(ref optimizer) => optimizer.changed_frame(),
+                None => animation.frames[index].clone(),
+            };
+            let mut pixels = to_pixels(&frame);
+            compositor.dispose_previous();
+            let mut reference = compositor.region(&frame, channels);
+            let keyframe = plan.keyframe_reason.is_some();
+            let mut over = None;
+            let mut merged_frames = 1;
+            if let Some(ref mut optimizer) = optimizer {
+                // Keyframes repaint the whole canvas so decoding can start there.
+                if keyframe && (frame.width, frame.height) != (width, height) {
+                    frame = optimizer.full_frame();
+                    pixels = to_pixels(&frame);
+                    reference = compositor.region(&frame, channels);
+                }
+                // Lossy coding would leave the transparent pixels slightly
+                // opaque and tint the canvas, so only lossless frames with
+                // an alpha channel get the Over candidate.
+                if lossless && !opaque && !keyframe {
+                    over = optimizer.over_frame(&frame);
+                }
+                merged_frames = optimizer.merged();
+                optimizer.finish(&mut frame, keyframe)?;
+            }
+
+            let (w, h) = (frame.width as usize, frame.height as usize);
+            // The look-ahead searched the whole canvas, so its field only
+            // fits frames that cover it.
+            let searched: &[MotionVector] = if (frame.width, frame.height) == (width, height) {
+                &plan.field
+            } else {
+                &[]
+            };
+            let mut quality = rate
+                .as_ref()
+                .map_or(self.config.quality, |r| r.choose_quality(index, keyframe));
+            let mut attempts = 1;
+            let (mut frame_type, mut payload, mut recon) = loop {
+                let coded = code(&pixels, &reference, w, h, keyframe, quality, searched)?;
+                let Some(ref mut rate) = rate else {
+                    break coded;
+                };
+                let bytes = FRAME_HEADER_LEN + coded.1.len();
+                let over_cap = rate.over_cap(bytes);
+                match rate.observe(index, keyframe, quality, bytes) {
+                    Some(retry) if over_cap || attempts < MAX_RATE_ATTEMPTS => {
+                        quality = retry;
+                        attempts += 1;
+                    }
+                    _ if over_cap => {
+                        return Err(WkError::EncodingError(format!(
+                            "Frame {} is {} bytes at quality {}, over the frame size cap",
+                            index, bytes, quality
+                        )));
+                    }
+                    _ => {
+                        rate.commit(bytes);
+                        break coded;
+                    }
+                }
+            };
+            // Transparent pixels are cheap to code intra but predict badly
+            // from the canvas, so the Over candidate is an I-frame. It blends,
+            // so it never counts as a keyframe in the index.
+            if let Some(mut candidate) = over {
+                candidate.dispose_mode = frame.dispose_mode;
+                let coded = code(&candidate.data, &reference, w, h, true, quality, &[])?;
+                if coded.1.len() < payload.len() {
+                    frame = candidate;
+                    (frame_type, payload, recon) = coded;
+                }
+            }
+            compositor.draw(&frame, &recon, channels);
+
+            let header_quality = if rate.is_some() { quality } else { 0 };
+            let mut data = frame.encode_header(frame_type, header_quality);
+            data.extend(payload);
+            stats.frames.push(FrameStats {
+                frame_type,
+                keyframe_reason: plan.keyframe_reason,
+                scene: plan.scene,
+                quality,
+                rect: (frame.x_offset, frame.y_offset, frame.width, frame.height),
+                merged_frames,
+                bytes: data.len(),
+            });
+            frame_chunks.push(Chunk::new(ChunkType::FrameData, data));
+        }
+        Ok((frame_chunks, stats))
+    }
+
+    fn write_file<W: Write>(
+        &self,
+        writer: W,
+        header: &WkHeader,
+        metadata: &[Chunk],
+        animation: &Animation,
+        frame_chunks: &[Chunk],
+    ) -> WkResult<()> {
+        let index = FrameIndex::from_frame_chunks(frame_chunks, header.width, header.height)?;
+        let mut chunk_writer = ChunkWriter::new(writer);
+        chunk_writer.write_chunk(&Chunk::new(ChunkType::ImageHeader, header.encode()))?;
+        for chunk in metadata {
+            chunk_writer.write_chunk(chunk)?;
+        }
+        let anim = animation.config.encode(frame_chunks.len() as u32);
+        chunk_writer.write_chunk(&Chunk::new(ChunkType::Animation, anim))?;
+        chunk_writer.write_chunk(&Chunk::new(ChunkType::FrameIndex, index.encode()))?;
+        for chunk in frame_chunks {
+            chunk_writer.write_chunk(chunk)?;
+        }
+        chunk_writer.finish()?;
+        Ok(())
+    }
+
+    pub fn encode_to_vec(&self, animation: &Animation) -> WkResult<Vec<u8>> {
+        let mut buffer = Vec::new();
+        self.encode(animation, &mut buffer)?;
+        Ok(buffer)
+    }
+}
+
+impl Default for WkAnimationEncoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
@@ -1,5 +1,10 @@
+use super::FrameType;
+use crate::error::{WkError, WkResult};
+use byteorder::{ByteOrder, LittleEndian};
 use serde::{Deserialize, Serialize};
 
+pub(crate) const FRAME_HEADER_LEN: usize = 24;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
 pub enum BlendMode {
     Source,
@@ -31,6 +36,27 @@ pub struct AnimationConfig {
     pub background_color: [u8; 4],
 }
 
+impl AnimationConfig {
+    pub(crate) fn encode(&self, frame_count: u32) -> Vec<u8> {
+        let mut data = vec![0u8; 12];
+        LittleEndian::write_u32(&mut data[0..4], self.loop_count);
+        data[4..8].copy_from_slice(&self.background_color);
+        LittleEndian::write_u32(&mut data[8..12], frame_count);
+        data
+    }
+
+    pub(crate) fn decode(data: &[u8]) -> WkResult<(Self, u32)> {
+        if data.len() < 12 {
+            return Err(WkError::InvalidChunk("Animation chunk too short".into()));
+        }
+        let config = Self {
+            loop_count: LittleEndian::read_u32(&data[0..4]),
+            background_color: [data[4], data[5], data[6], data[7]],
+        };
+        Ok((config, LittleEndian::read_u32(&data[8..12])))
+    }
+}
+
 impl Default for AnimationConfig {
     fn default() -> Self {
         Self {
@@ -63,7 +89,8 @@ impl AnimationFrame {
             height,
             blend_mode: BlendMode::default(),
             dispose_mode: DisposeMode::default(),
-            is_keyframe: true,
+            // Set by `Animation::add_keyframe` to request an I-frame.
+            is_keyframe: false,
             data,
         }
     }
@@ -79,6 +106,15 @@ impl AnimationFrame {
         self
     }
 
+    // Right and bottom edges, or None when offsets from a crafted header
+    // would overflow.
+    pub fn extent(&self) -> Option<(u32, u32)> {
+        Some((
+            self.x_offset.checked_add(self.width)?,
+            self.y_offset.checked_add(self.height)?,
+        ))
+    }
+
     pub fn with_blend_mode(mut self, mode: BlendMode) -> Self {
         self.blend_mode = mode;
         self
@@ -88,4 +124,79 @@ impl AnimationFrame {
         self.dispose_mode = mode;
         self
     }
+
+    // FRMD layout: type, blend, dispose, quality (0 = the IHDR quality), then
+    // delay, x, y, width, height as u32.
+    pub(crate) fn encode_header(&self, frame_type: FrameType, quality: u8) -> Vec<u8> {
+        let mut data = vec![0u8; FRAME_HEADER_LEN];
+        data[0] = frame_type as u8;
+        data[1] = self.blend_mode as u8;
+        data[2] = self.dispose_mode as u8;
+        data[3] = quality;
+        for (field, value) in data[4..].chunks_exact_mut(4).zip([
+            self.delay_ms,
+            self.x_offset,
+            self.y_offset,
+            self.width,
+            self.height,
+        ]) {
+            LittleEndian::write_u32(field, value);
+        }
+        data
+    }
+
+    // The quality a frame was coded at, when it differs from the file's.
+    pub(crate) fn header_quality(data: &[u8]) -> Option<u8> {
+        data.get(3).copied().filter(|&q| q != 0)
+    }
+
+    pub(crate) fn decode_header(data: &[u8]) -> WkResult<(Self, FrameType)> {
+        if data.len() < FRAME_HEADER_LEN {
+            return Err(WkError::InvalidChunk("Frame header too short".into()));
+        }
+        let frame_type = match data[0] {
+            0 => FrameType::IFrame,
+            1 => FrameType::PFrame,
+            other => {
+                return Err(WkError::InvalidChunk(format!(
+                    "Unknown frame type: {}",
+                    other
+                )))
+            }
+        };
+        let blend_mode = match data[1] {
+            0 => BlendMode::Source,
+            1 => BlendMode::Over,
+            other => {
+                return Err(WkError::InvalidChunk(format!(
+                    "Unknown blend mode: {}",
+                    other
+                )))
+            }
+        };
+        let dispose_mode = match data[2] {
+            0 => DisposeMode::None,
+            1 => DisposeMode::Background,
+            2 => DisposeMode::Previous,
+            other => {
+                return Err(WkError::InvalidChunk(format!(
+                    "Unknown dispose mode: {}",
+                    other
+                )))
+            }
+        };
+        let field = |i: usize| LittleEndian::read_u32(&data[4 + i * 4..8 + i * 4]);
+        let frame = Self {
+            delay_ms: field(0),
+            x_offset: field(1),
+            y_offset: field(2),
+            width: field(3),
+            height: field(4),
+            blend_mode,
+            dispose_mode,
+            is_keyframe: frame_type == FrameType::IFrame,
+            data: Vec::new(),
+        };
+        Ok((frame, frame_type))
+    }
 }
@@ -0,0 +1,191 @@
+use super::{AnimationFrame, BlendMode, DisposeMode, FrameType};
+use crate::error::{WkError, WkResult};
+use crate::format::Chunk;
+use byteorder::{ByteOrder, LittleEndian};
+
+const ENTRY_LEN: usize = 16;
+const FLAG_KEYFRAME: u8 = 1;
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct FrameIndexEntry {
+    // Byte offset of the FRMD chunk, counted from the first FRMD chunk.
+    pub offset: u64,
+    pub timestamp_ms: u32,
+    // Decoding can start here without any earlier frame.
+    pub is_keyframe: bool,
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct FrameIndex {
+    pub entries: Vec<FrameIndexEntry>,
+}
+
+impl FrameIndex {
+    // Builds the index for FRMD chunks written back to back.
+    pub(crate) fn from_frame_chunks(chunks: &[Chunk], width: u32, height: u32) -> WkResult<Self> {
+        let mut entries = Vec::with_capacity(chunks.len());
+        let (mut offset, mut timestamp_ms) = (0u64, 0u32);
+        for (index, chunk) in chunks.iter().enumerate() {
+            let (frame, frame_type) = AnimationFrame::decode_header(&chunk.data)?;
+            entries.push(FrameIndexEntry {
+                offset,
+                timestamp_ms,
+                is_keyframe: index == 0 || is_random_access(&frame, frame_type, width, height),
+            });
+            offset += chunk.encoded_len() as u64;
+            timestamp_ms = timestamp_ms.saturating_add(frame.delay_ms);
+        }
+        Ok(Self { entries })
+    }
+
+    // A stored index is only trusted where the frame headers agree: every
+    // keyframe after the first must really be a random access point, or
+    // seeking would start from a frame that depends on earlier ones.
+    pub(crate) fn check_keyframes(
+        &self,
+        chunks: &[Chunk],
+        width: u32,
+        height: u32,
+    ) -> WkResult<()> {
+        for (index, (entry, chunk)) in self.entries.iter().zip(chunks).enumerate().skip(1) {
+            if !entry.is_keyframe {
+                continue;
+            }
+            let (frame, frame_type) = AnimationFrame::decode_header(&chunk.data)?;
+            if !is_random_access(&frame, frame_type, width, height) {
+                return Err(WkError::InvalidChunk(format!(
+                    "Frame index marks frame {} as a keyframe but it depends on earlier frames",
+                    index
+                )));
+            }
+        }
+        Ok(())
+    }
+
+    // FIDX layout: frame count as u32, then per frame the offset as u64,
+    // the timestamp as u32, a flags byte and three reserved bytes.
+    pub fn encode(&self) -> Vec<u8> {
+        let mut data = vec![0u8; 4 + self.entries.len() * ENTRY_LEN];
+        LittleEndian::write_u32(&mut data[0..4], self.entries.len() as u32);
+        for (out, entry) in data[4..].chunks_exact_mut(ENTRY_LEN).zip(&self.entries) {
+            LittleEndian::write_u64(&mut out[0..8], entry.offset);
+            LittleEndian::write_u32(&mut out[8..12], entry.timestamp_ms);
+            out[12] = if entry.is_keyframe { FLAG_KEYFRAME } else { 0 };
+        }
+        data
+    }
+
+    pub fn decode(data: &[u8]) -> WkResult<Self> {
+        if data.len() < 4 {
+            return Err(WkError::InvalidChunk("Frame index too short".into()));
+        }
+        let count = LittleEndian::read_u32(&data[0..4]) as usize;
+        if data.len() != 4 + count * ENTRY_LEN {
+            return Err(WkError::InvalidChunk(
+                "Frame index does not match its frame count".into(),
+            ));
+        }
+        let entries: Vec<FrameIndexEntry> = data[4..]
+            .chunks_exact(ENTRY_LEN)
+            .map(|entry| FrameIndexEntry {
+                offset: LittleEndian::read_u64(&entry[0..8]),
+                timestamp_ms: LittleEndian::read_u32(&entry[8..12]),
+                is_keyframe: entry[12] & FLAG_KEYFRAME != 0,
+            })
+            .collect();
+        if entries.first().is_some_and(|e| !e.is_keyframe) {
+            return Err(WkError::InvalidChunk(
+                "Frame index does not start with a keyframe".into(),
+            ));
+        }
+        Ok(Self { entries })
+    }
+
+    pub fn len(&self) -> usize {
+        self.entries.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    pub fn keyframe_before(&self, frame: usize) -> usize {
+        (0..=frame.min(self.entries.len().saturating_sub(1)))
+            .rev()
+            .find(|&i| self.entries[i].is_keyframe)
+            .unwrap_or(0)
+    }
+
+    // The frame on screen at `time_ms`; times past the end give the last frame.
+    pub fn frame_at_time(&self, time_ms: u32) -> Option<usize> {
+        if self.entries.is_empty() {
+            return None;
+        }
+        let after = self.entries.partition_point(|e| e.timestamp_ms <= time_ms);
+        Some(after.saturating_sub(1))
+    }
+}
+
+// An intra frame that repaints the whole canvas, and is not restored
+// afterwards, leaves no trace of the frames before it.
+pub(crate) fn is_random_access(
+    frame: &AnimationFrame,
+    frame_type: FrameType,
+    width: u32,
+    height: u32,
+) -> bool {
+    frame_type == FrameType::IFrame
+        && frame.x_offset == 0
+        && frame.y_offset == 0
+        && frame.width == width
+        && frame.height == height
+        && frame.blend_mode == BlendMode::Source
+        && frame.dispose_mode != DisposeMode::Previous
+}
+
+#[cfg(test)]
+mod tests {
+    use super::super::frame::FRAME_HEADER_LEN;
+    use super::*;
+    use crate::format::ChunkType;
+
+    #[test]
+    fn test_index_roundtrip_and_lookup() {
+        let frame = |w, x, frame_type| {
+            let mut data = AnimationFrame::new(w, 4, Vec::new())
+                .with_offset(x, 0)
+                .with_delay(40)
+                .encode_header(frame_type, 0);
+            data.resize(FRAME_HEADER_LEN + 10, 0);
+            Chunk::new(ChunkType::FrameData, data)
+        };
+        let chunks = [
+            frame(8, 0, FrameType::IFrame),
+            frame(8, 0, FrameType::PFrame),
+            frame(4, 4, FrameType::IFrame),
+            frame(8, 0, FrameType::IFrame),
+            frame(8, 0, FrameType::PFrame),
+        ];
+        let index = FrameIndex::from_frame_chunks(&chunks, 8, 4).unwrap();
+        let keyframes: Vec<bool> = index.entries.iter().map(|e| e.is_keyframe).collect();
+        assert_eq!(keyframes, [true, false, false, true, false]);
+        assert_eq!(index.entries[3].offset, 3 * chunks[0].encoded_len() as u64);
+        assert_eq!(index.entries[4].timestamp_ms, 160);
+
+        assert_eq!(FrameIndex::decode(&index.encode()).unwrap(), index);
+        assert_eq!(index.keyframe_before(2), 0);
+        assert_eq!(index.keyframe_before(4), 3);
+        assert_eq!(index.frame_at_time(0), Some(0));
+        assert_eq!(index.frame_at_time(119), Some(2));
+        assert_eq!(index.frame_at_time(120), Some(3));
+        assert_eq!(index.frame_at_time(10_000), Some(4));
+
+        let mut forged = index.clone();
+        forged.entries[4].is_keyframe = true;
+        assert!(forged.check_keyframes(&chunks, 8, 4).is_err());
+        forged.entries[4].is_keyframe = false;
+        forged.entries[2].is_keyframe = true;
+        assert!(forged.check_keyframes(&chunks, 8, 4).is_err());
+        assert!(index.check_keyframes(&chunks, 8, 4).is_ok());
+    }
+}
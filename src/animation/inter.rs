@@ -0,0 +1,481 @@
+use super::motion::{
+    apply_motion_compensation, InterpolationFilter, MotionEstimator, MotionVector,
+};
+use crate::compression::arithmetic_coder::{
+    compress_coefficients_at, decompress_coefficients, read_block, write_block, BitReader,
+    BitWriter,
+};
+use crate::compression::color::{
+    convert_rgb_to_ycbcr_image, convert_ycbcr_to_rgb_image, ColorMatrix, ColorRange, ColorSpace,
+};
+use crate::compression::{
+    int_dct_8x8, int_idct_8x8, zigzag_scan, zigzag_unscan, AdaptiveQuantizer, QuantTable,
+};
+use crate::error::{WkError, WkResult};
+
+pub(crate) const MACROBLOCK: usize = 16;
+
+// P-frame payload: the interpolation filter byte, then a zlib-packed
+// bitstream holding, per 16x16 macroblock in raster order, a skip flag, the
+// motion vector delta against the median of the left, top and top-right
+// neighbours, and one residual block per 8x8 sub-block and plane. Lossy
+// residuals are quantised DCT coefficients in YCbCr; lossless residuals are
+// exact RGB(A) differences.
+pub(crate) struct InterFrameCoder {
+    lossless: bool,
+    quantizer: AdaptiveQuantizer,
+    tables: [QuantTable; 2],
+    space: ColorSpace,
+}
+
+impl InterFrameCoder {
+    pub(crate) fn new(lossless: bool, quality: u8) -> Self {
+        let quantizer = AdaptiveQuantizer::new(quality);
+        let tables = [
+            quantizer.get_table(quality, false),
+            quantizer.get_table(quality, true),
+        ];
+        Self {
+            lossless,
+            quantizer,
+            tables,
+            space: ColorSpace::new(ColorMatrix::Bt601, ColorRange::Full),
+        }
+    }
+
+    // Returns the payload and the reconstruction the decoder will produce.
+    // A full-pel field per macroblock that was already searched, such as the
+    // look-ahead's, is only refined; with none the field is searched here.
+    pub(crate) fn encode(
+        &self,
+        current: &[u8],
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+        estimator: &MotionEstimator,
+        deflate_level: u32,
+        searched: &[MotionVector],
+    ) -> (Vec<u8>, Vec<u8>) {
+        let cur_luma = luma_plane(current, channels);
+        let ref_luma = luma_plane(reference, channels);
+        let mb_w = width.div_ceil(MACROBLOCK);
+        let mb_h = height.div_ceil(MACROBLOCK);
+
+        // The field is found in parallel; choosing between each found
+        // vector and the median predictor depends on earlier choices.
+        let field = if searched.len() == mb_w * mb_h {
+            estimator.refine_field(&cur_luma, &ref_luma, width, height, MACROBLOCK, searched)
+        } else {
+            estimator.estimate_field(&cur_luma, &ref_luma, width, height, MACROBLOCK, &[])
+        };
+        let mut mvs = Vec::with_capacity(mb_w * mb_h);
+        for mby in 0..mb_h {
+            for mbx in 0..mb_w {
+                let (bx, by) = (mbx * MACROBLOCK, mby * MACROBLOCK);
+                let pred = predicted_mv(&mvs, mb_w, mbx, mby);
+                let found = field[mby * mb_w + mbx];
+                let sad = |mv: MotionVector| {
+                    estimator.block_sad(&cur_luma, &ref_luma, width, height, bx, by, MACROBLOCK, mv)
+                };
+                mvs.push(if sad(pred) <= sad(found) { pred } else { found });
+            }
+        }
+
+        let cur_planes = self.split_planes(current, width, height, channels);
+        let filter = estimator.filter();
+        let predicted = self.predict(reference, width, height, channels, &mvs, filter);
+        let mut recon = predicted.clone();
+
+        let mut writer = BitWriter::new();
+        let mut coded = Vec::with_capacity(mb_w * mb_h);
+        for mby in 0..mb_h {
+            for mbx in 0..mb_w {
+                let index = mby * mb_w + mbx;
+                let pred = predicted_mv(&mvs[..index], mb_w, mbx, mby);
+                let mut blocks = Vec::new();
+                for (p, (cur, pred_plane)) in cur_planes.iter().zip(&predicted).enumerate() {
+                    for (x0, y0) in sub_blocks(mbx, mby, width, height) {
+                        let residual = residual_block(cur, pred_plane, width, height, x0, y0);
+                        blocks.push(self.forward(&residual, p));
+                    }
+                }
+                let skip = mvs[index] == pred && blocks.iter().all(|b| b.iter().all(|&c| c == 0));
+                writer.write_bit(skip);
+                if skip {
+                    continue;
+                }
+                write_signed(&mut writer, mvs[index].x as i32 - pred.x as i32);
+                write_signed(&mut writer, mvs[index].y as i32 - pred.y as i32);
+                for block in &blocks {
+                    write_block(&mut writer, block);
+                }
+                coded.push((mbx, mby, blocks));
+            }
+        }
+
+        let mut payload = vec![filter as u8];
+        payload.extend(compress_coefficients_at(&writer.finish(), deflate_level));
+        if self.lossless {
+            return (payload, current.to_vec());
+        }
+        for (mbx, mby, blocks) in &coded {
+            self.add_residuals(&mut recon, width, height, *mbx, *mby, blocks);
+        }
+        (payload, self.interleave(&recon, width, height, channels))
+    }
+
+    pub(crate) fn decode(
+        &self,
+        payload: &[u8],
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> WkResult<Vec<u8>> {
+        if reference.len() != width * height * channels {
+            return Err(WkError::DecodingError(
+                "P-frame reference size mismatch".into(),
+            ));
+        }
+        let (&filter, payload) = payload
+            .split_first()
+            .ok_or_else(|| WkError::DecodingError("Empty P-frame payload".into()))?;
+        let filter = InterpolationFilter::from_u8(filter).ok_or_else(|| {
+            WkError::DecodingError(format!("Unknown interpolation filter: {}", filter))
+        })?;
+        let bits = decompress_coefficients(payload);
+        if bits.is_empty() {
+            return Err(WkError::DecodingError("Empty P-frame payload".into()));
+        }
+        let mut reader = BitReader::new(bits);
+        let mb_w = width.div_ceil(MACROBLOCK);
+        let mb_h = height.div_ceil(MACROBLOCK);
+
+        let mut mvs = Vec::with_capacity(mb_w * mb_h);
+        let mut coded = Vec::new();
+        for mby in 0..mb_h {
+            for mbx in 0..mb_w {
+                let pred = predicted_mv(&mvs, mb_w, mbx, mby);
+                if reader.read_bit() {
+                    mvs.push(pred);
+                    continue;
+                }
+                let mv = MotionVector::new(
+                    (pred.x as i32 + read_signed(&mut reader)) as i16,
+                    (pred.y as i32 + read_signed(&mut reader)) as i16,
+                );
+                let count = channels * sub_blocks(mbx, mby, width, height).count();
+                let blocks: Vec<[i16; 64]> = (0..count)
+                    .map(|_| {
+                        let mut block = [0i16; 64];
+                        block.copy_from_slice(&read_block(&mut reader, 64));
+                        block
+                    })
+                    .collect();
+                mvs.push(mv);
+                coded.push((mbx, mby, blocks));
+            }
+        }
+
+        let mut recon = self.predict(reference, width, height, channels, &mvs, filter);
+        for (mbx, mby, blocks) in &coded {
+            self.add_residuals(&mut recon, width, height, *mbx, *mby, blocks);
+        }
+        Ok(self.interleave(&recon, width, height, channels))
+    }
+
+    fn predict(
+        &self,
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+        mvs: &[MotionVector],
+        filter: InterpolationFilter,
+    ) -> Vec<Vec<u8>> {
+        self.split_planes(reference, width, height, channels)
+            .iter()
+            .map(|plane| apply_motion_compensation(plane, width, height, mvs, MACROBLOCK, filter))
+            .collect()
+    }
+
+    fn forward(&self, residual: &[i16; 64], plane: usize) -> [i16; 64] {
+        if self.lossless {
+            return *residual;
+        }
+        let table = &self.tables[(plane == 1 || plane == 2) as usize];
+        zigzag_scan(&self.quantizer.quantize(&int_dct_8x8(residual), table))
+    }
+
+    fn inverse(&self, coeffs: &[i16; 64], plane: usize) -> [i16; 64] {
+        if self.lossless {
+            return *coeffs;
+        }
+        let table = &self.tables[(plane == 1 || plane == 2) as usize];
+        int_idct_8x8(&self.quantizer.dequantize(&zigzag_unscan(coeffs), table))
+    }
+
+    fn add_residuals(
+        &self,
+        planes: &mut [Vec<u8>],
+        width: usize,
+        height: usize,
+        mbx: usize,
+        mby: usize,
+        blocks: &[[i16; 64]],
+    ) {
+        let per_plane = sub_blocks(mbx, mby, width, height).count();
+        for (p, plane) in planes.iter_mut().enumerate() {
+            for (i, (x0, y0)) in sub_blocks(mbx, mby, width, height).enumerate() {
+                let residual = self.inverse(&blocks[p * per_plane + i], p);
+                for y in 0..8.min(height - y0) {
+                    for x in 0..8.min(width - x0) {
+                        let idx = (y0 + y) * width + x0 + x;
+                        plane[idx] = (plane[idx] as i16 + residual[y * 8 + x]).clamp(0, 255) as u8;
+                    }
+                }
+            }
+        }
+    }
+
+    fn split_planes(
+        &self,
+        data: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> Vec<Vec<u8>> {
+        let mut planes: Vec<Vec<u8>> = if self.lossless || channels < 3 {
+            (0..channels.min(3))
+                .map(|c| data.iter().skip(c).step_by(channels).copied().collect())
+                .collect()
+        } else {
+            let (y, cb, cr) = convert_rgb_to_ycbcr_image(data, width, height, channels, self.space);
+            vec![y, cb, cr]
+        };
+        if channels == 4 {
+            planes.push(data.iter().skip(3).step_by(4).copied().collect());
+        }
+        planes
+    }
+
+    fn interleave(
+        &self,
+        planes: &[Vec<u8>],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> Vec<u8> {
+        let mut out = if self.lossless || channels < 3 {
+            vec![0u8; width * height * channels]
+        } else {
+            convert_ycbcr_to_rgb_image(
+                &planes[0], &planes[1], &planes[2], width, height, channels, self.space,
+            )
+        };
+        let direct = if self.lossless || channels < 3 {
+            0..channels
+        } else {
+            3..channels
+        };
+        for c in direct {
+            for (px, &v) in out.chunks_exact_mut(channels).zip(&planes[c]) {
+                px[c] = v;
+            }
+        }
+        out
+    }
+}
+
+pub(crate) fn luma_plane(data: &[u8], channels: usize) -> Vec<u8> {
+    if channels < 3 {
+        return data.iter().step_by(channels).copied().collect();
+    }
+    data.chunks_exact(channels)
+        .map(|p| ((77 * p[0] as u32 + 150 * p[1] as u32 + 29 * p[2] as u32) >> 8) as u8)
+        .collect()
+}
+
+fn predicted_mv(mvs: &[MotionVector], mb_w: usize, mbx: usize, mby: usize) -> MotionVector {
+    let index = mby * mb_w + mbx;
+    let left = (mbx > 0).then(|| mvs[index - 1]);
+    let top = (mby > 0).then(|| mvs[index - mb_w]);
+    let top_right = (mby > 0 && mbx + 1 < mb_w).then(|| mvs[index - mb_w + 1]);
+    match (left, top, top_right) {
+        (Some(a), Some(b), Some(c)) => {
+            MotionVector::new(median(a.x, b.x, c.x), median(a.y, b.y, c.y))
+        }
+        (Some(a), _, _) | (None, Some(a), _) => a,
+        _ => MotionVector::zero(),
+    }
+}
+
+fn median(a: i16, b: i16, c: i16) -> i16 {
+    a.max(b).min(a.min(b).max(c))
+}
+
+fn sub_blocks(
+    mbx: usize,
+    mby: usize,
+    width: usize,
+    height: usize,
+) -> impl Iterator<Item = (usize, usize)> {
+    let (x_start, y_start) = (mbx * MACROBLOCK, mby * MACROBLOCK);
+    let x_end = (x_start + MACROBLOCK).min(width);
+    let y_end = (y_start + MACROBLOCK).min(height);
+    (y_start..y_end)
+        .step_by(8)
+        .flat_map(move |y| (x_start..x_end).step_by(8).map(move |x| (x, y)))
+}
+
+fn residual_block(
+    current: &[u8],
+    predicted: &[u8],
+    width: usize,
+    height: usize,
+    x0: usize,
+    y0: usize,
+) -> [i16; 64] {
+    let mut block = [0i16; 64];
+    for y in 0..8.min(height - y0) {
+        for x in 0..8.min(width - x0) {
+            let idx = (y0 + y) * width + x0 + x;
+            block[y * 8 + x] = current[idx] as i16 - predicted[idx] as i16;
+        }
+    }
+    block
+}
+
+fn write_signed(writer: &mut BitWriter, value: i32) {
+    writer.write_exp_golomb(((value << 1) ^ (value >> 31)) as u32);
+}
+
+fn read_signed(reader: &mut BitReader) -> i32 {
+    let v = reader.read_exp_golomb();
+    (v >> 1) as i32 ^ -((v & 1) as i32)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn shifted_frames(width: usize, height: usize, shift: usize) -> (Vec<u8>, Vec<u8>) {
+        let pixel = |x: usize, y: usize| {
+            let v = ((x / 4 + y / 4) % 2) as u8 * 200 + (x * 3 + y) as u8 % 40;
+            [v, v / 2, 255 - v]
+        };
+        let reference = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| pixel(x, y)))
+            .collect();
+        let current = (0..height)
+            .flat_map(|y| (0..width).flat_map(move |x| pixel(x + shift, y)))
+            .collect();
+        (reference, current)
+    }
+
+    #[test]
+    fn test_lossless_pframe_roundtrip() {
+        let (width, height) = (40, 27);
+        let (reference, current) = shifted_frames(width, height, 3);
+        let coder = InterFrameCoder::new(true, 100);
+        let estimator = MotionEstimator::new(8);
+        let (payload, recon) =
+            coder.encode(&current, &reference, width, height, 3, &estimator, 6, &[]);
+        assert_eq!(recon, current);
+        let decoded = coder
+            .decode(&payload, &reference, width, height, 3)
+            .unwrap();
+        assert_eq!(decoded, current);
+        assert!(payload.len() < current.len() / 4);
+    }
+
+    #[test]
+    fn test_searched_field_is_refined_not_searched_again() {
+        let (width, height) = (40, 27);
+        let (reference, current) = shifted_frames(width, height, 3);
+        let coder = InterFrameCoder::new(true, 100);
+        // Too short a range to find the shift itself.
+        let estimator = MotionEstimator::new(1);
+        let searched = vec![MotionVector::from_pixels(3, 0); 3 * 2];
+        let (payload, recon) = coder.encode(
+            &current, &reference, width, height, 3, &estimator, 6, &searched,
+        );
+        let decoded = coder
+            .decode(&payload, &reference, width, height, 3)
+            .unwrap();
+        assert_eq!(decoded, recon);
+        let (unguided, _) =
+            coder.encode(&current, &reference, width, height, 3, &estimator, 6, &[]);
+        assert!(
+            payload.len() < unguided.len(),
+            "{} vs {}",
+            payload.len(),
+            unguided.len()
+        );
+    }
+
+    #[test]
+    fn test_lossy_pframe_matches_encoder_reconstruction() {
+        let (width, height) = (48, 32);
+        let (reference, current) = shifted_frames(width, height, 5);
+        let coder = InterFrameCoder::new(false, 80);
+        let estimator = MotionEstimator::new(8);
+        let (payload, recon) =
+            coder.encode(&current, &reference, width, height, 3, &estimator, 6, &[]);
+        let decoded = coder
+            .decode(&payload, &reference, width, height, 3)
+            .unwrap();
+        assert_eq!(decoded, recon);
+        let max_err = decoded
+            .iter()
+            .zip(&current)
+            .map(|(&a, &b)| a.abs_diff(b))
+            .max()
+            .unwrap();
+        assert!(max_err < 48, "max error {}", max_err);
+    }
+
+    #[test]
+    fn test_subpixel_pan_shrinks_residual() {
+        let (width, height) = (64, 48);
+        let scene = |shift: f32| -> Vec<u8> {
+            (0..height)
+                .flat_map(|y| {
+                    (0..width).flat_map(move |x| {
+                        let u = (x as f32 + shift) * 0.21;
+                        let v = y as f32 * 0.17;
+                        let l = 128.0 + 60.0 * u.sin() + 50.0 * (v + u * 0.5).cos();
+                        [l as u8, (l * 0.8) as u8, (255.0 - l) as u8]
+                    })
+                })
+                .collect()
+        };
+        let (reference, current) = (scene(0.0), scene(2.5));
+        let coder = InterFrameCoder::new(false, 85);
+        let encode = |estimator: &MotionEstimator| {
+            let (payload, recon) =
+                coder.encode(&current, &reference, width, height, 3, estimator, 6, &[]);
+            let decoded = coder
+                .decode(&payload, &reference, width, height, 3)
+                .unwrap();
+            assert_eq!(decoded, recon);
+            payload.len()
+        };
+        let subpel = encode(&MotionEstimator::new(8));
+        let full_pel = encode(&MotionEstimator::new(8).with_subpixel(false));
+        assert!(subpel < full_pel, "{} vs {}", subpel, full_pel);
+    }
+
+    #[test]
+    fn test_signed_golomb_roundtrip() {
+        let mut writer = BitWriter::new();
+        for v in [-70, -1, 0, 1, 33] {
+            write_signed(&mut writer, v);
+        }
+        let mut reader = BitReader::new(writer.finish());
+        for v in [-70, -1, 0, 1, 33] {
+            assert_eq!(read_signed(&mut reader), v);
+        }
+    }
+}
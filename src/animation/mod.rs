@@ -1,9 +1,27 @@
+pub mod compositor;
+pub mod decoder;
+pub mod encoder;
 pub mod frame;
+pub mod index;
+mod inter;
 pub mod motion;
+mod optimize;
+pub mod rate;
+pub mod scene;
 
+pub use compositor::Compositor;
+pub use decoder::{DecodedFrame, WkAnimationDecoder};
+pub use encoder::{AnimationEncodeStats, FrameStats, WkAnimationEncoder};
 pub use frame::{AnimationConfig, AnimationFrame, BlendMode, DisposeMode};
-pub use motion::{apply_motion_compensation, MotionEstimator, MotionVector, SearchPattern};
-
+pub use index::{FrameIndex, FrameIndexEntry};
+pub use motion::{
+    apply_motion_compensation, predict_block, InterpolationFilter, MotionEstimator, MotionVector,
+    SearchPattern, MV_SUBPEL,
+};
+pub use rate::{RateControl, RateTarget};
+pub use scene::{KeyframePlacement, KeyframePolicy, KeyframeReason, SceneMetrics};
+
+use crate::error::{WkError, WkResult};
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
@@ -56,6 +74,15 @@ impl Animation {
         self.frames.len()
     }
 
+    pub fn canvas_size(&self) -> WkResult<(u32, u32)> {
+        self.frames.iter().try_fold((0, 0), |(w, h), f| {
+            let (right, bottom) = f.extent().ok_or_else(|| {
+                WkError::InvalidFormat("Frame offset overflows the canvas".into())
+            })?;
+            Ok((w.max(right), h.max(bottom)))
+        })
+    }
+
     pub fn total_duration_ms(&self) -> u32 {
         self.frames.iter().map(|f| f.delay_ms).sum()
     }
@@ -1,4 +1,10 @@
-#[derive(Debug, Clone, Copy)]
+use crate::compression::simd::{block_sum_abs_diff, sum_abs_diff};
+use rayon::prelude::*;
+
+pub const MV_SUBPEL: i16 = 4;
+
+// Components are in quarter-pixel units.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub struct MotionVector {
     pub x: i16,
     pub y: i16,
@@ -11,6 +17,50 @@ impl MotionVector {
     pub fn new(x: i16, y: i16) -> Self {
         Self { x, y }
     }
+    pub fn from_pixels(x: i16, y: i16) -> Self {
+        Self {
+            x: x * MV_SUBPEL,
+            y: y * MV_SUBPEL,
+        }
+    }
+    pub fn is_full_pel(&self) -> bool {
+        self.x % MV_SUBPEL == 0 && self.y % MV_SUBPEL == 0
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum InterpolationFilter {
+    Bilinear = 0,
+    SixTap = 1,
+}
+
+impl InterpolationFilter {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Bilinear),
+            1 => Some(Self::SixTap),
+            _ => None,
+        }
+    }
+
+    // Taps for the quarter-pel phases 0..4, applied at offsets -2..=3 and
+    // summing to 64. The six-tap half-pel kernel is H.264's; quarter phases
+    // average it with the nearest integer sample.
+    fn taps(self, phase: usize) -> [i32; 6] {
+        match self {
+            Self::Bilinear => {
+                let f = phase as i32 * 16;
+                [0, 0, 64 - f, f, 0, 0]
+            }
+            Self::SixTap => [
+                [0, 0, 64, 0, 0, 0],
+                [1, -5, 52, 20, -5, 1],
+                [2, -10, 40, 40, -10, 2],
+                [1, -5, 20, 52, -5, 1],
+            ][phase],
+        }
+    }
 }
 
 #[derive(Debug, Clone, Copy)]
@@ -25,6 +75,7 @@ pub struct MotionEstimator {
     search_range: i16,
     pattern: SearchPattern,
     subpixel: bool,
+    filter: InterpolationFilter,
 }
 
 impl MotionEstimator {
@@ -33,6 +84,42 @@ impl MotionEstimator {
             search_range,
             pattern: SearchPattern::Diamond,
             subpixel: true,
+            filter: InterpolationFilter::SixTap,
+        }
+    }
+
+    pub fn with_subpixel(mut self, subpixel: bool) -> Self {
+        self.subpixel = subpixel;
+        self
+    }
+
+    pub fn with_filter(mut self, filter: InterpolationFilter) -> Self {
+        self.filter = filter;
+        self
+    }
+
+    pub fn filter(&self) -> InterpolationFilter {
+        self.filter
+    }
+
+    pub fn for_effort(effort: u8) -> Self {
+        let (search_range, pattern) = match effort {
+            0 => (4, SearchPattern::ThreeStep),
+            1 | 2 => (8, SearchPattern::Diamond),
+            3..=5 => (16, SearchPattern::Hexagon),
+            6 | 7 => (24, SearchPattern::Hexagon),
+            8 => (32, SearchPattern::Diamond),
+            _ => (32, SearchPattern::FullSearch),
+        };
+        Self {
+            search_range,
+            pattern,
+            subpixel: effort > 0,
+            filter: if effort >= 4 {
+                InterpolationFilter::SixTap
+            } else {
+                InterpolationFilter::Bilinear
+            },
         }
     }
 
@@ -46,20 +133,198 @@ impl MotionEstimator {
         block_y: usize,
         block_size: usize,
     ) -> MotionVector {
-        match self.pattern {
+        self.estimate_from(
+            current,
+            reference,
+            width,
+            height,
+            block_x,
+            block_y,
+            block_size,
+            &[],
+        )
+    }
+
+    // Like `estimate`, but the search starts from whichever of zero and the
+    // candidates (typically neighbouring blocks' vectors) matches best, and
+    // the candidates themselves are kept if nothing found beats them.
+    pub fn estimate_from(
+        &self,
+        current: &[u8],
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        block_x: usize,
+        block_y: usize,
+        block_size: usize,
+        candidates: &[MotionVector],
+    ) -> MotionVector {
+        let limit = self.search_range * MV_SUBPEL;
+        let in_range = |mv: &MotionVector| {
+            mv.x.abs() <= limit && mv.y.abs() <= limit && (self.subpixel || mv.is_full_pel())
+        };
+        let full_sad = |(dx, dy): (i16, i16)| {
+            self.compute_sad(
+                current, reference, width, height, block_x, block_y, block_size, dx, dy,
+            )
+        };
+        let mut start = (0, 0);
+        if !candidates.is_empty() {
+            let mut start_sad = full_sad(start);
+            for mv in candidates.iter().filter(|mv| in_range(mv)) {
+                let rounded = (
+                    (mv.x + MV_SUBPEL / 2).div_euclid(MV_SUBPEL),
+                    (mv.y + MV_SUBPEL / 2).div_euclid(MV_SUBPEL),
+                );
+                if rounded.0.abs() > self.search_range || rounded.1.abs() > self.search_range {
+                    continue;
+                }
+                let sad = full_sad(rounded);
+                if sad < start_sad {
+                    start_sad = sad;
+                    start = rounded;
+                }
+            }
+        }
+
+        let mv = match self.pattern {
             SearchPattern::FullSearch => self.full_search(
                 current, reference, width, height, block_x, block_y, block_size,
             ),
             SearchPattern::Diamond => self.diamond_search(
-                current, reference, width, height, block_x, block_y, block_size,
+                current, reference, width, height, block_x, block_y, block_size, start,
             ),
             SearchPattern::Hexagon => self.hexagon_search(
-                current, reference, width, height, block_x, block_y, block_size,
+                current, reference, width, height, block_x, block_y, block_size, start,
             ),
             SearchPattern::ThreeStep => self.three_step_search(
-                current, reference, width, height, block_x, block_y, block_size,
+                current, reference, width, height, block_x, block_y, block_size, start,
             ),
+        };
+        let mv = if self.subpixel {
+            self.refine_subpixel(
+                current, reference, width, height, block_x, block_y, block_size, mv,
+            )
+        } else {
+            mv
+        };
+        if candidates.is_empty() {
+            return mv;
         }
+        let sad = |mv: MotionVector| {
+            self.block_sad(
+                current, reference, width, height, block_x, block_y, block_size, mv,
+            )
+        };
+        let mut best = (sad(mv), mv);
+        for &candidate in candidates.iter().filter(|mv| in_range(mv)) {
+            let candidate_sad = sad(candidate);
+            if candidate_sad < best.0 {
+                best = (candidate_sad, candidate);
+            }
+        }
+        best.1
+    }
+
+    // One vector per block in raster order, searched in parallel. Each block
+    // starts from zero and its entry in `hints` (such as the previous frame's
+    // field, or empty for none). A second pass offers every block its
+    // neighbours' vectors, so motion one block found spreads to neighbours
+    // whose own search missed it.
+    pub fn estimate_field(
+        &self,
+        current: &[u8],
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        block_size: usize,
+        hints: &[MotionVector],
+    ) -> Vec<MotionVector> {
+        let blocks_w = width.div_ceil(block_size);
+        let blocks = blocks_w * height.div_ceil(block_size);
+        let origin = |i: usize| ((i % blocks_w) * block_size, (i / blocks_w) * block_size);
+        let first: Vec<MotionVector> = (0..blocks)
+            .into_par_iter()
+            .map(|i| {
+                let (bx, by) = origin(i);
+                let hint = hints.get(i).copied();
+                self.estimate_from(
+                    current,
+                    reference,
+                    width,
+                    height,
+                    bx,
+                    by,
+                    block_size,
+                    hint.as_slice(),
+                )
+            })
+            .collect();
+
+        (0..blocks)
+            .into_par_iter()
+            .map(|i| {
+                let (bx, by) = origin(i);
+                let (col, row) = (i % blocks_w, i / blocks_w);
+                let own = first[i];
+                let mut neighbours = Vec::with_capacity(4);
+                let adjacent = [
+                    (col > 0).then(|| i - 1),
+                    (col + 1 < blocks_w).then(|| i + 1),
+                    (row > 0).then(|| i - blocks_w),
+                    (i + blocks_w < blocks).then(|| i + blocks_w),
+                ];
+                for mv in adjacent.into_iter().flatten().map(|j| first[j]) {
+                    if mv != own && !neighbours.contains(&mv) {
+                        neighbours.push(mv);
+                    }
+                }
+                let sad = |mv: MotionVector| {
+                    self.block_sad(current, reference, width, height, bx, by, block_size, mv)
+                };
+                let own_sad = sad(own);
+                let improves = neighbours.iter().any(|&mv| sad(mv) < own_sad);
+                if !improves {
+                    return own;
+                }
+                neighbours.push(own);
+                self.estimate_from(
+                    current,
+                    reference,
+                    width,
+                    height,
+                    bx,
+                    by,
+                    block_size,
+                    &neighbours,
+                )
+            })
+            .collect()
+    }
+
+    // Refines a field already found at full-pel, such as the look-ahead's,
+    // to this estimator's precision without searching again.
+    pub fn refine_field(
+        &self,
+        current: &[u8],
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        block_size: usize,
+        field: &[MotionVector],
+    ) -> Vec<MotionVector> {
+        if !self.subpixel {
+            return field.to_vec();
+        }
+        let blocks_w = width.div_ceil(block_size);
+        field
+            .par_iter()
+            .enumerate()
+            .map(|(i, &mv)| {
+                let (bx, by) = ((i % blocks_w) * block_size, (i / blocks_w) * block_size);
+                self.refine_subpixel(current, reference, width, height, bx, by, block_size, mv)
+            })
+            .collect()
     }
 
     fn full_search(
@@ -82,17 +347,11 @@ impl MotionEstimator {
                 );
                 if sad < best_sad {
                     best_sad = sad;
-                    best_mv = MotionVector::new(dx, dy);
+                    best_mv = MotionVector::from_pixels(dx, dy);
                 }
             }
         }
 
-        if self.subpixel {
-            best_mv = self.refine_subpixel(
-                current, reference, width, height, block_x, block_y, block_size, best_mv,
-            );
-        }
-
         best_mv
     }
 
@@ -105,6 +364,7 @@ impl MotionEstimator {
         block_x: usize,
         block_y: usize,
         block_size: usize,
+        (mut cx, mut cy): (i16, i16),
     ) -> MotionVector {
         let ldsp: [(i16, i16); 9] = [
             (0, 0),
@@ -119,8 +379,6 @@ impl MotionEstimator {
         ];
         let sdsp: [(i16, i16); 5] = [(0, 0), (-1, 0), (1, 0), (0, -1), (0, 1)];
 
-        let mut cx = 0i16;
-        let mut cy = 0i16;
         let mut best_sad = u64::MAX;
 
         for _ in 0..16 {
@@ -166,7 +424,7 @@ impl MotionEstimator {
             }
         }
 
-        MotionVector::new(cx, cy)
+        MotionVector::from_pixels(cx, cy)
     }
 
     fn hexagon_search(
@@ -178,6 +436,7 @@ impl MotionEstimator {
         block_x: usize,
         block_y: usize,
         block_size: usize,
+        (mut cx, mut cy): (i16, i16),
     ) -> MotionVector {
         let hex: [(i16, i16); 7] = [(0, 0), (-2, 0), (2, 0), (-1, -2), (1, -2), (-1, 2), (1, 2)];
         let square: [(i16, i16); 9] = [
@@ -192,10 +451,8 @@ impl MotionEstimator {
             (1, 1),
         ];
 
-        let mut cx = 0i16;
-        let mut cy = 0i16;
         let mut best_sad = self.compute_sad(
-            current, reference, width, height, block_x, block_y, block_size, 0, 0,
+            current, reference, width, height, block_x, block_y, block_size, cx, cy,
         );
 
         for _ in 0..16 {
@@ -237,7 +494,7 @@ impl MotionEstimator {
             }
         }
 
-        MotionVector::new(cx, cy)
+        MotionVector::from_pixels(cx, cy)
     }
 
     fn three_step_search(
@@ -249,12 +506,11 @@ impl MotionEstimator {
         block_x: usize,
         block_y: usize,
         block_size: usize,
+        (mut cx, mut cy): (i16, i16),
     ) -> MotionVector {
         let mut step = self.search_range / 2;
-        let mut cx = 0i16;
-        let mut cy = 0i16;
         let mut best_sad = self.compute_sad(
-            current, reference, width, height, block_x, block_y, block_size, 0, 0,
+            current, reference, width, height, block_x, block_y, block_size, cx, cy,
         );
 
         while step >= 1 {
@@ -281,10 +537,10 @@ impl MotionEstimator {
             step /= 2;
         }
 
-        MotionVector::new(cx, cy)
+        MotionVector::from_pixels(cx, cy)
     }
 
-    fn compute_sad(
+    pub(crate) fn compute_sad(
         &self,
         current: &[u8],
         reference: &[u8],
@@ -296,38 +552,193 @@ impl MotionEstimator {
         dx: i16,
         dy: i16,
     ) -> u64 {
+        let cols = block_size.min(width.saturating_sub(block_x));
+        let rows = block_size.min(height.saturating_sub(block_y));
+        if cols == 0 || rows == 0 {
+            return 0;
+        }
+        // Interior blocks need no clamping and go straight to the SAD kernel.
+        let (rx, ry) = (
+            block_x as isize + dx as isize,
+            block_y as isize + dy as isize,
+        );
+        if rx >= 0 && ry >= 0 && rx as usize + cols <= width && ry as usize + rows <= height {
+            return block_sum_abs_diff(
+                &current[block_y * width + block_x..],
+                &reference[ry as usize * width + rx as usize..],
+                width,
+                cols,
+                rows,
+            );
+        }
+        let rx0 = block_x as i32 + dx as i32;
         let mut sad = 0u64;
-        for y in 0..block_size {
-            for x in 0..block_size {
-                let cx = block_x + x;
-                let cy = block_y + y;
-                let rx =
-                    (block_x as i32 + x as i32 + dx as i32).clamp(0, width as i32 - 1) as usize;
-                let ry =
-                    (block_y as i32 + y as i32 + dy as i32).clamp(0, height as i32 - 1) as usize;
-
-                if cx < width && cy < height {
-                    let c = current[cy * width + cx] as i32;
-                    let r = reference[ry * width + rx] as i32;
-                    sad += (c - r).unsigned_abs() as u64;
+        for cy in block_y..block_y + rows {
+            let ry = (cy as i32 + dy as i32).clamp(0, height as i32 - 1) as usize;
+            let row = &current[cy * width + block_x..cy * width + block_x + cols];
+            let ref_row = &reference[ry * width..(ry + 1) * width];
+            if rx0 >= 0 && rx0 as usize + cols <= width {
+                sad += sum_abs_diff(row, &ref_row[rx0 as usize..rx0 as usize + cols]);
+            } else {
+                for (x, &c) in row.iter().enumerate() {
+                    let rx = (rx0 + x as i32).clamp(0, width as i32 - 1) as usize;
+                    sad += c.abs_diff(ref_row[rx]) as u64;
                 }
             }
         }
         sad
     }
 
+    // Evaluates a block at any quarter-pel vector, interpolating when needed.
+    pub(crate) fn block_sad(
+        &self,
+        current: &[u8],
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        block_x: usize,
+        block_y: usize,
+        block_size: usize,
+        mv: MotionVector,
+    ) -> u64 {
+        if mv.is_full_pel() {
+            return self.compute_sad(
+                current,
+                reference,
+                width,
+                height,
+                block_x,
+                block_y,
+                block_size,
+                mv.x / MV_SUBPEL,
+                mv.y / MV_SUBPEL,
+            );
+        }
+        let cols = block_size.min(width.saturating_sub(block_x));
+        let rows = block_size.min(height.saturating_sub(block_y));
+        let mut predicted = vec![0u8; cols * rows];
+        predict_block(
+            reference,
+            width,
+            height,
+            block_x,
+            block_y,
+            cols,
+            rows,
+            mv,
+            self.filter,
+            &mut predicted,
+        );
+        (0..rows)
+            .map(|y| {
+                let start = (block_y + y) * width + block_x;
+                sum_abs_diff(
+                    &current[start..start + cols],
+                    &predicted[y * cols..(y + 1) * cols],
+                )
+            })
+            .sum()
+    }
+
+    // Half-pel steps around the integer winner, then quarter-pel steps
+    // around the half-pel winner.
     fn refine_subpixel(
         &self,
-        _current: &[u8],
-        _reference: &[u8],
-        _width: usize,
-        _height: usize,
-        _block_x: usize,
-        _block_y: usize,
-        _block_size: usize,
+        current: &[u8],
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        block_x: usize,
+        block_y: usize,
+        block_size: usize,
         mv: MotionVector,
     ) -> MotionVector {
-        mv
+        let sad = |mv: MotionVector| {
+            self.block_sad(
+                current, reference, width, height, block_x, block_y, block_size, mv,
+            )
+        };
+        let mut best = mv;
+        let mut best_sad = sad(mv);
+        for step in [MV_SUBPEL / 2, 1] {
+            let center = best;
+            for dy in [-step, 0, step] {
+                for dx in [-step, 0, step] {
+                    if dx == 0 && dy == 0 {
+                        continue;
+                    }
+                    let candidate = MotionVector::new(center.x + dx, center.y + dy);
+                    let candidate_sad = sad(candidate);
+                    if candidate_sad < best_sad {
+                        best_sad = candidate_sad;
+                        best = candidate;
+                    }
+                }
+            }
+        }
+        best
+    }
+}
+
+// Predicts a cols x rows block at (block_x, block_y) from the reference
+// displaced by a quarter-pel vector. Samples outside the plane are clamped
+// to its edges.
+pub fn predict_block(
+    reference: &[u8],
+    width: usize,
+    height: usize,
+    block_x: usize,
+    block_y: usize,
+    cols: usize,
+    rows: usize,
+    mv: MotionVector,
+    filter: InterpolationFilter,
+    out: &mut [u8],
+) {
+    let ix = block_x as i32 + (mv.x as i32).div_euclid(MV_SUBPEL as i32);
+    let iy = block_y as i32 + (mv.y as i32).div_euclid(MV_SUBPEL as i32);
+    let fx = (mv.x as i32).rem_euclid(MV_SUBPEL as i32) as usize;
+    let fy = (mv.y as i32).rem_euclid(MV_SUBPEL as i32) as usize;
+    let clamp_x = |x: i32| x.clamp(0, width as i32 - 1) as usize;
+    let clamp_y = |y: i32| y.clamp(0, height as i32 - 1) as usize;
+
+    if fx == 0 && fy == 0 {
+        for y in 0..rows {
+            let row = &reference[clamp_y(iy + y as i32) * width..][..width];
+            for x in 0..cols {
+                out[y * cols + x] = row[clamp_x(ix + x as i32)];
+            }
+        }
+        return;
+    }
+
+    let htaps = filter.taps(fx);
+    let vtaps = filter.taps(fy);
+    // Horizontal pass over rows -2..rows+3 keeps full precision (x64); the
+    // vertical pass then divides by 64 * 64 with rounding.
+    let mut temp = vec![0i32; (rows + 5) * cols];
+    for t in 0..rows + 5 {
+        let row = &reference[clamp_y(iy + t as i32 - 2) * width..][..width];
+        for x in 0..cols {
+            let base = ix + x as i32 - 2;
+            temp[t * cols + x] = htaps
+                .iter()
+                .enumerate()
+                .filter(|(_, &tap)| tap != 0)
+                .map(|(k, &tap)| tap * row[clamp_x(base + k as i32)] as i32)
+                .sum();
+        }
+    }
+    for y in 0..rows {
+        for x in 0..cols {
+            let sum: i32 = vtaps
+                .iter()
+                .enumerate()
+                .filter(|(_, &tap)| tap != 0)
+                .map(|(k, &tap)| tap * temp[(y + k) * cols + x])
+                .sum();
+            out[y * cols + x] = ((sum + 2048) >> 12).clamp(0, 255) as u8;
+        }
     }
 }
 
@@ -337,30 +748,171 @@ pub fn apply_motion_compensation(
     height: usize,
     mvs: &[MotionVector],
     block_size: usize,
+    filter: InterpolationFilter,
 ) -> Vec<u8> {
     let block_w = (width + block_size - 1) / block_size;
     let block_h = (height + block_size - 1) / block_size;
     let mut output = vec![0u8; width * height];
+    let mut block = vec![0u8; block_size * block_size];
 
     for by in 0..block_h {
         for bx in 0..block_w {
-            let mv = mvs[by * block_w + bx];
-            for y in 0..block_size {
-                for x in 0..block_size {
-                    let px = bx * block_size + x;
-                    let py = by * block_size + y;
-                    if px >= width || py >= height {
-                        continue;
-                    }
+            let (x0, y0) = (bx * block_size, by * block_size);
+            let cols = block_size.min(width - x0);
+            let rows = block_size.min(height - y0);
+            predict_block(
+                reference,
+                width,
+                height,
+                x0,
+                y0,
+                cols,
+                rows,
+                mvs[by * block_w + bx],
+                filter,
+                &mut block[..cols * rows],
+            );
+            for (y, row) in block[..cols * rows].chunks_exact(cols).enumerate() {
+                output[(y0 + y) * width + x0..][..cols].copy_from_slice(row);
+            }
+        }
+    }
 
-                    let rx = (px as i32 + mv.x as i32).clamp(0, width as i32 - 1) as usize;
-                    let ry = (py as i32 + mv.y as i32).clamp(0, height as i32 - 1) as usize;
+    output
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Rows are a horizontal ramp sampled at x + offset / 4, so horizontal
+    // sub-pixel shifts are exact; row levels vary so vertical shifts are not.
+    fn ramp(width: usize, height: usize, offset: i32) -> Vec<u8> {
+        (0..height)
+            .flat_map(|y| {
+                (0..width).map(move |x| (x as i32 * 4 + offset + (y * 7 % 10) as i32 * 9) as u8)
+            })
+            .collect()
+    }
 
-                    output[py * width + px] = reference[ry * width + rx];
+    #[test]
+    fn test_interpolation_is_exact_on_ramps() {
+        let (width, height) = (32, 32);
+        let reference = ramp(width, height, 0);
+        for filter in [InterpolationFilter::Bilinear, InterpolationFilter::SixTap] {
+            for offset in 0..4 {
+                let mut out = vec![0u8; 64];
+                let mv = MotionVector::new(offset as i16 + 4, 0);
+                predict_block(&reference, width, height, 8, 8, 8, 8, mv, filter, &mut out);
+                let expected = ramp(width, height, offset + 4);
+                for y in 0..8 {
+                    assert_eq!(out[y * 8..y * 8 + 8], expected[(8 + y) * width + 8..][..8]);
                 }
             }
         }
     }
 
-    output
+    #[test]
+    fn test_estimate_finds_quarter_pel_shift() {
+        let (width, height) = (40, 40);
+        let reference = ramp(width, height, 0);
+        let current = ramp(width, height, 7);
+        let estimator = MotionEstimator::new(4);
+        let mv = estimator.estimate(&current, &reference, width, height, 16, 16, 16);
+        assert_eq!(mv, MotionVector::new(7, 0));
+        let integer = estimator.with_subpixel(false);
+        let full = integer.estimate(&current, &reference, width, height, 16, 16, 16);
+        assert!(full.is_full_pel());
+    }
+
+    fn texture(width: usize, height: usize, dx: usize) -> Vec<u8> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width + dx) as f32, (i / width) as f32);
+                (128.0 + 60.0 * (x * 0.23).sin() + 50.0 * (y * 0.31 + x * 0.07).cos()) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_interior_sad_matches_clamped_sad() {
+        let (width, height) = (50, 37);
+        let current = texture(width, height, 3);
+        let reference = texture(width, height, 0);
+        let estimator = MotionEstimator::new(8);
+        for (bx, by) in [(0, 0), (16, 16), (32, 32), (48, 16)] {
+            for (dx, dy) in [(0, 0), (3, -2), (-5, 7), (8, 8), (-8, -8)] {
+                let mut expected = 0u64;
+                for y in by..(by + 16).min(height) {
+                    for x in bx..(bx + 16).min(width) {
+                        let rx = (x as i32 + dx).clamp(0, width as i32 - 1) as usize;
+                        let ry = (y as i32 + dy).clamp(0, height as i32 - 1) as usize;
+                        expected +=
+                            current[y * width + x].abs_diff(reference[ry * width + rx]) as u64;
+                    }
+                }
+                let sad = estimator.compute_sad(
+                    &current, &reference, width, height, bx, by, 16, dx as i16, dy as i16,
+                );
+                assert_eq!(sad, expected, "block ({bx}, {by}) at ({dx}, {dy})");
+            }
+        }
+    }
+
+    #[test]
+    fn test_field_matches_or_beats_single_block_search() {
+        let (width, height) = (96, 64);
+        let current = texture(width, height, 11);
+        let reference = texture(width, height, 0);
+        let estimator = MotionEstimator::new(16).with_subpixel(false);
+        let field = estimator.estimate_field(&current, &reference, width, height, 16, &[]);
+        assert_eq!(field.len(), 6 * 4);
+
+        let sad = |i: usize, mv: MotionVector| {
+            let (bx, by) = (i % 6 * 16, i / 6 * 16);
+            estimator.block_sad(&current, &reference, width, height, bx, by, 16, mv)
+        };
+        for (i, &mv) in field.iter().enumerate() {
+            let (bx, by) = (i % 6 * 16, i / 6 * 16);
+            let single = estimator.estimate(&current, &reference, width, height, bx, by, 16);
+            assert!(
+                sad(i, mv) <= sad(i, single),
+                "block {i}: {mv:?} vs {single:?}"
+            );
+        }
+
+        // A hint of the true motion is found exactly wherever it fits.
+        let hints = vec![MotionVector::from_pixels(11, 0); field.len()];
+        let hinted = estimator.estimate_field(&current, &reference, width, height, 16, &hints);
+        for (i, mv) in hinted.iter().enumerate() {
+            if i % 6 < 5 {
+                assert_eq!(*mv, MotionVector::from_pixels(11, 0), "block {i}");
+            }
+        }
+    }
+
+    #[test]
+    fn test_motion_compensation_copies_full_pel_blocks() {
+        let (width, height) = (20, 12);
+        let reference: Vec<u8> = (0..width * height).map(|i| (i * 7) as u8).collect();
+        let mvs = vec![MotionVector::from_pixels(2, 1); 6];
+        let out = apply_motion_compensation(
+            &reference,
+            width,
+            height,
+            &mvs,
+            8,
+            InterpolationFilter::SixTap,
+        );
+        assert_eq!(out[0], reference[width + 2]);
+        assert_eq!(out[width * 11 + 19], reference[width * 11 + 19]);
+    }
+
+    #[test]
+    fn test_search_range_widens_with_effort() {
+        let estimators: Vec<MotionEstimator> = (0..=9).map(MotionEstimator::for_effort).collect();
+        for pair in estimators.windows(2) {
+            assert!(pair[1].search_range >= pair[0].search_range);
+        }
+    }
 }
@@ -0,0 +1,364 @@
+use super::compositor::Compositor;
+use super::{Animation, AnimationFrame, BlendMode, DisposeMode};
+use crate::error::{WkError, WkResult};
+use std::slice;
+
+pub(crate) struct Target {
+    pub(crate) canvas: Vec<u8>,
+    pub(crate) delay_ms: u32,
+    pub(crate) requested_keyframe: bool,
+    pub(crate) merged: usize,
+}
+
+// Composites the source frames one at a time and yields each canvas the
+// animation shows. With merging, identical consecutive canvases come out
+// once with their delays added. Only the canvas being returned and the one
+// after it are held.
+pub(crate) struct Targets<'a> {
+    frames: slice::Iter<'a, AnimationFrame>,
+    compositor: Compositor,
+    merge: bool,
+    pending: Option<Target>,
+}
+
+impl<'a> Targets<'a> {
+    pub(crate) fn new(animation: &'a Animation, merge: bool) -> WkResult<Self> {
+        Ok(Self {
+            frames: animation.frames.iter(),
+            compositor: Compositor::for_animation(animation)?,
+            merge,
+            pending: None,
+        })
+    }
+
+    pub(crate) fn width(&self) -> usize {
+        self.compositor.width() as usize
+    }
+
+    pub(crate) fn height(&self) -> usize {
+        self.compositor.height() as usize
+    }
+
+    fn compose_next(&mut self) -> Option<WkResult<Target>> {
+        let frame = self.frames.next()?;
+        Some(self.compositor.compose(frame).map(|image| Target {
+            canvas: image.into_raw(),
+            delay_ms: frame.delay_ms,
+            requested_keyframe: frame.is_keyframe,
+            merged: 1,
+        }))
+    }
+}
+
+impl Iterator for Targets<'_> {
+    type Item = WkResult<Target>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let mut target = match self.pending.take() {
+            Some(target) => target,
+            None => match self.compose_next()? {
+                Ok(target) => target,
+                Err(e) => return Some(Err(e)),
+            },
+        };
+        while self.merge {
+            match self.compose_next() {
+                None => break,
+                Some(Err(e)) => return Some(Err(e)),
+                Some(Ok(next)) if next.canvas == target.canvas => {
+                    target.delay_ms = target.delay_ms.saturating_add(next.delay_ms);
+                    target.requested_keyframe |= next.requested_keyframe;
+                    target.merged += 1;
+                }
+                Some(Ok(next)) => {
+                    self.pending = Some(next);
+                    break;
+                }
+            }
+        }
+        Some(Ok(target))
+    }
+}
+
+// Rewrites an animation as the smallest frames that reproduce what it shows.
+// Source frames are composited exactly, identical consecutive canvases are
+// merged by adding their delays, and each frame is cropped to the pixels
+// that differ from the canvas it is drawn onto. Frames are produced in
+// order, holding only the current target, the next one and the base.
+pub(crate) struct FrameOptimizer<'a> {
+    width: usize,
+    height: usize,
+    background: [u8; 4],
+    targets: Targets<'a>,
+    current: Target,
+    next: Option<Target>,
+    // The canvas the current frame is drawn onto, after the last disposal.
+    base: Vec<u8>,
+    drawn: usize,
+}
+
+impl<'a> FrameOptimizer<'a> {
+    pub(crate) fn new(animation: &'a Animation) -> WkResult<Self> {
+        let mut targets = Targets::new(animation, true)?;
+        let current = targets
+            .next()
+            .transpose()?
+            .ok_or_else(|| WkError::EncodingError("Animation has no frames".into()))?;
+        let next = targets.next().transpose()?;
+        let (width, height) = (targets.width(), targets.height());
+        let background = animation.config.background_color;
+        Ok(Self {
+            width,
+            height,
+            background,
+            targets,
+            current,
+            next,
+            base: background.repeat(width * height),
+            drawn: 0,
+        })
+    }
+
+    pub(crate) fn merged(&self) -> usize {
+        self.current.merged
+    }
+
+    // The pixels of the current target that differ from the base; a frame
+    // that changes nothing still draws one pixel.
+    pub(crate) fn changed_frame(&self) -> AnimationFrame {
+        let rect = self
+            .changed_rect(&self.base, &self.current.canvas)
+            .unwrap_or((0, 0, 1, 1));
+        self.frame(rect)
+    }
+
+    pub(crate) fn full_frame(&self) -> AnimationFrame {
+        self.frame((0, 0, self.width, self.height))
+    }
+
+    // The same rectangle with its unchanged pixels made transparent and
+    // drawn with Over blending, which can be cheaper to code than repeating
+    // them. Only exact when every changed pixel is opaque, since Over mixes
+    // a translucent pixel with the canvas beneath it.
+    pub(crate) fn over_frame(&self, frame: &AnimationFrame) -> Option<AnimationFrame> {
+        let (x, y) = (frame.x_offset as usize, frame.y_offset as usize);
+        let w = frame.width as usize;
+        let mut data = frame.data.clone();
+        let mut unchanged = 0;
+        for (row, out) in data.chunks_exact_mut(w * 4).enumerate() {
+            let start = ((y + row) * self.width + x) * 4;
+            let base = &self.base[start..start + w * 4];
+            let target = &self.current.canvas[start..start + w * 4];
+            for ((out, before), after) in out
+                .chunks_exact_mut(4)
+                .zip(base.chunks_exact(4))
+                .zip(target.chunks_exact(4))
+            {
+                if before == after {
+                    out.fill(0);
+                    unchanged += 1;
+                } else if after[3] != 255 {
+                    return None;
+                }
+            }
+        }
+        (unchanged > 0).then(|| {
+            let mut over = frame.clone();
+            over.data = data;
+            over.blend_mode = BlendMode::Over;
+            over
+        })
+    }
+
+    // Picks the disposal that leaves the smallest rectangle for the next
+    // frame, moves the base on and advances to the next target. Keyframes
+    // may not restore to Previous.
+    pub(crate) fn finish(&mut self, frame: &mut AnimationFrame, keyframe: bool) -> WkResult<()> {
+        let target = &self.current.canvas;
+        let rect = (
+            frame.x_offset as usize,
+            frame.y_offset as usize,
+            frame.width as usize,
+            frame.height as usize,
+        );
+        let mut options = vec![(DisposeMode::None, target.clone())];
+        if let Some(ref next) = self.next {
+            let mut cleared = target.clone();
+            self.fill(&mut cleared, rect, None);
+            options.push((DisposeMode::Background, cleared));
+            if !keyframe && self.drawn > 0 {
+                let mut restored = target.clone();
+                self.fill(&mut restored, rect, Some(&self.base));
+                options.push((DisposeMode::Previous, restored));
+            }
+            // Ties keep the earlier option, so None wins unless another is smaller.
+            let area = |base: &Vec<u8>| {
+                self.changed_rect(base, &next.canvas)
+                    .map_or(0, |(_, _, w, h)| w * h)
+            };
+            let best = (0..options.len())
+                .min_by_key(|&i| area(&options[i].1))
+                .unwrap_or(0);
+            options.swap(0, best);
+        }
+        let (mode, base) = options.swap_remove(0);
+        frame.dispose_mode = mode;
+        self.base = base;
+        self.drawn += 1;
+        if let Some(next) = self.next.take() {
+            self.current = next;
+            self.next = self.targets.next().transpose()?;
+        }
+        Ok(())
+    }
+
+    fn frame(&self, (x, y, w, h): (usize, usize, usize, usize)) -> AnimationFrame {
+        let target = &self.current;
+        let mut data = Vec::with_capacity(w * h * 4);
+        for row in y..y + h {
+            let start = (row * self.width + x) * 4;
+            data.extend_from_slice(&target.canvas[start..start + w * 4]);
+        }
+        let mut frame = AnimationFrame::new(w as u32, h as u32, data)
+            .with_offset(x as u32, y as u32)
+            .with_delay(target.delay_ms)
+            .with_blend_mode(BlendMode::Source);
+        frame.is_keyframe = target.requested_keyframe;
+        frame
+    }
+
+    // Fills the rectangle with the background, or copies it from `source`.
+    fn fill(
+        &self,
+        canvas: &mut [u8],
+        (x, y, w, h): (usize, usize, usize, usize),
+        source: Option<&[u8]>,
+    ) {
+        for row in y..y + h {
+            let start = (row * self.width + x) * 4;
+            let span = &mut canvas[start..start + w * 4];
+            match source {
+                Some(source) => span.copy_from_slice(&source[start..start + w * 4]),
+                None => {
+                    for px in span.chunks_exact_mut(4) {
+                        px.copy_from_slice(&self.background);
+                    }
+                }
+            }
+        }
+    }
+
+    fn changed_rect(&self, base: &[u8], target: &[u8]) -> Option<(usize, usize, usize, usize)> {
+        let (mut x0, mut y0, mut x1, mut y1) = (usize::MAX, usize::MAX, 0, 0);
+        for y in 0..self.height {
+            let row = y * self.width * 4..(y + 1) * self.width * 4;
+            let (a, b) = (&base[row.clone()], &target[row]);
+            if a == b {
+                continue;
+            }
+            let first = (0..self.width).find(|&x| a[x * 4..x * 4 + 4] != b[x * 4..x * 4 + 4]);
+            let last = (0..self.width).rfind(|&x| a[x * 4..x * 4 + 4] != b[x * 4..x * 4 + 4]);
+            if let (Some(first), Some(last)) = (first, last) {
+                x0 = x0.min(first);
+                x1 = x1.max(last + 1);
+                y0 = y0.min(y);
+                y1 = y + 1;
+            }
+        }
+        (x0 != usize::MAX).then(|| (x0, y0, x1 - x0, y1 - y0))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn solid(w: u32, h: u32, px: [u8; 4]) -> AnimationFrame {
+        AnimationFrame::new(w, h, px.repeat((w * h) as usize))
+    }
+
+    #[test]
+    fn test_crops_merges_and_clears() {
+        let mut animation = Animation::new();
+        animation.add_frame(solid(10, 8, [0, 0, 0, 255]).with_delay(50));
+        animation.add_frame(solid(10, 8, [0, 0, 0, 255]).with_delay(30));
+        animation.add_frame(solid(2, 2, [9, 9, 9, 255]).with_offset(3, 4));
+        assert_eq!(Targets::new(&animation, true).unwrap().count(), 2);
+        let mut optimizer = FrameOptimizer::new(&animation).unwrap();
+        assert_eq!(optimizer.merged(), 2);
+
+        let mut first = optimizer.full_frame();
+        assert_eq!(first.delay_ms, 80);
+        optimizer.finish(&mut first, true).unwrap();
+        assert_eq!(first.dispose_mode, DisposeMode::None);
+        let second = optimizer.changed_frame();
+        assert_eq!(
+            (
+                second.x_offset,
+                second.y_offset,
+                second.width,
+                second.height
+            ),
+            (3, 4, 2, 2)
+        );
+    }
+
+    #[test]
+    fn test_picks_disposal_for_smaller_next_frame() {
+        // A sprite hops over a background-coloured canvas: clearing its old
+        // position leaves only the new position to draw.
+        let mut animation = Animation::new();
+        animation.config.background_color = [0, 0, 0, 255];
+        animation.add_frame(solid(12, 4, [0, 0, 0, 255]));
+        for x in [0, 8] {
+            let mut sprite = solid(4, 4, [200, 0, 0, 255]).with_offset(x, 0);
+            sprite.dispose_mode = DisposeMode::Background;
+            animation.add_frame(sprite);
+        }
+        let mut optimizer = FrameOptimizer::new(&animation).unwrap();
+        let mut frame = optimizer.full_frame();
+        optimizer.finish(&mut frame, true).unwrap();
+        let mut frame = optimizer.changed_frame();
+        assert_eq!((frame.x_offset, frame.width), (0, 4));
+        optimizer.finish(&mut frame, false).unwrap();
+        assert_ne!(frame.dispose_mode, DisposeMode::None);
+        let frame = optimizer.changed_frame();
+        assert_eq!((frame.x_offset, frame.width), (8, 4));
+    }
+
+    #[test]
+    fn test_over_candidate_leaves_unchanged_pixels_transparent() {
+        // Opaque dots appear at opposite corners of a translucent canvas.
+        let backdrop = solid(8, 8, [50, 60, 70, 128]);
+        let mut dotted = backdrop.clone();
+        dotted.data[..4].copy_from_slice(&[255, 0, 0, 255]);
+        dotted.data[63 * 4..].copy_from_slice(&[0, 255, 0, 255]);
+        let mut animation = Animation::new();
+        animation.add_frame(backdrop);
+        animation.add_frame(dotted);
+
+        let mut optimizer = FrameOptimizer::new(&animation).unwrap();
+        let mut first = optimizer.full_frame();
+        // Translucent pixels would be blended with the canvas beneath them.
+        assert!(optimizer.over_frame(&first).is_none());
+        optimizer.finish(&mut first, true).unwrap();
+
+        let second = optimizer.changed_frame();
+        assert_eq!((second.width, second.height), (8, 8));
+        let over = optimizer.over_frame(&second).unwrap();
+        assert_eq!(over.blend_mode, BlendMode::Over);
+        let drawn: Vec<usize> = over
+            .data
+            .chunks_exact(4)
+            .enumerate()
+            .filter(|(_, p)| p[3] != 0)
+            .map(|(i, _)| i)
+            .collect();
+        assert_eq!(drawn, [0, 63]);
+
+        let mut compositor = Compositor::for_animation(&animation).unwrap();
+        compositor.compose(&first).unwrap();
+        let shown = compositor.compose(&over).unwrap();
+        assert_eq!(shown.into_raw(), animation.frames[1].data);
+    }
+}
@@ -0,0 +1,236 @@
+use super::scene::FramePlan;
+use crate::error::{WkError, WkResult};
+
+// Bits are shared out in proportion to complexity^QCOMP, so busy frames get
+// more bytes but not proportionally more, as in x264's qcomp.
+const QCOMP: f64 = 0.6;
+// Frame size grows by about e^(QUALITY_SLOPE) per quality step.
+const QUALITY_SLOPE: f64 = 0.045;
+const PIVOT_QUALITY: f64 = 50.0;
+// Bytes per unit of SAD at the pivot quality before any frame is coded.
+const INITIAL_SCALE: f64 = 0.05;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum RateTarget {
+    // Average bits per second over the animation's duration.
+    Bitrate(u64),
+    // Size of the whole file in bytes.
+    TotalBytes(u64),
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct RateControl {
+    pub target: RateTarget,
+    // Frames over this many bytes are re-coded at a lower quality.
+    pub max_frame_bytes: Option<usize>,
+    pub min_quality: u8,
+    pub max_quality: u8,
+    // Extra share for keyframes, which later P-frames predict from.
+    pub keyframe_boost: f32,
+}
+
+impl RateControl {
+    pub fn bitrate(bits_per_second: u64) -> Self {
+        Self::new(RateTarget::Bitrate(bits_per_second))
+    }
+
+    pub fn total_size(bytes: u64) -> Self {
+        Self::new(RateTarget::TotalBytes(bytes))
+    }
+
+    fn new(target: RateTarget) -> Self {
+        Self {
+            target,
+            max_frame_bytes: None,
+            min_quality: 5,
+            max_quality: 95,
+            keyframe_boost: 1.5,
+        }
+    }
+
+    pub fn with_max_frame_bytes(mut self, bytes: usize) -> Self {
+        self.max_frame_bytes = Some(bytes);
+        self
+    }
+
+    pub fn with_quality_range(mut self, min: u8, max: u8) -> Self {
+        self.min_quality = min.clamp(1, 99);
+        self.max_quality = max.clamp(self.min_quality, 99);
+        self
+    }
+
+    // The fields are public, so a range set without `with_quality_range` is
+    // checked here. Quality 0 in a frame header means "the IHDR quality", so
+    // no frame may be coded at 0.
+    pub(crate) fn validated(mut self) -> WkResult<Self> {
+        self.min_quality = self.min_quality.max(1);
+        if self.min_quality > self.max_quality {
+            return Err(WkError::EncodingError(format!(
+                "Rate control quality range {}..={} is empty",
+                self.min_quality, self.max_quality
+            )));
+        }
+        Ok(self)
+    }
+
+    pub(crate) fn target_bytes(&self, duration_ms: u64) -> u64 {
+        match self.target {
+            RateTarget::Bitrate(bps) => bps.saturating_mul(duration_ms) / 8000,
+            RateTarget::TotalBytes(bytes) => bytes,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FrameCost {
+    keyframe: bool,
+    intra_cost: f64,
+    inter_cost: f64,
+}
+
+impl FrameCost {
+    fn cost(&self, keyframe: bool) -> f64 {
+        if keyframe {
+            self.intra_cost
+        } else {
+            self.inter_cost
+        }
+    }
+}
+
+// Chooses a quality for each frame so the frame payloads fill a byte budget.
+// The encoder's look-ahead gives every frame's type and its intra and
+// motion-compensated cost; while coding, each frame gets the remaining
+// budget in proportion to its weight, and a simple size model per frame
+// type, refit after every frame, turns that into a quality.
+pub(crate) struct RateController {
+    control: RateControl,
+    costs: Vec<FrameCost>,
+    weights: Vec<f64>,
+    remaining_bytes: f64,
+    scale: [Option<f64>; 2],
+    floor: f64,
+}
+
+impl RateController {
+    pub(crate) fn new(
+        control: RateControl,
+        plans: &[FramePlan],
+        width: u32,
+        height: u32,
+        payload_bytes: u64,
+    ) -> WkResult<Self> {
+        if payload_bytes == 0 {
+            return Err(WkError::EncodingError(
+                "Rate target leaves no room for frame data".into(),
+            ));
+        }
+        let costs: Vec<FrameCost> = plans
+            .iter()
+            .map(|plan| FrameCost {
+                keyframe: plan.keyframe_reason.is_some(),
+                intra_cost: plan.intra_cost as f64,
+                inter_cost: plan.scene.map_or(plan.intra_cost, |m| m.inter_cost) as f64,
+            })
+            .collect();
+
+        // Even an unchanged frame costs a few bytes to say so.
+        let floor = (width as u64 * height as u64) as f64 / 64.0 + 1.0;
+        let weights = costs
+            .iter()
+            .map(|cost| {
+                let boost = if cost.keyframe {
+                    control.keyframe_boost as f64
+                } else {
+                    1.0
+                };
+                cost.cost(cost.keyframe).max(floor).powf(QCOMP) * boost
+            })
+            .collect();
+        Ok(Self {
+            control,
+            costs,
+            weights,
+            remaining_bytes: payload_bytes as f64,
+            scale: [None, None],
+            floor,
+        })
+    }
+
+    fn budget(&self, index: usize) -> f64 {
+        let rest: f64 = self.weights[index.min(self.weights.len())..].iter().sum();
+        let share = match self.weights.get(index) {
+            Some(&weight) if rest > 0.0 => self.remaining_bytes * weight / rest,
+            _ => self.remaining_bytes,
+        };
+        let share = share.max(1.0);
+        match self.control.max_frame_bytes {
+            Some(cap) => share.min(cap as f64),
+            None => share,
+        }
+    }
+
+    fn cost(&self, index: usize, keyframe: bool) -> f64 {
+        self.costs
+            .get(index)
+            .map_or(self.floor, |cost| cost.cost(keyframe))
+            .max(self.floor)
+    }
+
+    fn quality_for(&self, index: usize, keyframe: bool, budget: f64) -> u8 {
+        let scale = self.scale[keyframe as usize]
+            .or(self.scale[!keyframe as usize])
+            .unwrap_or(INITIAL_SCALE);
+        let quality =
+            PIVOT_QUALITY + (budget / (self.cost(index, keyframe) * scale)).ln() / QUALITY_SLOPE;
+        quality.round().clamp(
+            self.control.min_quality as f64,
+            self.control.max_quality as f64,
+        ) as u8
+    }
+
+    pub(crate) fn choose_quality(&self, index: usize, keyframe: bool) -> u8 {
+        self.quality_for(index, keyframe, self.budget(index))
+    }
+
+    // Refits the size model to a coded frame and returns a better quality
+    // when the frame broke the cap, or when it was the first of its type and
+    // missed its budget by a wide margin. A frame over the cap always gets a
+    // lower quality until it reaches the minimum.
+    pub(crate) fn observe(
+        &mut self,
+        index: usize,
+        keyframe: bool,
+        quality: u8,
+        bytes: usize,
+    ) -> Option<u8> {
+        let calibrating = self.scale[keyframe as usize].is_none();
+        let fitted = bytes as f64
+            / (self.cost(index, keyframe)
+                * (QUALITY_SLOPE * (quality as f64 - PIVOT_QUALITY)).exp());
+        let slot = &mut self.scale[keyframe as usize];
+        *slot = Some(match *slot {
+            Some(old) => old * 0.5 + fitted * 0.5,
+            None => fitted,
+        });
+
+        let budget = self.budget(index);
+        let retry = if self.over_cap(bytes) {
+            let lower = self.quality_for(index, keyframe, budget * 0.9);
+            (quality > self.control.min_quality).then(|| lower.min(quality - 1))
+        } else if calibrating && !(0.7..=1.3).contains(&(bytes as f64 / budget)) {
+            Some(self.quality_for(index, keyframe, budget))
+        } else {
+            None
+        };
+        retry.filter(|&q| q != quality)
+    }
+
+    pub(crate) fn over_cap(&self, bytes: usize) -> bool {
+        self.control.max_frame_bytes.is_some_and(|cap| bytes > cap)
+    }
+
+    pub(crate) fn commit(&mut self, bytes: usize) {
+        self.remaining_bytes -= bytes as f64;
+    }
+}
@@ -0,0 +1,381 @@
+use super::inter::{luma_plane, MACROBLOCK};
+use super::motion::{MotionEstimator, MotionVector};
+use super::optimize::Targets;
+use super::Animation;
+use crate::error::WkResult;
+
+const HISTOGRAM_BINS: usize = 64;
+// Macroblock-sized, so P-frames can start from the look-ahead's field.
+const ANALYSIS_BLOCK: usize = MACROBLOCK;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct KeyframePolicy {
+    // Keyframes are at most this many frames apart; 0 disables the limit.
+    pub max_interval: usize,
+    // Scene cuts closer than this to the last keyframe stay P-frames.
+    pub min_interval: usize,
+    // Luma histogram difference, from 0 (same) to 1 (disjoint).
+    pub histogram_threshold: f32,
+    // Motion-compensated SAD over the frame's own intra SAD.
+    pub sad_ratio_threshold: f32,
+}
+
+impl Default for KeyframePolicy {
+    fn default() -> Self {
+        Self {
+            max_interval: 120,
+            min_interval: 4,
+            histogram_threshold: 0.35,
+            sad_ratio_threshold: 0.85,
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum KeyframePlacement {
+    // Only frames marked `is_keyframe` become I-frames.
+    Manual,
+    // Frames marked `is_keyframe`, scene cuts and the interval limit.
+    Auto(KeyframePolicy),
+}
+
+impl Default for KeyframePlacement {
+    fn default() -> Self {
+        Self::Auto(KeyframePolicy::default())
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum KeyframeReason {
+    First,
+    Requested,
+    SceneCut,
+    MaxInterval,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq)]
+pub struct SceneMetrics {
+    pub histogram_diff: f32,
+    pub sad_ratio: f32,
+    // Motion-compensated luma SAD, and SAD from each block's mean.
+    pub inter_cost: u64,
+    pub intra_cost: u64,
+}
+
+impl SceneMetrics {
+    // Compares a frame with the canvas it would be predicted from, returning
+    // the motion field found on the way too.
+    pub(crate) fn measure(
+        current: &[u8],
+        reference: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+        estimator: &MotionEstimator,
+    ) -> (Self, Vec<MotionVector>) {
+        let cur = luma_plane(current, channels);
+        let reference = luma_plane(reference, channels);
+
+        let field = estimator.estimate_field(&cur, &reference, width, height, ANALYSIS_BLOCK, &[]);
+        let mut inter = 0u64;
+        let mut mvs = field.iter();
+        for by in (0..height).step_by(ANALYSIS_BLOCK) {
+            for bx in (0..width).step_by(ANALYSIS_BLOCK) {
+                let mv = mvs.next().copied().unwrap_or(MotionVector::zero());
+                inter += estimator.block_sad(
+                    &cur,
+                    &reference,
+                    width,
+                    height,
+                    bx,
+                    by,
+                    ANALYSIS_BLOCK,
+                    mv,
+                );
+            }
+        }
+        let intra = intra_cost(&cur, width, height);
+        // Flat frames have almost no intra cost, so a floor of one level per
+        // pixel keeps small changes on them from reading as cuts.
+        let floor = (width * height) as u64;
+        let metrics = Self {
+            histogram_diff: histogram_diff(&cur, &reference),
+            sad_ratio: inter as f32 / intra.max(floor) as f32,
+            inter_cost: inter,
+            intra_cost: intra,
+        };
+        (metrics, field)
+    }
+
+    // Motion search explains less than it costs, or the tones change sharply
+    // and motion search only half explains the frame.
+    pub fn is_scene_cut(&self, policy: &KeyframePolicy) -> bool {
+        self.sad_ratio >= policy.sad_ratio_threshold
+            || (self.histogram_diff >= policy.histogram_threshold
+                && self.sad_ratio >= policy.sad_ratio_threshold * 0.5)
+    }
+}
+
+fn histogram(plane: &[u8]) -> [u32; HISTOGRAM_BINS] {
+    let mut bins = [0u32; HISTOGRAM_BINS];
+    for &v in plane {
+        bins[v as usize * HISTOGRAM_BINS / 256] += 1;
+    }
+    bins
+}
+
+fn histogram_diff(a: &[u8], b: &[u8]) -> f32 {
+    if a.is_empty() {
+        return 0.0;
+    }
+    let (ha, hb) = (histogram(a), histogram(b));
+    let diff: u64 = ha
+        .iter()
+        .zip(&hb)
+        .map(|(&x, &y)| x.abs_diff(y) as u64)
+        .sum();
+    diff as f32 / (2 * a.len()) as f32
+}
+
+// Sum of each block's absolute deviations from its mean: a cheap intra cost.
+fn intra_cost(luma: &[u8], width: usize, height: usize) -> u64 {
+    let mut total = 0;
+    for by in (0..height).step_by(ANALYSIS_BLOCK) {
+        for bx in (0..width).step_by(ANALYSIS_BLOCK) {
+            let cols = ANALYSIS_BLOCK.min(width - bx);
+            let rows = ANALYSIS_BLOCK.min(height - by);
+            let row = |y: usize| &luma[(by + y) * width + bx..][..cols];
+            let sum: u64 = (0..rows)
+                .map(|y| row(y).iter().map(|&v| v as u64).sum::<u64>())
+                .sum();
+            let mean = (sum / (cols * rows) as u64) as u8;
+            total += (0..rows)
+                .map(|y| row(y).iter().map(|&v| v.abs_diff(mean) as u64).sum::<u64>())
+                .sum::<u64>();
+        }
+    }
+    total
+}
+
+// Decides frame types as frames arrive, counting from the last keyframe.
+pub(crate) struct KeyframeScheduler {
+    placement: KeyframePlacement,
+    since_keyframe: usize,
+}
+
+impl KeyframeScheduler {
+    pub(crate) fn new(placement: KeyframePlacement) -> Self {
+        Self {
+            placement,
+            since_keyframe: 0,
+        }
+    }
+
+    pub(crate) fn needs_metrics(&self, index: usize) -> bool {
+        index > 0 && matches!(self.placement, KeyframePlacement::Auto(_))
+    }
+
+    pub(crate) fn decide(
+        &mut self,
+        index: usize,
+        requested: bool,
+        metrics: Option<&SceneMetrics>,
+    ) -> Option<KeyframeReason> {
+        let reason = if index == 0 {
+            Some(KeyframeReason::First)
+        } else {
+            match self.placement {
+                KeyframePlacement::Manual => requested.then_some(KeyframeReason::Requested),
+                KeyframePlacement::Auto(policy) => {
+                    let distance = self.since_keyframe + 1;
+                    if requested {
+                        Some(KeyframeReason::Requested)
+                    } else if policy.max_interval > 0 && distance >= policy.max_interval {
+                        Some(KeyframeReason::MaxInterval)
+                    } else if distance >= policy.min_interval
+                        && metrics.is_some_and(|m| m.is_scene_cut(&policy))
+                    {
+                        Some(KeyframeReason::SceneCut)
+                    } else {
+                        None
+                    }
+                }
+            }
+        };
+        self.since_keyframe = if reason.is_some() {
+            0
+        } else {
+            self.since_keyframe + 1
+        };
+        reason
+    }
+}
+
+// What the look-ahead learns about one coded frame before any is coded.
+#[derive(Debug, Clone)]
+pub(crate) struct FramePlan {
+    pub(crate) keyframe_reason: Option<KeyframeReason>,
+    pub(crate) scene: Option<SceneMetrics>,
+    // Measured only when frame costs were asked for.
+    pub(crate) intra_cost: u64,
+    // Full-pel motion against the previous canvas, per macroblock; empty
+    // when the frame was not measured.
+    pub(crate) field: Vec<MotionVector>,
+}
+
+// One pass over the canvases the encoder will code, comparing each with the
+// one before it. Keyframe placement and rate control both read this, so the
+// frame types rate control plans for are the ones that get coded.
+pub(crate) struct Lookahead {
+    pub(crate) plans: Vec<FramePlan>,
+    pub(crate) opaque: bool,
+}
+
+impl Lookahead {
+    // Rate control needs the costs of every frame, including those that
+    // automatic placement would not measure.
+    pub(crate) fn run(
+        animation: &Animation,
+        merge_duplicates: bool,
+        placement: KeyframePlacement,
+        estimator: &MotionEstimator,
+        with_costs: bool,
+    ) -> WkResult<Self> {
+        let targets = Targets::new(animation, merge_duplicates)?;
+        let (width, height) = (targets.width(), targets.height());
+        let mut scheduler = KeyframeScheduler::new(placement);
+        let mut plans: Vec<FramePlan> = Vec::new();
+        let mut opaque = true;
+        let mut previous: Option<Vec<u8>> = None;
+        for target in targets {
+            let target = target?;
+            let index = plans.len();
+            opaque &= target.canvas.chunks_exact(4).all(|p| p[3] == 255);
+            let (scene, field) = match previous
+                .as_ref()
+                .filter(|_| with_costs || scheduler.needs_metrics(index))
+            {
+                Some(prev) => {
+                    let (metrics, field) =
+                        SceneMetrics::measure(&target.canvas, prev, width, height, 4, estimator);
+                    (Some(metrics), field)
+                }
+                None => (None, Vec::new()),
+            };
+            let keyframe_reason =
+                scheduler.decide(index, target.requested_keyframe, scene.as_ref());
+            let intra_cost = match scene {
+                Some(metrics) => metrics.intra_cost,
+                None if with_costs => intra_cost(&luma_plane(&target.canvas, 4), width, height),
+                None => 0,
+            };
+            plans.push(FramePlan {
+                keyframe_reason,
+                scene,
+                intra_cost,
+                field,
+            });
+            previous = Some(target.canvas);
+        }
+        Ok(Self { plans, opaque })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn texture(width: usize, height: usize, seed: u32) -> Vec<u8> {
+        (0..width * height)
+            .map(|i| {
+                let (x, y) = ((i % width) as f32, (i / width) as f32);
+                let f = 0.2 + seed as f32 * 0.07;
+                (128.0 + 60.0 * (x * f).sin() + 50.0 * (y * f * 1.3 + x * 0.1).cos()) as u8
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_shift_is_not_a_cut_but_new_content_is() {
+        let (width, height) = (48, 48);
+        let estimator = MotionEstimator::new(8).with_subpixel(false);
+        let base = texture(width + 4, height, 1);
+        let crop = |dx: usize| -> Vec<u8> {
+            (0..height)
+                .flat_map(|y| base[y * (width + 4) + dx..][..width].to_vec())
+                .collect()
+        };
+        let policy = KeyframePolicy::default();
+
+        let (moved, _) = SceneMetrics::measure(&crop(3), &crop(0), width, height, 1, &estimator);
+        assert!(!moved.is_scene_cut(&policy), "{moved:?}");
+
+        let other = texture(width, height, 5);
+        let (cut, _) = SceneMetrics::measure(&other, &crop(0), width, height, 1, &estimator);
+        assert!(cut.is_scene_cut(&policy), "{cut:?}");
+    }
+
+    #[test]
+    fn test_scheduler_intervals() {
+        let policy = KeyframePolicy {
+            max_interval: 5,
+            min_interval: 2,
+            ..KeyframePolicy::default()
+        };
+        let cut = SceneMetrics {
+            histogram_diff: 1.0,
+            sad_ratio: 2.0,
+            ..SceneMetrics::default()
+        };
+        let still = SceneMetrics::default();
+        let mut scheduler = KeyframeScheduler::new(KeyframePlacement::Auto(policy));
+        let decisions: Vec<_> = [
+            &still, &cut, &still, &cut, &still, &still, &still, &still, &still,
+        ]
+        .iter()
+        .enumerate()
+        .map(|(i, m)| scheduler.decide(i, false, Some(m)))
+        .collect();
+        use KeyframeReason::*;
+        assert_eq!(
+            decisions,
+            [
+                Some(First),
+                None,
+                None,
+                Some(SceneCut),
+                None,
+                None,
+                None,
+                None,
+                Some(MaxInterval)
+            ]
+        );
+
+        let mut manual = KeyframeScheduler::new(KeyframePlacement::Manual);
+        assert_eq!(manual.decide(0, false, None), Some(First));
+        assert_eq!(manual.decide(1, true, None), Some(Requested));
+        assert_eq!(manual.decide(2, false, None), None);
+
+        // Requested keyframes are kept under automatic placement too, and
+        // restart the interval count.
+        let mut auto = KeyframeScheduler::new(KeyframePlacement::Auto(policy));
+        let decisions: Vec<_> = [false, true, false, false, false, false, false]
+            .iter()
+            .enumerate()
+            .map(|(i, &requested)| auto.decide(i, requested, Some(&still)))
+            .collect();
+        assert_eq!(
+            decisions,
+            [
+                Some(First),
+                Some(Requested),
+                None,
+                None,
+                None,
+                None,
+                Some(MaxInterval)
+            ]
+        );
+    }
+}
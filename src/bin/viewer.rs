@@ -3,9 +3,10 @@
 use eframe::egui;
 use std::path::PathBuf;
 use std::time::Instant;
+use wk_format::format::{ToneMapConfig, ToneMapOperator};
 use wk_format::metadata::exif::ExifBuilder;
 use wk_format::metadata::icc::IccProfile;
-use wk_format::{WkDecoder, WkEncoder, WkMetadata, WkResult};
+use wk_format::{WkAnimationDecoder, WkDecoder, WkEncoder, WkMetadata, WkResult};
 
 fn main() -> eframe::Result<()> {
     let options = eframe::NativeOptions {
@@ -54,6 +55,61 @@ struct WkViewerApp {
     fps: f32,
     last_frame_time: Instant,
     frame_count: u32,
+    tone_map_operator: ToneMapOperator,
+    target_peak_nits: f32,
+    playback: Option<Playback>,
+}
+
+// Composited frames of an animated WK file, advanced by their delays.
+struct Playback {
+    frames: Vec<(egui::ColorImage, u32)>,
+    loop_count: u32,
+    loops_done: u32,
+    index: usize,
+    shown_at: Instant,
+}
+
+impl Playback {
+    fn load(path: &std::path::Path) -> WkResult<Self> {
+        let file = std::fs::File::open(path)?;
+        let decoder = WkAnimationDecoder::new(std::io::BufReader::new(file))?;
+        let loop_count = decoder.loop_count();
+        let frames = decoder
+            .map(|frame| {
+                let frame = frame?;
+                let size = [frame.image.width() as usize, frame.image.height() as usize];
+                let image = egui::ColorImage::from_rgba_unmultiplied(size, frame.image.as_raw());
+                Ok((image, frame.delay_ms.max(10)))
+            })
+            .collect::<WkResult<Vec<_>>>()?;
+        Ok(Self {
+            frames,
+            loop_count,
+            loops_done: 0,
+            index: 0,
+            shown_at: Instant::now(),
+        })
+    }
+
+    // Returns the frame to show when it is time to advance, and how long
+    // to wait before checking again.
+    fn tick(&mut self) -> (Option<&egui::ColorImage>, Option<std::time::Duration>) {
+        let delay = std::time::Duration::from_millis(self.frames[self.index].1 as u64);
+        let elapsed = self.shown_at.elapsed();
+        if elapsed < delay {
+            return (None, Some(delay - elapsed));
+        }
+        if self.index + 1 == self.frames.len() {
+            self.loops_done += 1;
+            if self.loop_count != 0 && self.loops_done >= self.loop_count {
+                return (None, None);
+            }
+        }
+        self.index = (self.index + 1) % self.frames.len();
+        self.shown_at = Instant::now();
+        let next = std::time::Duration::from_millis(self.frames[self.index].1 as u64);
+        (Some(&self.frames[self.index].0), Some(next))
+    }
 }
 
 #[derive(Clone)]
@@ -68,6 +124,7 @@ struct LoadedImage {
     compression: String,
     quality: u8,
     is_wk: bool,
+    is_hdr: bool,
 }
 
 #[derive(Clone)]
@@ -204,10 +261,14 @@ impl WkViewerApp {
             fps: 0.0,
             last_frame_time: Instant::now(),
             frame_count: 0,
+            tone_map_operator: ToneMapOperator::Bt2390,
+            target_peak_nits: 203.0,
+            playback: None,
         }
     }
 
     fn load_file(&mut self, path: &std::path::Path, ctx: &egui::Context) {
+        self.playback = None;
         let ext = path.extension().and_then(|e| e.to_str()).unwrap_or("");
         if ext.eq_ignore_ascii_case("wk") {
             self.load_wk_file(path, ctx);
@@ -223,10 +284,16 @@ impl WkViewerApp {
         match std::fs::File::open(path) {
             Ok(file) => {
                 let file_size = file.metadata().map(|m| m.len()).unwrap_or(0);
-                match WkDecoder::new().decode(std::io::BufReader::new(file)) {
-                    Ok(decoded) => {
+                let tone_map = ToneMapConfig::default()
+                    .with_operator(self.tone_map_operator)
+                    .with_target_peak(self.target_peak_nits);
+                let decoded = WkDecoder::new()
+                    .decode(std::io::BufReader::new(file))
+                    .and_then(|decoded| Ok((decoded.to_sdr(&tone_map)?, decoded)));
+                match decoded {
+                    Ok((display, decoded)) => {
                         self.decode_time_ms = start.elapsed().as_secs_f64() * 1000.0;
-                        let rgba = decoded.image.to_rgba8();
+                        let rgba = display.to_rgba8();
                         let raw = rgba.as_raw();
                         self.histogram_data = Some(HistogramData::from_rgba(raw));
                         let size = [rgba.width() as usize, rgba.height() as usize];
@@ -250,10 +317,22 @@ impl WkViewerApp {
                             compression: format!("{:?}", decoded.header.compression_mode),
                             quality: decoded.header.quality,
                             is_wk: true,
+                            is_hdr: decoded.is_hdr(),
                         });
                         self.error_message = None;
                         self.success_message =
                             Some(format!("WK loaded in {:.2}ms", self.decode_time_ms));
+                        if decoded.header.has_animation {
+                            match Playback::load(path) {
+                                Ok(playback) if playback.frames.len() > 1 => {
+                                    self.playback = Some(playback)
+                                }
+                                Ok(_) => {}
+                                Err(e) => {
+                                    self.error_message = Some(format!("Animation error: {}", e))
+                                }
+                            }
+                        }
                     }
                     Err(e) => {
                         self.error_message = Some(format!("Decode error: {}", e));
@@ -299,6 +378,7 @@ impl WkViewerApp {
                     compression: "N/A".to_string(),
                     quality: 0,
                     is_wk: false,
+                    is_hdr: false,
                 });
                 self.error_message = None;
                 self.success_message = Some("Ready to convert!".to_string());
@@ -322,6 +402,15 @@ impl eframe::App for WkViewerApp {
         if let Some(path) = self.dropped_file.take() {
             self.load_file(&path, ctx);
         }
+        if let (Some(playback), Some(img)) = (&mut self.playback, &mut self.current_image) {
+            let (frame, wait) = playback.tick();
+            if let Some(frame) = frame {
+                img.texture.set(frame.clone(), egui::TextureOptions::LINEAR);
+            }
+            if let Some(wait) = wait {
+                ctx.request_repaint_after(wait);
+            }
+        }
         ctx.input(|i| {
             if !i.raw.dropped_files.is_empty() {
                 if let Some(path) = i.raw.dropped_files[0].path.clone() {
@@ -411,6 +500,7 @@ impl eframe::App for WkViewerApp {
         });
 
         let mut should_convert = false;
+        let mut should_retone = false;
         let img_clone = self.current_image.clone();
         let hist_clone = self.histogram_data.clone();
 
@@ -466,6 +556,39 @@ impl eframe::App for WkViewerApp {
                         }
                         ui.checkbox(&mut self.show_stats, "📊 Show Stats");
 
+                        if img.is_hdr {
+                            ui.add_space(15.0);
+                            ui.separator();
+                            ui.heading("🌗 HDR Tone Mapping");
+                            egui::ComboBox::from_label("Operator")
+                                .selected_text(format!("{:?}", self.tone_map_operator))
+                                .show_ui(ui, |ui| {
+                                    for op in [
+                                        ToneMapOperator::Bt2390,
+                                        ToneMapOperator::Reinhard,
+                                        ToneMapOperator::AcesFilmic,
+                                        ToneMapOperator::Hable,
+                                    ] {
+                                        should_retone |= ui
+                                            .selectable_value(
+                                                &mut self.tone_map_operator,
+                                                op,
+                                                format!("{:?}", op),
+                                            )
+                                            .changed();
+                                    }
+                                });
+                            ui.horizontal(|ui| {
+                                ui.label("Display peak:");
+                                should_retone |= ui
+                                    .add(
+                                        egui::Slider::new(&mut self.target_peak_nits, 80.0..=600.0)
+                                            .suffix(" nits"),
+                                    )
+                                    .drag_stopped();
+                            });
+                        }
+
                         if !img.is_wk {
                             ui.add_space(15.0);
                             ui.separator();
@@ -514,6 +637,11 @@ impl eframe::App for WkViewerApp {
         if should_convert {
             self.pending_convert = true;
         }
+        if should_retone {
+            if let Some(path) = img_clone.map(|img| img.file_path) {
+                self.load_wk_file(&path, ctx);
+            }
+        }
 
         if self.view_mode == ViewMode::Batch {
             egui::SidePanel::left("batch")
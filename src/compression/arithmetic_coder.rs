@@ -181,6 +181,11 @@ impl Default for CABACContext {
 
 pub fn encode_block(coeffs: &[i16]) -> Vec<u8> {
     let mut writer = BitWriter::new();
+    write_block(&mut writer, coeffs);
+    writer.finish()
+}
+
+pub fn write_block(writer: &mut BitWriter, coeffs: &[i16]) {
     let n = coeffs.len().min(64);
 
     let mut last_nz = 0;
@@ -193,10 +198,11 @@ pub fn encode_block(coeffs: &[i16]) -> Vec<u8> {
     if coeffs.iter().take(n).all(|&c| c == 0) {
         writer.write_bits(0, 6);
         writer.write_bit(true);
-        return writer.finish();
+        return;
     }
 
-    writer.write_bits((last_nz + 1) as u32, 6);
+    // A count of 64 does not fit in six bits; it is written as 0 with the zero flag clear.
+    writer.write_bits(((last_nz + 1) & 63) as u32, 6);
     writer.write_bit(false);
 
     let mut i = 0;
@@ -219,13 +225,44 @@ pub fn encode_block(coeffs: &[i16]) -> Vec<u8> {
             i += 1;
         }
     }
+}
 
-    writer.finish()
+fn exp_golomb_bits(value: u32) -> u32 {
+    2 * (32 - (value + 1).leading_zeros()) - 1
+}
+
+// Bit length `encode_block` would produce, without writing anything.
+pub fn block_bits(coeffs: &[i16]) -> u32 {
+    let n = coeffs.len().min(64);
+    let Some(last_nz) = coeffs[..n].iter().rposition(|&c| c != 0) else {
+        return 7;
+    };
+    let mut bits = 7;
+    let mut i = 0;
+    while i <= last_nz {
+        if coeffs[i] == 0 {
+            let mut run = 0;
+            while i + run <= last_nz && coeffs[i + run] == 0 {
+                run += 1;
+            }
+            run = run.min(32);
+            bits += 1 + exp_golomb_bits(run as u32 - 1);
+            i += run;
+        } else {
+            bits += 2 + exp_golomb_bits(coeffs[i].unsigned_abs() as u32 - 1);
+            i += 1;
+        }
+    }
+    bits
 }
 
 pub fn decode_block(data: &[u8], size: usize) -> Vec<i16> {
-    let mut coeffs = vec![0i16; size];
     let mut reader = BitReader::new(data.to_vec());
+    read_block(&mut reader, size)
+}
+
+pub fn read_block(reader: &mut BitReader, size: usize) -> Vec<i16> {
+    let mut coeffs = vec![0i16; size];
     let n = size.min(64);
 
     let count = reader.read_bits(6) as usize;
@@ -234,7 +271,7 @@ pub fn decode_block(data: &[u8], size: usize) -> Vec<i16> {
     if count == 0 && is_zero {
         return coeffs;
     }
-    let last_nz = count.saturating_sub(1);
+    let last_nz = if count == 0 { 63 } else { count - 1 };
 
     let mut i = 0;
     while i <= last_nz && i < n {
@@ -258,7 +295,11 @@ pub fn decode_block(data: &[u8], size: usize) -> Vec<i16> {
 }
 
 pub fn compress_coefficients(data: &[u8]) -> Vec<u8> {
-    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(9));
+    compress_coefficients_at(data, 9)
+}
+
+pub fn compress_coefficients_at(data: &[u8], level: u32) -> Vec<u8> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::new(level));
     let _ = encoder.write_all(data);
     encoder.finish().unwrap_or_default()
 }
@@ -322,6 +363,15 @@ mod tests {
         for i in 0..7 {
             assert_eq!(coeffs[i], decoded[i], "Mismatch at {}", i);
         }
+
+        let mut full = coeffs.clone();
+        full[63] = -2;
+        assert_eq!(decode_block(&encode_block(&full), 64), full);
+
+        for block in [&coeffs, &full, &vec![0; 64]] {
+            let bytes = (block_bits(block) as usize).div_ceil(8);
+            assert_eq!(bytes, encode_block(block).len());
+        }
     }
 
     #[test]
@@ -1,3 +1,6 @@
+use crate::format::hdr::ColorGamut;
+use std::ops::{Add, Div, Mul, Sub};
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum ColorSpace {
     RGB,
@@ -5,6 +8,8 @@ pub enum ColorSpace {
     YCbCr709,
     YCbCr2020,
     YCbCrFull,
+    YCbCr709Full,
+    YCbCr2020Full,
 }
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
@@ -14,97 +19,231 @@ pub enum ChromaSubsampling {
     YUV422,
 }
 
-pub fn rgb_to_ycbcr(r: u8, g: u8, b: u8, space: ColorSpace) -> (u8, u8, u8) {
-    let (r, g, b) = (r as f32, g as f32, b as f32);
-    let (y, cb, cr) = match space {
-        ColorSpace::RGB => return (r as u8, g as u8, b as u8),
-        ColorSpace::YCbCrFull => {
-            let y = 0.299 * r + 0.587 * g + 0.114 * b;
-            let cb = 128.0 - 0.168736 * r - 0.331264 * g + 0.5 * b;
-            let cr = 128.0 + 0.5 * r - 0.418688 * g - 0.081312 * b;
-            return (
-                y.round().clamp(0.0, 255.0) as u8,
-                cb.round().clamp(0.0, 255.0) as u8,
-                cr.round().clamp(0.0, 255.0) as u8,
-            );
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ColorMatrix {
+    Identity = 0,
+    Bt601 = 1,
+    Bt709 = 2,
+    Bt2020 = 3,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum ColorRange {
+    Full = 0,
+    Limited = 1,
+}
+
+impl ColorMatrix {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Identity),
+            1 => Some(Self::Bt601),
+            2 => Some(Self::Bt709),
+            3 => Some(Self::Bt2020),
+            _ => None,
         }
-        ColorSpace::YCbCr601 => {
-            let y = 16.0 + 65.481 * r / 255.0 + 128.553 * g / 255.0 + 24.966 * b / 255.0;
-            let cb = 128.0 - 37.797 * r / 255.0 - 74.203 * g / 255.0 + 112.0 * b / 255.0;
-            let cr = 128.0 + 112.0 * r / 255.0 - 93.786 * g / 255.0 - 18.214 * b / 255.0;
-            (y, cb, cr)
+    }
+
+    pub fn for_gamut(gamut: ColorGamut) -> Self {
+        match gamut {
+            ColorGamut::Rec2020 => Self::Bt2020,
+            _ => Self::Bt601,
         }
-        ColorSpace::YCbCr709 => {
-            let y = 16.0 + 46.742 * r / 255.0 + 157.243 * g / 255.0 + 15.874 * b / 255.0;
-            let cb = 128.0 - 25.765 * r / 255.0 - 86.674 * g / 255.0 + 112.439 * b / 255.0;
-            let cr = 128.0 + 112.439 * r / 255.0 - 102.129 * g / 255.0 - 10.310 * b / 255.0;
-            (y, cb, cr)
+    }
+
+    fn coefficients(&self) -> (f32, f32) {
+        match self {
+            Self::Identity | Self::Bt601 => (0.299, 0.114),
+            Self::Bt709 => (0.2126, 0.0722),
+            Self::Bt2020 => (0.2627, 0.0593),
         }
-        ColorSpace::YCbCr2020 => {
-            let y = 16.0 + 46.559 * r / 255.0 + 156.629 * g / 255.0 + 16.812 * b / 255.0;
-            let cb = 128.0 - 25.494 * r / 255.0 - 85.723 * g / 255.0 + 111.217 * b / 255.0;
-            let cr = 128.0 + 111.217 * r / 255.0 - 101.370 * g / 255.0 - 9.847 * b / 255.0;
-            (y, cb, cr)
+    }
+}
+
+impl ColorRange {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Full),
+            1 => Some(Self::Limited),
+            _ => None,
         }
-    };
-    (
-        y.round().clamp(16.0, 235.0) as u8,
-        cb.round().clamp(16.0, 240.0) as u8,
-        cr.round().clamp(16.0, 240.0) as u8,
-    )
+    }
 }
 
-pub fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8, space: ColorSpace) -> (u8, u8, u8) {
-    let (y, cb, cr) = (y as f32, cb as f32, cr as f32);
-    let (r, g, b) = match space {
-        ColorSpace::RGB => return (y as u8, cb as u8, cr as u8),
-        ColorSpace::YCbCrFull => {
-            let cb1 = cb - 128.0;
-            let cr1 = cr - 128.0;
-            let r = y + 1.402 * cr1;
-            let g = y - 0.344136 * cb1 - 0.714136 * cr1;
-            let b = y + 1.772 * cb1;
-            return (
-                r.round().clamp(0.0, 255.0) as u8,
-                g.round().clamp(0.0, 255.0) as u8,
-                b.round().clamp(0.0, 255.0) as u8,
-            );
+impl ColorSpace {
+    pub fn new(matrix: ColorMatrix, range: ColorRange) -> Self {
+        match (matrix, range) {
+            (ColorMatrix::Identity, _) => Self::RGB,
+            (ColorMatrix::Bt601, ColorRange::Full) => Self::YCbCrFull,
+            (ColorMatrix::Bt601, ColorRange::Limited) => Self::YCbCr601,
+            (ColorMatrix::Bt709, ColorRange::Full) => Self::YCbCr709Full,
+            (ColorMatrix::Bt709, ColorRange::Limited) => Self::YCbCr709,
+            (ColorMatrix::Bt2020, ColorRange::Full) => Self::YCbCr2020Full,
+            (ColorMatrix::Bt2020, ColorRange::Limited) => Self::YCbCr2020,
         }
-        ColorSpace::YCbCr601 => {
-            let y1 = (y - 16.0) * 255.0 / 219.0;
-            let cb1 = (cb - 128.0) * 255.0 / 224.0;
-            let cr1 = (cr - 128.0) * 255.0 / 224.0;
-            let r = y1 + 1.402 * cr1;
-            let g = y1 - 0.344136 * cb1 - 0.714136 * cr1;
-            let b = y1 + 1.772 * cb1;
-            (r, g, b)
+    }
+
+    pub fn matrix(&self) -> ColorMatrix {
+        match self {
+            Self::RGB => ColorMatrix::Identity,
+            Self::YCbCr601 | Self::YCbCrFull => ColorMatrix::Bt601,
+            Self::YCbCr709 | Self::YCbCr709Full => ColorMatrix::Bt709,
+            Self::YCbCr2020 | Self::YCbCr2020Full => ColorMatrix::Bt2020,
         }
-        ColorSpace::YCbCr709 => {
-            let y1 = (y - 16.0) * 255.0 / 219.0;
-            let cb1 = (cb - 128.0) * 255.0 / 224.0;
-            let cr1 = (cr - 128.0) * 255.0 / 224.0;
-            let r = y1 + 1.5748 * cr1;
-            let g = y1 - 0.1873 * cb1 - 0.4681 * cr1;
-            let b = y1 + 1.8556 * cb1;
-            (r, g, b)
+    }
+
+    pub fn range(&self) -> ColorRange {
+        match self {
+            Self::YCbCr601 | Self::YCbCr709 | Self::YCbCr2020 => ColorRange::Limited,
+            _ => ColorRange::Full,
         }
-        ColorSpace::YCbCr2020 => {
-            let y1 = (y - 16.0) * 255.0 / 219.0;
-            let cb1 = (cb - 128.0) * 255.0 / 224.0;
-            let cr1 = (cr - 128.0) * 255.0 / 224.0;
-            let r = y1 + 1.4746 * cr1;
-            let g = y1 - 0.1646 * cb1 - 0.5714 * cr1;
-            let b = y1 + 1.8814 * cb1;
-            (r, g, b)
+    }
+}
+
+pub(crate) trait Lanes:
+    Copy
+    + Add<Output = Self>
+    + Sub<Output = Self>
+    + Mul<Output = Self>
+    + Div<Output = Self>
+    + Add<f32, Output = Self>
+    + Sub<f32, Output = Self>
+    + Mul<f32, Output = Self>
+    + Div<f32, Output = Self>
+{
+}
+
+impl<T> Lanes for T where
+    T: Copy
+        + Add<Output = T>
+        + Sub<Output = T>
+        + Mul<Output = T>
+        + Div<Output = T>
+        + Add<f32, Output = T>
+        + Sub<f32, Output = T>
+        + Mul<f32, Output = T>
+        + Div<f32, Output = T>
+{
+}
+
+pub(crate) struct Converted<T> {
+    pub values: [T; 3],
+    pub lo: f32,
+    pub hi: [f32; 3],
+}
+
+impl<T> Converted<T> {
+    fn full(values: [T; 3]) -> Self {
+        Self {
+            values,
+            lo: 0.0,
+            hi: [255.0; 3],
         }
-    };
+    }
+}
+
+// Shared by the scalar path (`T = f32`) and the `simd` path (`T = f32x8`); both evaluate the
+// same IEEE operations in the same order, so they round to the same bytes.
+pub(crate) fn rgb_to_ycbcr_lanes<T: Lanes>(r: T, g: T, b: T, space: ColorSpace) -> Converted<T> {
+    match space {
+        ColorSpace::RGB => Converted::full([r, g, b]),
+        ColorSpace::YCbCrFull => Converted::full([
+            r * 0.299 + g * 0.587 + b * 0.114,
+            r * -0.168736 + 128.0 - g * 0.331264 + b * 0.5,
+            r * 0.5 + 128.0 - g * 0.418688 - b * 0.081312,
+        ]),
+        ColorSpace::YCbCr601 => Converted {
+            values: [
+                r * 65.481 / 255.0 + 16.0 + g * 128.553 / 255.0 + b * 24.966 / 255.0,
+                r * -37.797 / 255.0 + 128.0 - g * 74.203 / 255.0 + b * 112.0 / 255.0,
+                r * 112.0 / 255.0 + 128.0 - g * 93.786 / 255.0 - b * 18.214 / 255.0,
+            ],
+            lo: 16.0,
+            hi: [235.0, 240.0, 240.0],
+        },
+        ColorSpace::YCbCr709
+        | ColorSpace::YCbCr2020
+        | ColorSpace::YCbCr709Full
+        | ColorSpace::YCbCr2020Full => {
+            let (kr, kb) = space.matrix().coefficients();
+            let y = r * kr + g * (1.0 - kr - kb) + b * kb;
+            let cb = (b - y) / (2.0 * (1.0 - kb));
+            let cr = (r - y) / (2.0 * (1.0 - kr));
+            match space.range() {
+                ColorRange::Full => Converted::full([y, cb + 128.0, cr + 128.0]),
+                ColorRange::Limited => Converted {
+                    values: [
+                        y * 219.0 / 255.0 + 16.0,
+                        cb * 224.0 / 255.0 + 128.0,
+                        cr * 224.0 / 255.0 + 128.0,
+                    ],
+                    lo: 16.0,
+                    hi: [235.0, 240.0, 240.0],
+                },
+            }
+        }
+    }
+}
+
+pub(crate) fn ycbcr_to_rgb_lanes<T: Lanes>(y: T, cb: T, cr: T, space: ColorSpace) -> Converted<T> {
+    match space {
+        ColorSpace::RGB => Converted::full([y, cb, cr]),
+        ColorSpace::YCbCrFull | ColorSpace::YCbCr601 => {
+            let (y, cb, cr) = if space == ColorSpace::YCbCrFull {
+                (y, cb - 128.0, cr - 128.0)
+            } else {
+                (
+                    (y - 16.0) * 255.0 / 219.0,
+                    (cb - 128.0) * 255.0 / 224.0,
+                    (cr - 128.0) * 255.0 / 224.0,
+                )
+            };
+            Converted::full([
+                y + cr * 1.402,
+                y - cb * 0.344136 - cr * 0.714136,
+                y + cb * 1.772,
+            ])
+        }
+        ColorSpace::YCbCr709
+        | ColorSpace::YCbCr2020
+        | ColorSpace::YCbCr709Full
+        | ColorSpace::YCbCr2020Full => {
+            let (y, cb, cr) = match space.range() {
+                ColorRange::Full => (y, cb - 128.0, cr - 128.0),
+                ColorRange::Limited => (
+                    (y - 16.0) * 255.0 / 219.0,
+                    (cb - 128.0) * 255.0 / 224.0,
+                    (cr - 128.0) * 255.0 / 224.0,
+                ),
+            };
+            let (kr, kb) = space.matrix().coefficients();
+            let r = y + cr * (2.0 * (1.0 - kr));
+            let b = y + cb * (2.0 * (1.0 - kb));
+            let g = (y - r * kr - b * kb) / (1.0 - kr - kb);
+            Converted::full([r, g, b])
+        }
+    }
+}
+
+fn round_to_u8(c: Converted<f32>) -> (u8, u8, u8) {
+    let [a, b, d] = c.values;
     (
-        r.round().clamp(0.0, 255.0) as u8,
-        g.round().clamp(0.0, 255.0) as u8,
-        b.round().clamp(0.0, 255.0) as u8,
+        a.round().clamp(c.lo, c.hi[0]) as u8,
+        b.round().clamp(c.lo, c.hi[1]) as u8,
+        d.round().clamp(c.lo, c.hi[2]) as u8,
     )
 }
 
+pub fn rgb_to_ycbcr(r: u8, g: u8, b: u8, space: ColorSpace) -> (u8, u8, u8) {
+    round_to_u8(rgb_to_ycbcr_lanes(r as f32, g as f32, b as f32, space))
+}
+
+pub fn ycbcr_to_rgb(y: u8, cb: u8, cr: u8, space: ColorSpace) -> (u8, u8, u8) {
+    round_to_u8(ycbcr_to_rgb_lanes(y as f32, cb as f32, cr as f32, space))
+}
+
 pub fn convert_rgb_to_ycbcr_image(
     data: &[u8],
     width: usize,
@@ -113,19 +252,18 @@ pub fn convert_rgb_to_ycbcr_image(
     space: ColorSpace,
 ) -> (Vec<u8>, Vec<u8>, Vec<u8>) {
     let n = width * height;
-    let mut y = Vec::with_capacity(n);
-    let mut cb = Vec::with_capacity(n);
-    let mut cr = Vec::with_capacity(n);
-    for i in 0..n {
+    let mut y = vec![0u8; n];
+    let mut cb = vec![0u8; n];
+    let mut cr = vec![0u8; n];
+    #[cfg(feature = "simd")]
+    let start =
+        super::simd::rgb_to_ycbcr_lanes_into(data, channels, space, [&mut y, &mut cb, &mut cr]);
+    #[cfg(not(feature = "simd"))]
+    let start = 0;
+    for i in start..n {
         let offset = i * channels;
-        let r = data[offset];
-        let g = data[offset + 1];
-        let b = data[offset + 2];
-        // let (yv, cbv, crv) = rgb_to_ycbcr(rgb[i * 3], rgb[i * 3 + 1], rgb[i * 3 + 2], space);
-        let (yv, cbv, crv) = rgb_to_ycbcr(r, g, b, space);
-        y.push(yv);
-        cb.push(cbv);
-        cr.push(crv);
+        (y[i], cb[i], cr[i]) =
+            rgb_to_ycbcr(data[offset], data[offset + 1], data[offset + 2], space);
     }
     (y, cb, cr)
 }
@@ -140,16 +278,20 @@ pub fn convert_ycbcr_to_rgb_image(
     space: ColorSpace,
 ) -> Vec<u8> {
     let n = width * height;
-    let mut rgb = Vec::with_capacity(n * channels);
-    for i in 0..n {
+    let stride = if channels == 4 { 4 } else { 3 };
+    let mut rgb = vec![255u8; n * stride];
+    #[cfg(feature = "simd")]
+    let start = super::simd::ycbcr_to_rgb_lanes_into(
+        [&y[..n], &cb[..n], &cr[..n]],
+        stride,
+        space,
+        &mut rgb,
+    );
+    #[cfg(not(feature = "simd"))]
+    let start = 0;
+    for i in start..n {
         let (r, g, b) = ycbcr_to_rgb(y[i], cb[i], cr[i], space);
-        rgb.push(r);
-        rgb.push(g);
-        rgb.push(b);
-
-        if channels == 4 {
-            rgb.push(255);
-        }
+        rgb[i * stride..i * stride + 3].copy_from_slice(&[r, g, b]);
     }
     rgb
 }
@@ -208,3 +350,64 @@ pub fn upsample_420(
     }
     out
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_matrix_range_roundtrip() {
+        for matrix in [
+            ColorMatrix::Identity,
+            ColorMatrix::Bt601,
+            ColorMatrix::Bt709,
+            ColorMatrix::Bt2020,
+        ] {
+            for range in [ColorRange::Full, ColorRange::Limited] {
+                let space = ColorSpace::new(matrix, range);
+                assert_eq!(space.matrix(), matrix);
+                for (r, g, b) in [(255, 0, 0), (12, 200, 90), (128, 128, 128), (30, 60, 250)] {
+                    let (y, cb, cr) = rgb_to_ycbcr(r, g, b, space);
+                    let back = ycbcr_to_rgb(y, cb, cr, space);
+                    let err = (back.0 as i32 - r as i32)
+                        .abs()
+                        .max((back.1 as i32 - g as i32).abs())
+                        .max((back.2 as i32 - b as i32).abs());
+                    assert!(err <= 3, "{:?} {:?} ({}, {}, {})", matrix, range, r, g, b);
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_image_conversion_matches_per_pixel() {
+        let (w, h) = (37, 5);
+        let rgb: Vec<u8> = (0..w * h * 4).map(|i| (i * 97 + i / 7) as u8).collect();
+        for space in [
+            ColorSpace::RGB,
+            ColorSpace::YCbCr601,
+            ColorSpace::YCbCr709,
+            ColorSpace::YCbCr2020,
+            ColorSpace::YCbCrFull,
+            ColorSpace::YCbCr709Full,
+            ColorSpace::YCbCr2020Full,
+        ] {
+            for channels in [3, 4] {
+                let (y, cb, cr) = convert_rgb_to_ycbcr_image(&rgb, w, h, channels, space);
+                let back = convert_ycbcr_to_rgb_image(&y, &cb, &cr, w, h, channels, space);
+                for i in 0..w * h {
+                    let px = &rgb[i * channels..];
+                    assert_eq!(
+                        (y[i], cb[i], cr[i]),
+                        rgb_to_ycbcr(px[0], px[1], px[2], space)
+                    );
+                    let (r, g, b) = ycbcr_to_rgb(y[i], cb[i], cr[i], space);
+                    assert_eq!(back[i * channels..i * channels + 3], [r, g, b]);
+                    if channels == 4 {
+                        assert_eq!(back[i * 4 + 3], 255);
+                    }
+                }
+            }
+        }
+    }
+}
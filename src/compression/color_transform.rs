@@ -0,0 +1,334 @@
+use super::predictor::apply_optimal_predictor;
+use crate::error::{WkError, WkResult};
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ColorTransform {
+    None,
+    SubtractGreen,
+    YCoCgR,
+    Predictor {
+        green_to_red: i8,
+        green_to_blue: i8,
+        red_to_blue: i8,
+    },
+}
+
+fn color_delta(multiplier: i8, value: u8) -> u8 {
+    ((multiplier as i32 * value as i8 as i32) >> 5) as u8
+}
+
+fn half(value: u8) -> u8 {
+    ((value as i8) >> 1) as u8
+}
+
+impl ColorTransform {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::None => 0,
+            Self::SubtractGreen => 1,
+            Self::YCoCgR => 2,
+            Self::Predictor { .. } => 3,
+        }
+    }
+
+    pub fn encode(&self) -> Vec<u8> {
+        match *self {
+            Self::Predictor {
+                green_to_red,
+                green_to_blue,
+                red_to_blue,
+            } => vec![
+                self.id(),
+                green_to_red as u8,
+                green_to_blue as u8,
+                red_to_blue as u8,
+            ],
+            _ => vec![self.id()],
+        }
+    }
+
+    pub fn decode(data: &[u8]) -> WkResult<(Self, usize)> {
+        let truncated = || WkError::DecodingError("Truncated color transform".into());
+        match *data.first().ok_or_else(truncated)? {
+            0 => Ok((Self::None, 1)),
+            1 => Ok((Self::SubtractGreen, 1)),
+            2 => Ok((Self::YCoCgR, 1)),
+            3 => {
+                let params = data.get(1..4).ok_or_else(truncated)?;
+                Ok((
+                    Self::Predictor {
+                        green_to_red: params[0] as i8,
+                        green_to_blue: params[1] as i8,
+                        red_to_blue: params[2] as i8,
+                    },
+                    4,
+                ))
+            }
+            id => Err(WkError::DecodingError(format!(
+                "Unknown color transform: {}",
+                id
+            ))),
+        }
+    }
+
+    pub fn forward(&self, data: &mut [u8], channels: usize) {
+        if channels < 3 || *self == Self::None {
+            return;
+        }
+        for px in data.chunks_exact_mut(channels) {
+            let (r, g, b) = (px[0], px[1], px[2]);
+            match *self {
+                Self::None => {}
+                Self::SubtractGreen => {
+                    px[0] = r.wrapping_sub(g);
+                    px[2] = b.wrapping_sub(g);
+                }
+                Self::YCoCgR => {
+                    let co = r.wrapping_sub(b);
+                    let t = b.wrapping_add(half(co));
+                    let cg = g.wrapping_sub(t);
+                    px[0] = t.wrapping_add(half(cg));
+                    px[1] = co;
+                    px[2] = cg;
+                }
+                Self::Predictor {
+                    green_to_red,
+                    green_to_blue,
+                    red_to_blue,
+                } => {
+                    let red = r.wrapping_sub(g);
+                    px[0] = red.wrapping_sub(color_delta(green_to_red, g));
+                    px[2] = b
+                        .wrapping_sub(g)
+                        .wrapping_sub(color_delta(green_to_blue, g))
+                        .wrapping_sub(color_delta(red_to_blue, red));
+                }
+            }
+        }
+    }
+
+    pub fn inverse(&self, data: &mut [u8], channels: usize) {
+        if channels < 3 || *self == Self::None {
+            return;
+        }
+        for px in data.chunks_exact_mut(channels) {
+            match *self {
+                Self::None => {}
+                Self::SubtractGreen => {
+                    px[0] = px[0].wrapping_add(px[1]);
+                    px[2] = px[2].wrapping_add(px[1]);
+                }
+                Self::YCoCgR => {
+                    let (y, co, cg) = (px[0], px[1], px[2]);
+                    let t = y.wrapping_sub(half(cg));
+                    let g = cg.wrapping_add(t);
+                    let b = t.wrapping_sub(half(co));
+                    px[0] = b.wrapping_add(co);
+                    px[1] = g;
+                    px[2] = b;
+                }
+                Self::Predictor {
+                    green_to_red,
+                    green_to_blue,
+                    red_to_blue,
+                } => {
+                    let g = px[1];
+                    let red = px[0].wrapping_add(color_delta(green_to_red, g));
+                    px[2] = px[2]
+                        .wrapping_add(color_delta(red_to_blue, red))
+                        .wrapping_add(color_delta(green_to_blue, g))
+                        .wrapping_add(g);
+                    px[0] = red.wrapping_add(g);
+                }
+            }
+        }
+    }
+
+    pub fn learn(data: &[u8], width: usize, channels: usize) -> Self {
+        // Fit multipliers on horizontal gradients so that flat colour regions don't dominate.
+        let step = (data.len() / channels / 65536).max(1);
+        let mut gradients = Vec::new();
+        for (i, px) in data.chunks_exact(channels).enumerate().step_by(step) {
+            if i.is_multiple_of(width) {
+                continue;
+            }
+            let prev = &data[(i - 1) * channels..i * channels];
+            let dr = px[0]
+                .wrapping_sub(px[1])
+                .wrapping_sub(prev[0].wrapping_sub(prev[1]));
+            let db = px[2]
+                .wrapping_sub(px[1])
+                .wrapping_sub(prev[2].wrapping_sub(prev[1]));
+            let dg = px[1].wrapping_sub(prev[1]);
+            gradients.push((dr, dg, db));
+        }
+
+        let best_multiplier = |target: &dyn Fn(&(u8, u8, u8)) -> u8,
+                               source: &dyn Fn(&(u8, u8, u8)) -> u8| {
+            let mut best = (f64::MAX, 0i8);
+            for m in (-64i32..=64).step_by(2) {
+                let mut hist = [0u32; 256];
+                for g in &gradients {
+                    hist[target(g).wrapping_sub(color_delta(m as i8, source(g))) as usize] += 1;
+                }
+                let cost = entropy_bits(&hist);
+                if cost < best.0 {
+                    best = (cost, m as i8);
+                }
+            }
+            best.1
+        };
+
+        let green_to_red = best_multiplier(&|g| g.0, &|g| g.1);
+        let green_to_blue = best_multiplier(&|g| g.2, &|g| g.1);
+        let red_to_blue = best_multiplier(
+            &|g| g.2.wrapping_sub(color_delta(green_to_blue, g.1)),
+            &|g| g.0,
+        );
+
+        Self::Predictor {
+            green_to_red,
+            green_to_blue,
+            red_to_blue,
+        }
+    }
+}
+
+fn entropy_bits(hist: &[u32; 256]) -> f64 {
+    let total: u32 = hist.iter().sum();
+    if total == 0 {
+        return 0.0;
+    }
+    let total = total as f64;
+    hist.iter()
+        .filter(|&&c| c > 0)
+        .map(|&c| -(c as f64) * (c as f64 / total).log2())
+        .sum()
+}
+
+pub fn estimate_lossless_cost(data: &[u8], width: usize, height: usize, channels: usize) -> f64 {
+    let filtered = apply_optimal_predictor(data, width, height, channels);
+    let mut hist = [0u32; 256];
+    for &b in &filtered {
+        hist[b as usize] += 1;
+    }
+    entropy_bits(&hist)
+}
+
+pub fn select_color_transform(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    channels: usize,
+) -> ColorTransform {
+    let candidates = color_transform_candidates(data, width, channels, 4);
+    select_color_transform_among(data, width, height, channels, candidates)
+}
+
+// The first `count` of None, SubtractGreen, YCoCgR and a transform learned from the image.
+pub fn color_transform_candidates(
+    data: &[u8],
+    width: usize,
+    channels: usize,
+    count: usize,
+) -> Vec<ColorTransform> {
+    if channels < 3 {
+        return vec![ColorTransform::None];
+    }
+    let mut candidates = vec![
+        ColorTransform::None,
+        ColorTransform::SubtractGreen,
+        ColorTransform::YCoCgR,
+    ];
+    candidates.truncate(count.max(1));
+    if count > 3 {
+        candidates.push(ColorTransform::learn(data, width, channels));
+    }
+    candidates
+}
+
+pub fn select_color_transform_among(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    channels: usize,
+    candidates: Vec<ColorTransform>,
+) -> ColorTransform {
+    if candidates.len() < 2 {
+        return candidates
+            .into_iter()
+            .next()
+            .unwrap_or(ColorTransform::None);
+    }
+    let mut best = (f64::MAX, ColorTransform::None);
+    for transform in candidates {
+        let mut transformed = data.to_vec();
+        transform.forward(&mut transformed, channels);
+        let cost = estimate_lossless_cost(&transformed, width, height, channels);
+        if cost < best.0 {
+            best = (cost, transform);
+        }
+    }
+    best.1
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn noisy_image(width: usize, height: usize) -> Vec<u8> {
+        let mut seed = 0x1234_5678u32;
+        let mut data = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                seed = seed.wrapping_mul(1_664_525).wrapping_add(1_013_904_223);
+                let n = (seed >> 28) as usize;
+                let base = (x * 3 + y * 2 + n) as u8;
+                data.extend_from_slice(&[base.wrapping_add(40), base, base.wrapping_sub(20)]);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_transforms_are_reversible() {
+        let mut data: Vec<u8> = (0..=255u8)
+            .flat_map(|a| [a, a.wrapping_mul(7), a.wrapping_mul(13), 255 - a])
+            .collect();
+        data.extend(
+            noisy_image(8, 8)
+                .chunks(3)
+                .flat_map(|p| [p[0], p[1], p[2], 128]),
+        );
+
+        for transform in [
+            ColorTransform::SubtractGreen,
+            ColorTransform::YCoCgR,
+            ColorTransform::Predictor {
+                green_to_red: -17,
+                green_to_blue: 33,
+                red_to_blue: -64,
+            },
+        ] {
+            let mut transformed = data.clone();
+            transform.forward(&mut transformed, 4);
+            let (parsed, _) = ColorTransform::decode(&transform.encode()).unwrap();
+            parsed.inverse(&mut transformed, 4);
+            assert_eq!(transformed, data, "{:?}", transform);
+        }
+    }
+
+    #[test]
+    fn test_correlated_channels_pick_a_transform() {
+        let data = noisy_image(64, 64);
+        let transform = select_color_transform(&data, 64, 64, 3);
+        assert_ne!(transform, ColorTransform::None);
+
+        let mut transformed = data.clone();
+        transform.forward(&mut transformed, 3);
+        assert!(
+            estimate_lossless_cost(&transformed, 64, 64, 3)
+                < estimate_lossless_cost(&data, 64, 64, 3)
+        );
+    }
+}
@@ -62,6 +62,65 @@ pub fn idct_8x8(coeffs: &[i16; 64]) -> [i16; 64] {
     output
 }
 
+// Inverts only the top-left `size`×`size` coefficients, yielding the block at 1/(8/size) scale.
+// The result is packed at the start of the array with a row stride of `size`.
+pub fn idct_scaled(coeffs: &[i16; 64], size: usize) -> [i16; 64] {
+    let mut output = [0i16; 64];
+    if size == 1 {
+        output[0] = (coeffs[0] as f64 / 8.0).round() as i16;
+        return output;
+    }
+
+    let basis = |x: usize, u: usize| {
+        alpha(u) * ((2 * x + 1) as f64 * u as f64 * PI / (2 * size) as f64).cos()
+    };
+    let mut temp = [0.0f64; 64];
+    for v in 0..size {
+        for x in 0..size {
+            temp[v * size + x] = (0..size)
+                .map(|u| basis(x, u) * coeffs[v * 8 + u] as f64)
+                .sum();
+        }
+    }
+    for y in 0..size {
+        for x in 0..size {
+            let sum: f64 = (0..size).map(|v| basis(y, v) * temp[v * size + x]).sum();
+            output[y * size + x] = (0.25 * sum).round() as i16;
+        }
+    }
+    output
+}
+
+// Bottom row and right column of the 8×8 inverse DCT, enough to predict the neighbouring blocks.
+pub fn idct_edges(coeffs: &[i16; 64]) -> ([i16; 8], [i16; 8]) {
+    let mut basis = [[0.0f64; 8]; 8];
+    for (x, row) in basis.iter_mut().enumerate() {
+        for (u, b) in row.iter_mut().enumerate() {
+            *b = alpha(u) * ((2 * x + 1) as f64 * u as f64 * PI / 16.0).cos();
+        }
+    }
+
+    let mut last_row = [0.0f64; 8];
+    let mut last_col = [0.0f64; 8];
+    for v in 0..8 {
+        for u in 0..8 {
+            let coeff = coeffs[v * 8 + u] as f64;
+            last_row[u] += basis[7][v] * coeff;
+            last_col[v] += basis[7][u] * coeff;
+        }
+    }
+
+    let bottom = std::array::from_fn(|x| {
+        let sum: f64 = (0..8).map(|u| basis[x][u] * last_row[u]).sum();
+        (0.25 * sum).round() as i16
+    });
+    let right = std::array::from_fn(|y| {
+        let sum: f64 = (0..8).map(|v| basis[y][v] * last_col[v]).sum();
+        (0.25 * sum).round() as i16
+    });
+    (bottom, right)
+}
+
 pub fn dct_8x8_fast(block: &[i16; 64]) -> [i16; 64] {
     dct_8x8(block)
 }
@@ -91,3 +150,30 @@ pub fn zigzag_unscan(scanned: &[i16; 64]) -> [i16; 64] {
     }
     output
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sample_coeffs() -> [i16; 64] {
+        std::array::from_fn(|i| ((i as i16 * 37) % 41 - 20) * (8 - (i as i16 / 8)))
+    }
+
+    #[test]
+    fn test_reduced_idct_matches_full() {
+        let coeffs = sample_coeffs();
+        let full = idct_8x8(&coeffs);
+        assert_eq!(idct_scaled(&coeffs, 8), full);
+
+        let (bottom, right) = idct_edges(&coeffs);
+        for i in 0..8 {
+            assert!((bottom[i] - full[56 + i]).abs() <= 1);
+            assert!((right[i] - full[i * 8 + 7]).abs() <= 1);
+        }
+
+        let mut dc_only = [0i16; 64];
+        dc_only[0] = 800;
+        assert_eq!(idct_scaled(&dc_only, 1)[0], 100);
+        assert!(idct_scaled(&dc_only, 2)[..4].iter().all(|&v| v == 100));
+    }
+}
@@ -271,6 +271,61 @@ impl DeblockingFilter {
         data[(y + 1) * stride + x] = new_q1.clamp(0, 255) as u8;
     }
 
+    fn filter_sample(
+        &self,
+        data: &mut [u8],
+        stride: usize,
+        x: usize,
+        y: usize,
+        s: Samples,
+        level: i32,
+        dir: EdgeDir,
+    ) {
+        if !Self::should_filter(&s, self.config.edge_threshold, level) {
+            return;
+        }
+        let apply = if self.config.simple {
+            [Self::apply_simple_v, Self::apply_simple_h]
+        } else if Self::is_strong_flat(&s, level) {
+            [Self::apply_strong_v, Self::apply_strong_h]
+        } else if Self::is_flat(&s, level) {
+            [Self::apply_normal_v, Self::apply_normal_h]
+        } else {
+            [Self::apply_simple_v, Self::apply_simple_h]
+        };
+        match dir {
+            EdgeDir::Vertical => apply[0](data, stride, x, y, level),
+            EdgeDir::Horizontal => apply[1](data, stride, x, y, level),
+        }
+    }
+
+    #[cfg(feature = "simd")]
+    fn filter_lanes(
+        &self,
+        data: &mut [u8],
+        count: usize,
+        level: i32,
+        at: impl Fn(usize, usize) -> usize,
+    ) -> usize {
+        let body = count - count % 8;
+        for start in (0..body).step_by(8) {
+            let taps =
+                std::array::from_fn(|k| std::array::from_fn(|i| data[at(start + i, k)] as i16));
+            let out = super::simd::deblock_lanes(
+                taps,
+                self.config.edge_threshold,
+                level,
+                self.config.simple,
+            );
+            for (k, row) in out.iter().enumerate() {
+                for (i, &v) in row.iter().enumerate() {
+                    data[at(start + i, k + 1)] = v as u8;
+                }
+            }
+        }
+        body
+    }
+
     fn filter_edge_v(
         &self,
         data: &mut [u8],
@@ -284,30 +339,20 @@ impl DeblockingFilter {
         chroma: bool,
     ) {
         let eff_level = if chroma { (level * 3) / 4 } else { level };
-        if eff_level <= 0 {
+        if eff_level <= 0 || edge_x < 3 || edge_x + 2 >= w {
             return;
         }
 
-        for i in 0..len {
-            let y = y_start + i;
-            if y >= h {
-                break;
-            }
-
+        let count = len.min(h.saturating_sub(y_start));
+        #[cfg(feature = "simd")]
+        let first = self.filter_lanes(data, count, eff_level, |i, k| {
+            (y_start + i) * stride + edge_x + k - 3
+        });
+        #[cfg(not(feature = "simd"))]
+        let first = 0;
+        for y in y_start + first..y_start + count {
             if let Some(s) = Self::gather_samples_v(data, stride, edge_x, y, w, h) {
-                if !Self::should_filter(&s, self.config.edge_threshold, eff_level) {
-                    continue;
-                }
-
-                if self.config.simple {
-                    Self::apply_simple_v(data, stride, edge_x, y, eff_level);
-                } else if Self::is_strong_flat(&s, eff_level) {
-                    Self::apply_strong_v(data, stride, edge_x, y, eff_level);
-                } else if Self::is_flat(&s, eff_level) {
-                    Self::apply_normal_v(data, stride, edge_x, y, eff_level);
-                } else {
-                    Self::apply_simple_v(data, stride, edge_x, y, eff_level);
-                }
+                self.filter_sample(data, stride, edge_x, y, s, eff_level, EdgeDir::Vertical);
             }
         }
     }
@@ -325,30 +370,20 @@ impl DeblockingFilter {
         chroma: bool,
     ) {
         let eff_level = if chroma { (level * 3) / 4 } else { level };
-        if eff_level <= 0 {
+        if eff_level <= 0 || edge_y < 3 || edge_y + 2 >= h {
             return;
         }
 
-        for i in 0..len {
-            let x = x_start + i;
-            if x >= w {
-                break;
-            }
-
+        let count = len.min(w.saturating_sub(x_start));
+        #[cfg(feature = "simd")]
+        let first = self.filter_lanes(data, count, eff_level, |i, k| {
+            (edge_y + k - 3) * stride + x_start + i
+        });
+        #[cfg(not(feature = "simd"))]
+        let first = 0;
+        for x in x_start + first..x_start + count {
             if let Some(s) = Self::gather_samples_h(data, stride, x, edge_y, w, h) {
-                if !Self::should_filter(&s, self.config.edge_threshold, eff_level) {
-                    continue;
-                }
-
-                if self.config.simple {
-                    Self::apply_simple_h(data, stride, x, edge_y, eff_level);
-                } else if Self::is_strong_flat(&s, eff_level) {
-                    Self::apply_strong_h(data, stride, x, edge_y, eff_level);
-                } else if Self::is_flat(&s, eff_level) {
-                    Self::apply_normal_h(data, stride, x, edge_y, eff_level);
-                } else {
-                    Self::apply_simple_h(data, stride, x, edge_y, eff_level);
-                }
+                self.filter_sample(data, stride, x, edge_y, s, eff_level, EdgeDir::Horizontal);
             }
         }
     }
@@ -468,4 +503,67 @@ mod tests {
         };
         assert!(!DeblockingFilter::is_flat(&not_flat, 10));
     }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn test_lane_filter_matches_scalar() {
+        let (w, h) = (45, 37);
+        let mut seed = 12345u32;
+        let plane: Vec<u8> = (0..w * h)
+            .map(|i| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (((i % w) / 8 * 40 + (i / w) / 8 * 25) as u32 + (seed >> 28)) as u8
+            })
+            .collect();
+
+        for quality in [40, 75, 85, 95] {
+            let filter = DeblockingFilter::new(DeblockConfig::from_quality(quality));
+            let level = DeblockingFilter::apply_sharpness(
+                filter.config.strength as i32,
+                filter.config.sharpness,
+            );
+            for chroma in [false, true] {
+                let eff = if chroma { (level * 3) / 4 } else { level };
+                for edge in [3, 8, 16, 24, 32, 40] {
+                    let mut lanes = plane.clone();
+                    let mut scalar = plane.clone();
+                    filter.filter_edge_v(&mut lanes, w, edge, 0, h, w, h, level, chroma);
+                    for y in 0..h {
+                        if let Some(s) =
+                            DeblockingFilter::gather_samples_v(&scalar, w, edge, y, w, h)
+                        {
+                            filter.filter_sample(
+                                &mut scalar,
+                                w,
+                                edge,
+                                y,
+                                s,
+                                eff,
+                                EdgeDir::Vertical,
+                            );
+                        }
+                    }
+                    assert_eq!(lanes, scalar);
+
+                    filter.filter_edge_h(&mut lanes, w, edge, 0, w, w, h, level, chroma);
+                    for x in 0..w {
+                        if let Some(s) =
+                            DeblockingFilter::gather_samples_h(&scalar, w, x, edge, w, h)
+                        {
+                            filter.filter_sample(
+                                &mut scalar,
+                                w,
+                                x,
+                                edge,
+                                s,
+                                eff,
+                                EdgeDir::Horizontal,
+                            );
+                        }
+                    }
+                    assert_eq!(lanes, scalar);
+                }
+            }
+        }
+    }
 }
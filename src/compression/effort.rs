@@ -0,0 +1,108 @@
+use super::intra_prediction::IntraMode;
+use super::predictor::{PredictorType, ALL_PREDICTORS};
+
+pub const MAX_EFFORT: u8 = 9;
+pub const DEFAULT_EFFORT: u8 = 5;
+
+const SMOOTH_MODES: [IntraMode; 5] = [
+    IntraMode::DC,
+    IntraMode::Horizontal,
+    IntraMode::Vertical,
+    IntraMode::Planar,
+    IntraMode::TrueMotion,
+];
+
+const FAST_PREDICTORS: [PredictorType; 3] =
+    [PredictorType::Sub, PredictorType::Up, PredictorType::Paeth];
+
+// Row predictors are picked by a residual heuristic, so a wider set can code
+// larger; each level also tries the sets of the levels below it.
+const PREDICTOR_SETS: [&[PredictorType]; 3] =
+    [&[PredictorType::Paeth], &FAST_PREDICTORS, &ALL_PREDICTORS];
+
+#[derive(Debug, Clone, Copy)]
+pub struct EffortProfile {
+    pub intra_modes: &'static [IntraMode],
+    pub rdo_candidates: usize,
+    pub retry_edge_modes: bool,
+    pub deflate_level: u32,
+    pub try_entropy_coders: bool,
+    pub predictor_sets: &'static [&'static [PredictorType]],
+    pub transform_trials: usize,
+    pub try_lossless_engines: bool,
+}
+
+impl EffortProfile {
+    pub fn from_effort(effort: u8) -> Self {
+        let effort = effort.min(MAX_EFFORT);
+        let intra_modes: &'static [IntraMode] = match effort {
+            0 => &IntraMode::SAFE_EDGE[..1],
+            1 | 2 => &IntraMode::SAFE_EDGE,
+            3 | 4 => &SMOOTH_MODES,
+            _ => &IntraMode::ALL,
+        };
+        // From the default up, the best few modes by SAD are re-ranked by rate and distortion.
+        let rdo_candidates = match effort {
+            0..=4 => 0,
+            5 => 2,
+            6 => 3,
+            7 => 5,
+            _ => IntraMode::ALL.len(),
+        };
+        let deflate_level = match effort {
+            0 => 1,
+            1 => 3,
+            2 | 3 => 6,
+            _ => 9,
+        };
+        let predictor_sets = match effort {
+            0 => &PREDICTOR_SETS[..1],
+            1 | 2 => &PREDICTOR_SETS[..2],
+            _ => &PREDICTOR_SETS[..],
+        };
+
+        Self {
+            intra_modes,
+            rdo_candidates,
+            retry_edge_modes: effort >= 3,
+            deflate_level,
+            try_entropy_coders: effort >= 9,
+            predictor_sets,
+            transform_trials: match effort {
+                0 => 1,
+                1 | 2 => 3,
+                _ => 4,
+            },
+            try_lossless_engines: effort >= 7,
+        }
+    }
+}
+
+impl Default for EffortProfile {
+    fn default() -> Self {
+        Self::from_effort(DEFAULT_EFFORT)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_profiles_widen_with_effort() {
+        let profiles: Vec<EffortProfile> =
+            (0..=MAX_EFFORT).map(EffortProfile::from_effort).collect();
+        for pair in profiles.windows(2) {
+            assert!(pair[1].intra_modes.len() >= pair[0].intra_modes.len());
+            assert!(pair[1].rdo_candidates >= pair[0].rdo_candidates);
+            assert!(pair[1].deflate_level >= pair[0].deflate_level);
+            assert!(pair[1].transform_trials >= pair[0].transform_trials);
+            assert!(pair[1].predictor_sets.len() >= pair[0].predictor_sets.len());
+        }
+        assert_eq!(
+            EffortProfile::from_effort(42).rdo_candidates,
+            IntraMode::ALL.len()
+        );
+        assert_eq!(EffortProfile::default().intra_modes, &IntraMode::ALL[..]);
+    }
+}
@@ -1,20 +1,52 @@
 use super::adaptive_quant::{AdaptiveQuantizer, QuantTable};
 use super::arithmetic_coder::{
-    compress_coefficients, decode_coefficients, decompress_coefficients, encode_coefficients,
-    ArithmeticDecoder, ArithmeticEncoder, CABACContext,
+    block_bits, compress_coefficients_at, decode_coefficients, decompress_coefficients,
+    encode_coefficients, ArithmeticDecoder, ArithmeticEncoder, CABACContext,
+};
+use super::color::{
+    convert_rgb_to_ycbcr_image, convert_ycbcr_to_rgb_image, ColorMatrix, ColorRange, ColorSpace,
+};
+use super::color_transform::{color_transform_candidates, ColorTransform};
+use super::dct::{
+    dct_8x8_fast, idct_8x8_fast, idct_edges, idct_scaled, zigzag_scan, zigzag_unscan,
 };
-use super::color::{convert_rgb_to_ycbcr_image, convert_ycbcr_to_rgb_image, ColorSpace};
-use super::dct::{dct_8x8_fast, idct_8x8_fast, zigzag_scan, zigzag_unscan};
 use super::deblocking::{DeblockConfig, DeblockingFilter};
+use super::effort::{EffortProfile, DEFAULT_EFFORT};
 use super::entropy::{EntropyDecoder, EntropyEncoder};
 use super::intra_prediction::{IntraMode, IntraPredictor};
-use super::predictor::{apply_optimal_predictor, reverse_predictor};
+use super::loco;
+use super::lz77;
+use super::multi_dct::{int_dct_8x8, int_idct_8x8, int_idct_edges, int_idct_scaled};
+use super::predictor::{apply_predictors_among, reverse_predictor};
 use super::quantizer::Quantizer;
-use super::simd::{dct_8x8_simd, detect_simd, idct_8x8_simd, SimdLevel};
-use crate::error::WkResult;
+use super::simd::{detect_simd, idct_8x8_simd, int_idct_8x8_simd, SimdLevel};
+use crate::error::{WkError, WkResult};
 use crate::format::header::CompressionMode;
+use crate::format::progressive::{
+    find_resync_marker, merge_progressive_coefficients, reorder_coefficients, DamagedRegion,
+    ScanPass, RESYNC_MARKER,
+};
 use rayon::prelude::*;
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+#[repr(u8)]
+pub enum LosslessEngine {
+    Huffman = 0,
+    Loco = 1,
+    Lz77 = 2,
+}
+
+impl LosslessEngine {
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::Huffman),
+            1 => Some(Self::Loco),
+            2 => Some(Self::Lz77),
+            _ => None,
+        }
+    }
+}
+
 #[derive(Debug, Clone)]
 pub struct CompressionConfig {
     pub mode: CompressionMode,
@@ -24,6 +56,16 @@ pub struct CompressionConfig {
     pub use_intra_prediction: bool,
     pub use_adaptive_quant: bool,
     pub use_simd: bool,
+    pub color_matrix: Option<ColorMatrix>,
+    pub color_range: ColorRange,
+    // None lets the effort level pick: Huffman, or the smallest of all engines
+    // from effort 7.
+    pub lossless_engine: Option<LosslessEngine>,
+    // Progressive streams turn intra prediction off, since a block would
+    // otherwise depend on fully refined neighbours, so they code larger.
+    pub progressive: bool,
+    pub resync_rows: u16,
+    pub effort: u8,
 }
 
 impl Default for CompressionConfig {
@@ -36,6 +78,12 @@ impl Default for CompressionConfig {
             use_intra_prediction: true,
             use_adaptive_quant: true,
             use_simd: true,
+            color_matrix: None,
+            color_range: ColorRange::Full,
+            lossless_engine: None,
+            progressive: false,
+            resync_rows: 0,
+            effort: DEFAULT_EFFORT,
         }
     }
 }
@@ -50,6 +98,12 @@ impl CompressionConfig {
             use_intra_prediction: false,
             use_adaptive_quant: false,
             use_simd: true,
+            color_matrix: None,
+            color_range: ColorRange::Full,
+            lossless_engine: None,
+            progressive: false,
+            resync_rows: 0,
+            effort: DEFAULT_EFFORT,
         }
     }
 
@@ -62,6 +116,12 @@ impl CompressionConfig {
             use_intra_prediction: true,
             use_adaptive_quant: true,
             use_simd: true,
+            color_matrix: None,
+            color_range: ColorRange::Full,
+            lossless_engine: None,
+            progressive: false,
+            resync_rows: 0,
+            effort: DEFAULT_EFFORT,
         }
     }
 
@@ -74,6 +134,12 @@ impl CompressionConfig {
             use_intra_prediction: false,
             use_adaptive_quant: false,
             use_simd: true,
+            color_matrix: None,
+            color_range: ColorRange::Full,
+            lossless_engine: None,
+            progressive: false,
+            resync_rows: 0,
+            effort: DEFAULT_EFFORT,
         }
     }
 
@@ -86,13 +152,323 @@ impl CompressionConfig {
             use_intra_prediction: true,
             use_adaptive_quant: true,
             use_simd: true,
+            color_matrix: None,
+            color_range: ColorRange::Full,
+            lossless_engine: None,
+            progressive: false,
+            resync_rows: 0,
+            effort: DEFAULT_EFFORT,
+        }
+    }
+}
+
+const STREAM_EXTENDED: u8 = 0x02;
+const LOSSLESS_V2_MARKER: [u8; 4] = [0xFF; 4];
+
+const EXTENSION_PROGRESSIVE: u8 = 0x01;
+const EXTENSION_RESYNC: u8 = 0x02;
+const EXTENSION_INT_TRANSFORM: u8 = 0x04;
+const SEGMENT_HEADER_LEN: usize = 14;
+const RDO_LAMBDA: f64 = 4.0;
+
+#[derive(Debug, Clone, Copy)]
+struct LossyExtension {
+    color_matrix: ColorMatrix,
+    color_range: ColorRange,
+    progressive: bool,
+    resync_rows: u16,
+    integer_transform: bool,
+}
+
+impl LossyExtension {
+    fn legacy() -> Self {
+        Self {
+            color_matrix: ColorMatrix::Bt601,
+            color_range: ColorRange::Full,
+            progressive: false,
+            resync_rows: 0,
+            integer_transform: false,
+        }
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut flags = 0;
+        if self.progressive {
+            flags |= EXTENSION_PROGRESSIVE;
+        }
+        if self.resync_rows > 0 {
+            flags |= EXTENSION_RESYNC;
+        }
+        if self.integer_transform {
+            flags |= EXTENSION_INT_TRANSFORM;
+        }
+        let mut bytes = vec![self.color_matrix as u8, self.color_range as u8, flags];
+        if self.resync_rows > 0 {
+            bytes.extend(&self.resync_rows.to_le_bytes());
+        }
+        bytes
+    }
+
+    fn decode(data: &[u8]) -> WkResult<Self> {
+        if data.len() < 2 {
+            return Err(WkError::DecodingError("Stream extension too short".into()));
+        }
+        let color_matrix = ColorMatrix::from_u8(data[0])
+            .ok_or_else(|| WkError::DecodingError("Unknown color matrix".into()))?;
+        let color_range = ColorRange::from_u8(data[1])
+            .ok_or_else(|| WkError::DecodingError("Unknown color range".into()))?;
+        let flags = data.get(2).copied().unwrap_or(0);
+        let resync_rows = if flags & EXTENSION_RESYNC != 0 {
+            let rows = data
+                .get(3..5)
+                .ok_or_else(|| WkError::DecodingError("Stream extension too short".into()))?;
+            u16::from_le_bytes([rows[0], rows[1]]).max(1)
+        } else {
+            0
+        };
+        Ok(Self {
+            color_matrix,
+            color_range,
+            progressive: flags & EXTENSION_PROGRESSIVE != 0,
+            resync_rows,
+            integer_transform: flags & EXTENSION_INT_TRANSFORM != 0,
+        })
+    }
+
+    fn color_space(&self) -> ColorSpace {
+        ColorSpace::new(self.color_matrix, self.color_range)
+    }
+}
+
+struct LossyStream<'a> {
+    use_cabac: bool,
+    use_intra: bool,
+    use_adaptive: bool,
+    base_table: [u16; 64],
+    chroma_table: [u16; 64],
+    extension: LossyExtension,
+    payload: &'a [u8],
+}
+
+#[derive(Default)]
+struct LossyPlane {
+    modes: Vec<u8>,
+    qps: Vec<u8>,
+    blocks: Vec<[i16; 64]>,
+}
+
+impl LossyPlane {
+    fn slice(&self, blocks: std::ops::Range<usize>) -> Self {
+        Self {
+            modes: self.modes[blocks.clone()].to_vec(),
+            qps: self.qps[blocks.clone()].to_vec(),
+            blocks: self.blocks[blocks].to_vec(),
+        }
+    }
+
+    fn encode_side_info(&self, output: &mut Vec<u8>) {
+        output.extend(&(self.modes.len() as u32).to_le_bytes());
+        output.extend(&self.modes);
+        output.extend(&(self.qps.len() as u32).to_le_bytes());
+        output.extend(&self.qps);
+    }
+
+    fn decode_side_info(data: &[u8], pos: &mut usize) -> WkResult<Self> {
+        let modes = read_section(data, pos)?.to_vec();
+        let qps = read_section(data, pos)?.to_vec();
+        Ok(Self {
+            modes,
+            qps,
+            blocks: Vec::new(),
+        })
+    }
+}
+
+// A lossy stream with resync segments, decoded while it is still arriving.
+// Each segment is entropy decoded and reconstructed once, when it is whole;
+// deblocking and colour conversion then revisit only the block row above it.
+pub(crate) struct SegmentDecoder {
+    width: usize,
+    height: usize,
+    channels: usize,
+    planes: Vec<LossyPlane>,
+    // Reconstructed planes before deblocking.
+    padded: Vec<Vec<u8>>,
+    pixels: Vec<u8>,
+    // Where in the payload to look for the next marker.
+    scanned: usize,
+    // Block rows reconstructed and pixel rows final, counted from the top.
+    block_rows: usize,
+    final_rows: usize,
+}
+
+impl SegmentDecoder {
+    pub(crate) fn new(width: usize, height: usize, channels: usize) -> Self {
+        let blocks = width.div_ceil(8) * height.div_ceil(8);
+        let plane_count = coded_planes(channels);
+        Self {
+            width,
+            height,
+            channels,
+            planes: (0..plane_count)
+                .map(|_| LossyPlane {
+                    modes: vec![0; blocks],
+                    qps: vec![0; blocks],
+                    blocks: vec![[0; 64]; blocks],
+                })
+                .collect(),
+            padded: vec![vec![128; blocks * 64]; plane_count],
+            pixels: vec![0; width * height * channels],
+            scanned: 0,
+            block_rows: 0,
+            final_rows: 0,
+        }
+    }
+
+    pub(crate) fn final_rows(&self) -> usize {
+        self.final_rows
+    }
+
+    // Rows from `final_rows` down are not decoded yet.
+    pub(crate) fn pixels(&self) -> &[u8] {
+        &self.pixels
+    }
+}
+
+fn read_section<'a>(data: &'a [u8], pos: &mut usize) -> WkResult<&'a [u8]> {
+    let truncated = || WkError::DecodingError("Truncated lossy stream".into());
+    let len_bytes = data.get(*pos..*pos + 4).ok_or_else(truncated)?;
+    let len = u32::from_le_bytes([len_bytes[0], len_bytes[1], len_bytes[2], len_bytes[3]]) as usize;
+    let section = data.get(*pos + 4..*pos + 4 + len).ok_or_else(truncated)?;
+    *pos += 4 + len;
+    Ok(section)
+}
+
+// Covers the index and length as well as the body, so a damaged header
+// cannot place an intact body at the wrong rows.
+fn segment_crc(index_and_len: &[u8], body: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(index_and_len);
+    hasher.update(body);
+    hasher.finalize()
+}
+
+fn segment_top(by: usize, segment_rows: usize) -> usize {
+    by.checked_div(segment_rows).unwrap_or(0) * segment_rows
+}
+
+fn is_chroma_plane(plane: usize, channels: usize, color_space: ColorSpace) -> bool {
+    plane > 0 && channels >= 3 && color_space != ColorSpace::RGB
+}
+
+// Interleaves decoded planes into pixels, converting colour images to RGB.
+fn interleave_planes(
+    planes: &[Vec<u8>],
+    width: usize,
+    height: usize,
+    channels: usize,
+    color_space: ColorSpace,
+) -> Vec<u8> {
+    if channels >= 3 {
+        convert_ycbcr_to_rgb_image(
+            &planes[0],
+            &planes[1],
+            &planes[2],
+            width,
+            height,
+            channels,
+            color_space,
+        )
+    } else {
+        let mut out = vec![0u8; width * height * channels];
+        for ch in 0..channels {
+            for i in 0..(width * height) {
+                out[i * channels + ch] = planes[ch][i];
+            }
         }
+        out
+    }
+}
+
+// Fills damaged block rows by interpolating between the nearest intact pixel rows.
+fn conceal_rows(padded: &mut [u8], stride: usize, damaged_rows: &[bool], block_size: usize) {
+    let rows = padded.len() / stride;
+    let mut by = 0;
+    while by < damaged_rows.len() {
+        if !damaged_rows[by] {
+            by += 1;
+            continue;
+        }
+        let start = by;
+        while by < damaged_rows.len() && damaged_rows[by] {
+            by += 1;
+        }
+        let (y0, y1) = (start * block_size, (by * block_size).min(rows));
+        let above = y0.checked_sub(1);
+        let below = (y1 < rows).then_some(y1);
+        for x in 0..stride {
+            for y in y0..y1 {
+                padded[y * stride + x] = match (above, below) {
+                    (Some(a), Some(b)) => {
+                        let (va, vb) = (
+                            padded[a * stride + x] as usize,
+                            padded[b * stride + x] as usize,
+                        );
+                        let t = y - a;
+                        ((va * (b - y) + vb * t + (b - a) / 2) / (b - a)) as u8
+                    }
+                    (Some(a), None) => padded[a * stride + x],
+                    (None, Some(b)) => padded[b * stride + x],
+                    (None, None) => 128,
+                };
+            }
+        }
+    }
+}
+
+pub(crate) fn box_downscale(
+    data: Vec<u8>,
+    width: usize,
+    height: usize,
+    channels: usize,
+    scale: usize,
+) -> Vec<u8> {
+    if scale <= 1 {
+        return data;
+    }
+    let (out_w, out_h) = (width.div_ceil(scale), height.div_ceil(scale));
+    let mut output = vec![0u8; out_w * out_h * channels];
+    for oy in 0..out_h {
+        let rows = oy * scale..((oy + 1) * scale).min(height);
+        for ox in 0..out_w {
+            let cols = ox * scale..((ox + 1) * scale).min(width);
+            let count = rows.len() * cols.len();
+            for ch in 0..channels {
+                let sum: usize = rows
+                    .clone()
+                    .flat_map(|y| cols.clone().map(move |x| (y * width + x) * channels + ch))
+                    .map(|i| data[i] as usize)
+                    .sum();
+                output[(oy * out_w + ox) * channels + ch] = ((sum + count / 2) / count) as u8;
+            }
+        }
+    }
+    output
+}
+
+// Colour images code three planes; any alpha channel is not carried by the lossy stream.
+fn coded_planes(channels: usize) -> usize {
+    if channels >= 3 {
+        3
+    } else {
+        channels
     }
 }
 
 pub struct CompressionEngine {
     config: CompressionConfig,
     simd_level: SimdLevel,
+    decode_scale: usize,
 }
 
 impl CompressionEngine {
@@ -102,7 +478,29 @@ impl CompressionEngine {
         } else {
             SimdLevel::None
         };
-        Self { config, simd_level }
+        Self {
+            config,
+            simd_level,
+            decode_scale: 1,
+        }
+    }
+
+    // Lossy v3 streams are reconstructed directly at 1/scale; other payloads are box-filtered.
+    pub fn with_decode_scale(mut self, scale: usize) -> Self {
+        self.decode_scale = scale.clamp(1, 8).next_power_of_two();
+        self
+    }
+
+    fn profile(&self) -> EffortProfile {
+        EffortProfile::from_effort(self.config.effort)
+    }
+
+    fn int_idct(&self, coeffs: &[i16; 64]) -> [i16; 64] {
+        if self.simd_level != SimdLevel::None {
+            int_idct_8x8_simd(coeffs)
+        } else {
+            int_idct_8x8(coeffs)
+        }
     }
 
     pub fn compress_lossless(
@@ -112,9 +510,59 @@ impl CompressionEngine {
         height: usize,
         channels: usize,
     ) -> WkResult<Vec<u8>> {
-        let filtered = apply_optimal_predictor(data, width, height, channels);
-        let mut encoder = EntropyEncoder::new();
-        Ok(encoder.encode_with_huffman(&filtered))
+        let profile = self.profile();
+        let transforms =
+            color_transform_candidates(data, width, channels, profile.transform_trials);
+        let engines = match self.config.lossless_engine {
+            Some(engine) => vec![engine],
+            None if profile.try_lossless_engines => vec![
+                LosslessEngine::Loco,
+                LosslessEngine::Huffman,
+                LosslessEngine::Lz77,
+            ],
+            None => vec![LosslessEngine::Huffman],
+        };
+
+        let mut best: Option<Vec<u8>> = None;
+        for transform in transforms {
+            let mut transformed = data.to_vec();
+            transform.forward(&mut transformed, channels);
+            for &engine in &engines {
+                let bodies = match engine {
+                    LosslessEngine::Huffman => profile
+                        .predictor_sets
+                        .iter()
+                        .map(|&predictors| {
+                            let filtered = apply_predictors_among(
+                                &transformed,
+                                width,
+                                height,
+                                channels,
+                                predictors,
+                            );
+                            let mut encoder = EntropyEncoder::new();
+                            encoder.encode_with_huffman(&filtered)
+                        })
+                        .collect(),
+                    LosslessEngine::Loco => {
+                        vec![loco::compress(&transformed, width, height, channels)]
+                    }
+                    LosslessEngine::Lz77 => {
+                        vec![lz77::compress(&transformed, width, height, channels)]
+                    }
+                };
+                for body in bodies {
+                    let mut output = LOSSLESS_V2_MARKER.to_vec();
+                    output.push(engine as u8);
+                    output.extend(transform.encode());
+                    output.extend(body);
+                    if best.as_ref().is_none_or(|b| output.len() < b.len()) {
+                        best = Some(output);
+                    }
+                }
+            }
+        }
+        Ok(best.unwrap_or_default())
     }
 
     pub fn decompress_lossless(
@@ -124,31 +572,46 @@ impl CompressionEngine {
         height: usize,
         channels: usize,
     ) -> WkResult<Vec<u8>> {
-        let decoder = EntropyDecoder::new();
-        let filtered = decoder.decode_huffman(data)?;
-        reverse_predictor(&filtered, width, height, channels)
+        // Legacy payloads start with the Huffman frequency table, which can never hold the marker.
+        if !data.starts_with(&LOSSLESS_V2_MARKER) {
+            let decoder = EntropyDecoder::new();
+            let filtered = decoder.decode_huffman(data)?;
+            return reverse_predictor(&filtered, width, height, channels);
+        }
+
+        let engine = *data
+            .get(4)
+            .ok_or_else(|| WkError::DecodingError("Truncated lossless header".into()))?;
+        let (transform, transform_len) = ColorTransform::decode(&data[5..])?;
+        let body = &data[5 + transform_len..];
+
+        let engine = LosslessEngine::from_u8(engine).ok_or_else(|| {
+            WkError::DecodingError(format!("Unknown lossless engine: {}", engine))
+        })?;
+        let mut pixels = match engine {
+            LosslessEngine::Huffman => {
+                let filtered = EntropyDecoder::new().decode_huffman(body)?;
+                reverse_predictor(&filtered, width, height, channels)?
+            }
+            LosslessEngine::Loco => loco::decompress(body, width, height, channels)?,
+            LosslessEngine::Lz77 => lz77::decompress(body, width, height, channels)?,
+        };
+        transform.inverse(&mut pixels, channels);
+        Ok(pixels)
     }
 
-    pub fn compress_lossy_v3(
+    fn lossy_stream_header(
         &self,
-        data: &[u8],
-        width: usize,
-        height: usize,
-        channels: usize,
-    ) -> WkResult<Vec<u8>> {
-        let adaptive_quant = AdaptiveQuantizer::new(self.config.quality);
-        let predictor = IntraPredictor::new(8);
-        let block_width = (width + 7) / 8;
-        let block_height = (height + 7) / 8;
-        let padded_w = block_width * 8;
-        let padded_h = block_height * 8;
-
+        extension: &LossyExtension,
+        use_intra: bool,
+        use_cabac: bool,
+    ) -> Vec<u8> {
         let mut output = Vec::new();
-        output.push(if self.config.use_cabac { 1 } else { 0 });
-        output.push(if self.config.use_intra_prediction {
-            1
+        output.push(if use_cabac { 1 } else { 0 });
+        output.push(if use_intra {
+            1 | STREAM_EXTENDED
         } else {
-            0
+            STREAM_EXTENDED
         });
         output.push(if self.config.use_adaptive_quant { 1 } else { 0 });
 
@@ -160,12 +623,39 @@ impl CompressionEngine {
         for &v in &chroma_table.table {
             output.extend(&v.to_le_bytes());
         }
+        let extension_bytes = extension.encode();
+        output.extend(&(extension_bytes.len() as u16).to_le_bytes());
+        output.extend(&extension_bytes);
+        output
+    }
 
-        let mut all_data: Vec<u8> = Vec::new();
+    fn encode_lossy_planes(
+        &self,
+        data: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+        color_space: ColorSpace,
+        use_intra: bool,
+        segment_rows: usize,
+        intra_modes: &[IntraMode],
+        rdo_candidates: usize,
+    ) -> Vec<LossyPlane> {
+        let adaptive_quant = AdaptiveQuantizer::new(self.config.quality);
+        let predictor = IntraPredictor::new(8);
+        let edge_modes: Vec<IntraMode> = intra_modes
+            .iter()
+            .copied()
+            .filter(|mode| IntraMode::SAFE_EDGE.contains(mode))
+            .collect();
+        let block_width = width.div_ceil(8);
+        let block_height = height.div_ceil(8);
+        let padded_w = block_width * 8;
+        let padded_h = block_height * 8;
 
         let ycbcr_planes: Vec<Vec<u8>> = if channels >= 3 {
             let (y, cb, cr) =
-                convert_rgb_to_ycbcr_image(data, width, height, channels, ColorSpace::YCbCrFull);
+                convert_rgb_to_ycbcr_image(data, width, height, channels, color_space);
             vec![y, cb, cr]
         } else {
             (0..channels)
@@ -179,8 +669,9 @@ impl CompressionEngine {
                 .collect()
         };
 
+        let mut planes = Vec::with_capacity(ycbcr_planes.len());
         for ch in 0..ycbcr_planes.len() {
-            let is_chroma = ch > 0 && ycbcr_planes.len() >= 3;
+            let is_chroma = ch > 0 && ycbcr_planes.len() >= 3 && color_space != ColorSpace::RGB;
             let channel_data = &ycbcr_planes[ch];
 
             let mut padded = vec![128u8; padded_w * padded_h];
@@ -200,9 +691,7 @@ impl CompressionEngine {
             }
 
             let mut reconstructed = padded.clone();
-            let mut intra_modes = Vec::new();
-            let mut block_qps = Vec::new();
-            let mut coeffs_data = Vec::new();
+            let mut plane = LossyPlane::default();
 
             for by in 0..block_height {
                 for bx in 0..block_width {
@@ -213,32 +702,10 @@ impl CompressionEngine {
                         }
                     }
 
+                    // Prediction restarts at each resync segment so segments decode independently.
+                    let top_row = segment_top(by, segment_rows);
                     let (top, left, top_left) =
-                        self.get_neighbors(&reconstructed, padded_w, bx, by);
-
-                    let (mode, residual, pred) = if self.config.use_intra_prediction {
-                        let is_first_row = by == 0;
-                        let is_first_col = bx == 0;
-                        let (best_mode, _) = predictor.select_best_mode_edge(
-                            &block,
-                            &top,
-                            &left,
-                            top_left,
-                            is_first_row,
-                            is_first_col,
-                        );
-                        let pred = predictor.predict(best_mode, &top, &left, top_left);
-                        let res: Vec<i16> = block
-                            .iter()
-                            .zip(pred.iter())
-                            .map(|(&b, &p)| b as i16 - p as i16)
-                            .collect();
-                        (best_mode, res, pred)
-                    } else {
-                        let res: Vec<i16> = block.iter().map(|&b| b as i16 - 128).collect();
-                        (IntraMode::DC, res, vec![128u8; 64])
-                    };
-                    intra_modes.push(mode.to_u8());
+                        self.get_neighbors(&reconstructed, padded_w, bx, by, top_row);
 
                     let qp = if self.config.use_adaptive_quant {
                         let stats = adaptive_quant.analyze_block(&block, 8);
@@ -246,90 +713,290 @@ impl CompressionEngine {
                     } else {
                         self.config.quality
                     };
-                    block_qps.push(qp);
-
-                    let mut block_i16 = [0i16; 64];
-                    for i in 0..64 {
-                        block_i16[i] = residual[i];
-                    }
+                    plane.qps.push(qp);
+                    let table = adaptive_quant.get_table(qp, is_chroma);
 
-                    let dct = if self.simd_level != SimdLevel::None {
-                        dct_8x8_simd(&block_i16)
-                    } else {
-                        dct_8x8_fast(&block_i16)
+                    let code = |pred: &[u8]| -> ([i16; 64], [u8; 64]) {
+                        let residual: [i16; 64] =
+                            std::array::from_fn(|i| block[i] as i16 - pred[i] as i16);
+                        let quantized = adaptive_quant.quantize(&int_dct_8x8(&residual), &table);
+                        let idct_block =
+                            self.int_idct(&adaptive_quant.dequantize(&quantized, &table));
+                        let recon = std::array::from_fn(|i| {
+                            (pred[i] as i16 + idct_block[i]).clamp(0, 255) as u8
+                        });
+                        (quantized, recon)
                     };
 
-                    let table = adaptive_quant.get_table(qp, is_chroma);
-                    let quantized = adaptive_quant.quantize(&dct, &table);
-                    let scanned = zigzag_scan(&quantized);
-                    coeffs_data.push(scanned);
-
-                    let dequantized = adaptive_quant.dequantize(&quantized, &table);
-                    let idct_block = if self.simd_level != SimdLevel::None {
-                        idct_8x8_simd(&dequantized)
+                    let (mode, quantized, recon) = if use_intra {
+                        let candidates = if by == top_row || bx == 0 {
+                            &edge_modes
+                        } else {
+                            intra_modes
+                        };
+                        let ranked =
+                            predictor.rank_modes(&block, &top, &left, top_left, candidates);
+                        let depth = rdo_candidates.min(ranked.len());
+                        if depth > 1 {
+                            let lambda = RDO_LAMBDA * (table.table[1] as f64).powi(2);
+                            let previous = plane.modes.last().copied();
+                            let switch_bits = 1 + candidates.len().ilog2();
+                            ranked[..depth]
+                                .iter()
+                                .map(|&(mode, _)| {
+                                    let (quantized, recon) =
+                                        code(&predictor.predict(mode, &top, &left, top_left));
+                                    let sse: u64 = block
+                                        .iter()
+                                        .zip(&recon)
+                                        .map(|(&a, &b)| (a.abs_diff(b) as u64).pow(2))
+                                        .sum();
+                                    let mut bits = block_bits(&zigzag_scan(&quantized)) + 1;
+                                    if previous != Some(mode.to_u8()) {
+                                        bits += switch_bits;
+                                    }
+                                    (sse as f64 + lambda * bits as f64, mode, quantized, recon)
+                                })
+                                .min_by(|a, b| a.0.total_cmp(&b.0))
+                                .map(|(_, mode, quantized, recon)| (mode, quantized, recon))
+                                .unwrap()
+                        } else {
+                            let mode = ranked[0].0;
+                            let (quantized, recon) =
+                                code(&predictor.predict(mode, &top, &left, top_left));
+                            (mode, quantized, recon)
+                        }
                     } else {
-                        idct_8x8_fast(&dequantized)
+                        let (quantized, recon) = code(&[128u8; 64]);
+                        (IntraMode::DC, quantized, recon)
                     };
+                    plane.modes.push(mode.to_u8());
+                    plane.blocks.push(quantized);
 
                     for y in 0..8 {
-                        for x in 0..8 {
-                            let pred_val = pred[y * 8 + x] as i16;
-                            let val = (pred_val + idct_block[y * 8 + x]).clamp(0, 255) as u8;
-                            reconstructed[(by * 8 + y) * padded_w + bx * 8 + x] = val;
-                        }
+                        reconstructed[(by * 8 + y) * padded_w + bx * 8..][..8]
+                            .copy_from_slice(&recon[y * 8..y * 8 + 8]);
                     }
                 }
             }
+            planes.push(plane);
+        }
+        planes
+    }
 
-            let mode_bytes: Vec<u8> = intra_modes.iter().map(|&m| m).collect();
-            all_data.extend(&(mode_bytes.len() as u32).to_le_bytes());
-            all_data.extend(&mode_bytes);
+    fn encode_coefficient_section(
+        &self,
+        blocks: &[Vec<i16>],
+        use_cabac: bool,
+        output: &mut Vec<u8>,
+    ) {
+        let encoded = if use_cabac {
+            let mut cabac_encoder = ArithmeticEncoder::new();
+            let mut ctx = CABACContext::new(8);
+            for coeffs in blocks {
+                encode_coefficients(&mut cabac_encoder, &mut ctx, coeffs);
+            }
+            cabac_encoder.finish()
+        } else {
+            let flat: Vec<i16> = blocks.iter().flatten().copied().collect();
+            EntropyEncoder::new().encode_rle_huffman(&flat)
+        };
+        output.extend(&(encoded.len() as u32).to_le_bytes());
+        output.extend(&encoded);
+    }
 
-            let qp_bytes: Vec<u8> = block_qps.clone();
-            all_data.extend(&(qp_bytes.len() as u32).to_le_bytes());
-            all_data.extend(&qp_bytes);
+    pub fn compress_lossy_v3(
+        &self,
+        data: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> WkResult<Vec<u8>> {
+        let extension = LossyExtension {
+            color_matrix: self.config.color_matrix.unwrap_or(ColorMatrix::Bt601),
+            color_range: self.config.color_range,
+            progressive: false,
+            resync_rows: self.config.resync_rows,
+            integer_transform: true,
+        };
+        let use_intra = self.config.use_intra_prediction;
+        let segment_rows = extension.resync_rows as usize;
+        let profile = self.profile();
+
+        let block_width = width.div_ceil(8);
+        let block_height = height.div_ceil(8);
+        let encode_rows = |planes: &[LossyPlane], rows: std::ops::Range<usize>, use_cabac: bool| {
+            let blocks = rows.start * block_width..rows.end * block_width;
+            let mut all_data: Vec<u8> = Vec::new();
+            for plane in planes {
+                plane.slice(blocks.clone()).encode_side_info(&mut all_data);
+                let scanned: Vec<Vec<i16>> = plane.blocks[blocks.clone()]
+                    .iter()
+                    .map(|b| zigzag_scan(b).to_vec())
+                    .collect();
+                self.encode_coefficient_section(&scanned, use_cabac, &mut all_data);
+            }
+            compress_coefficients_at(&all_data, profile.deflate_level)
+        };
 
-            if self.config.use_cabac {
-                let mut cabac_encoder = ArithmeticEncoder::new();
-                let mut ctx = CABACContext::new(8);
-                for coeffs in &coeffs_data {
-                    encode_coefficients(&mut cabac_encoder, &mut ctx, coeffs);
-                }
-                let encoded = cabac_encoder.finish();
-                all_data.extend(&(encoded.len() as u32).to_le_bytes());
-                all_data.extend(&encoded);
+        let encode_stream = |planes: &[LossyPlane], use_cabac: bool| {
+            let mut output = self.lossy_stream_header(&extension, use_intra, use_cabac);
+            let payload = if segment_rows == 0 {
+                encode_rows(planes, 0..block_height, use_cabac)
             } else {
-                let flat: Vec<i16> = coeffs_data.iter().flatten().copied().collect();
-                let mut encoder = EntropyEncoder::new();
-                let encoded = encoder.encode_rle_huffman(&flat);
-                all_data.extend(&(encoded.len() as u32).to_le_bytes());
-                all_data.extend(&encoded);
-            }
+                let mut segments = Vec::new();
+                for (index, top) in (0..block_height).step_by(segment_rows).enumerate() {
+                    let rows = top..(top + segment_rows).min(block_height);
+                    let compressed = encode_rows(planes, rows, use_cabac);
+                    let mut index_and_len = (index as u16).to_le_bytes().to_vec();
+                    index_and_len.extend(&(compressed.len() as u32).to_le_bytes());
+                    segments.extend(&RESYNC_MARKER);
+                    segments.extend(&index_and_len);
+                    segments.extend(&segment_crc(&index_and_len, &compressed).to_le_bytes());
+                    segments.extend(compressed);
+                }
+                segments
+            };
+            output.extend(&(payload.len() as u32).to_le_bytes());
+            output.extend(payload);
+            output
+        };
+
+        // Repetitive content often codes smaller with only DC, horizontal and vertical prediction.
+        let mut passes = vec![(profile.intra_modes, profile.rdo_candidates)];
+        if profile.retry_edge_modes {
+            passes.push((&IntraMode::SAFE_EDGE, 0));
+        }
+        let mut coders = vec![self.config.use_cabac];
+        if profile.try_entropy_coders {
+            coders.push(!self.config.use_cabac);
         }
 
-        let compressed = compress_coefficients(&all_data);
-        output.extend(&(compressed.len() as u32).to_le_bytes());
-        output.extend(compressed);
-        Ok(output)
+        // Passes differ in distortion as well as size, so they are compared
+        // by squared error plus λ times their bits, as in per-block RDO.
+        let measure = passes.len() > 1;
+        let outputs: Vec<(u64, Vec<u8>)> = passes
+            .par_iter()
+            .map(
+                |&(modes, rdo_candidates)| -> WkResult<Vec<(u64, Vec<u8>)>> {
+                    let planes = self.encode_lossy_planes(
+                        data,
+                        width,
+                        height,
+                        channels,
+                        extension.color_space(),
+                        use_intra,
+                        segment_rows,
+                        modes,
+                        rdo_candidates,
+                    );
+                    let streams: Vec<Vec<u8>> = coders
+                        .iter()
+                        .map(|&use_cabac| encode_stream(&planes, use_cabac))
+                        .collect();
+                    // Both coefficient coders carry the same coefficients.
+                    let sse = if measure {
+                        let recon = CompressionEngine::new(self.config.clone())
+                            .decompress_lossy_v3(&streams[0], width, height, channels)?;
+                        data.iter()
+                            .zip(&recon)
+                            .map(|(&a, &b)| (a.abs_diff(b) as u64).pow(2))
+                            .sum()
+                    } else {
+                        0
+                    };
+                    Ok(streams.into_iter().map(|stream| (sse, stream)).collect())
+                },
+            )
+            .collect::<WkResult<Vec<_>>>()?
+            .into_iter()
+            .flatten()
+            .collect();
+        let ac_step = QuantTable::aggressive(self.config.quality, false).table[1] as f64;
+        let lambda = RDO_LAMBDA * ac_step.powi(2);
+        let cost =
+            |(sse, stream): &(u64, Vec<u8>)| *sse as f64 + lambda * (stream.len() * 8) as f64;
+        Ok(outputs
+            .into_iter()
+            .min_by(|a, b| cost(a).total_cmp(&cost(b)))
+            .map(|(_, stream)| stream)
+            .unwrap_or_default())
     }
 
-    pub fn decompress_lossy_v3(
+    pub fn compress_progressive(
         &self,
         data: &[u8],
         width: usize,
         height: usize,
         channels: usize,
-    ) -> WkResult<Vec<u8>> {
+    ) -> WkResult<Vec<Vec<u8>>> {
+        if self.config.resync_rows > 0 {
+            return Err(WkError::UnsupportedFeature(
+                "Progressive coding cannot be combined with resync segments".into(),
+            ));
+        }
+        // Intra prediction would make each block depend on fully refined neighbours.
+        let extension = LossyExtension {
+            color_matrix: self.config.color_matrix.unwrap_or(ColorMatrix::Bt601),
+            color_range: self.config.color_range,
+            progressive: true,
+            resync_rows: 0,
+            integer_transform: true,
+        };
+        let use_cabac = self.config.use_cabac;
+        let profile = self.profile();
+        let deflate_level = profile.deflate_level;
+        let mut base = self.lossy_stream_header(&extension, false, use_cabac);
+        let planes = self.encode_lossy_planes(
+            data,
+            width,
+            height,
+            channels,
+            extension.color_space(),
+            false,
+            0,
+            profile.intra_modes,
+            profile.rdo_candidates,
+        );
+
+        let pass_section = |plane: &LossyPlane, pass: ScanPass, output: &mut Vec<u8>| {
+            let coeffs: Vec<Vec<i16>> = plane
+                .blocks
+                .iter()
+                .map(|b| reorder_coefficients(b, pass))
+                .collect();
+            self.encode_coefficient_section(&coeffs, use_cabac, output);
+        };
+
+        let mut all_data = Vec::new();
+        for plane in &planes {
+            plane.encode_side_info(&mut all_data);
+            pass_section(plane, ScanPass::DC, &mut all_data);
+        }
+        let compressed = compress_coefficients_at(&all_data, deflate_level);
+        base.extend(&(compressed.len() as u32).to_le_bytes());
+        base.extend(compressed);
+
+        let mut payloads = vec![base];
+        for pass in [ScanPass::ACLow, ScanPass::ACHigh] {
+            let mut pass_data = Vec::new();
+            for plane in &planes {
+                pass_section(plane, pass, &mut pass_data);
+            }
+            let mut payload = vec![pass.id()];
+            payload.extend(compress_coefficients_at(&pass_data, deflate_level));
+            payloads.push(payload);
+        }
+        Ok(payloads)
+    }
+
+    fn parse_lossy_stream<'a>(&self, data: &'a [u8]) -> WkResult<LossyStream<'a>> {
         if data.len() < 259 {
             return Err(crate::error::WkError::DecodingError(
                 "Data too short".into(),
             ));
         }
 
-        let use_cabac = data[0] != 0;
-        let use_intra = data[1] != 0;
-        let use_adaptive = data[2] != 0;
-
         let mut base_table = [0u16; 64];
         let mut chroma_table = [0u16; 64];
         for i in 0..64 {
@@ -339,159 +1006,597 @@ impl CompressionEngine {
             chroma_table[i] = u16::from_le_bytes([data[131 + i * 2], data[131 + i * 2 + 1]]);
         }
 
-        let compressed_len =
-            u32::from_le_bytes([data[259], data[260], data[261], data[262]]) as usize;
-        let compressed_data = &data[263..263 + compressed_len.min(data.len().saturating_sub(263))];
-        let all_data = decompress_coefficients(compressed_data);
+        let (extension, len_pos) = if data[1] & STREAM_EXTENDED != 0 {
+            let ext_len = u16::from_le_bytes([data[259], data[260]]) as usize;
+            let ext_end = 261 + ext_len;
+            if data.len() < ext_end + 4 {
+                return Err(WkError::DecodingError("Data too short".into()));
+            }
+            (LossyExtension::decode(&data[261..ext_end])?, ext_end)
+        } else {
+            (LossyExtension::legacy(), 259)
+        };
+
+        let compressed_len = u32::from_le_bytes([
+            data[len_pos],
+            data[len_pos + 1],
+            data[len_pos + 2],
+            data[len_pos + 3],
+        ]) as usize;
+        let data_start = len_pos + 4;
+        let payload = &data
+            [data_start..data_start + compressed_len.min(data.len().saturating_sub(data_start))];
+
+        Ok(LossyStream {
+            use_cabac: data[0] != 0,
+            use_intra: data[1] & 1 != 0,
+            use_adaptive: data[2] != 0,
+            base_table,
+            chroma_table,
+            extension,
+            payload,
+        })
+    }
+
+    fn decode_coefficient_section(
+        &self,
+        all_data: &[u8],
+        pos: &mut usize,
+        use_cabac: bool,
+        blocks: usize,
+        size: usize,
+    ) -> WkResult<Vec<Vec<i16>>> {
+        let coeffs_data = read_section(all_data, pos)?;
+        if use_cabac {
+            let mut decoder = ArithmeticDecoder::new(coeffs_data.to_vec());
+            let mut ctx = CABACContext::new(8);
+            Ok((0..blocks)
+                .map(|_| decode_coefficients(&mut decoder, &mut ctx, size))
+                .collect())
+        } else {
+            let decoder = EntropyDecoder::new();
+            let flat = decoder.decode_rle_huffman(coeffs_data)?;
+            Ok(flat.chunks(size).map(|c| c.to_vec()).collect())
+        }
+    }
+
+    pub fn decompress_lossy_v3(
+        &self,
+        data: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> WkResult<Vec<u8>> {
+        let stream = self.parse_lossy_stream(data)?;
+        if stream.extension.progressive {
+            return Err(WkError::InvalidFormat(
+                "Progressive stream decoded without its IDPS passes".into(),
+            ));
+        }
+        if stream.extension.resync_rows > 0 {
+            let (output, damaged) = self.decompress_resilient(data, width, height, channels)?;
+            if !damaged.is_empty() {
+                return Err(WkError::DecodingError("Corrupted lossy segment".into()));
+            }
+            return Ok(output);
+        }
 
+        let all_data = decompress_coefficients(stream.payload);
+        let blocks_per_channel = width.div_ceil(8) * height.div_ceil(8);
         let mut pos = 0usize;
-        let block_width = (width + 7) / 8;
-        let block_height = (height + 7) / 8;
-        let padded_w = block_width * 8;
-        let padded_h = block_height * 8;
-        let blocks_per_channel = block_width * block_height;
+        let mut planes = Vec::new();
+        for _ in 0..coded_planes(channels) {
+            let mut plane = LossyPlane::decode_side_info(&all_data, &mut pos)?;
+            let all_coeffs = self.decode_coefficient_section(
+                &all_data,
+                &mut pos,
+                stream.use_cabac,
+                blocks_per_channel,
+                64,
+            )?;
+            plane.blocks = all_coeffs
+                .iter()
+                .map(|coeffs| {
+                    let mut scanned = [0i16; 64];
+                    for (i, &v) in coeffs.iter().enumerate().take(64) {
+                        scanned[i] = v;
+                    }
+                    zigzag_unscan(&scanned)
+                })
+                .collect();
+            planes.push(plane);
+        }
 
-        let predictor = IntraPredictor::new(8);
-        let mut ycbcr_planes: Vec<Vec<u8>> = vec![vec![0u8; width * height]; channels];
+        Ok(self.reconstruct_lossy(&stream, &planes, &[], width, height, channels))
+    }
 
-        for ch in 0..channels {
-            let is_chroma = ch > 0 && channels >= 3;
+    pub fn decompress_resilient(
+        &self,
+        data: &[u8],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> WkResult<(Vec<u8>, Vec<DamagedRegion>)> {
+        let stream = self.parse_lossy_stream(data)?;
+        let segment_rows = stream.extension.resync_rows as usize;
+        if segment_rows == 0 {
+            return Err(WkError::DecodingError(
+                "Stream has no resync segments".into(),
+            ));
+        }
 
-            let modes_len = u32::from_le_bytes([
-                all_data[pos],
-                all_data[pos + 1],
-                all_data[pos + 2],
-                all_data[pos + 3],
-            ]) as usize;
-            pos += 4;
-            let modes: Vec<u8> = all_data[pos..pos + modes_len].to_vec();
-            pos += modes_len;
-
-            let qps_len = u32::from_le_bytes([
-                all_data[pos],
-                all_data[pos + 1],
-                all_data[pos + 2],
-                all_data[pos + 3],
-            ]) as usize;
-            pos += 4;
-            let qps: Vec<u8> = all_data[pos..pos + qps_len].to_vec();
-            pos += qps_len;
-
-            let coeffs_len = u32::from_le_bytes([
-                all_data[pos],
-                all_data[pos + 1],
-                all_data[pos + 2],
-                all_data[pos + 3],
-            ]) as usize;
-            pos += 4;
-            let coeffs_data = &all_data[pos..pos + coeffs_len];
-            pos += coeffs_len;
-
-            let all_coeffs: Vec<Vec<i16>> = if use_cabac {
-                let mut decoder = ArithmeticDecoder::new(coeffs_data.to_vec());
-                let mut ctx = CABACContext::new(8);
-                (0..blocks_per_channel)
-                    .map(|_| decode_coefficients(&mut decoder, &mut ctx, 64))
-                    .collect()
+        let block_width = width.div_ceil(8);
+        let block_height = height.div_ceil(8);
+        let plane_count = coded_planes(channels);
+        let mut planes: Vec<LossyPlane> = (0..plane_count)
+            .map(|_| LossyPlane {
+                modes: vec![0; block_width * block_height],
+                qps: vec![0; block_width * block_height],
+                blocks: vec![[0; 64]; block_width * block_height],
+            })
+            .collect();
+        let mut damaged_rows = vec![true; block_height];
+
+        let segments = stream.payload;
+        let mut pos = 0;
+        while let Some(marker) = find_resync_marker(segments, pos) {
+            pos = marker + 1;
+            let Some(header) = segments.get(marker..marker + SEGMENT_HEADER_LEN) else {
+                break;
+            };
+            let index = u16::from_le_bytes([header[4], header[5]]) as usize;
+            let len = u32::from_le_bytes([header[6], header[7], header[8], header[9]]) as usize;
+            let crc = u32::from_le_bytes([header[10], header[11], header[12], header[13]]);
+            let start = marker + SEGMENT_HEADER_LEN;
+            let Some(body) = segments.get(start..start.saturating_add(len)) else {
+                continue;
+            };
+            let top = index * segment_rows;
+            if segment_crc(&header[4..10], body) != crc || top >= block_height {
+                continue;
+            }
+
+            let rows = top..(top + segment_rows).min(block_height);
+            let blocks = rows.start * block_width..rows.end * block_width;
+            if let Ok(decoded) = self.decode_segment(&stream, body, blocks.len(), plane_count) {
+                for (plane, segment) in planes.iter_mut().zip(decoded) {
+                    plane.modes[blocks.clone()].copy_from_slice(&segment.modes);
+                    plane.qps[blocks.clone()].copy_from_slice(&segment.qps);
+                    plane.blocks[blocks.clone()].copy_from_slice(&segment.blocks);
+                }
+                damaged_rows[rows].fill(false);
+                pos = start + len;
+            }
+        }
+
+        let mut damaged = Vec::new();
+        let mut by = 0;
+        while by < block_height {
+            if damaged_rows[by] {
+                let start = by;
+                while by < block_height && damaged_rows[by] {
+                    by += 1;
+                }
+                let y = start * 8;
+                damaged.push(DamagedRegion {
+                    x: 0,
+                    y: y as u32,
+                    width: width as u32,
+                    height: ((by * 8).min(height) - y) as u32,
+                });
             } else {
-                let decoder = EntropyDecoder::new();
-                let flat = decoder.decode_rle_huffman(coeffs_data)?;
-                flat.chunks(64).map(|c| c.to_vec()).collect()
+                by += 1;
+            }
+        }
+
+        let output =
+            self.reconstruct_lossy(&stream, &planes, &damaged_rows, width, height, channels);
+        Ok((output, damaged))
+    }
+
+    // Decodes, in order, the segments of `data` that have arrived whole
+    // since the last call. A damaged or out-of-order segment stops it there,
+    // leaving the rest to a full decode. Returns how many rows from the top
+    // are final; deblocking reaches a few pixels across block edges, so the
+    // block row above the last whole segment is held back.
+    pub(crate) fn decode_new_segments(
+        &self,
+        decoder: &mut SegmentDecoder,
+        data: &[u8],
+    ) -> WkResult<usize> {
+        let stream = self.parse_lossy_stream(data)?;
+        let segment_rows = stream.extension.resync_rows as usize;
+        if segment_rows == 0 || stream.extension.progressive || self.decode_scale != 1 {
+            return Err(WkError::DecodingError(
+                "Stream cannot be decoded segment by segment".into(),
+            ));
+        }
+        let color_space = stream.extension.color_space();
+        let (width, height, channels) = (decoder.width, decoder.height, decoder.channels);
+        let block_width = width.div_ceil(8);
+        let block_height = height.div_ceil(8);
+        let first_row = decoder.block_rows;
+
+        let segments = stream.payload;
+        while decoder.block_rows < block_height {
+            let Some(marker) = find_resync_marker(segments, decoder.scanned) else {
+                break;
+            };
+            let Some(header) = segments.get(marker..marker + SEGMENT_HEADER_LEN) else {
+                break;
+            };
+            let index = u16::from_le_bytes([header[4], header[5]]) as usize;
+            let len = u32::from_le_bytes([header[6], header[7], header[8], header[9]]) as usize;
+            let crc = u32::from_le_bytes([header[10], header[11], header[12], header[13]]);
+            let start = marker + SEGMENT_HEADER_LEN;
+            let Some(body) = segments.get(start..start.saturating_add(len)) else {
+                break;
             };
+            if segment_crc(&header[4..10], body) != crc {
+                decoder.scanned = marker + 1;
+                continue;
+            }
+            let top = index * segment_rows;
+            if top != decoder.block_rows {
+                break;
+            }
 
-            let mut padded = vec![128u8; padded_w * padded_h];
+            let rows = top..(top + segment_rows).min(block_height);
+            let blocks = rows.start * block_width..rows.end * block_width;
+            let plane_count = decoder.planes.len();
+            let Ok(decoded) = self.decode_segment(&stream, body, blocks.len(), plane_count) else {
+                break;
+            };
+            for (ch, (plane, segment)) in decoder.planes.iter_mut().zip(decoded).enumerate() {
+                plane.modes[blocks.clone()].copy_from_slice(&segment.modes);
+                plane.qps[blocks.clone()].copy_from_slice(&segment.qps);
+                plane.blocks[blocks.clone()].copy_from_slice(&segment.blocks);
+                self.reconstruct_rows(
+                    &stream,
+                    plane,
+                    is_chroma_plane(ch, channels, color_space),
+                    rows.clone(),
+                    block_width,
+                    &[],
+                    &mut decoder.padded[ch],
+                );
+            }
+            decoder.block_rows = rows.end;
+            decoder.scanned = start + len;
+        }
+        if decoder.block_rows == first_row {
+            return Ok(decoder.final_rows);
+        }
 
-            for by in 0..block_height {
-                for bx in 0..block_width {
-                    let block_idx = by * block_width + bx;
-                    if block_idx >= all_coeffs.len() {
-                        continue;
-                    }
+        let final_rows = if decoder.block_rows == block_height {
+            height
+        } else {
+            (decoder.block_rows - 1) * 8
+        };
+        if final_rows > decoder.final_rows {
+            // Deblocking a window that starts a block row above the new rows
+            // gives them the same pixels as deblocking the whole image.
+            let padded_w = block_width * 8;
+            let window = decoder.final_rows.saturating_sub(8)..decoder.block_rows * 8;
+            let band = decoder.final_rows..final_rows;
+            let mut planes = Vec::with_capacity(decoder.padded.len());
+            for (ch, padded) in decoder.padded.iter().enumerate() {
+                let mut rows = padded[window.start * padded_w..window.end * padded_w].to_vec();
+                let is_chroma = is_chroma_plane(ch, channels, color_space);
+                self.deblock_padded(&mut rows, padded_w, window.len(), is_chroma);
+                let mut plane = Vec::with_capacity(width * band.len());
+                for y in band.clone() {
+                    let row = (y - window.start) * padded_w;
+                    plane.extend_from_slice(&rows[row..row + width]);
+                }
+                planes.push(plane);
+            }
+            let pixels = interleave_planes(&planes, width, band.len(), channels, color_space);
+            let row_bytes = width * channels;
+            decoder.pixels[band.start * row_bytes..band.end * row_bytes].copy_from_slice(&pixels);
+            decoder.final_rows = final_rows;
+        }
+        Ok(decoder.final_rows)
+    }
 
+    fn decode_segment(
+        &self,
+        stream: &LossyStream,
+        body: &[u8],
+        blocks: usize,
+        plane_count: usize,
+    ) -> WkResult<Vec<LossyPlane>> {
+        let all_data = decompress_coefficients(body);
+        let mut pos = 0usize;
+        let mut planes = Vec::with_capacity(plane_count);
+        for _ in 0..plane_count {
+            let mut plane = LossyPlane::decode_side_info(&all_data, &mut pos)?;
+            let coeffs =
+                self.decode_coefficient_section(&all_data, &mut pos, stream.use_cabac, blocks, 64)?;
+            if plane.modes.len() != blocks || plane.qps.len() != blocks || coeffs.len() != blocks {
+                return Err(WkError::DecodingError(
+                    "Segment block count mismatch".into(),
+                ));
+            }
+            plane.blocks = coeffs
+                .iter()
+                .map(|c| {
                     let mut scanned = [0i16; 64];
-                    for (i, &v) in all_coeffs[block_idx].iter().enumerate().take(64) {
+                    for (i, &v) in c.iter().enumerate().take(64) {
                         scanned[i] = v;
                     }
-                    let zigzagged = zigzag_unscan(&scanned);
+                    zigzag_unscan(&scanned)
+                })
+                .collect();
+            planes.push(plane);
+        }
+        Ok(planes)
+    }
 
-                    let qp = qps.get(block_idx).copied().unwrap_or(85);
-                    let table = if use_adaptive {
-                        QuantTable::for_quality(qp, is_chroma)
-                    } else {
-                        QuantTable {
-                            table: if is_chroma { chroma_table } else { base_table },
-                        }
-                    };
+    pub fn decompress_progressive(
+        &self,
+        data: &[u8],
+        passes: &[&[u8]],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> WkResult<Vec<u8>> {
+        let stream = self.parse_lossy_stream(data)?;
+        if !stream.extension.progressive {
+            return self.decompress_lossy_v3(data, width, height, channels);
+        }
 
-                    let mut dequantized = [0i16; 64];
-                    for i in 0..64 {
-                        dequantized[i] = (zigzagged[i] as i32 * table.table[i] as i32) as i16;
-                    }
+        let all_data = decompress_coefficients(stream.payload);
+        let blocks_per_channel = width.div_ceil(8) * height.div_ceil(8);
+        let plane_count = coded_planes(channels);
+        let mut pos = 0usize;
+        let mut planes = Vec::with_capacity(plane_count);
+        let mut bands: Vec<[Vec<Vec<i16>>; 3]> = Vec::with_capacity(plane_count);
+        for _ in 0..plane_count {
+            planes.push(LossyPlane::decode_side_info(&all_data, &mut pos)?);
+            let dc = self.decode_coefficient_section(
+                &all_data,
+                &mut pos,
+                stream.use_cabac,
+                blocks_per_channel,
+                ScanPass::DC.coefficient_count(),
+            )?;
+            bands.push([dc, Vec::new(), Vec::new()]);
+        }
 
-                    let block = if self.simd_level != SimdLevel::None {
-                        idct_8x8_simd(&dequantized)
-                    } else {
-                        idct_8x8_fast(&dequantized)
-                    };
+        for pass_data in passes {
+            let (&id, body) = pass_data
+                .split_first()
+                .ok_or_else(|| WkError::DecodingError("Empty progressive pass".into()))?;
+            let pass = ScanPass::from_u8(id)
+                .filter(|p| matches!(p, ScanPass::ACLow | ScanPass::ACHigh))
+                .ok_or_else(|| WkError::DecodingError(format!("Unknown scan pass: {}", id)))?;
+            let band = if pass == ScanPass::ACLow { 1 } else { 2 };
+
+            let pass_coeffs = decompress_coefficients(body);
+            let mut pos = 0usize;
+            for plane_bands in bands.iter_mut() {
+                plane_bands[band] = self.decode_coefficient_section(
+                    &pass_coeffs,
+                    &mut pos,
+                    stream.use_cabac,
+                    blocks_per_channel,
+                    pass.coefficient_count(),
+                )?;
+            }
+        }
 
-                    let mode = IntraMode::from_u8(modes.get(block_idx).copied().unwrap_or(0))
-                        .unwrap_or(IntraMode::DC);
-                    let (top, left, top_left) = self.get_neighbors(&padded, padded_w, bx, by);
+        for (plane, [dc, ac_low, ac_high]) in planes.iter_mut().zip(&bands) {
+            let empty = Vec::new();
+            plane.blocks = (0..blocks_per_channel)
+                .map(|i| {
+                    merge_progressive_coefficients(
+                        dc.get(i).unwrap_or(&empty),
+                        ac_low.get(i).unwrap_or(&empty),
+                        ac_high.get(i).unwrap_or(&empty),
+                    )
+                })
+                .collect();
+        }
 
-                    let pred_block = if use_intra {
-                        predictor.predict(mode, &top, &left, top_left)
-                    } else {
-                        vec![128u8; 64]
-                    };
+        Ok(self.reconstruct_lossy(&stream, &planes, &[], width, height, channels))
+    }
 
-                    for y in 0..8 {
-                        for x in 0..8 {
-                            let px = bx * 8 + x;
-                            let py = by * 8 + y;
-                            let residual = block[y * 8 + x];
-                            let pred_val = pred_block[y * 8 + x] as i16;
-                            let val = (pred_val + residual).clamp(0, 255) as u8;
-                            padded[py * padded_w + px] = val;
-                        }
-                    }
+    pub fn is_progressive_stream(&self, data: &[u8]) -> bool {
+        self.parse_lossy_stream(data)
+            .is_ok_and(|stream| stream.extension.progressive)
+    }
+
+    pub fn stream_color_matrix(&self, data: &[u8]) -> Option<ColorMatrix> {
+        self.parse_lossy_stream(data)
+            .ok()
+            .map(|stream| stream.extension.color_matrix)
+    }
+
+    pub fn has_resync_segments(&self, data: &[u8]) -> bool {
+        self.parse_lossy_stream(data)
+            .is_ok_and(|stream| stream.extension.resync_rows > 0)
+    }
+
+    fn reconstruct_lossy(
+        &self,
+        stream: &LossyStream,
+        planes: &[LossyPlane],
+        damaged_rows: &[bool],
+        width: usize,
+        height: usize,
+        channels: usize,
+    ) -> Vec<u8> {
+        let color_space = stream.extension.color_space();
+        let block_width = width.div_ceil(8);
+        let block_height = height.div_ceil(8);
+        let scale = self.decode_scale;
+        let size = 8 / scale;
+        let (out_w, out_h) = (width.div_ceil(scale), height.div_ceil(scale));
+        let padded_w = block_width * size;
+        let padded_h = block_height * size;
+
+        let mut ycbcr_planes: Vec<Vec<u8>> = vec![vec![0u8; out_w * out_h]; planes.len()];
+
+        for (ch, plane) in planes.iter().enumerate() {
+            let is_chroma = is_chroma_plane(ch, channels, color_space);
+            let mut padded = vec![128u8; padded_w * padded_h];
+            self.reconstruct_rows(
+                stream,
+                plane,
+                is_chroma,
+                0..block_height,
+                block_width,
+                damaged_rows,
+                &mut padded,
+            );
+            conceal_rows(&mut padded, padded_w, damaged_rows, size);
+            self.deblock_padded(&mut padded, padded_w, padded_h, is_chroma);
+
+            for y in 0..out_h {
+                for x in 0..out_w {
+                    ycbcr_planes[ch][y * out_w + x] = padded[y * padded_w + x];
                 }
             }
-            let deblock_config = DeblockConfig::from_quality(self.config.quality);
-            let deblock_filter = DeblockingFilter::new(deblock_config);
-            if is_chroma {
-                deblock_filter.apply_chroma(&mut padded, padded_w, padded_h, 8);
-            } else {
-                deblock_filter.apply(&mut padded, padded_w, padded_h, 8);
+        }
+
+        interleave_planes(&ycbcr_planes, out_w, out_h, channels, color_space)
+    }
+
+    // Predicts and reconstructs block rows `rows` of one plane into `padded`.
+    // The range has to start where prediction restarts: at the first row or
+    // at a segment top.
+    fn reconstruct_rows(
+        &self,
+        stream: &LossyStream,
+        plane: &LossyPlane,
+        is_chroma: bool,
+        rows: std::ops::Range<usize>,
+        block_width: usize,
+        damaged_rows: &[bool],
+        padded: &mut [u8],
+    ) {
+        let scale = self.decode_scale;
+        let size = 8 / scale;
+        let padded_w = block_width * size;
+        let predictor = IntraPredictor::new(8);
+        // Full-resolution block edges, so intra prediction stays exact at reduced scales.
+        let mut above = vec![128u8; block_width * 8];
+        let mut below = vec![128u8; block_width * 8];
+
+        for by in rows {
+            below.fill(128);
+            if damaged_rows.get(by).copied().unwrap_or(false) {
+                std::mem::swap(&mut above, &mut below);
+                continue;
             }
+            let top_row = segment_top(by, stream.extension.resync_rows as usize);
+            let mut left = vec![128u8; 8];
+            for bx in 0..block_width {
+                let block_idx = by * block_width + bx;
+                let Some(coeffs) = plane.blocks.get(block_idx) else {
+                    left.fill(128);
+                    continue;
+                };
+
+                let qp = plane.qps.get(block_idx).copied().unwrap_or(85);
+                let table = if stream.use_adaptive {
+                    QuantTable::for_quality(qp, is_chroma)
+                } else {
+                    QuantTable {
+                        table: if is_chroma {
+                            stream.chroma_table
+                        } else {
+                            stream.base_table
+                        },
+                    }
+                };
 
-            for y in 0..height {
-                for x in 0..width {
-                    ycbcr_planes[ch][y * width + x] = padded[y * padded_w + x];
+                let mut dequantized = [0i16; 64];
+                for i in 0..64 {
+                    dequantized[i] = (coeffs[i] as i32 * table.table[i] as i32) as i16;
                 }
-            }
-        }
 
-        let output = if channels >= 3 {
-            convert_ycbcr_to_rgb_image(
-                &ycbcr_planes[0],
-                &ycbcr_planes[1],
-                &ycbcr_planes[2],
-                width,
-                height,
-                channels,
-                ColorSpace::YCbCrFull,
-            )
-        } else {
-            let mut out = vec![0u8; width * height * channels];
-            for ch in 0..channels {
-                for i in 0..(width * height) {
-                    out[i * channels + ch] = ycbcr_planes[ch][i];
+                // Streams from before the integer transform keep decoding with the float one.
+                let integer = stream.extension.integer_transform;
+                let block = match (size < 8, integer) {
+                    (true, true) => int_idct_scaled(&dequantized, size),
+                    (true, false) => idct_scaled(&dequantized, size),
+                    (false, true) => self.int_idct(&dequantized),
+                    (false, false) if self.simd_level != SimdLevel::None => {
+                        idct_8x8_simd(&dequantized)
+                    }
+                    (false, false) => idct_8x8_fast(&dequantized),
+                };
+
+                let mode = IntraMode::from_u8(plane.modes.get(block_idx).copied().unwrap_or(0))
+                    .unwrap_or(IntraMode::DC);
+                let has_top = by > top_row;
+                let top = if has_top {
+                    above[bx * 8..bx * 8 + 8].to_vec()
+                } else {
+                    vec![128u8; 8]
+                };
+                if bx == 0 {
+                    left.fill(128);
+                }
+                let top_left = if bx > 0 && has_top {
+                    above[bx * 8 - 1]
+                } else {
+                    128
+                };
+
+                let pred_block = if stream.use_intra {
+                    predictor.predict(mode, &top, &left, top_left)
+                } else {
+                    vec![128u8; 64]
+                };
+
+                let (bottom_residual, right_residual) = if size < 8 && integer {
+                    int_idct_edges(&dequantized)
+                } else if size < 8 {
+                    idct_edges(&dequantized)
+                } else {
+                    (
+                        std::array::from_fn(|x| block[56 + x]),
+                        std::array::from_fn(|y| block[y * 8 + 7]),
+                    )
+                };
+                for i in 0..8 {
+                    below[bx * 8 + i] =
+                        (pred_block[56 + i] as i16 + bottom_residual[i]).clamp(0, 255) as u8;
+                    left[i] =
+                        (pred_block[i * 8 + 7] as i16 + right_residual[i]).clamp(0, 255) as u8;
+                }
+
+                let pred_block = box_downscale(pred_block, 8, 8, 1, scale);
+                for y in 0..size {
+                    for x in 0..size {
+                        let px = bx * size + x;
+                        let py = by * size + y;
+                        let residual = block[y * size + x];
+                        let pred_val = pred_block[y * size + x] as i16;
+                        let val = (pred_val + residual).clamp(0, 255) as u8;
+                        padded[py * padded_w + px] = val;
+                    }
                 }
             }
-            out
-        };
+            std::mem::swap(&mut above, &mut below);
+        }
+    }
 
-        Ok(output)
+    fn deblock_padded(&self, padded: &mut [u8], padded_w: usize, padded_h: usize, is_chroma: bool) {
+        let size = 8 / self.decode_scale;
+        // Below 1/2 scale block edges are only a pixel or two apart, so deblocking is skipped.
+        if size >= 4 {
+            let deblock_config = DeblockConfig::from_quality(self.config.quality);
+            let deblock_filter = DeblockingFilter::new(deblock_config);
+            if is_chroma {
+                deblock_filter.apply_chroma(padded, padded_w, padded_h, size);
+            } else {
+                deblock_filter.apply(padded, padded_w, padded_h, size);
+            }
+        }
     }
 
     fn get_neighbors(
@@ -500,15 +1605,16 @@ impl CompressionEngine {
         stride: usize,
         bx: usize,
         by: usize,
+        top_row: usize,
     ) -> (Vec<u8>, Vec<u8>, u8) {
         let mut top = vec![128u8; 8];
         let mut left = vec![128u8; 8];
-        let top_left = if bx > 0 && by > 0 {
+        let top_left = if bx > 0 && by > top_row {
             padded[(by * 8 - 1) * stride + bx * 8 - 1]
         } else {
             128
         };
-        if by > 0 {
+        if by > top_row {
             for x in 0..8 {
                 top[x] = padded[(by * 8 - 1) * stride + bx * 8 + x];
             }
@@ -549,6 +1655,7 @@ impl CompressionEngine {
             self.decompress_lossy_v3(data, width, height, channels)
         } else {
             self.decompress_lossy_legacy(data, width, height, channels)
+                .map(|raw| box_downscale(raw, width, height, channels, self.decode_scale))
         }
     }
 
@@ -735,7 +1842,9 @@ impl CompressionEngine {
         mode: CompressionMode,
     ) -> WkResult<Vec<u8>> {
         match mode {
-            CompressionMode::Lossless => self.decompress_lossless(data, width, height, channels),
+            CompressionMode::Lossless => self
+                .decompress_lossless(data, width, height, channels)
+                .map(|raw| box_downscale(raw, width, height, channels, self.decode_scale)),
             CompressionMode::Lossy | CompressionMode::Mixed => {
                 self.decompress_lossy(data, width, height, channels)
             }
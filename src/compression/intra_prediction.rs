@@ -1,3 +1,5 @@
+use super::simd::sum_abs_diff;
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum IntraMode {
     DC,
@@ -204,29 +206,16 @@ impl IntraPredictor {
                     }
                 }
             }
-            IntraMode::Planar => {
-                let tr = top.get(n - 1).copied().unwrap_or(128) as i32;
-                let bl = left.get(n - 1).copied().unwrap_or(128) as i32;
-                for y in 0..n {
-                    for x in 0..n {
-                        let t = top[x] as i32;
-                        let l = left[y] as i32;
-                        let h = (n - 1 - x) as i32 * l + (x + 1) as i32 * tr;
-                        let v = (n - 1 - y) as i32 * t + (y + 1) as i32 * bl;
-                        pred[y * n + x] = ((h + v + n as i32) / (2 * n as i32)).clamp(0, 255) as u8;
-                    }
-                }
+            #[cfg(feature = "simd")]
+            IntraMode::Planar if n.is_power_of_two() && (8..=64).contains(&n) => {
+                super::simd::planar_lanes(n, &top, &left, &mut pred);
             }
-            IntraMode::TrueMotion => {
-                for y in 0..n {
-                    for x in 0..n {
-                        let t = top[x] as i32;
-                        let l = left[y] as i32;
-                        let val = t + l - top_left as i32;
-                        pred[y * n + x] = val.clamp(0, 255) as u8;
-                    }
-                }
+            IntraMode::Planar => planar(n, &top, &left, &mut pred),
+            #[cfg(feature = "simd")]
+            IntraMode::TrueMotion if n.is_multiple_of(8) => {
+                super::simd::true_motion_lanes(n, &top, &left, top_left, &mut pred);
             }
+            IntraMode::TrueMotion => true_motion(n, &top, &left, top_left, &mut pred),
         }
         pred
     }
@@ -255,23 +244,30 @@ impl IntraPredictor {
         } else {
             &IntraMode::ALL[..]
         };
+        self.rank_modes(block, top, left, top_left, candidates)[0]
+    }
 
-        let mut best_mode = IntraMode::DC;
-        let mut best_sad = u64::MAX;
-
-        for &mode in candidates {
-            let pred = self.predict(mode, top, left, top_left);
-            let sad: u64 = block
-                .iter()
-                .zip(pred.iter())
-                .map(|(&a, &b)| (a as i32 - b as i32).unsigned_abs() as u64)
-                .sum();
-            if sad < best_sad {
-                best_sad = sad;
-                best_mode = mode;
-            }
+    // Candidates ordered by SAD, best first; ties keep candidate order.
+    pub fn rank_modes(
+        &self,
+        block: &[u8],
+        top: &[u8],
+        left: &[u8],
+        top_left: u8,
+        candidates: &[IntraMode],
+    ) -> Vec<(IntraMode, u64)> {
+        let mut ranked: Vec<(IntraMode, u64)> = candidates
+            .iter()
+            .map(|&mode| {
+                let pred = self.predict(mode, top, left, top_left);
+                (mode, sum_abs_diff(block, &pred))
+            })
+            .collect();
+        ranked.sort_by_key(|&(_, sad)| sad);
+        if ranked.is_empty() {
+            ranked.push((IntraMode::DC, u64::MAX));
         }
-        (best_mode, best_sad)
+        ranked
     }
 
     pub fn compute_residual(&self, block: &[u8], prediction: &[u8]) -> Vec<i16> {
@@ -291,6 +287,31 @@ impl IntraPredictor {
     }
 }
 
+pub(crate) fn planar(n: usize, top: &[u8], left: &[u8], pred: &mut [u8]) {
+    let tr = top.get(n - 1).copied().unwrap_or(128) as i32;
+    let bl = left.get(n - 1).copied().unwrap_or(128) as i32;
+    for y in 0..n {
+        for x in 0..n {
+            let t = top[x] as i32;
+            let l = left[y] as i32;
+            let h = (n - 1 - x) as i32 * l + (x + 1) as i32 * tr;
+            let v = (n - 1 - y) as i32 * t + (y + 1) as i32 * bl;
+            pred[y * n + x] = ((h + v + n as i32) / (2 * n as i32)).clamp(0, 255) as u8;
+        }
+    }
+}
+
+pub(crate) fn true_motion(n: usize, top: &[u8], left: &[u8], top_left: u8, pred: &mut [u8]) {
+    for y in 0..n {
+        for x in 0..n {
+            let t = top[x] as i32;
+            let l = left[y] as i32;
+            let val = t + l - top_left as i32;
+            pred[y * n + x] = val.clamp(0, 255) as u8;
+        }
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
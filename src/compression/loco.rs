@@ -0,0 +1,282 @@
+use super::vp8_coder::{BitModel, RangeDecoder, RangeEncoder};
+use crate::error::{WkError, WkResult};
+
+const CONTEXTS: usize = 365;
+const BUCKETS: usize = 8;
+const MAGNITUDE_BITS: usize = 8;
+const RESET: i32 = 64;
+const T1: i32 = 3;
+const T2: i32 = 7;
+const T3: i32 = 21;
+
+fn quantize_gradient(d: i32) -> i32 {
+    match d {
+        d if d <= -T3 => -4,
+        d if d <= -T2 => -3,
+        d if d <= -T1 => -2,
+        d if d < 0 => -1,
+        0 => 0,
+        d if d < T1 => 1,
+        d if d < T2 => 2,
+        d if d < T3 => 3,
+        _ => 4,
+    }
+}
+
+fn med_predict(a: i32, b: i32, c: i32) -> i32 {
+    if c >= a.max(b) {
+        a.min(b)
+    } else if c <= a.min(b) {
+        a.max(b)
+    } else {
+        a + b - c
+    }
+}
+
+struct ResidualModels {
+    zero: [BitModel; BUCKETS],
+    sign: [BitModel; BUCKETS],
+    prefix: [[BitModel; MAGNITUDE_BITS]; BUCKETS],
+    suffix: [[BitModel; MAGNITUDE_BITS]; BUCKETS],
+}
+
+impl ResidualModels {
+    fn new() -> Self {
+        Self {
+            zero: [BitModel::new(); BUCKETS],
+            sign: [BitModel::new(); BUCKETS],
+            prefix: [[BitModel::new(); MAGNITUDE_BITS]; BUCKETS],
+            suffix: [[BitModel::new(); MAGNITUDE_BITS]; BUCKETS],
+        }
+    }
+}
+
+struct ChannelState {
+    a: Vec<i32>,
+    b: Vec<i32>,
+    c: Vec<i32>,
+    n: Vec<i32>,
+    models: ResidualModels,
+}
+
+struct Prediction {
+    context: usize,
+    sign: i32,
+    value: i32,
+    bucket: usize,
+}
+
+impl ChannelState {
+    fn new() -> Self {
+        Self {
+            a: vec![4; CONTEXTS],
+            b: vec![0; CONTEXTS],
+            c: vec![0; CONTEXTS],
+            n: vec![1; CONTEXTS],
+            models: ResidualModels::new(),
+        }
+    }
+
+    fn predict(&self, plane: &[u8], width: usize, x: usize, y: usize) -> Prediction {
+        let at = |px: usize, py: usize| plane[py * width + px] as i32;
+        let a = if x > 0 {
+            at(x - 1, y)
+        } else if y > 0 {
+            at(x, y - 1)
+        } else {
+            0
+        };
+        let b = if y > 0 { at(x, y - 1) } else { a };
+        let c = if x > 0 && y > 0 { at(x - 1, y - 1) } else { b };
+        let d = if y > 0 && x + 1 < width {
+            at(x + 1, y - 1)
+        } else {
+            b
+        };
+
+        let mut q = [
+            quantize_gradient(d - b),
+            quantize_gradient(b - c),
+            quantize_gradient(c - a),
+        ];
+        let sign = if q[0] < 0 || (q[0] == 0 && (q[1] < 0 || (q[1] == 0 && q[2] < 0))) {
+            q = [-q[0], -q[1], -q[2]];
+            -1
+        } else {
+            1
+        };
+        let context = ((q[0] * 81 + q[1] * 9 + q[2]) as usize).min(CONTEXTS - 1);
+
+        let value = (med_predict(a, b, c) + sign * self.c[context]).clamp(0, 255);
+
+        let mut k = 0;
+        while (self.n[context] << k) < self.a[context] && k < BUCKETS - 1 {
+            k += 1;
+        }
+
+        Prediction {
+            context,
+            sign,
+            value,
+            bucket: k,
+        }
+    }
+
+    fn update(&mut self, context: usize, error: i32) {
+        self.b[context] += error;
+        self.a[context] += error.abs();
+        if self.n[context] == RESET {
+            self.a[context] >>= 1;
+            self.b[context] >>= 1;
+            self.n[context] >>= 1;
+        }
+        self.n[context] += 1;
+
+        let n = self.n[context];
+        if self.b[context] <= -n {
+            self.b[context] += n;
+            if self.c[context] > -128 {
+                self.c[context] -= 1;
+            }
+            if self.b[context] <= -n {
+                self.b[context] = -n + 1;
+            }
+        } else if self.b[context] > 0 {
+            self.b[context] -= n;
+            if self.c[context] < 127 {
+                self.c[context] += 1;
+            }
+            if self.b[context] > 0 {
+                self.b[context] = 0;
+            }
+        }
+    }
+}
+
+fn wrap_error(error: i32) -> i32 {
+    ((error + 128).rem_euclid(256)) - 128
+}
+
+fn encode_residual(enc: &mut RangeEncoder, models: &mut ResidualModels, bucket: usize, e: i32) {
+    enc.encode_with_model(e != 0, &mut models.zero[bucket]);
+    if e == 0 {
+        return;
+    }
+    enc.encode_with_model(e < 0, &mut models.sign[bucket]);
+
+    let m = e.unsigned_abs();
+    let bits = 32 - m.leading_zeros() as usize;
+    for i in 1..bits {
+        enc.encode_with_model(true, &mut models.prefix[bucket][i - 1]);
+    }
+    if bits < MAGNITUDE_BITS + 1 {
+        enc.encode_with_model(false, &mut models.prefix[bucket][bits - 1]);
+    }
+    for i in (0..bits - 1).rev() {
+        enc.encode_with_model((m >> i) & 1 != 0, &mut models.suffix[bucket][i]);
+    }
+}
+
+fn decode_residual(dec: &mut RangeDecoder, models: &mut ResidualModels, bucket: usize) -> i32 {
+    if !dec.decode_with_model(&mut models.zero[bucket]) {
+        return 0;
+    }
+    let negative = dec.decode_with_model(&mut models.sign[bucket]);
+
+    let mut bits = 1;
+    while bits < MAGNITUDE_BITS + 1 && dec.decode_with_model(&mut models.prefix[bucket][bits - 1]) {
+        bits += 1;
+    }
+    let mut m = 1u32;
+    for i in (0..bits - 1).rev() {
+        m = (m << 1) | dec.decode_with_model(&mut models.suffix[bucket][i]) as u32;
+    }
+
+    if negative {
+        -(m as i32)
+    } else {
+        m as i32
+    }
+}
+
+fn split_planes(data: &[u8], channels: usize) -> Vec<Vec<u8>> {
+    (0..channels)
+        .map(|ch| data.iter().skip(ch).step_by(channels).copied().collect())
+        .collect()
+}
+
+pub fn compress(data: &[u8], width: usize, height: usize, channels: usize) -> Vec<u8> {
+    let mut enc = RangeEncoder::new();
+    for plane in split_planes(data, channels) {
+        let mut state = ChannelState::new();
+        for y in 0..height {
+            for x in 0..width {
+                let pred = state.predict(&plane, width, x, y);
+                let actual = plane[y * width + x] as i32;
+                let error = wrap_error(pred.sign * (actual - pred.value));
+                encode_residual(&mut enc, &mut state.models, pred.bucket, error);
+                state.update(pred.context, error);
+            }
+        }
+    }
+    enc.finish()
+}
+
+pub fn decompress(data: &[u8], width: usize, height: usize, channels: usize) -> WkResult<Vec<u8>> {
+    if data.is_empty() && width * height * channels > 0 {
+        return Err(WkError::DecodingError("Empty LOCO stream".into()));
+    }
+
+    let mut dec = RangeDecoder::new(data.to_vec());
+    let mut output = vec![0u8; width * height * channels];
+    for ch in 0..channels {
+        let mut plane = vec![0u8; width * height];
+        let mut state = ChannelState::new();
+        for y in 0..height {
+            for x in 0..width {
+                let pred = state.predict(&plane, width, x, y);
+                let error = decode_residual(&mut dec, &mut state.models, pred.bucket);
+                let value = (pred.value + pred.sign * error).rem_euclid(256);
+                plane[y * width + x] = value as u8;
+                state.update(pred.context, error);
+            }
+        }
+        for (i, &v) in plane.iter().enumerate() {
+            output[i * channels + ch] = v;
+        }
+    }
+    Ok(output)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_loco_roundtrip() {
+        let (w, h) = (37, 23);
+        let mut seed = 7u32;
+        let data: Vec<u8> = (0..w * h * 3)
+            .map(|i| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let smooth = ((i / 3) % w * 5 + (i / 3) / w * 3) as u32;
+                (smooth + (seed >> 29)) as u8
+            })
+            .collect();
+
+        let encoded = compress(&data, w, h, 3);
+        assert_eq!(decompress(&encoded, w, h, 3).unwrap(), data);
+
+        let extremes: Vec<u8> = (0..w * h)
+            .map(|i| if i % 3 == 0 { 0 } else { 255 })
+            .collect();
+        let encoded = compress(&extremes, w, h, 1);
+        assert_eq!(decompress(&encoded, w, h, 1).unwrap(), extremes);
+    }
+
+    #[test]
+    fn test_loco_flat_image_is_tiny() {
+        let data = vec![90u8; 256 * 256];
+        assert!(compress(&data, 256, 256, 1).len() < 256);
+    }
+}
@@ -0,0 +1,462 @@
+use super::vp8_coder::{BitModel, RangeDecoder, RangeEncoder};
+use crate::error::{WkError, WkResult};
+
+const CACHE_BITS: u8 = 8;
+const MIN_MATCH: usize = 3;
+const MAX_MATCH: usize = 4096;
+const MAX_CHAIN: usize = 32;
+const HASH_BITS: u32 = 16;
+const NEIGHBOURHOOD_CODES: usize = 120;
+const UINT_BITS: usize = 33;
+const ACTIVITY_CONTEXTS: usize = 3;
+
+// Short (dx, dy) offsets ordered by distance, so nearby 2D references get the smallest codes.
+fn neighbourhood() -> Vec<(i64, i64)> {
+    let mut offsets: Vec<(i64, i64)> = (0..=7)
+        .flat_map(|dy| (-8..=8).map(move |dx| (dx, dy)))
+        .filter(|&(dx, dy)| dy > 0 || dx > 0)
+        .collect();
+    offsets.sort_by_key(|&(dx, dy)| (dx * dx + dy * dy, dy, dx));
+    offsets.truncate(NEIGHBOURHOOD_CODES);
+    offsets
+}
+
+fn code_to_distance(code: usize, width: usize, table: &[(i64, i64)]) -> usize {
+    match table.get(code.wrapping_sub(1)) {
+        Some(&(dx, dy)) => (dy * width as i64 + dx).max(1) as usize,
+        None => code - NEIGHBOURHOOD_CODES,
+    }
+}
+
+fn distance_to_code(distance: usize, width: usize, table: &[(i64, i64)]) -> usize {
+    table
+        .iter()
+        .position(|&(dx, dy)| dy * width as i64 + dx == distance as i64)
+        .map(|i| i + 1)
+        .unwrap_or(distance + NEIGHBOURHOOD_CODES)
+}
+
+fn cache_index(pixel: u32, bits: u8) -> usize {
+    (pixel.wrapping_mul(0x1e35_a7bd) >> (32 - bits as u32)) as usize
+}
+
+fn pack(px: &[u8]) -> u32 {
+    px.iter()
+        .enumerate()
+        .fold(0, |acc, (i, &v)| acc | (v as u32) << (i * 8))
+}
+
+fn channel(pixel: u32, ch: usize) -> i32 {
+    ((pixel >> (ch * 8)) & 0xFF) as i32
+}
+
+struct UintModels {
+    prefix: [BitModel; UINT_BITS],
+    suffix: [BitModel; UINT_BITS],
+}
+
+impl UintModels {
+    fn new() -> Self {
+        Self {
+            prefix: [BitModel::new(); UINT_BITS],
+            suffix: [BitModel::new(); UINT_BITS],
+        }
+    }
+
+    fn encode(&mut self, enc: &mut RangeEncoder, value: u32) {
+        let v = value as u64 + 1;
+        let bits = 64 - v.leading_zeros() as usize;
+        for i in 1..bits {
+            enc.encode_with_model(true, &mut self.prefix[i - 1]);
+        }
+        if bits < UINT_BITS {
+            enc.encode_with_model(false, &mut self.prefix[bits - 1]);
+        }
+        for i in (0..bits - 1).rev() {
+            enc.encode_with_model((v >> i) & 1 != 0, &mut self.suffix[i]);
+        }
+    }
+
+    fn decode(&mut self, dec: &mut RangeDecoder) -> u32 {
+        let mut bits = 1;
+        while bits < UINT_BITS && dec.decode_with_model(&mut self.prefix[bits - 1]) {
+            bits += 1;
+        }
+        let mut v = 1u64;
+        for i in (0..bits - 1).rev() {
+            v = (v << 1) | dec.decode_with_model(&mut self.suffix[i]) as u64;
+        }
+        (v - 1).min(u32::MAX as u64) as u32
+    }
+}
+
+struct LiteralModels {
+    zero: [BitModel; ACTIVITY_CONTEXTS],
+    sign: [BitModel; ACTIVITY_CONTEXTS],
+    magnitude: Vec<UintModels>,
+}
+
+impl LiteralModels {
+    fn new() -> Self {
+        Self {
+            zero: [BitModel::new(); ACTIVITY_CONTEXTS],
+            sign: [BitModel::new(); ACTIVITY_CONTEXTS],
+            magnitude: (0..ACTIVITY_CONTEXTS).map(|_| UintModels::new()).collect(),
+        }
+    }
+}
+
+struct Models {
+    is_match: [BitModel; 3],
+    is_cached: [BitModel; 3],
+    cache_index: Vec<BitModel>,
+    length: UintModels,
+    distance: UintModels,
+    literals: Vec<LiteralModels>,
+}
+
+impl Models {
+    fn new(channels: usize, cache_bits: u8) -> Self {
+        Self {
+            is_match: [BitModel::new(); 3],
+            is_cached: [BitModel::new(); 3],
+            cache_index: vec![BitModel::new(); 1 << cache_bits],
+            length: UintModels::new(),
+            distance: UintModels::new(),
+            literals: (0..channels).map(|_| LiteralModels::new()).collect(),
+        }
+    }
+}
+
+#[derive(Clone, Copy)]
+enum Symbol {
+    Literal = 0,
+    Cached = 1,
+    Copy = 2,
+}
+
+struct ColorCache {
+    bits: u8,
+    entries: Vec<u32>,
+}
+
+impl ColorCache {
+    fn new(bits: u8) -> Self {
+        Self {
+            bits,
+            entries: vec![0; if bits > 0 { 1 << bits } else { 0 }],
+        }
+    }
+
+    fn lookup(&self, pixel: u32) -> Option<usize> {
+        if self.bits == 0 {
+            return None;
+        }
+        let index = cache_index(pixel, self.bits);
+        (self.entries[index] == pixel).then_some(index)
+    }
+
+    fn insert(&mut self, pixel: u32) {
+        if self.bits > 0 {
+            self.entries[cache_index(pixel, self.bits)] = pixel;
+        }
+    }
+}
+
+// Per-channel MED prediction from already reconstructed pixels, plus an activity context.
+fn predict(pixels: &[u32], width: usize, pos: usize, ch: usize) -> (i32, usize) {
+    let (x, y) = (pos % width, pos / width);
+    let a = if x > 0 {
+        channel(pixels[pos - 1], ch)
+    } else if y > 0 {
+        channel(pixels[pos - width], ch)
+    } else {
+        0
+    };
+    let b = if y > 0 {
+        channel(pixels[pos - width], ch)
+    } else {
+        a
+    };
+    let c = if x > 0 && y > 0 {
+        channel(pixels[pos - width - 1], ch)
+    } else {
+        b
+    };
+
+    let prediction = if c >= a.max(b) {
+        a.min(b)
+    } else if c <= a.min(b) {
+        a.max(b)
+    } else {
+        a + b - c
+    };
+    let activity = match (a - c).abs() + (b - c).abs() {
+        0 => 0,
+        1..=7 => 1,
+        _ => 2,
+    };
+    (prediction, activity)
+}
+
+struct MatchFinder {
+    head: Vec<usize>,
+    prev: Vec<usize>,
+}
+
+impl MatchFinder {
+    fn new(len: usize) -> Self {
+        Self {
+            head: vec![usize::MAX; 1 << HASH_BITS],
+            prev: vec![usize::MAX; len],
+        }
+    }
+
+    fn hash(pixels: &[u32], pos: usize) -> usize {
+        let h = pixels[pos].wrapping_mul(0x9E37_79B1) ^ pixels[pos + 1].wrapping_mul(0x85EB_CA6B);
+        (h >> (32 - HASH_BITS)) as usize
+    }
+
+    fn insert(&mut self, pixels: &[u32], pos: usize) {
+        if pos + 1 < pixels.len() {
+            let h = Self::hash(pixels, pos);
+            self.prev[pos] = self.head[h];
+            self.head[h] = pos;
+        }
+    }
+
+    fn find(&self, pixels: &[u32], pos: usize, width: usize) -> (usize, usize) {
+        let limit = (pixels.len() - pos).min(MAX_MATCH);
+        if limit < MIN_MATCH {
+            return (0, 0);
+        }
+        let match_len = |distance: usize| {
+            (0..limit)
+                .take_while(|&i| pixels[pos + i] == pixels[pos + i - distance])
+                .count()
+        };
+
+        let mut best = (0, 0);
+        for distance in [1, width] {
+            if distance <= pos {
+                let len = match_len(distance);
+                if len > best.0 {
+                    best = (len, distance);
+                }
+            }
+        }
+
+        let mut candidate = self.head[Self::hash(pixels, pos)];
+        for _ in 0..MAX_CHAIN {
+            if candidate == usize::MAX || best.0 == limit {
+                break;
+            }
+            let len = match_len(pos - candidate);
+            if len > best.0 {
+                best = (len, pos - candidate);
+            }
+            candidate = self.prev[candidate];
+        }
+        best
+    }
+}
+
+pub fn compress(data: &[u8], width: usize, height: usize, channels: usize) -> Vec<u8> {
+    let pixels: Vec<u32> = data.chunks_exact(channels).map(pack).collect();
+    let table = neighbourhood();
+    let mut models = Models::new(channels, CACHE_BITS);
+    let mut cache = ColorCache::new(CACHE_BITS);
+    let mut finder = MatchFinder::new(pixels.len());
+    let mut enc = RangeEncoder::new();
+
+    let mut pos = 0;
+    let mut last = Symbol::Literal;
+    while pos < width * height {
+        let (len, distance) = finder.find(&pixels, pos, width);
+        if len >= MIN_MATCH {
+            enc.encode_with_model(true, &mut models.is_match[last as usize]);
+            models.length.encode(&mut enc, (len - MIN_MATCH) as u32);
+            let code = distance_to_code(distance, width, &table);
+            models.distance.encode(&mut enc, (code - 1) as u32);
+            for p in pos..pos + len {
+                cache.insert(pixels[p]);
+                finder.insert(&pixels, p);
+            }
+            pos += len;
+            last = Symbol::Copy;
+            continue;
+        }
+
+        enc.encode_with_model(false, &mut models.is_match[last as usize]);
+        let pixel = pixels[pos];
+        if CACHE_BITS > 0 {
+            let hit = cache.lookup(pixel);
+            enc.encode_with_model(hit.is_some(), &mut models.is_cached[last as usize]);
+            if let Some(index) = hit {
+                let mut node = 1;
+                for i in (0..CACHE_BITS).rev() {
+                    let bit = (index >> i) & 1 != 0;
+                    enc.encode_with_model(bit, &mut models.cache_index[node]);
+                    node = (node << 1) | bit as usize;
+                }
+                finder.insert(&pixels, pos);
+                pos += 1;
+                last = Symbol::Cached;
+                continue;
+            }
+        }
+
+        for ch in 0..channels {
+            let (prediction, ctx) = predict(&pixels, width, pos, ch);
+            let residual = ((channel(pixel, ch) - prediction + 128).rem_euclid(256)) - 128;
+            let lit = &mut models.literals[ch];
+            enc.encode_with_model(residual != 0, &mut lit.zero[ctx]);
+            if residual != 0 {
+                enc.encode_with_model(residual < 0, &mut lit.sign[ctx]);
+                lit.magnitude[ctx].encode(&mut enc, residual.unsigned_abs() - 1);
+            }
+        }
+        cache.insert(pixel);
+        finder.insert(&pixels, pos);
+        pos += 1;
+        last = Symbol::Literal;
+    }
+
+    let mut output = vec![CACHE_BITS];
+    output.extend(enc.finish());
+    output
+}
+
+pub fn decompress(data: &[u8], width: usize, height: usize, channels: usize) -> WkResult<Vec<u8>> {
+    let cache_bits = *data
+        .first()
+        .ok_or_else(|| WkError::DecodingError("Empty LZ77 stream".into()))?;
+    if cache_bits > 16 {
+        return Err(WkError::DecodingError(format!(
+            "Invalid color cache size: {}",
+            cache_bits
+        )));
+    }
+
+    let total = width * height;
+    let table = neighbourhood();
+    let mut models = Models::new(channels, cache_bits);
+    let mut cache = ColorCache::new(cache_bits);
+    let mut dec = RangeDecoder::new(data[1..].to_vec());
+    let mut pixels = vec![0u32; total];
+
+    let mut pos = 0;
+    let mut last = Symbol::Literal;
+    while pos < total {
+        if dec.decode_with_model(&mut models.is_match[last as usize]) {
+            let len = models.length.decode(&mut dec) as usize + MIN_MATCH;
+            let code = models.distance.decode(&mut dec) as usize + 1;
+            let distance = code_to_distance(code, width, &table);
+            if distance > pos || len > total - pos {
+                return Err(WkError::DecodingError("Invalid backward reference".into()));
+            }
+            for p in pos..pos + len {
+                pixels[p] = pixels[p - distance];
+                cache.insert(pixels[p]);
+            }
+            pos += len;
+            last = Symbol::Copy;
+            continue;
+        }
+
+        if cache_bits > 0 && dec.decode_with_model(&mut models.is_cached[last as usize]) {
+            let mut node = 1;
+            for _ in 0..cache_bits {
+                node = (node << 1) | dec.decode_with_model(&mut models.cache_index[node]) as usize;
+            }
+            pixels[pos] = cache.entries[node - (1 << cache_bits)];
+            pos += 1;
+            last = Symbol::Cached;
+            continue;
+        }
+
+        let mut pixel = 0u32;
+        for ch in 0..channels {
+            let (prediction, ctx) = predict(&pixels, width, pos, ch);
+            let lit = &mut models.literals[ch];
+            let residual = if dec.decode_with_model(&mut lit.zero[ctx]) {
+                let negative = dec.decode_with_model(&mut lit.sign[ctx]);
+                let magnitude = lit.magnitude[ctx].decode(&mut dec) as i32 + 1;
+                if negative {
+                    -magnitude
+                } else {
+                    magnitude
+                }
+            } else {
+                0
+            };
+            pixel |= ((prediction + residual).rem_euclid(256) as u32) << (ch * 8);
+        }
+        pixels[pos] = pixel;
+        cache.insert(pixel);
+        pos += 1;
+        last = Symbol::Literal;
+    }
+
+    Ok(pixels
+        .iter()
+        .flat_map(|&p| (0..channels).map(move |ch| channel(p, ch) as u8))
+        .collect())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn tiled_screenshot(width: usize, height: usize) -> Vec<u8> {
+        let mut seed = 99u32;
+        let tile: Vec<u8> = (0..24 * 16 * 3)
+            .map(|_| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                (seed >> 24) as u8
+            })
+            .collect();
+        let mut data = Vec::with_capacity(width * height * 3);
+        for y in 0..height {
+            for x in 0..width {
+                let t = ((y % 16) * 24 + x % 24) * 3;
+                data.extend_from_slice(&tile[t..t + 3]);
+            }
+        }
+        data
+    }
+
+    #[test]
+    fn test_lz77_roundtrip() {
+        let data = tiled_screenshot(100, 50);
+        let encoded = compress(&data, 100, 50, 3);
+        assert_eq!(decompress(&encoded, 100, 50, 3).unwrap(), data);
+
+        for (w, h, channels) in [(1, 40, 1), (3, 7, 4), (5, 1, 2)] {
+            let data: Vec<u8> = (0..w * h * channels).map(|i| (i * i % 7) as u8).collect();
+            let encoded = compress(&data, w, h, channels);
+            assert_eq!(decompress(&encoded, w, h, channels).unwrap(), data);
+        }
+    }
+
+    #[test]
+    fn test_repeated_tiles_beat_loco() {
+        let data = tiled_screenshot(240, 160);
+        let lz77 = compress(&data, 240, 160, 3);
+        let loco = super::super::loco::compress(&data, 240, 160, 3);
+        assert!(lz77.len() * 4 < loco.len());
+    }
+
+    #[test]
+    fn test_distance_codes_roundtrip() {
+        let table = neighbourhood();
+        assert_eq!(table.len(), NEIGHBOURHOOD_CODES);
+        assert_eq!(table[0], (1, 0));
+        for width in [1, 7, 640] {
+            for distance in [1, 2, width, width + 1, 3 * width + 5, 100_000] {
+                let code = distance_to_code(distance, width, &table);
+                assert_eq!(code_to_distance(code, width, &table), distance);
+            }
+        }
+    }
+}
@@ -1,12 +1,16 @@
 pub mod adaptive_quant;
 pub mod arithmetic_coder;
 pub mod color;
+pub mod color_transform;
 pub mod context_model;
 pub mod dct;
 pub mod deblocking;
+pub mod effort;
 pub mod engine;
 pub mod entropy;
 pub mod intra_prediction;
+pub mod loco;
+pub mod lz77;
 pub mod multi_dct;
 pub mod predictor;
 pub mod probability_tables;
@@ -20,10 +24,12 @@ pub use adaptive_quant::{AdaptiveQuantizer, BlockStats, QuantTable};
 pub use arithmetic_coder::{ArithmeticDecoder, ArithmeticEncoder, CABACContext, ProbabilityModel};
 pub use color::{
     convert_rgb_to_ycbcr_image, convert_ycbcr_to_rgb_image, downsample_420, rgb_to_ycbcr,
-    upsample_420, ycbcr_to_rgb, ChromaSubsampling, ColorSpace,
+    upsample_420, ycbcr_to_rgb, ChromaSubsampling, ColorMatrix, ColorRange, ColorSpace,
 };
+pub use color_transform::ColorTransform;
 pub use dct::{dct_8x8, dct_8x8_fast, idct_8x8, idct_8x8_fast, zigzag_scan, zigzag_unscan};
-pub use engine::{CompressionConfig, CompressionEngine};
+pub use effort::{EffortProfile, DEFAULT_EFFORT, MAX_EFFORT};
+pub use engine::{CompressionConfig, CompressionEngine, LosslessEngine};
 pub use entropy::{EntropyDecoder, EntropyEncoder};
 pub use intra_prediction::{IntraMode, IntraPredictor};
 pub use multi_dct::{dct_16x16, idct_16x16, int_dct_8x8, int_idct_8x8, BlockSize};
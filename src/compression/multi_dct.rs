@@ -102,7 +102,7 @@ pub fn idct_16x16(coeffs: &[i16; 256]) -> [i16; 256] {
     output
 }
 
-const INT_DCT_8_MATRIX: [[i32; 8]; 8] = [
+pub(crate) const INT_DCT_8_MATRIX: [[i32; 8]; 8] = [
     [64, 64, 64, 64, 64, 64, 64, 64],
     [89, 75, 50, 18, -18, -50, -75, -89],
     [83, 36, -36, -83, -83, -36, 36, 83],
@@ -113,54 +113,79 @@ const INT_DCT_8_MATRIX: [[i32; 8]; 8] = [
     [18, -50, 75, -89, 89, -75, 50, -18],
 ];
 
-pub fn int_dct_8x8(block: &[i16; 64]) -> [i32; 64] {
+// The N-point matrices (N = 4, 2, 1) are the even rows of the 8-point one, as in HEVC.
+fn int_basis(size: usize, u: usize, x: usize) -> i32 {
+    INT_DCT_8_MATRIX[u * (8 / size)][x]
+}
+
+// M·f·Mᵀ is 2^15 times the orthonormal DCT, so coefficients share the float transform's scale.
+pub fn int_dct_8x8(block: &[i16; 64]) -> [i16; 64] {
     let mut temp = [0i32; 64];
     for y in 0..8 {
         for u in 0..8 {
-            let mut sum = 0i32;
-            for x in 0..8 {
-                sum += block[y * 8 + x] as i32 * INT_DCT_8_MATRIX[u][x];
-            }
-            temp[y * 8 + u] = sum;
+            temp[y * 8 + u] = (0..8)
+                .map(|x| block[y * 8 + x] as i32 * INT_DCT_8_MATRIX[u][x])
+                .sum();
         }
     }
-    let mut output = [0i32; 64];
-    for x in 0..8 {
-        for v in 0..8 {
-            let mut sum = 0i64;
-            for y in 0..8 {
-                sum += temp[y * 8 + x] as i64 * INT_DCT_8_MATRIX[v][y] as i64;
-            }
-            output[v * 8 + x] = ((sum + 2048) >> 12) as i32;
+    let mut output = [0i16; 64];
+    for v in 0..8 {
+        for u in 0..8 {
+            let sum: i64 = (0..8)
+                .map(|y| temp[y * 8 + u] as i64 * INT_DCT_8_MATRIX[v][y] as i64)
+                .sum();
+            output[v * 8 + u] = ((sum + (1 << 14)) >> 15).clamp(-32768, 32767) as i16;
         }
     }
     output
 }
 
-pub fn int_idct_8x8(coeffs: &[i32; 64]) -> [i16; 64] {
+fn int_idct_rows(coeffs: &[i16; 64], size: usize) -> [i32; 64] {
     let mut temp = [0i32; 64];
-    for y in 0..8 {
-        for x in 0..8 {
-            let mut sum = 0i64;
-            for u in 0..8 {
-                sum += coeffs[y * 8 + u] as i64 * INT_DCT_8_MATRIX[u][x] as i64;
-            }
-            temp[y * 8 + x] = ((sum + 32) >> 6) as i32;
+    for v in 0..size {
+        for x in 0..size {
+            let sum: i32 = (0..size)
+                .map(|u| coeffs[v * 8 + u] as i32 * int_basis(size, u, x))
+                .sum();
+            temp[v * 8 + x] = (sum + 64) >> 7;
         }
     }
+    temp
+}
+
+fn int_idct_column(temp: &[i32; 64], size: usize, x: usize, y: usize) -> i16 {
+    let sum: i32 = (0..size)
+        .map(|v| temp[v * 8 + x] * int_basis(size, v, y))
+        .sum();
+    ((sum + 128) >> 8).clamp(-32768, 32767) as i16
+}
+
+pub fn int_idct_8x8(coeffs: &[i16; 64]) -> [i16; 64] {
+    int_idct_scaled(coeffs, 8)
+}
+
+// Inverts the top-left `size`×`size` coefficients with the N-point integer transform, packed
+// at the start of the output with a row stride of `size`.
+pub fn int_idct_scaled(coeffs: &[i16; 64], size: usize) -> [i16; 64] {
+    let temp = int_idct_rows(coeffs, size);
     let mut output = [0i16; 64];
-    for x in 0..8 {
-        for y in 0..8 {
-            let mut sum = 0i64;
-            for v in 0..8 {
-                sum += temp[v * 8 + x] as i64 * INT_DCT_8_MATRIX[v][y] as i64;
-            }
-            output[y * 8 + x] = ((sum + 32) >> 6) as i16;
+    for y in 0..size {
+        for x in 0..size {
+            output[y * size + x] = int_idct_column(&temp, size, x, y);
         }
     }
     output
 }
 
+// Bottom row and right column of `int_idct_8x8`, bit-identical to the full transform.
+pub fn int_idct_edges(coeffs: &[i16; 64]) -> ([i16; 8], [i16; 8]) {
+    let temp = int_idct_rows(coeffs, 8);
+    (
+        std::array::from_fn(|x| int_idct_column(&temp, 8, x, 7)),
+        std::array::from_fn(|y| int_idct_column(&temp, 8, 7, y)),
+    )
+}
+
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum BlockSize {
     B8x8,
@@ -86,6 +86,63 @@ pub fn apply_predictor(
     filtered
 }
 
+fn predict(predictor: PredictorType, left: u8, up: u8, up_left: u8) -> u8 {
+    match predictor {
+        PredictorType::None => 0,
+        PredictorType::Sub => left,
+        PredictorType::Up => up,
+        PredictorType::Average => ((left as u16 + up as u16) / 2) as u8,
+        PredictorType::Paeth => paeth_predictor(left, up, up_left),
+    }
+}
+
+fn neighbours(row: &[u8], prev: &[u8], x: usize, channels: usize) -> (u8, u8, u8) {
+    if x >= channels {
+        (row[x - channels], prev[x], prev[x - channels])
+    } else {
+        (0, prev[x], 0)
+    }
+}
+
+pub(crate) fn filter_row(
+    predictor: PredictorType,
+    row: &[u8],
+    prev: &[u8],
+    channels: usize,
+    out: &mut [u8],
+) {
+    #[cfg(feature = "simd")]
+    let resume = super::simd::filter_row_lanes(predictor, row, prev, channels, out);
+    #[cfg(not(feature = "simd"))]
+    let resume = channels;
+    for x in (0..channels.min(row.len())).chain(resume..row.len()) {
+        let (left, up, up_left) = neighbours(row, prev, x, channels);
+        out[x] = row[x].wrapping_sub(predict(predictor, left, up, up_left));
+    }
+}
+
+pub(crate) fn row_cost(
+    predictor: PredictorType,
+    row: &[u8],
+    prev: &[u8],
+    channels: usize,
+) -> usize {
+    #[cfg(feature = "simd")]
+    let (mut cost, resume) = super::simd::row_cost_lanes(predictor, row, prev, channels);
+    #[cfg(not(feature = "simd"))]
+    let (mut cost, resume) = (0, channels);
+    for x in (0..channels.min(row.len())).chain(resume..row.len()) {
+        let (left, up, up_left) = neighbours(row, prev, x, channels);
+        let delta = row[x].wrapping_sub(predict(predictor, left, up, up_left));
+        cost += if delta > 127 {
+            256 - delta as usize
+        } else {
+            delta as usize
+        };
+    }
+    cost
+}
+
 pub fn reverse_predictor(
     filtered: &[u8],
     width: usize,
@@ -94,95 +151,85 @@ pub fn reverse_predictor(
 ) -> WkResult<Vec<u8>> {
     let stride = width * channels;
     let mut data = vec![0u8; width * height * channels];
-    let mut in_idx = 0;
+    let zeros = vec![0u8; stride];
 
     for y in 0..height {
-        let predictor = PredictorType::from_u8(filtered[in_idx]);
-        in_idx += 1;
-
-        for x in 0..stride {
-            let idx = y * stride + x;
-            let delta = filtered[in_idx];
-            in_idx += 1;
-
-            let left = if x >= channels {
-                data[idx - channels]
-            } else {
-                0
-            };
-            let up = if y > 0 { data[idx - stride] } else { 0 };
-            let up_left = if x >= channels && y > 0 {
-                data[idx - stride - channels]
-            } else {
-                0
-            };
-
-            let prediction = match predictor {
-                PredictorType::None => 0,
-                PredictorType::Sub => left,
-                PredictorType::Up => up,
-                PredictorType::Average => ((left as u16 + up as u16) / 2) as u8,
-                PredictorType::Paeth => paeth_predictor(left, up, up_left),
-            };
-
-            data[idx] = delta.wrapping_add(prediction);
+        let predictor = PredictorType::from_u8(filtered[y * (stride + 1)]);
+        let delta = &filtered[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+        let (done, rest) = data.split_at_mut(y * stride);
+        let prev = if y > 0 {
+            &done[(y - 1) * stride..]
+        } else {
+            &zeros[..]
+        };
+        let row = &mut rest[..stride];
+
+        match predictor {
+            PredictorType::None => row.copy_from_slice(delta),
+            PredictorType::Up => {
+                #[cfg(feature = "simd")]
+                let resume = super::simd::unfilter_up_lanes(delta, prev, row);
+                #[cfg(not(feature = "simd"))]
+                let resume = 0;
+                for x in resume..stride {
+                    row[x] = delta[x].wrapping_add(prev[x]);
+                }
+            }
+            _ => {
+                for x in 0..stride {
+                    let (left, up, up_left) = neighbours(row, prev, x, channels);
+                    row[x] = delta[x].wrapping_add(predict(predictor, left, up, up_left));
+                }
+            }
         }
     }
 
     Ok(data)
 }
 
+pub const ALL_PREDICTORS: [PredictorType; 5] = [
+    PredictorType::None,
+    PredictorType::Sub,
+    PredictorType::Up,
+    PredictorType::Average,
+    PredictorType::Paeth,
+];
+
 pub fn select_optimal_predictor(
     row: &[u8],
     prev_row: Option<&[u8]>,
     channels: usize,
 ) -> PredictorType {
-    let predictors = [
-        PredictorType::None,
-        PredictorType::Sub,
-        PredictorType::Up,
-        PredictorType::Average,
-        PredictorType::Paeth,
-    ];
-
-    let mut best = PredictorType::None;
-    let mut best_score = usize::MAX;
-
-    for &predictor in &predictors {
-        let mut score = 0usize;
-
-        for (x, &raw) in row.iter().enumerate() {
-            let left = if x >= channels { row[x - channels] } else { 0 };
-            let up = prev_row.map(|r| r[x]).unwrap_or(0);
-            let up_left = if x >= channels {
-                prev_row.map(|r| r[x - channels]).unwrap_or(0)
-            } else {
-                0
-            };
-
-            let prediction = match predictor {
-                PredictorType::None => 0,
-                PredictorType::Sub => left,
-                PredictorType::Up => up,
-                PredictorType::Average => ((left as u16 + up as u16) / 2) as u8,
-                PredictorType::Paeth => paeth_predictor(left, up, up_left),
-            };
+    select_predictor_among(row, prev_row, channels, &ALL_PREDICTORS)
+}
 
-            let delta = raw.wrapping_sub(prediction);
-            let abs_delta = if delta > 127 {
-                256 - delta as usize
-            } else {
-                delta as usize
-            };
-            score += abs_delta;
+pub fn select_predictor_among(
+    row: &[u8],
+    prev_row: Option<&[u8]>,
+    channels: usize,
+    predictors: &[PredictorType],
+) -> PredictorType {
+    let zeros;
+    let prev = match prev_row {
+        Some(prev) => prev,
+        None => {
+            zeros = vec![0u8; row.len()];
+            &zeros
         }
+    };
 
+    let mut best = predictors.first().copied().unwrap_or(PredictorType::None);
+    if predictors.len() < 2 {
+        return best;
+    }
+    let mut best_score = usize::MAX;
+    for &predictor in predictors {
+        let score = row_cost(predictor, row, prev, channels);
         if score < best_score {
             best_score = score;
             best = predictor;
         }
     }
-
     best
 }
 
@@ -191,46 +238,78 @@ pub fn apply_optimal_predictor(
     width: usize,
     height: usize,
     channels: usize,
+) -> Vec<u8> {
+    apply_predictors_among(data, width, height, channels, &ALL_PREDICTORS)
+}
+
+pub fn apply_predictors_among(
+    data: &[u8],
+    width: usize,
+    height: usize,
+    channels: usize,
+    predictors: &[PredictorType],
 ) -> Vec<u8> {
     let stride = width * channels;
     let mut filtered = vec![0u8; data.len() + height];
-    let mut out_idx = 0;
+    let zeros = vec![0u8; stride];
 
     for y in 0..height {
-        let row_start = y * stride;
-        let row = &data[row_start..row_start + stride];
-        let prev_row = if y > 0 {
-            Some(&data[(y - 1) * stride..(y - 1) * stride + stride])
+        let row = &data[y * stride..(y + 1) * stride];
+        let prev = if y > 0 {
+            &data[(y - 1) * stride..y * stride]
         } else {
-            None
+            &zeros[..]
         };
 
-        let predictor = select_optimal_predictor(row, prev_row, channels);
-        filtered[out_idx] = predictor as u8;
-        out_idx += 1;
-
-        for x in 0..stride {
-            let raw = row[x];
-            let left = if x >= channels { row[x - channels] } else { 0 };
-            let up = prev_row.map(|r| r[x]).unwrap_or(0);
-            let up_left = if x >= channels {
-                prev_row.map(|r| r[x - channels]).unwrap_or(0)
-            } else {
-                0
-            };
+        let predictor = select_predictor_among(row, Some(prev), channels, predictors);
+        let out = &mut filtered[y * (stride + 1)..(y + 1) * (stride + 1)];
+        out[0] = predictor as u8;
+        filter_row(predictor, row, prev, channels, &mut out[1..]);
+    }
 
-            let prediction = match predictor {
-                PredictorType::None => 0,
-                PredictorType::Sub => left,
-                PredictorType::Up => up,
-                PredictorType::Average => ((left as u16 + up as u16) / 2) as u8,
-                PredictorType::Paeth => paeth_predictor(left, up, up_left),
-            };
+    filtered
+}
 
-            filtered[out_idx] = raw.wrapping_sub(prediction);
-            out_idx += 1;
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_row_filters_match_fixed_predictors() {
+        let (w, h, channels) = (29, 6, 3);
+        let data: Vec<u8> = (0..w * h * channels)
+            .map(|i| ((i * 37) ^ (i / 11)) as u8)
+            .collect();
+        let stride = w * channels;
+        for predictor in [
+            PredictorType::None,
+            PredictorType::Sub,
+            PredictorType::Up,
+            PredictorType::Average,
+            PredictorType::Paeth,
+        ] {
+            let filtered = apply_predictor(&data, w, h, channels, predictor);
+            assert_eq!(reverse_predictor(&filtered, w, h, channels).unwrap(), data);
+
+            let zeros = vec![0u8; stride];
+            for y in 0..h {
+                let row = &data[y * stride..(y + 1) * stride];
+                let prev = if y > 0 {
+                    &data[(y - 1) * stride..y * stride]
+                } else {
+                    &zeros[..]
+                };
+                let expected = &filtered[y * (stride + 1) + 1..(y + 1) * (stride + 1)];
+                let mut out = vec![0u8; stride];
+                filter_row(predictor, row, prev, channels, &mut out);
+                assert_eq!(out, expected);
+
+                let cost: usize = expected
+                    .iter()
+                    .map(|&d| (d as i8).unsigned_abs() as usize)
+                    .sum();
+                assert_eq!(row_cost(predictor, row, prev, channels), cost);
+            }
         }
     }
-
-    filtered
 }
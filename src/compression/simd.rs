@@ -1,5 +1,12 @@
+#[cfg(feature = "simd")]
+use super::color::{rgb_to_ycbcr_lanes, ycbcr_to_rgb_lanes, ColorSpace};
+use super::multi_dct::{int_idct_8x8, INT_DCT_8_MATRIX};
+#[cfg(feature = "simd")]
+use super::predictor::PredictorType;
 #[cfg(target_arch = "x86_64")]
 use std::arch::x86_64::*;
+#[cfg(feature = "simd")]
+use wide::{f32x8, i16x16, i16x8, u8x16, CmpGe, CmpGt, CmpLt};
 
 #[derive(Debug, Clone, Copy, PartialEq, Eq)]
 pub enum SimdLevel {
@@ -149,6 +156,55 @@ unsafe fn idct_8x8_sse(coeffs: &[i16; 64]) -> [i16; 64] {
     idct_8x8_scalar(coeffs)
 }
 
+pub fn int_idct_8x8_simd(coeffs: &[i16; 64]) -> [i16; 64] {
+    #[cfg(target_arch = "x86_64")]
+    {
+        if is_x86_feature_detected!("sse4.1") {
+            return unsafe { int_idct_8x8_sse(coeffs) };
+        }
+    }
+    int_idct_8x8(coeffs)
+}
+
+// Same integer operations as `int_idct_8x8`, four lanes at a time, so results are bit-identical.
+#[cfg(target_arch = "x86_64")]
+#[target_feature(enable = "sse4.1")]
+unsafe fn int_idct_8x8_sse(coeffs: &[i16; 64]) -> [i16; 64] {
+    let basis: [[__m128i; 2]; 8] = std::array::from_fn(|u| {
+        let row = &INT_DCT_8_MATRIX[u];
+        [
+            _mm_setr_epi32(row[0], row[1], row[2], row[3]),
+            _mm_setr_epi32(row[4], row[5], row[6], row[7]),
+        ]
+    });
+
+    let mut temp = [[_mm_setzero_si128(); 2]; 8];
+    for v in 0..8 {
+        let mut lo = _mm_set1_epi32(64);
+        let mut hi = _mm_set1_epi32(64);
+        for u in 0..8 {
+            let c = _mm_set1_epi32(coeffs[v * 8 + u] as i32);
+            lo = _mm_add_epi32(lo, _mm_mullo_epi32(c, basis[u][0]));
+            hi = _mm_add_epi32(hi, _mm_mullo_epi32(c, basis[u][1]));
+        }
+        temp[v] = [_mm_srai_epi32(lo, 7), _mm_srai_epi32(hi, 7)];
+    }
+
+    let mut output = [0i16; 64];
+    for y in 0..8 {
+        let mut lo = _mm_set1_epi32(128);
+        let mut hi = _mm_set1_epi32(128);
+        for v in 0..8 {
+            let m = _mm_set1_epi32(INT_DCT_8_MATRIX[v][y]);
+            lo = _mm_add_epi32(lo, _mm_mullo_epi32(temp[v][0], m));
+            hi = _mm_add_epi32(hi, _mm_mullo_epi32(temp[v][1], m));
+        }
+        let packed = _mm_packs_epi32(_mm_srai_epi32(lo, 8), _mm_srai_epi32(hi, 8));
+        _mm_storeu_si128(output.as_mut_ptr().add(y * 8) as *mut __m128i, packed);
+    }
+    output
+}
+
 pub fn idct_8x8_scalar(coeffs: &[i16; 64]) -> [i16; 64] {
     use std::f64::consts::PI;
     let inv_sqrt2 = 0.7071067811865476;
@@ -225,3 +281,460 @@ pub fn dequantize_simd(block: &[i16; 64], table: &[u16; 64]) -> [i16; 64] {
     }
     output
 }
+
+pub fn sum_abs_diff(a: &[u8], b: &[u8]) -> u64 {
+    let len = a.len().min(b.len());
+    #[cfg(feature = "simd")]
+    let (mut sad, start) = sum_abs_diff_lanes(&a[..len], &b[..len]);
+    #[cfg(not(feature = "simd"))]
+    let (mut sad, start) = (0, 0);
+    for i in start..len {
+        sad += a[i].abs_diff(b[i]) as u64;
+    }
+    sad
+}
+
+// SAD of two equally strided blocks that lie wholly inside their planes.
+pub fn block_sum_abs_diff(a: &[u8], b: &[u8], stride: usize, cols: usize, rows: usize) -> u64 {
+    #[cfg(feature = "simd")]
+    if cols.is_multiple_of(16) {
+        return block_sum_abs_diff_lanes(a, b, stride, cols, rows);
+    }
+    (0..rows)
+        .map(|y| sum_abs_diff(&a[y * stride..][..cols], &b[y * stride..][..cols]))
+        .sum()
+}
+
+#[cfg(feature = "simd")]
+fn load_u8x16(data: &[u8]) -> u8x16 {
+    u8x16::from(<[u8; 16]>::try_from(&data[..16]).unwrap())
+}
+
+#[cfg(feature = "simd")]
+fn load_i16x16(data: &[u8]) -> i16x16 {
+    i16x16::from(load_u8x16(data))
+}
+
+#[cfg(feature = "simd")]
+fn load_i16x8(data: &[u8]) -> i16x8 {
+    i16x8::from(std::array::from_fn::<i16, 8, _>(|i| data[i] as i16))
+}
+
+// Sixteen lanes of u16 partial sums overflow after 128 blocks of |a - b| <= 255.
+#[cfg(feature = "simd")]
+fn sum_abs_diff_lanes(a: &[u8], b: &[u8]) -> (u64, usize) {
+    let blocks = a.len() / 16;
+    let mut sad = 0u64;
+    for run in (0..blocks).step_by(128) {
+        let mut acc = i16x16::splat(0);
+        for block in run..(run + 128).min(blocks) {
+            let x = load_u8x16(&a[block * 16..]);
+            let y = load_u8x16(&b[block * 16..]);
+            acc += i16x16::from(x.saturating_sub(y) | y.saturating_sub(x));
+        }
+        sad += acc.to_array().iter().map(|&v| v as u16 as u64).sum::<u64>();
+    }
+    (sad, blocks * 16)
+}
+
+// One accumulator for the whole block, flushed before its u16 lanes overflow.
+#[cfg(feature = "simd")]
+fn block_sum_abs_diff_lanes(a: &[u8], b: &[u8], stride: usize, cols: usize, rows: usize) -> u64 {
+    let flush = |acc: i16x16| acc.to_array().iter().map(|&v| v as u16 as u64).sum::<u64>();
+    let mut sad = 0u64;
+    let mut acc = i16x16::splat(0);
+    let mut pending = 0;
+    for y in 0..rows {
+        let (row_a, row_b) = (&a[y * stride..][..cols], &b[y * stride..][..cols]);
+        for x in (0..cols).step_by(16) {
+            let p = load_u8x16(&row_a[x..]);
+            let q = load_u8x16(&row_b[x..]);
+            acc += i16x16::from(p.saturating_sub(q) | q.saturating_sub(p));
+            pending += 1;
+            if pending == 128 {
+                sad += flush(acc);
+                acc = i16x16::splat(0);
+                pending = 0;
+            }
+        }
+    }
+    sad + flush(acc)
+}
+
+// `f32::round` rounds halves away from zero; negative values all clamp to `lo >= 0`, so
+// rounding via floor gives the same bytes.
+#[cfg(feature = "simd")]
+fn round_clamp_lanes(v: f32x8, lo: f32, hi: f32) -> [i32; 8] {
+    let floor = v.floor();
+    let rounded = (v - floor)
+        .cmp_ge(f32x8::splat(0.5))
+        .blend(floor + 1.0, floor);
+    rounded
+        .max(f32x8::splat(lo))
+        .min(f32x8::splat(hi))
+        .trunc_int()
+        .to_array()
+}
+
+#[cfg(feature = "simd")]
+pub fn rgb_to_ycbcr_lanes_into(
+    data: &[u8],
+    channels: usize,
+    space: ColorSpace,
+    planes: [&mut [u8]; 3],
+) -> usize {
+    let n = planes[0].len();
+    let body = n - n % 8;
+    for i in (0..body).step_by(8) {
+        let px = &data[i * channels..];
+        let lane = |c: usize| f32x8::from(std::array::from_fn(|k| px[k * channels + c] as f32));
+        let out = rgb_to_ycbcr_lanes(lane(0), lane(1), lane(2), space);
+        for c in 0..3 {
+            let v = round_clamp_lanes(out.values[c], out.lo, out.hi[c]);
+            for (dst, &v) in planes[c][i..i + 8].iter_mut().zip(&v) {
+                *dst = v as u8;
+            }
+        }
+    }
+    body
+}
+
+#[cfg(feature = "simd")]
+pub fn ycbcr_to_rgb_lanes_into(
+    planes: [&[u8]; 3],
+    channels: usize,
+    space: ColorSpace,
+    rgb: &mut [u8],
+) -> usize {
+    let n = planes[0].len();
+    let body = n - n % 8;
+    for i in (0..body).step_by(8) {
+        let lane = |c: usize| f32x8::from(std::array::from_fn(|k| planes[c][i + k] as f32));
+        let out = ycbcr_to_rgb_lanes(lane(0), lane(1), lane(2), space);
+        for c in 0..3 {
+            let v = round_clamp_lanes(out.values[c], out.lo, out.hi[c]);
+            for (k, &v) in v.iter().enumerate() {
+                rgb[(i + k) * channels + c] = v as u8;
+            }
+        }
+    }
+    body
+}
+
+#[cfg(feature = "simd")]
+pub fn true_motion_lanes(n: usize, top: &[u8], left: &[u8], top_left: u8, pred: &mut [u8]) {
+    for y in 0..n {
+        let offset = left[y] as i16 - top_left as i16;
+        for x in (0..n).step_by(8) {
+            let v = (load_i16x8(&top[x..]) + offset)
+                .max(i16x8::splat(0))
+                .min(i16x8::splat(255));
+            for (dst, &v) in pred[y * n + x..y * n + x + 8].iter_mut().zip(&v.to_array()) {
+                *dst = v as u8;
+            }
+        }
+    }
+}
+
+// The weighted sum is at most 2 * n * 255 + n, which fits i16 for n <= 64, and the
+// divisor 2 * n is a power of two.
+#[cfg(feature = "simd")]
+pub fn planar_lanes(n: usize, top: &[u8], left: &[u8], pred: &mut [u8]) {
+    let tr = top[n - 1] as i16;
+    let bl = left[n - 1] as i16;
+    let shift = (2 * n).trailing_zeros() as i32;
+    for y in 0..n {
+        let l = left[y] as i16;
+        for x in (0..n).step_by(8) {
+            let xs = i16x8::from(std::array::from_fn::<i16, 8, _>(|i| (x + i) as i16));
+            let h = (i16x8::splat(n as i16 - 1) - xs) * l + (xs + 1) * tr;
+            let v = load_i16x8(&top[x..]) * (n - 1 - y) as i16 + (y as i16 + 1) * bl;
+            let value = (h + v + n as i16) >> shift;
+            for (dst, &v) in pred[y * n + x..y * n + x + 8]
+                .iter_mut()
+                .zip(&value.to_array())
+            {
+                *dst = v as u8;
+            }
+        }
+    }
+}
+
+#[cfg(feature = "simd")]
+fn clamp_u8_lanes(v: i16x8) -> i16x8 {
+    v.max(i16x8::splat(0)).min(i16x8::splat(255))
+}
+
+// Eight deblocking positions at once: `taps` holds p2, p1, p0, q0, q1, q2 and the result is
+// the filtered p1, p0, q0, q1. Divisions match Rust's truncating `/` on the scalar path.
+#[cfg(feature = "simd")]
+pub fn deblock_lanes(taps: [[i16; 8]; 6], edge_th: u8, level: i32, simple: bool) -> [[i16; 8]; 4] {
+    let [p2, p1, p0, q0, q1, q2] = taps.map(i16x8::from);
+    let level_v = i16x8::splat(level as i16);
+
+    let boundary = (p0 - q0).abs();
+    let grad_in = (p1 - p0).abs().max((q1 - q0).abs());
+    let filter = !boundary.cmp_gt(i16x8::splat(edge_th as i16))
+        | (boundary.cmp_lt(level_v * 4) & grad_in.cmp_lt(level_v * 2));
+
+    let d = (q0 - p0) * 3;
+    let delta = ((d + ((d >> 15_i32) & i16x8::splat(7))) >> 3_i32)
+        .max(-level_v)
+        .min(level_v);
+    let simple_out = [
+        p1,
+        clamp_u8_lanes(p0 + delta),
+        clamp_u8_lanes(q0 - delta),
+        q1,
+    ];
+
+    let out = if simple {
+        simple_out
+    } else {
+        let a = (p1 - q1).max(i16x8::splat(-128)).min(i16x8::splat(127));
+        let delta = (((q0 - p0) * 3 + a + 4) >> 3_i32)
+            .max(-level_v)
+            .min(level_v);
+        let delta2 = (delta + 1) >> 1_i32;
+        let normal = [
+            clamp_u8_lanes(p1 + delta2),
+            clamp_u8_lanes(p0 + delta),
+            clamp_u8_lanes(q0 - delta),
+            clamp_u8_lanes(q1 - delta2),
+        ];
+
+        let sixth = |v: i16x8| i16x8::mul_keep_high(v + 3, i16x8::splat(10923));
+        let strong = [
+            sixth(p2 + p1 * 2 + p0 * 2 + q0),
+            sixth(p1 + p0 * 2 + q0 * 2 + q1),
+            sixth(p0 + q0 * 2 + q1 * 2 + q2),
+            sixth(q0 + q1 * 2 + q2 * 2 + q2),
+        ];
+
+        let flat = (p2 - p1)
+            .abs()
+            .max((p1 - p0).abs())
+            .max((q1 - q0).abs())
+            .max((q2 - q1).abs())
+            .cmp_lt(level_v);
+        let strong_flat = flat & boundary.cmp_lt(i16x8::splat((level / 2).max(1) as i16));
+        std::array::from_fn(|i| strong_flat.blend(strong[i], flat.blend(normal[i], simple_out[i])))
+    };
+
+    let original = [p1, p0, q0, q1];
+    std::array::from_fn(|i| filter.blend(out[i], original[i]).to_array())
+}
+
+#[cfg(feature = "simd")]
+fn residual_lanes(
+    predictor: PredictorType,
+    raw: &[u8],
+    left: &[u8],
+    up: &[u8],
+    up_left: &[u8],
+) -> i16x16 {
+    let raw = load_i16x16(raw);
+    let prediction = match predictor {
+        PredictorType::None => i16x16::splat(0),
+        PredictorType::Sub => load_i16x16(left),
+        PredictorType::Up => load_i16x16(up),
+        PredictorType::Average => (load_i16x16(left) + load_i16x16(up)) >> 1_i32,
+        PredictorType::Paeth => {
+            let (a, b, c) = (load_i16x16(left), load_i16x16(up), load_i16x16(up_left));
+            let pa = (b - c).abs();
+            let pb = (a - c).abs();
+            let pc = (a + b - c - c).abs();
+            let pick_a = !(pa.cmp_gt(pb) | pa.cmp_gt(pc));
+            let pick_b = !pb.cmp_gt(pc);
+            pick_a.blend(a, pick_b.blend(b, c))
+        }
+    };
+    (raw - prediction) & i16x16::splat(255)
+}
+
+// Filters bytes `channels..` of a row sixteen at a time and returns where the scalar tail
+// should resume.
+#[cfg(feature = "simd")]
+pub fn filter_row_lanes(
+    predictor: PredictorType,
+    row: &[u8],
+    prev: &[u8],
+    channels: usize,
+    out: &mut [u8],
+) -> usize {
+    let mut x = channels;
+    while x + 16 <= row.len() {
+        let residual = residual_lanes(
+            predictor,
+            &row[x..],
+            &row[x - channels..],
+            &prev[x..],
+            &prev[x - channels..],
+        );
+        for (dst, &v) in out[x..x + 16].iter_mut().zip(&residual.to_array()) {
+            *dst = v as u8;
+        }
+        x += 16;
+    }
+    x
+}
+
+#[cfg(feature = "simd")]
+pub fn row_cost_lanes(
+    predictor: PredictorType,
+    row: &[u8],
+    prev: &[u8],
+    channels: usize,
+) -> (usize, usize) {
+    let mut x = channels;
+    let mut cost = 0usize;
+    while x + 16 <= row.len() {
+        let residual = residual_lanes(
+            predictor,
+            &row[x..],
+            &row[x - channels..],
+            &prev[x..],
+            &prev[x - channels..],
+        );
+        let magnitude = residual.min(i16x16::splat(256) - residual);
+        cost += magnitude
+            .to_array()
+            .iter()
+            .map(|&v| v as usize)
+            .sum::<usize>();
+        x += 16;
+    }
+    (cost, x)
+}
+
+#[cfg(feature = "simd")]
+pub fn unfilter_up_lanes(delta: &[u8], prev: &[u8], out: &mut [u8]) -> usize {
+    let body = out.len() - out.len() % 16;
+    for x in (0..body).step_by(16) {
+        let v = load_u8x16(&delta[x..]) + load_u8x16(&prev[x..]);
+        out[x..x + 16].copy_from_slice(&v.to_array());
+    }
+    body
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::compression::multi_dct::{
+        int_dct_8x8, int_idct_8x8, int_idct_edges, int_idct_scaled,
+    };
+
+    fn random_blocks(count: usize, range: i32) -> impl Iterator<Item = [i16; 64]> {
+        let mut seed = 0x2545_f491u32;
+        (0..count).map(move |_| {
+            std::array::from_fn(|_| {
+                seed ^= seed << 13;
+                seed ^= seed >> 17;
+                seed ^= seed << 5;
+                ((seed % (2 * range as u32 + 1)) as i32 - range) as i16
+            })
+        })
+    }
+
+    #[test]
+    fn test_integer_idct_paths_bit_identical() {
+        for range in [32, 2048, 32767] {
+            for coeffs in random_blocks(10_000, range) {
+                let scalar = int_idct_8x8(&coeffs);
+                assert_eq!(int_idct_8x8_simd(&coeffs), scalar);
+
+                let (bottom, right) = int_idct_edges(&coeffs);
+                assert_eq!(bottom[..], scalar[56..]);
+                assert!((0..8).all(|y| right[y] == scalar[y * 8 + 7]));
+            }
+        }
+
+        let mut dc_only = [0i16; 64];
+        dc_only[0] = 800;
+        assert_eq!(int_idct_scaled(&dc_only, 1)[0], 100);
+        assert!(int_idct_scaled(&dc_only, 4)[..16].iter().all(|&v| v == 100));
+    }
+
+    #[test]
+    fn test_integer_transform_roundtrip() {
+        for block in random_blocks(5_000, 255) {
+            let restored = int_idct_8x8(&int_dct_8x8(&block));
+            assert!(block
+                .iter()
+                .zip(&restored)
+                .all(|(&a, &b)| (a - b).abs() <= 2));
+        }
+    }
+
+    #[test]
+    fn test_sum_abs_diff_matches_scalar() {
+        let mut seed = 0x9e37_79b9u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        };
+        for len in [0, 7, 16, 31, 64, 300, 4096] {
+            let a: Vec<u8> = (0..len).map(|_| next()).collect();
+            let b: Vec<u8> = (0..len).map(|_| next()).collect();
+            let expected: u64 = a.iter().zip(&b).map(|(&x, &y)| x.abs_diff(y) as u64).sum();
+            assert_eq!(sum_abs_diff(&a, &b), expected);
+        }
+        assert_eq!(sum_abs_diff(&[255; 4096], &[0; 4096]), 255 * 4096);
+    }
+
+    #[test]
+    fn test_block_sum_abs_diff_matches_scalar() {
+        let mut seed = 0x2545_f491u32;
+        let mut next = || {
+            seed ^= seed << 13;
+            seed ^= seed >> 17;
+            seed ^= seed << 5;
+            seed as u8
+        };
+        let stride = 80;
+        let a: Vec<u8> = (0..stride * 300).map(|_| next()).collect();
+        let b: Vec<u8> = (0..stride * 300).map(|_| next()).collect();
+        for (cols, rows) in [(16, 16), (8, 8), (32, 300), (13, 5), (64, 1)] {
+            let expected: u64 = (0..rows)
+                .flat_map(|y| (0..cols).map(move |x| y * stride + x))
+                .map(|i| a[i].abs_diff(b[i]) as u64)
+                .sum();
+            assert_eq!(block_sum_abs_diff(&a, &b, stride, cols, rows), expected);
+        }
+        let white = vec![255u8; stride * 300];
+        let black = vec![0u8; stride * 300];
+        assert_eq!(
+            block_sum_abs_diff(&white, &black, stride, 64, 300),
+            255 * 64 * 300
+        );
+    }
+
+    #[cfg(feature = "simd")]
+    #[test]
+    fn test_intra_lanes_match_scalar() {
+        use crate::compression::intra_prediction::{planar, true_motion};
+
+        for n in [8, 16, 32, 64] {
+            for block in random_blocks(200, 128) {
+                let edge = |offset: usize| -> Vec<u8> {
+                    (0..2 * n)
+                        .map(|i| (block[(i + offset) % 64] + 128) as u8)
+                        .collect()
+                };
+                let (top, left) = (edge(0), edge(17));
+                let top_left = block[63].clamp(0, 255) as u8;
+
+                let (mut scalar, mut lanes) = (vec![0u8; n * n], vec![0u8; n * n]);
+                planar(n, &top, &left, &mut scalar);
+                planar_lanes(n, &top, &left, &mut lanes);
+                assert_eq!(scalar, lanes);
+
+                true_motion(n, &top, &left, top_left, &mut scalar);
+                true_motion_lanes(n, &top, &left, top_left, &mut lanes);
+                assert_eq!(scalar, lanes);
+            }
+        }
+    }
+}
@@ -1,3 +1,32 @@
+#[derive(Debug, Clone, Copy)]
+pub struct BitModel {
+    prob: u16,
+}
+
+impl BitModel {
+    pub fn new() -> Self {
+        Self { prob: 2048 }
+    }
+
+    pub fn prob(&self) -> u32 {
+        (self.prob >> 4) as u32
+    }
+
+    pub fn update(&mut self, bit: bool) {
+        if bit {
+            self.prob -= self.prob >> 5;
+        } else {
+            self.prob += (4096 - self.prob) >> 5;
+        }
+    }
+}
+
+impl Default for BitModel {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
 pub struct RangeEncoder {
     low: u64,
     range: u64,
@@ -69,6 +98,11 @@ impl RangeEncoder {
         self.encode(bit, 128);
     }
 
+    pub fn encode_with_model(&mut self, bit: bool, model: &mut BitModel) {
+        self.encode(bit, model.prob());
+        model.update(bit);
+    }
+
     pub fn encode_value(&mut self, value: u32, bits: u8) {
         for i in (0..bits).rev() {
             self.encode_bit(((value >> i) & 1) != 0);
@@ -158,6 +192,12 @@ impl RangeDecoder {
         self.decode(128)
     }
 
+    pub fn decode_with_model(&mut self, model: &mut BitModel) -> bool {
+        let bit = self.decode(model.prob());
+        model.update(bit);
+        bit
+    }
+
     pub fn decode_value(&mut self, bits: u8) -> u32 {
         let mut v = 0u32;
         for _ in 0..bits {
@@ -244,6 +284,24 @@ mod tests {
         }
     }
 
+    #[test]
+    fn test_adaptive_model_roundtrip() {
+        let bits: Vec<bool> = (0..2000).map(|i| i % 17 == 0 || i % 5 == 1).collect();
+        let mut enc = RangeEncoder::new();
+        let mut model = BitModel::new();
+        for &bit in &bits {
+            enc.encode_with_model(bit, &mut model);
+        }
+        let data = enc.finish();
+        assert!(data.len() < bits.len() / 8);
+
+        let mut dec = RangeDecoder::new(data);
+        let mut model = BitModel::new();
+        for &bit in &bits {
+            assert_eq!(dec.decode_with_model(&mut model), bit);
+        }
+    }
+
     #[test]
     fn test_various_probs() {
         let mut enc = RangeEncoder::new();
@@ -1,10 +1,19 @@
+use crate::animation::{
+    Animation, AnimationFrame, BlendMode, Compositor, DisposeMode, WkAnimationDecoder,
+    WkAnimationEncoder,
+};
 use crate::decoder::{DecodedImage, WkDecoder};
 use crate::encoder::WkEncoder;
-use crate::error::WkResult;
+use crate::error::{WkError, WkResult};
+use crate::format::header::WkHeader;
+use crate::format::{ChunkReader, ChunkType};
 use crate::metadata::WkMetadata;
-use image::DynamicImage;
+use image::codecs::gif::{GifEncoder, Repeat};
+use image::codecs::webp::WebPDecoder;
+use image::metadata::LoopCount;
+use image::{AnimationDecoder, Delay, DynamicImage, Frame, ImageFormat, ImageReader};
 use std::fs::File;
-use std::io::{BufReader, BufWriter};
+use std::io::{BufRead, BufReader, BufWriter, Read, Seek, Write};
 use std::path::Path;
 
 pub struct WkConverter {
@@ -41,11 +50,38 @@ impl WkConverter {
         self
     }
 
+    // Animated GIF, APNG and WebP inputs become animated WK files.
     pub fn to_wk<P: AsRef<Path>, Q: AsRef<Path>>(&self, input: P, output: Q) -> WkResult<()> {
+        if let Some(animation) = read_animation(&input)? {
+            return self.animation_to_wk(&animation, output);
+        }
         let img = image::open(input)?;
         self.image_to_wk(&img, output)
     }
 
+    pub fn animation_to_wk<P: AsRef<Path>>(
+        &self,
+        animation: &Animation,
+        output: P,
+    ) -> WkResult<()> {
+        let file = File::create(output)?;
+        let writer = BufWriter::new(file);
+
+        let encoder = if self.lossless {
+            WkAnimationEncoder::lossless()
+        } else {
+            WkAnimationEncoder::lossy(self.quality)
+        };
+
+        let encoder = if let Some(ref meta) = self.metadata {
+            encoder.with_metadata(meta.clone())
+        } else {
+            encoder
+        };
+
+        encoder.encode(animation, writer)
+    }
+
     pub fn image_to_wk<P: AsRef<Path>>(&self, image: &DynamicImage, output: P) -> WkResult<()> {
         let file = File::create(output)?;
         let writer = BufWriter::new(file);
@@ -65,12 +101,52 @@ impl WkConverter {
         encoder.encode(image, writer)
     }
 
+    // Animated WK files written to .gif, .png or .apng keep every frame.
     pub fn from_wk<P: AsRef<Path>, Q: AsRef<Path>>(&self, input: P, output: Q) -> WkResult<()> {
+        let extension = output
+            .as_ref()
+            .extension()
+            .and_then(|e| e.to_str())
+            .map(str::to_ascii_lowercase);
+        if matches!(extension.as_deref(), Some("gif" | "png" | "apng")) {
+            if let Some(animation) = self.wk_to_animation(&input)? {
+                return match extension.as_deref() {
+                    Some("gif") => write_gif(&animation, output),
+                    _ => write_apng(&animation, output),
+                };
+            }
+        }
         let decoded = self.wk_to_image(input)?;
         decoded.image.save(output)?;
         Ok(())
     }
 
+    // Returns composited full-canvas frames, or None for a still image.
+    pub fn wk_to_animation<P: AsRef<Path>>(&self, input: P) -> WkResult<Option<Animation>> {
+        let file = File::open(input)?;
+        let chunks = ChunkReader::new(BufReader::new(file)).read_all_chunks()?;
+        let header_chunk = chunks
+            .iter()
+            .find(|c| c.chunk_type == ChunkType::ImageHeader)
+            .ok_or_else(|| WkError::MissingChunk("IHDR".into()))?;
+        if !WkHeader::decode(&header_chunk.data)?.has_animation {
+            return Ok(None);
+        }
+
+        let decoder = WkAnimationDecoder::from_chunks(chunks)?;
+        let mut animation = Animation::new().with_loop_count(decoder.loop_count());
+        animation.config.background_color = decoder.config().background_color;
+        for frame in decoder {
+            let frame = frame?;
+            let (width, height) = frame.image.dimensions();
+            animation.add_frame(
+                AnimationFrame::new(width, height, frame.image.into_raw())
+                    .with_delay(frame.delay_ms),
+            );
+        }
+        Ok(Some(animation))
+    }
+
     pub fn wk_to_image<P: AsRef<Path>>(&self, input: P) -> WkResult<DecodedImage> {
         let file = File::open(input)?;
         let reader = BufReader::new(file);
@@ -88,3 +164,256 @@ impl Default for WkConverter {
         Self::new()
     }
 }
+
+// Reads an animated GIF, APNG or WebP. GIF and APNG frames are read as the
+// stored sub-frames, keeping their offsets, disposal and blending. The WebP
+// decoder only hands out composited canvases, so WebP frames become
+// full-canvas frames and the source disposal is dropped. Returns None for
+// still images.
+pub fn read_animation<P: AsRef<Path>>(input: P) -> WkResult<Option<Animation>> {
+    let format = ImageReader::open(&input)?.with_guessed_format()?.format();
+    let reader = BufReader::new(File::open(&input)?);
+    match format {
+        Some(ImageFormat::Gif) => read_gif(reader),
+        Some(ImageFormat::Png) => read_apng(reader),
+        Some(ImageFormat::WebP) => {
+            let decoder = WebPDecoder::new(reader)?;
+            if !decoder.has_animation() {
+                return Ok(None);
+            }
+            collect_animation(decoder)
+        }
+        _ => Ok(None),
+    }
+}
+
+fn read_gif<R: Read>(reader: R) -> WkResult<Option<Animation>> {
+    let gif_error = |e: gif::DecodingError| WkError::DecodingError(e.to_string());
+    let mut options = gif::DecodeOptions::new();
+    options.set_color_output(gif::ColorOutput::RGBA);
+    let mut decoder = options.read_info(reader).map_err(gif_error)?;
+    let screen = (decoder.width() as u32, decoder.height() as u32);
+
+    let mut frames = Vec::new();
+    while let Some(frame) = decoder.read_next_frame().map_err(gif_error)? {
+        // Transparent pixels leave the canvas underneath showing.
+        let blend = match frame.transparent {
+            Some(_) => BlendMode::Over,
+            None => BlendMode::Source,
+        };
+        let dispose = match frame.dispose {
+            gif::DisposalMethod::Background => DisposeMode::Background,
+            gif::DisposalMethod::Previous => DisposeMode::Previous,
+            gif::DisposalMethod::Any | gif::DisposalMethod::Keep => DisposeMode::None,
+        };
+        frames.push(
+            AnimationFrame::new(
+                frame.width as u32,
+                frame.height as u32,
+                frame.buffer.to_vec(),
+            )
+            .with_offset(frame.left as u32, frame.top as u32)
+            .with_delay(frame.delay as u32 * 10)
+            .with_blend_mode(blend)
+            .with_dispose_mode(dispose),
+        );
+    }
+    // The loop extension is only certain to have been read once the frames are.
+    // It counts repetitions after the first play, and a GIF without one reads
+    // as `Finite(0)`: played once.
+    let loop_count = match decoder.repeat() {
+        gif::Repeat::Finite(n) => n as u32 + 1,
+        gif::Repeat::Infinite => 0,
+    };
+    assemble_animation(frames, screen, loop_count)
+}
+
+fn read_apng<R: BufRead + Seek>(reader: R) -> WkResult<Option<Animation>> {
+    let png_error = |e: png::DecodingError| WkError::DecodingError(e.to_string());
+    let mut decoder = png::Decoder::new(reader);
+    decoder.set_transformations(png::Transformations::normalize_to_color8());
+    let mut reader = decoder.read_info().map_err(png_error)?;
+    let Some(control) = reader.info().animation_control else {
+        return Ok(None);
+    };
+    let screen = (reader.info().width, reader.info().height);
+    let mut buffer =
+        vec![
+            0;
+            reader
+                .output_buffer_size()
+                .ok_or_else(|| WkError::DecodingError("APNG canvas too large".into()))?
+        ];
+    // Without an fcTL before it, IDAT is a still fallback outside the animation.
+    if reader.info().frame_control.is_none() {
+        reader.next_frame(&mut buffer).map_err(png_error)?;
+    }
+
+    let mut frames = Vec::new();
+    for _ in 0..control.num_frames {
+        let output = reader.next_frame(&mut buffer).map_err(png_error)?;
+        let control = reader
+            .info()
+            .frame_control
+            .ok_or_else(|| WkError::MissingChunk("fcTL".into()))?;
+        let pixels = rgba_pixels(&buffer[..output.buffer_size()], output.color_type);
+        let denom = match control.delay_den {
+            0 => 100,
+            d => d as u32,
+        };
+        let blend = match control.blend_op {
+            png::BlendOp::Source => BlendMode::Source,
+            png::BlendOp::Over => BlendMode::Over,
+        };
+        let dispose = match control.dispose_op {
+            png::DisposeOp::None => DisposeMode::None,
+            png::DisposeOp::Background => DisposeMode::Background,
+            png::DisposeOp::Previous => DisposeMode::Previous,
+        };
+        frames.push(
+            AnimationFrame::new(control.width, control.height, pixels)
+                .with_offset(control.x_offset, control.y_offset)
+                .with_delay((control.delay_num as u32 * 1000 + denom / 2) / denom)
+                .with_blend_mode(blend)
+                .with_dispose_mode(dispose),
+        );
+    }
+    assemble_animation(frames, screen, control.num_plays)
+}
+
+// PNG output after expansion to 8 bits: grey, grey + alpha, RGB or RGBA.
+fn rgba_pixels(data: &[u8], color_type: png::ColorType) -> Vec<u8> {
+    match color_type {
+        png::ColorType::Rgba => data.to_vec(),
+        png::ColorType::Rgb => data
+            .chunks_exact(3)
+            .flat_map(|p| [p[0], p[1], p[2], 255])
+            .collect(),
+        png::ColorType::GrayscaleAlpha => data
+            .chunks_exact(2)
+            .flat_map(|p| [p[0], p[0], p[0], p[1]])
+            .collect(),
+        png::ColorType::Grayscale | png::ColorType::Indexed => {
+            data.iter().flat_map(|&v| [v, v, v, 255]).collect()
+        }
+    }
+}
+
+// The canvas size comes from the frames, so a first frame smaller than the
+// screen is drawn onto a blank screen-sized frame. Disposing that frame
+// clears or restores the same blank pixels the smaller one would have.
+fn assemble_animation(
+    mut frames: Vec<AnimationFrame>,
+    (width, height): (u32, u32),
+    loop_count: u32,
+) -> WkResult<Option<Animation>> {
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+    let mut animation = Animation::new().with_loop_count(loop_count);
+    let first = &frames[0];
+    let covers_screen =
+        (first.x_offset, first.y_offset, first.width, first.height) == (0, 0, width, height);
+    let inside_screen = first
+        .extent()
+        .is_some_and(|(right, bottom)| right <= width && bottom <= height);
+    if !covers_screen && inside_screen {
+        let image =
+            Compositor::new(width, height, animation.config.background_color).compose(first)?;
+        frames[0] = AnimationFrame::new(width, height, image.into_raw())
+            .with_delay(first.delay_ms)
+            .with_dispose_mode(first.dispose_mode);
+    }
+    for (index, frame) in frames.into_iter().enumerate() {
+        if index == 0 {
+            animation.add_keyframe(frame);
+        } else {
+            animation.add_delta_frame(frame);
+        }
+    }
+    Ok(Some(animation))
+}
+
+// WebP frames arrive composited onto the full canvas, so their offsets are
+// zero and the source disposal has already been applied.
+fn collect_animation<'a, D: AnimationDecoder<'a>>(decoder: D) -> WkResult<Option<Animation>> {
+    let loop_count = match decoder.loop_count() {
+        LoopCount::Infinite => 0,
+        LoopCount::Finite(n) => n.get(),
+    };
+    let frames = decoder.into_frames().collect_frames()?;
+    if frames.len() < 2 {
+        return Ok(None);
+    }
+
+    let mut animation = Animation::new().with_loop_count(loop_count);
+    for (index, frame) in frames.into_iter().enumerate() {
+        let (numer, denom) = frame.delay().numer_denom_ms();
+        let (left, top) = (frame.left(), frame.top());
+        let buffer = frame.into_buffer();
+        let frame = AnimationFrame::new(buffer.width(), buffer.height(), buffer.into_raw())
+            .with_offset(left, top)
+            .with_delay((numer + denom / 2) / denom.max(1));
+        if index == 0 {
+            animation.add_keyframe(frame);
+        } else {
+            animation.add_delta_frame(frame);
+        }
+    }
+    Ok(Some(animation))
+}
+
+fn composited_frames(animation: &Animation) -> WkResult<Vec<(image::RgbaImage, u32)>> {
+    let mut compositor = Compositor::for_animation(animation)?;
+    animation
+        .frames
+        .iter()
+        .map(|frame| Ok((compositor.compose(frame)?, frame.delay_ms)))
+        .collect()
+}
+
+pub fn write_gif<P: AsRef<Path>>(animation: &Animation, output: P) -> WkResult<()> {
+    let frames = composited_frames(animation)?;
+    let mut encoder = GifEncoder::new(BufWriter::new(File::create(output)?));
+    // A single play is written without a loop extension.
+    match animation.config.loop_count {
+        0 => encoder.set_repeat(Repeat::Infinite)?,
+        1 => {}
+        n => encoder.set_repeat(Repeat::Finite((n - 1).min(u16::MAX as u32) as u16))?,
+    }
+    encoder.encode_frames(frames.into_iter().map(|(image, delay)| {
+        Frame::from_parts(image, 0, 0, Delay::from_numer_denom_ms(delay, 1))
+    }))?;
+    Ok(())
+}
+
+pub fn write_apng<P: AsRef<Path>>(animation: &Animation, output: P) -> WkResult<()> {
+    let png_error = |e: png::EncodingError| WkError::EncodingError(e.to_string());
+    let frames = composited_frames(animation)?;
+    let (width, height) = animation.canvas_size()?;
+    let mut writer = BufWriter::new(File::create(output)?);
+
+    let mut encoder = png::Encoder::new(&mut writer, width, height);
+    encoder.set_color(png::ColorType::Rgba);
+    encoder.set_depth(png::BitDepth::Eight);
+    encoder
+        .set_animated(frames.len() as u32, animation.config.loop_count)
+        .map_err(png_error)?;
+    let mut png_writer = encoder.write_header().map_err(png_error)?;
+    for (image, delay) in &frames {
+        let (numer, denom) = if *delay <= u16::MAX as u32 {
+            (*delay as u16, 1000)
+        } else {
+            ((*delay / 10).min(u16::MAX as u32) as u16, 100)
+        };
+        png_writer
+            .set_frame_delay(numer, denom)
+            .map_err(png_error)?;
+        png_writer
+            .write_image_data(image.as_raw())
+            .map_err(png_error)?;
+    }
+    png_writer.finish().map_err(png_error)?;
+    writer.flush()?;
+    Ok(())
+}
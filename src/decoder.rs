@@ -1,15 +1,148 @@
+use crate::animation::WkAnimationDecoder;
+use crate::compression::engine::{box_downscale, SegmentDecoder};
 use crate::compression::{CompressionConfig, CompressionEngine};
 use crate::error::{WkError, WkResult};
-use crate::format::header::{ColorType, WkHeader};
-use crate::format::{ChunkReader, ChunkType};
+use crate::format::gainmap::{sdr_to_linear, GainMap};
+use crate::format::gamut::{GamutConverter, GamutMapping};
+use crate::format::hdr::{expand_to_16bit, ColorGamut, HDRMetadata, TransferFunction};
+use crate::format::header::{ColorType, CompressionMode, WkHeader};
+use crate::format::progressive::{DamagedRegion, ScanPass, Tile, TileGrid};
+use crate::format::tonemap::{tone_map_to_8bit, ToneMapConfig};
+use crate::format::{Chunk, ChunkReader, ChunkType};
 use crate::metadata::{CustomMetadata, ExifData, IccProfile, WkMetadata, XmpData};
-use image::{DynamicImage, ImageBuffer, Luma, LumaA, Rgb, Rgba};
-use std::io::Read;
+use image::{DynamicImage, ImageBuffer, Luma, LumaA, Rgb, Rgb32FImage, Rgba, RgbaImage};
+use rayon::prelude::*;
+use std::io::{Read, Seek};
 
 pub struct DecodedImage {
     pub image: DynamicImage,
     pub metadata: WkMetadata,
     pub header: WkHeader,
+    pub gain_map: Option<GainMap>,
+}
+
+impl DecodedImage {
+    pub fn has_gain_map(&self) -> bool {
+        self.gain_map.is_some()
+    }
+
+    pub fn to_hdr(&self, display_headroom: f32) -> WkResult<Rgb32FImage> {
+        let sdr = self.image.to_rgb8();
+        match self.gain_map {
+            Some(ref gain_map) => gain_map.apply(&sdr, display_headroom),
+            None => Ok(sdr_to_linear(&sdr)),
+        }
+    }
+
+    pub fn is_hdr(&self) -> bool {
+        self.gain_map.is_some()
+            || self
+                .metadata
+                .hdr
+                .as_ref()
+                .is_some_and(|hdr| hdr.transfer != TransferFunction::SDR)
+    }
+
+    pub fn gamut(&self) -> ColorGamut {
+        self.metadata
+            .hdr
+            .as_ref()
+            .map(|hdr| hdr.gamut)
+            .or_else(|| {
+                self.metadata
+                    .icc_profile
+                    .as_ref()
+                    .and_then(|icc| ColorGamut::from_color_space(icc.color_space))
+            })
+            .unwrap_or(ColorGamut::SRGB)
+    }
+
+    pub fn to_sdr(&self, config: &ToneMapConfig) -> WkResult<DynamicImage> {
+        // A gain-map image already carries an authored SDR rendition as its base layer.
+        let hdr = match self.metadata.hdr {
+            Some(ref hdr) if self.gain_map.is_none() && hdr.transfer != TransferFunction::SDR => {
+                Some(hdr)
+            }
+            _ => None,
+        };
+        if hdr.is_none() && self.gamut() == ColorGamut::SRGB {
+            return Ok(self.image.clone());
+        }
+
+        let (color_type, mut raw) = match self.image {
+            DynamicImage::ImageLuma8(ref img) => (ColorType::Grayscale, img.as_raw().clone()),
+            DynamicImage::ImageLumaA8(ref img) => (ColorType::GrayscaleAlpha, img.as_raw().clone()),
+            DynamicImage::ImageRgba8(ref img) => (ColorType::Rgba, img.as_raw().clone()),
+            _ => (ColorType::Rgb, self.image.to_rgb8().into_raw()),
+        };
+        let channels = color_type.channels() as usize;
+
+        let data = match hdr {
+            Some(hdr) => {
+                let config = config.clone().with_metadata(hdr);
+                let samples = HDRMetadata {
+                    bit_depth: 16,
+                    ..hdr.clone()
+                };
+                tone_map_to_8bit(&expand_to_16bit(&raw, 8), channels, &samples, &config)
+            }
+            None => {
+                GamutConverter::new(self.gamut(), ColorGamut::SRGB, config.gamut_mapping)
+                    .convert_rgb8(&mut raw, channels);
+                raw
+            }
+        };
+
+        let header = WkHeader {
+            color_type,
+            ..self.header.clone()
+        };
+        WkDecoder::new().raw_to_image(&data, &header)
+    }
+
+    pub fn to_srgb(&self, mapping: GamutMapping) -> WkResult<DynamicImage> {
+        self.to_sdr(&ToneMapConfig::default().with_gamut_mapping(mapping))
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum DecodeScale {
+    #[default]
+    Full,
+    Half,
+    Quarter,
+    Eighth,
+}
+
+impl DecodeScale {
+    pub fn denominator(&self) -> u32 {
+        match self {
+            Self::Full => 1,
+            Self::Half => 2,
+            Self::Quarter => 4,
+            Self::Eighth => 8,
+        }
+    }
+
+    pub fn apply(&self, size: u32) -> u32 {
+        size.div_ceil(self.denominator())
+    }
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct DecodeOptions {
+    pub scale: DecodeScale,
+}
+
+impl DecodeOptions {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn with_scale(mut self, scale: DecodeScale) -> Self {
+        self.scale = scale;
+        self
+    }
 }
 
 pub struct WkDecoder;
@@ -20,6 +153,14 @@ impl WkDecoder {
     }
 
     pub fn decode<R: Read>(&self, reader: R) -> WkResult<DecodedImage> {
+        self.decode_with_options(reader, &DecodeOptions::default())
+    }
+
+    pub fn decode_with_options<R: Read>(
+        &self,
+        reader: R,
+        options: &DecodeOptions,
+    ) -> WkResult<DecodedImage> {
         let mut chunk_reader = ChunkReader::new(reader);
         let chunks = chunk_reader.read_all_chunks()?;
 
@@ -30,70 +171,433 @@ impl WkDecoder {
 
         let header = WkHeader::decode(&header_chunk.data)?;
 
-        let mut metadata = WkMetadata::new();
+        // Still-image readers of an animation get its first composited frame.
+        // Frames draw at arbitrary offsets onto the full canvas, so the canvas
+        // is box-filtered afterwards like a scaled lossless decode.
+        if header.has_animation {
+            let mut animation = WkAnimationDecoder::from_chunks(chunks)?;
+            let frame = animation
+                .next()
+                .ok_or_else(|| WkError::MissingChunk("FRMD".into()))??;
+            let scaled = scaled_header(&header, options.scale);
+            let rgba = box_downscale(
+                frame.image.into_raw(),
+                header.width as usize,
+                header.height as usize,
+                4,
+                options.scale.denominator() as usize,
+            );
+            let image = RgbaImage::from_raw(scaled.width, scaled.height, rgba)
+                .map(DynamicImage::ImageRgba8)
+                .ok_or_else(|| WkError::DecodingError("Frame size mismatch".into()))?;
+            return Ok(DecodedImage {
+                header: scaled,
+                image,
+                metadata: animation.metadata().clone(),
+                gain_map: None,
+            });
+        }
 
+        let mut metadata = WkMetadata::new();
+        let mut gain_map = None;
         for chunk in &chunks {
-            match chunk.chunk_type {
-                ChunkType::IccProfile => {
-                    if let Ok(icc) = bincode::deserialize::<IccProfile>(&chunk.data) {
-                        metadata.icc_profile = Some(icc);
-                    }
-                }
-                ChunkType::Exif => {
-                    if let Ok(exif) = bincode::deserialize::<ExifData>(&chunk.data) {
-                        metadata.exif = Some(exif);
-                    }
-                }
-                ChunkType::Xmp => {
-                    if let Ok(xmp) = bincode::deserialize::<XmpData>(&chunk.data) {
-                        metadata.xmp = Some(xmp);
-                    }
-                }
-                ChunkType::Custom => {
-                    if let Ok(custom) = bincode::deserialize::<CustomMetadata>(&chunk.data) {
-                        metadata.custom = custom;
-                    }
+            read_metadata_chunk(chunk, &mut metadata, &mut gain_map)?;
+        }
+
+        let raw_data = match chunks.iter().find(|c| c.chunk_type == ChunkType::TileIndex) {
+            Some(index) => {
+                let grid = TileGrid::decode_index(&index.data, header.width, header.height)?;
+                let tiles: Vec<&[u8]> = chunks
+                    .iter()
+                    .filter(|c| c.chunk_type == ChunkType::TileData)
+                    .map(|c| c.data.as_slice())
+                    .collect();
+                self.decode_tiled(&header, &grid, &tiles, options.scale)?
+            }
+            None => {
+                let data_chunk = chunks
+                    .iter()
+                    .find(|c| {
+                        matches!(
+                            c.chunk_type,
+                            ChunkType::ImageData | ChunkType::ImageDataLossy
+                        )
+                    })
+                    .ok_or_else(|| WkError::MissingChunk("IDAT/IDLS".into()))?;
+
+                let has_passes = chunks
+                    .iter()
+                    .any(|c| c.chunk_type == ChunkType::ImageDataProgressive);
+                if data_chunk.chunk_type == ChunkType::ImageDataLossy
+                    && !has_passes
+                    && CompressionEngine::new(CompressionConfig::default())
+                        .is_progressive_stream(&data_chunk.data)
+                {
+                    return Err(WkError::InvalidFormat(
+                        "Progressive stream is missing its IDPS passes".into(),
+                    ));
                 }
-                _ => {}
+
+                // At 1/8 scale only DC coefficients contribute, so refinement passes are skipped.
+                let passes: Vec<&[u8]> = chunks
+                    .iter()
+                    .filter(|c| c.chunk_type == ChunkType::ImageDataProgressive)
+                    .filter(|_| options.scale != DecodeScale::Eighth)
+                    .map(|c| c.data.as_slice())
+                    .collect();
+
+                self.decode_payload(&header, data_chunk, &passes, options.scale)?
             }
-        }
+        };
+        let header = scaled_header(&header, options.scale);
+        let image = self.raw_to_image(&raw_data, &header)?;
 
-        let data_chunk = chunks
-            .iter()
-            .find(|c| {
-                matches!(
-                    c.chunk_type,
-                    ChunkType::ImageData | ChunkType::ImageDataLossy
-                )
-            })
-            .ok_or_else(|| WkError::MissingChunk("IDAT/IDLS".into()))?;
+        Ok(DecodedImage {
+            image,
+            metadata,
+            header,
+            gain_map,
+        })
+    }
 
-        let is_lossy = matches!(data_chunk.chunk_type, ChunkType::ImageDataLossy);
+    fn decode_payload(
+        &self,
+        header: &WkHeader,
+        data_chunk: &Chunk,
+        passes: &[&[u8]],
+        scale: DecodeScale,
+    ) -> WkResult<Vec<u8>> {
+        let width = header.width as usize;
+        let height = header.height as usize;
+        let channels = header.color_type.channels() as usize;
 
-        let config = if is_lossy {
+        let config = if data_chunk.chunk_type == ChunkType::ImageDataLossy {
             CompressionConfig::lossy(header.quality)
         } else {
             CompressionConfig::lossless()
         };
+        let engine = CompressionEngine::new(config).with_decode_scale(scale.denominator() as usize);
+        // With no passes this renders the DC-only preview.
+        if data_chunk.chunk_type == ChunkType::ImageDataLossy
+            && engine.is_progressive_stream(&data_chunk.data)
+        {
+            engine.decompress_progressive(&data_chunk.data, passes, width, height, channels)
+        } else {
+            engine.decompress(
+                &data_chunk.data,
+                width,
+                height,
+                channels,
+                header.compression_mode,
+            )
+        }
+    }
 
-        let engine = CompressionEngine::new(config);
-        let raw_data = engine.decompress(
-            &data_chunk.data,
+    // Returns the image at `scale`. Tiles whose edges fall on the scaled
+    // pixel grid are each decoded at that scale; otherwise the full image
+    // is box-filtered down.
+    fn decode_tiled(
+        &self,
+        header: &WkHeader,
+        grid: &TileGrid,
+        tiles: &[&[u8]],
+        scale: DecodeScale,
+    ) -> WkResult<Vec<u8>> {
+        if tiles.len() != grid.tile_count() {
+            return Err(WkError::MissingChunk("TILE".into()));
+        }
+        let tiles: Vec<(&Tile, &[u8])> = grid.tiles.iter().zip(tiles.iter().copied()).collect();
+        let region = (0, 0, header.width, header.height);
+        let denominator = scale.denominator();
+        if grid.tile_width.is_multiple_of(denominator)
+            && grid.tile_height.is_multiple_of(denominator)
+        {
+            return self.decode_tiles(header, &tiles, region, scale);
+        }
+        let raw_data = self.decode_tiles(header, &tiles, region, DecodeScale::Full)?;
+        Ok(box_downscale(
+            raw_data,
             header.width as usize,
             header.height as usize,
             header.color_type.channels() as usize,
-            header.compression_mode,
-        )?;
+            denominator as usize,
+        ))
+    }
 
-        let image = self.raw_to_image(&raw_data, &header)?;
+    // Decodes the given tiles at `scale` and copies the parts overlapping
+    // `region` into a region-sized buffer. `region` and tile origins must be
+    // multiples of the scale denominator.
+    fn decode_tiles(
+        &self,
+        header: &WkHeader,
+        tiles: &[(&Tile, &[u8])],
+        region: (u32, u32, u32, u32),
+        scale: DecodeScale,
+    ) -> WkResult<Vec<u8>> {
+        let channels = header.color_type.channels() as usize;
+        let config = match header.compression_mode {
+            CompressionMode::Lossless => CompressionConfig::lossless(),
+            _ => CompressionConfig::lossy(header.quality),
+        };
+        let denominator = scale.denominator();
+        let engine = CompressionEngine::new(config).with_decode_scale(denominator as usize);
+        let decoded = tiles
+            .par_iter()
+            .map(|(tile, data)| {
+                let (w, h) = (tile.width as usize, tile.height as usize);
+                let pixels = engine.decompress(data, w, h, channels, header.compression_mode)?;
+                let (w, h) = (scale.apply(tile.width), scale.apply(tile.height));
+                if pixels.len() != w as usize * h as usize * channels {
+                    return Err(WkError::DecodingError("Tile size mismatch".into()));
+                }
+                let scaled = Tile {
+                    x: tile.x / denominator,
+                    y: tile.y / denominator,
+                    width: w,
+                    height: h,
+                    ..**tile
+                };
+                Ok((scaled, pixels))
+            })
+            .collect::<WkResult<Vec<_>>>()?;
+
+        let (x, y) = (region.0 / denominator, region.1 / denominator);
+        let (width, height) = (scale.apply(region.2), scale.apply(region.3));
+        let mut output = vec![0u8; width as usize * height as usize * channels];
+        for (tile, pixels) in &decoded {
+            let (x0, x1) = (tile.x.max(x), (tile.x + tile.width).min(x + width));
+            let (y0, y1) = (tile.y.max(y), (tile.y + tile.height).min(y + height));
+            if x0 >= x1 || y0 >= y1 {
+                continue;
+            }
+            let len = (x1 - x0) as usize * channels;
+            for py in y0..y1 {
+                let src = ((py - tile.y) as usize * tile.width as usize + (x0 - tile.x) as usize)
+                    * channels;
+                let dst = ((py - y) as usize * width as usize + (x0 - x) as usize) * channels;
+                output[dst..dst + len].copy_from_slice(&pixels[src..src + len]);
+            }
+        }
+        Ok(output)
+    }
+
+    pub fn decode_region<R: Read + Seek>(
+        &self,
+        mut reader: R,
+        x: u32,
+        y: u32,
+        width: u32,
+        height: u32,
+    ) -> WkResult<DecodedImage> {
+        let mut chunk_reader = ChunkReader::new(&mut reader);
+        let mut header = None;
+        let mut metadata = WkMetadata::new();
+        let mut gain_map = None;
+        let grid = loop {
+            let chunk = chunk_reader.read_chunk()?;
+            match chunk.chunk_type {
+                ChunkType::ImageHeader => header = Some(WkHeader::decode(&chunk.data)?),
+                ChunkType::TileIndex => {
+                    let header = header
+                        .as_ref()
+                        .ok_or_else(|| WkError::MissingChunk("IHDR".into()))?;
+                    break Some(TileGrid::decode_index(
+                        &chunk.data,
+                        header.width,
+                        header.height,
+                    )?);
+                }
+                ChunkType::ImageData | ChunkType::ImageDataLossy | ChunkType::End => break None,
+                _ => read_metadata_chunk(&chunk, &mut metadata, &mut gain_map)?,
+            }
+        };
+        let header = header.ok_or_else(|| WkError::MissingChunk("IHDR".into()))?;
+
+        let in_bounds = x
+            .checked_add(width)
+            .zip(y.checked_add(height))
+            .is_some_and(|(right, bottom)| right <= header.width && bottom <= header.height);
+        if width == 0 || height == 0 || !in_bounds {
+            return Err(WkError::InvalidFormat(format!(
+                "Region {}x{}+{}+{} is outside the {}x{} image",
+                width, height, x, y, header.width, header.height
+            )));
+        }
+        let region_header = WkHeader {
+            width,
+            height,
+            ..header.clone()
+        };
+
+        let Some(grid) = grid else {
+            chunk_reader.seek_to(0)?;
+            let decoded = self.decode(reader)?;
+            return Ok(DecodedImage {
+                image: decoded.image.crop_imm(x, y, width, height),
+                header: region_header,
+                ..decoded
+            });
+        };
 
+        let base = chunk_reader.position()?;
+        let bad_offset = || WkError::InvalidFormat("Tile offset out of range".into());
+        let mut tiles = Vec::new();
+        for index in grid.tiles_in_region(x, y, width, height) {
+            let tile = &grid.tiles[index];
+            chunk_reader.seek_to(base.checked_add(tile.data_offset).ok_or_else(bad_offset)?)?;
+            let chunk = chunk_reader.read_chunk()?;
+            if chunk.chunk_type != ChunkType::TileData {
+                return Err(WkError::InvalidChunk(format!(
+                    "Tile {} does not point at a TILE chunk",
+                    index
+                )));
+            }
+            tiles.push((tile, chunk.data));
+        }
+
+        // Metadata chunks such as the gain map may follow the tiles.
+        let mut tiles_end = 0u64;
+        for tile in &grid.tiles {
+            let end = tile
+                .data_offset
+                .checked_add(8 + tile.data_size as u64 + 4)
+                .ok_or_else(bad_offset)?;
+            tiles_end = tiles_end.max(end);
+        }
+        chunk_reader.seek_to(base.checked_add(tiles_end).ok_or_else(bad_offset)?)?;
+        loop {
+            let chunk = chunk_reader.read_chunk()?;
+            if chunk.chunk_type == ChunkType::End {
+                break;
+            }
+            read_metadata_chunk(&chunk, &mut metadata, &mut gain_map)?;
+        }
+
+        let tiles: Vec<(&Tile, &[u8])> = tiles
+            .iter()
+            .map(|(tile, data)| (*tile, data.as_slice()))
+            .collect();
+        let raw_data =
+            self.decode_tiles(&header, &tiles, (x, y, width, height), DecodeScale::Full)?;
+        let image = self.raw_to_image(&raw_data, &region_header)?;
         Ok(DecodedImage {
             image,
             metadata,
-            header,
+            header: region_header,
+            gain_map,
         })
     }
 
+    pub fn decode_resilient<R: Read>(
+        &self,
+        reader: R,
+    ) -> WkResult<(DecodedImage, Vec<DamagedRegion>)> {
+        let mut chunk_reader = ChunkReader::new(reader).with_strict(false);
+        let mut chunks = Vec::new();
+        loop {
+            match chunk_reader.read_chunk() {
+                Ok(chunk) if chunk.chunk_type == ChunkType::End => break,
+                Ok(chunk) => chunks.push(chunk),
+                Err(_) if !chunks.is_empty() => break,
+                Err(e) => return Err(e),
+            }
+        }
+
+        let header = chunks
+            .iter()
+            .find(|c| c.chunk_type == ChunkType::ImageHeader)
+            .ok_or_else(|| WkError::MissingChunk("IHDR".into()))
+            .and_then(|c| WkHeader::decode(&c.data))?;
+
+        let mut metadata = WkMetadata::new();
+        let mut gain_map = None;
+        for chunk in chunks.iter().filter(|c| c.verify_crc()) {
+            read_metadata_chunk(chunk, &mut metadata, &mut gain_map)?;
+        }
+
+        let (width, height) = (header.width as usize, header.height as usize);
+        let channels = header.color_type.channels() as usize;
+        let whole = vec![DamagedRegion {
+            x: 0,
+            y: 0,
+            width: header.width,
+            height: header.height,
+        }];
+
+        let data_chunk = chunks.iter().find(|c| {
+            matches!(
+                c.chunk_type,
+                ChunkType::ImageData | ChunkType::ImageDataLossy
+            )
+        });
+        let engine = CompressionEngine::new(CompressionConfig::lossy(header.quality));
+        let concealed = || (vec![128; width * height * channels], whole.clone());
+        let (raw_data, damaged) = match data_chunk {
+            Some(chunk)
+                if chunk.chunk_type == ChunkType::ImageDataLossy
+                    && engine.has_resync_segments(&chunk.data) =>
+            {
+                engine
+                    .decompress_resilient(&chunk.data, width, height, channels)
+                    .unwrap_or_else(|_| concealed())
+            }
+            Some(chunk) => {
+                let passes: Vec<&[u8]> = chunks
+                    .iter()
+                    .filter(|c| c.chunk_type == ChunkType::ImageDataProgressive && c.verify_crc())
+                    .map(|c| c.data.as_slice())
+                    .collect();
+                match self.decode_payload(&header, chunk, &passes, DecodeScale::Full) {
+                    Ok(raw) if chunk.verify_crc() => (raw, Vec::new()),
+                    Ok(raw) => (raw, whole.clone()),
+                    Err(_) => concealed(),
+                }
+            }
+            None => concealed(),
+        };
+
+        let image = self.raw_to_image(&raw_data, &header)?;
+        Ok((
+            DecodedImage {
+                image,
+                metadata,
+                header,
+                gain_map,
+            },
+            damaged,
+        ))
+    }
+
+    pub fn decode_progressive<R: Read>(&self, reader: R) -> ProgressiveDecoder<R> {
+        ProgressiveDecoder {
+            reader: ChunkReader::new(reader),
+            state: DecodeState::default(),
+            finished: false,
+        }
+    }
+
+    pub fn decode_hdr<R: Read>(&self, reader: R, display_headroom: f32) -> WkResult<Rgb32FImage> {
+        self.decode(reader)?.to_hdr(display_headroom)
+    }
+
+    pub fn decode_sdr<R: Read>(&self, reader: R, config: &ToneMapConfig) -> WkResult<DecodedImage> {
+        let mut decoded = self.decode(reader)?;
+        decoded.image = decoded.to_sdr(config)?;
+        decoded.metadata.hdr = None;
+        decoded.gain_map = None;
+        if decoded.metadata.icc_profile.is_some() {
+            decoded.metadata.icc_profile = Some(IccProfile::srgb());
+        }
+        Ok(decoded)
+    }
+
+    pub fn decode_srgb<R: Read>(&self, reader: R, mapping: GamutMapping) -> WkResult<DecodedImage> {
+        self.decode_sdr(
+            reader,
+            &ToneMapConfig::default().with_gamut_mapping(mapping),
+        )
+    }
+
     fn raw_to_image(&self, data: &[u8], header: &WkHeader) -> WkResult<DynamicImage> {
         let w = header.width;
         let h = header.height;
@@ -141,6 +645,235 @@ impl WkDecoder {
     }
 }
 
+fn scaled_header(header: &WkHeader, scale: DecodeScale) -> WkHeader {
+    WkHeader {
+        width: scale.apply(header.width),
+        height: scale.apply(header.height),
+        ..header.clone()
+    }
+}
+
+pub(crate) fn read_metadata_chunk(
+    chunk: &Chunk,
+    metadata: &mut WkMetadata,
+    gain_map: &mut Option<GainMap>,
+) -> WkResult<()> {
+    match chunk.chunk_type {
+        ChunkType::IccProfile => {
+            if let Ok(icc) = bincode::deserialize::<IccProfile>(&chunk.data) {
+                metadata.icc_profile = Some(icc);
+            }
+        }
+        ChunkType::Exif => {
+            if let Ok(exif) = bincode::deserialize::<ExifData>(&chunk.data) {
+                metadata.exif = Some(exif);
+            }
+        }
+        ChunkType::Xmp => {
+            if let Ok(xmp) = bincode::deserialize::<XmpData>(&chunk.data) {
+                metadata.xmp = Some(xmp);
+            }
+        }
+        ChunkType::Custom => {
+            if let Ok(custom) = bincode::deserialize::<CustomMetadata>(&chunk.data) {
+                metadata.custom = custom;
+            }
+        }
+        ChunkType::HdrMetadata => {
+            if let Ok(hdr) = bincode::deserialize::<HDRMetadata>(&chunk.data) {
+                metadata.hdr = Some(hdr);
+            }
+        }
+        ChunkType::GainMap => {
+            *gain_map = Some(GainMap::decode(&chunk.data)?);
+        }
+        _ => {}
+    }
+    Ok(())
+}
+
+#[derive(Default)]
+pub(crate) struct DecodeState {
+    pub(crate) header: Option<WkHeader>,
+    pub(crate) metadata: WkMetadata,
+    pub(crate) gain_map: Option<GainMap>,
+    data_chunk: Option<Chunk>,
+    passes: Vec<Vec<u8>>,
+    tile_grid: Option<TileGrid>,
+    tiles: Vec<Vec<u8>>,
+}
+
+impl DecodeState {
+    // Returns the scan pass that became decodable with this chunk, if any.
+    pub(crate) fn accept(&mut self, chunk: Chunk) -> WkResult<Option<ScanPass>> {
+        match chunk.chunk_type {
+            ChunkType::ImageHeader => self.header = Some(WkHeader::decode(&chunk.data)?),
+            ChunkType::ImageData => {
+                self.data_chunk = Some(chunk);
+                return Ok(Some(ScanPass::All));
+            }
+            ChunkType::ImageDataLossy => {
+                let engine = CompressionEngine::new(CompressionConfig::default());
+                let pass = if engine.is_progressive_stream(&chunk.data) {
+                    ScanPass::DC
+                } else {
+                    ScanPass::All
+                };
+                self.data_chunk = Some(chunk);
+                return Ok(Some(pass));
+            }
+            ChunkType::ImageDataProgressive => {
+                let pass = chunk
+                    .data
+                    .first()
+                    .and_then(|&id| ScanPass::from_u8(id))
+                    .ok_or_else(|| WkError::DecodingError("Unknown scan pass".into()))?;
+                self.passes.push(chunk.data);
+                return Ok(Some(pass));
+            }
+            ChunkType::TileIndex => {
+                let header = self
+                    .header
+                    .as_ref()
+                    .ok_or_else(|| WkError::MissingChunk("IHDR".into()))?;
+                self.tile_grid = Some(TileGrid::decode_index(
+                    &chunk.data,
+                    header.width,
+                    header.height,
+                )?);
+            }
+            ChunkType::TileData => {
+                self.tiles.push(chunk.data);
+                let complete = self
+                    .tile_grid
+                    .as_ref()
+                    .is_some_and(|grid| grid.tile_count() == self.tiles.len());
+                return Ok(complete.then_some(ScanPass::All));
+            }
+            _ => read_metadata_chunk(&chunk, &mut self.metadata, &mut self.gain_map)?,
+        }
+        Ok(None)
+    }
+
+    pub(crate) fn render(&self) -> WkResult<DecodedImage> {
+        let header = self
+            .header
+            .clone()
+            .ok_or_else(|| WkError::MissingChunk("IHDR".into()))?;
+
+        let decoder = WkDecoder::new();
+        let raw_data = match self.tile_grid {
+            Some(ref grid) => {
+                let tiles: Vec<&[u8]> = self.tiles.iter().map(Vec::as_slice).collect();
+                decoder.decode_tiled(&header, grid, &tiles, DecodeScale::Full)?
+            }
+            None => {
+                let data_chunk = self
+                    .data_chunk
+                    .as_ref()
+                    .ok_or_else(|| WkError::MissingChunk("IDAT/IDLS".into()))?;
+                let passes: Vec<&[u8]> = self.passes.iter().map(Vec::as_slice).collect();
+                decoder.decode_payload(&header, data_chunk, &passes, DecodeScale::Full)?
+            }
+        };
+        let image = decoder.raw_to_image(&raw_data, &header)?;
+        Ok(DecodedImage {
+            image,
+            metadata: self.metadata.clone(),
+            header,
+            gain_map: self.gain_map.clone(),
+        })
+    }
+
+    // Renders a lossy data chunk that has only partly arrived. Streams split
+    // into resync segments decode segment by segment into `segments`, which
+    // the caller keeps while the chunk arrives; other streams need the whole
+    // chunk. Returns how many rows from the top are final, with the image,
+    // when that grew.
+    pub(crate) fn render_partial(
+        &self,
+        segments: &mut Option<SegmentDecoder>,
+        data: &[u8],
+    ) -> WkResult<Option<(u32, DecodedImage)>> {
+        let Some(ref header) = self.header else {
+            return Ok(None);
+        };
+        let engine = CompressionEngine::new(CompressionConfig::lossy(header.quality));
+        if self.tile_grid.is_some()
+            || !engine.has_resync_segments(data)
+            || engine.is_progressive_stream(data)
+        {
+            return Ok(None);
+        }
+        let decoder = segments.get_or_insert_with(|| {
+            SegmentDecoder::new(
+                header.width as usize,
+                header.height as usize,
+                header.color_type.channels() as usize,
+            )
+        });
+        let before = decoder.final_rows();
+        let Ok(rows) = engine.decode_new_segments(decoder, data) else {
+            return Ok(None);
+        };
+        if rows <= before {
+            return Ok(None);
+        }
+        let image = WkDecoder::new().raw_to_image(decoder.pixels(), header)?;
+        Ok(Some((
+            rows as u32,
+            DecodedImage {
+                image,
+                metadata: self.metadata.clone(),
+                header: header.clone(),
+                gain_map: self.gain_map.clone(),
+            },
+        )))
+    }
+}
+
+pub struct ProgressiveDecoder<R: Read> {
+    reader: ChunkReader<R>,
+    state: DecodeState,
+    finished: bool,
+}
+
+impl<R: Read> ProgressiveDecoder<R> {
+    fn next_pass(&mut self) -> WkResult<Option<ScanPass>> {
+        loop {
+            let chunk = self.reader.read_chunk()?;
+            if chunk.chunk_type == ChunkType::End {
+                return Ok(None);
+            }
+            if let Some(pass) = self.state.accept(chunk)? {
+                return Ok(Some(pass));
+            }
+        }
+    }
+}
+
+impl<R: Read> Iterator for ProgressiveDecoder<R> {
+    type Item = WkResult<(ScanPass, DecodedImage)>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.finished {
+            return None;
+        }
+        let result = match self.next_pass() {
+            Ok(Some(pass)) => self.state.render().map(|decoded| (pass, decoded)),
+            Ok(None) => {
+                self.finished = true;
+                return None;
+            }
+            Err(e) => Err(e),
+        };
+        if result.is_err() {
+            self.finished = true;
+        }
+        Some(result)
+    }
+}
+
 impl Default for WkDecoder {
     fn default() -> Self {
         Self::new()
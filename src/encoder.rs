@@ -1,14 +1,20 @@
-use crate::compression::{CompressionConfig, CompressionEngine};
-use crate::error::WkResult;
+use crate::compression::color::{ColorMatrix, ColorRange};
+use crate::compression::{CompressionConfig, CompressionEngine, LosslessEngine, MAX_EFFORT};
+use crate::error::{WkError, WkResult};
+use crate::format::gainmap::{GainMap, GainMapConfig};
+use crate::format::hdr::ColorGamut;
 use crate::format::header::{ColorType, CompressionMode, WkHeader};
-use crate::format::{Chunk, ChunkType, ChunkWriter};
+use crate::format::{Chunk, ChunkType, ChunkWriter, TileGrid};
 use crate::metadata::WkMetadata;
-use image::DynamicImage;
+use image::{DynamicImage, Rgb32FImage};
+use rayon::prelude::*;
 use std::io::Write;
 
 pub struct WkEncoder {
     config: CompressionConfig,
     metadata: WkMetadata,
+    gain_map_config: GainMapConfig,
+    tile_size: Option<u32>,
 }
 
 impl WkEncoder {
@@ -16,6 +22,8 @@ impl WkEncoder {
         Self {
             config: CompressionConfig::default(),
             metadata: WkMetadata::new(),
+            gain_map_config: GainMapConfig::default(),
+            tile_size: None,
         }
     }
 
@@ -23,6 +31,8 @@ impl WkEncoder {
         Self {
             config: CompressionConfig::lossless(),
             metadata: WkMetadata::new(),
+            gain_map_config: GainMapConfig::default(),
+            tile_size: None,
         }
     }
 
@@ -30,6 +40,8 @@ impl WkEncoder {
         Self {
             config: CompressionConfig::lossy(quality),
             metadata: WkMetadata::new(),
+            gain_map_config: GainMapConfig::default(),
+            tile_size: None,
         }
     }
 
@@ -51,6 +63,60 @@ impl WkEncoder {
         self
     }
 
+    pub fn with_color_matrix(mut self, matrix: ColorMatrix) -> Self {
+        self.config.color_matrix = Some(matrix);
+        self
+    }
+
+    pub fn with_color_range(mut self, range: ColorRange) -> Self {
+        self.config.color_range = range;
+        self
+    }
+
+    pub fn with_lossless_engine(mut self, engine: LosslessEngine) -> Self {
+        self.config.lossless_engine = Some(engine);
+        self
+    }
+
+    pub fn with_effort(mut self, effort: u8) -> Self {
+        self.config.effort = effort.min(MAX_EFFORT);
+        self
+    }
+
+    pub fn with_progressive(mut self, progressive: bool) -> Self {
+        self.config.progressive = progressive;
+        self
+    }
+
+    pub fn with_resync_interval(mut self, block_rows: u16) -> Self {
+        self.config.resync_rows = block_rows;
+        self
+    }
+
+    pub fn with_tile_size(mut self, tile_size: u32) -> Self {
+        self.tile_size = (tile_size > 0).then_some(tile_size.max(16));
+        self
+    }
+
+    pub fn with_gain_map_config(mut self, config: GainMapConfig) -> Self {
+        self.gain_map_config = config;
+        self
+    }
+
+    fn resolved_config(&self) -> CompressionConfig {
+        let mut config = self.config.clone();
+        if config.color_matrix.is_none() {
+            let gamut = self.metadata.hdr.as_ref().map(|hdr| hdr.gamut).or_else(|| {
+                self.metadata
+                    .icc_profile
+                    .as_ref()
+                    .and_then(|icc| ColorGamut::from_color_space(icc.color_space))
+            });
+            config.color_matrix = Some(ColorMatrix::for_gamut(gamut.unwrap_or(ColorGamut::SRGB)));
+        }
+        config
+    }
+
     fn image_to_raw(image: &DynamicImage) -> (ColorType, Vec<u8>) {
         match image {
             DynamicImage::ImageLuma8(img) => (ColorType::Grayscale, img.as_raw().clone()),
@@ -65,6 +131,26 @@ impl WkEncoder {
     }
 
     pub fn encode<W: Write>(&self, image: &DynamicImage, writer: W) -> WkResult<()> {
+        self.encode_with_chunks(image, Vec::new(), writer)
+    }
+
+    pub fn encode_with_gain_map<W: Write>(
+        &self,
+        sdr: &DynamicImage,
+        hdr: &Rgb32FImage,
+        writer: W,
+    ) -> WkResult<()> {
+        let gain_map = GainMap::compute(&sdr.to_rgb8(), hdr, &self.gain_map_config)?;
+        let chunk = Chunk::new(ChunkType::GainMap, gain_map.encode()?);
+        self.encode_with_chunks(sdr, vec![chunk], writer)
+    }
+
+    fn encode_with_chunks<W: Write>(
+        &self,
+        image: &DynamicImage,
+        trailing_chunks: Vec<Chunk>,
+        writer: W,
+    ) -> WkResult<()> {
         let width = image.width();
         let height = image.height();
         let (color_type, raw_data) = Self::image_to_raw(image);
@@ -80,58 +166,114 @@ impl WkEncoder {
             bit_depth: 8,
         };
 
-        let engine = CompressionEngine::new(self.config.clone());
-        let compressed = engine.compress(
-            &raw_data,
-            width as usize,
-            height as usize,
-            color_type.channels() as usize,
-        )?;
+        let engine = CompressionEngine::new(self.resolved_config());
+        let channels = color_type.channels() as usize;
+        let data_chunks = match self.tile_size {
+            // Each tile is its own stream, so resync segments restart per
+            // tile; progressive passes have no place in the tile layout.
+            Some(_) if self.config.progressive && self.config.mode != CompressionMode::Lossless => {
+                return Err(WkError::UnsupportedFeature(
+                    "Progressive coding cannot be combined with tiles".into(),
+                ))
+            }
+            Some(tile_size) => {
+                Self::encode_tiles(&engine, &raw_data, width, height, channels, tile_size)?
+            }
+            None => self.encode_payload(&engine, &raw_data, width, height, channels)?,
+        };
 
         let mut chunk_writer = ChunkWriter::new(writer);
 
         let header_chunk = Chunk::new(ChunkType::ImageHeader, header.encode());
         chunk_writer.write_chunk(&header_chunk)?;
 
-        if let Some(ref icc) = self.metadata.icc_profile {
-            let icc_data = bincode::serialize(icc)
-                .map_err(|e| crate::error::WkError::MetadataError(e.to_string()))?;
-            let icc_chunk = Chunk::new(ChunkType::IccProfile, icc_data);
-            chunk_writer.write_chunk(&icc_chunk)?;
+        for chunk in metadata_chunks(&self.metadata)? {
+            chunk_writer.write_chunk(&chunk)?;
         }
 
-        if let Some(ref exif) = self.metadata.exif {
-            let exif_data = bincode::serialize(exif)
-                .map_err(|e| crate::error::WkError::MetadataError(e.to_string()))?;
-            let exif_chunk = Chunk::new(ChunkType::Exif, exif_data);
-            chunk_writer.write_chunk(&exif_chunk)?;
+        for chunk in &data_chunks {
+            chunk_writer.write_chunk(chunk)?;
         }
 
-        if let Some(ref xmp) = self.metadata.xmp {
-            let xmp_data = bincode::serialize(xmp)
-                .map_err(|e| crate::error::WkError::MetadataError(e.to_string()))?;
-            let xmp_chunk = Chunk::new(ChunkType::Xmp, xmp_data);
-            chunk_writer.write_chunk(&xmp_chunk)?;
+        for chunk in &trailing_chunks {
+            chunk_writer.write_chunk(chunk)?;
         }
 
-        let custom_data = self.metadata.custom.clone();
-        if !custom_data.fields.is_empty() || custom_data.author.is_some() {
-            let custom_bytes = bincode::serialize(&custom_data)
-                .map_err(|e| crate::error::WkError::MetadataError(e.to_string()))?;
-            let custom_chunk = Chunk::new(ChunkType::Custom, custom_bytes);
-            chunk_writer.write_chunk(&custom_chunk)?;
+        chunk_writer.finish()?;
+
+        Ok(())
+    }
+
+    fn encode_payload(
+        &self,
+        engine: &CompressionEngine,
+        raw_data: &[u8],
+        width: u32,
+        height: u32,
+        channels: usize,
+    ) -> WkResult<Vec<Chunk>> {
+        let (width, height) = (width as usize, height as usize);
+        if self.config.progressive && self.config.mode != CompressionMode::Lossless {
+            let mut payloads = engine.compress_progressive(raw_data, width, height, channels)?;
+            let passes = payloads.split_off(1);
+            let mut chunks = vec![Chunk::new(ChunkType::ImageDataLossy, payloads.remove(0))];
+            chunks.extend(
+                passes
+                    .into_iter()
+                    .map(|pass| Chunk::new(ChunkType::ImageDataProgressive, pass)),
+            );
+            return Ok(chunks);
         }
 
         let data_type = match self.config.mode {
             CompressionMode::Lossless => ChunkType::ImageData,
             _ => ChunkType::ImageDataLossy,
         };
-        let data_chunk = Chunk::new(data_type, compressed);
-        chunk_writer.write_chunk(&data_chunk)?;
+        let compressed = engine.compress(raw_data, width, height, channels)?;
+        Ok(vec![Chunk::new(data_type, compressed)])
+    }
 
-        chunk_writer.finish()?;
+    // Tiles are coded independently; the index records each TILE chunk's offset from the end of TIDX.
+    fn encode_tiles(
+        engine: &CompressionEngine,
+        raw_data: &[u8],
+        width: u32,
+        height: u32,
+        channels: usize,
+        tile_size: u32,
+    ) -> WkResult<Vec<Chunk>> {
+        let mut grid = TileGrid::new(width, height, tile_size);
+        let stride = width as usize * channels;
+        let tile_chunks = grid
+            .tiles
+            .par_iter()
+            .map(|tile| {
+                let row_len = tile.width as usize * channels;
+                let mut pixels = Vec::with_capacity(row_len * tile.height as usize);
+                for y in tile.y..tile.y + tile.height {
+                    let start = y as usize * stride + tile.x as usize * channels;
+                    pixels.extend_from_slice(&raw_data[start..start + row_len]);
+                }
+                let compressed = engine.compress(
+                    &pixels,
+                    tile.width as usize,
+                    tile.height as usize,
+                    channels,
+                )?;
+                Ok(Chunk::new(ChunkType::TileData, compressed))
+            })
+            .collect::<WkResult<Vec<_>>>()?;
 
-        Ok(())
+        let mut offset = 0;
+        for (tile, chunk) in grid.tiles.iter_mut().zip(&tile_chunks) {
+            tile.data_offset = offset;
+            tile.data_size = chunk.data.len() as u32;
+            offset += chunk.encoded_len() as u64;
+        }
+
+        let mut chunks = vec![Chunk::new(ChunkType::TileIndex, grid.encode_index())];
+        chunks.extend(tile_chunks);
+        Ok(chunks)
     }
 
     pub fn encode_to_vec(&self, image: &DynamicImage) -> WkResult<Vec<u8>> {
@@ -141,6 +283,32 @@ impl WkEncoder {
     }
 }
 
+pub(crate) fn metadata_chunks(metadata: &WkMetadata) -> WkResult<Vec<Chunk>> {
+    let serialize = |chunk_type, value: Result<Vec<u8>, bincode::Error>| {
+        value
+            .map(|data| Chunk::new(chunk_type, data))
+            .map_err(|e| WkError::MetadataError(e.to_string()))
+    };
+    let mut chunks = Vec::new();
+    if let Some(ref icc) = metadata.icc_profile {
+        chunks.push(serialize(ChunkType::IccProfile, bincode::serialize(icc))?);
+    }
+    if let Some(ref exif) = metadata.exif {
+        chunks.push(serialize(ChunkType::Exif, bincode::serialize(exif))?);
+    }
+    if let Some(ref xmp) = metadata.xmp {
+        chunks.push(serialize(ChunkType::Xmp, bincode::serialize(xmp))?);
+    }
+    if let Some(ref hdr) = metadata.hdr {
+        chunks.push(serialize(ChunkType::HdrMetadata, bincode::serialize(hdr))?);
+    }
+    let custom = &metadata.custom;
+    if !custom.fields.is_empty() || custom.author.is_some() {
+        chunks.push(serialize(ChunkType::Custom, bincode::serialize(custom))?);
+    }
+    Ok(chunks)
+}
+
 impl Default for WkEncoder {
     fn default() -> Self {
         Self::new()
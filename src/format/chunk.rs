@@ -1,6 +1,6 @@
 use crate::error::{WkError, WkResult};
 use byteorder::{LittleEndian, ReadBytesExt, WriteBytesExt};
-use std::io::{Read, Write};
+use std::io::{Read, Seek, SeekFrom, Write};
 
 pub const WK_MAGIC: &[u8; 8] = b"WK3.0\x00\x00\x00";
 
@@ -13,9 +13,15 @@ pub enum ChunkType {
     Xmp = 0x04,
     Thumbnail = 0x05,
     Animation = 0x06,
+    GainMap = 0x07,
+    HdrMetadata = 0x08,
     ImageData = 0x10,
     ImageDataLossy = 0x11,
     FrameData = 0x12,
+    ImageDataProgressive = 0x13,
+    TileIndex = 0x14,
+    TileData = 0x15,
+    FrameIndex = 0x16,
     Custom = 0xFE,
     End = 0xFF,
 }
@@ -29,9 +35,15 @@ impl ChunkType {
             0x04 => Ok(Self::Xmp),
             0x05 => Ok(Self::Thumbnail),
             0x06 => Ok(Self::Animation),
+            0x07 => Ok(Self::GainMap),
+            0x08 => Ok(Self::HdrMetadata),
             0x10 => Ok(Self::ImageData),
             0x11 => Ok(Self::ImageDataLossy),
             0x12 => Ok(Self::FrameData),
+            0x13 => Ok(Self::ImageDataProgressive),
+            0x14 => Ok(Self::TileIndex),
+            0x15 => Ok(Self::TileData),
+            0x16 => Ok(Self::FrameIndex),
             0xFE => Ok(Self::Custom),
             0xFF => Ok(Self::End),
             _ => Err(WkError::InvalidChunk(format!(
@@ -49,9 +61,15 @@ impl ChunkType {
             Self::Xmp => *b"XMP\x00",
             Self::Thumbnail => *b"THUM",
             Self::Animation => *b"ANIM",
+            Self::GainMap => *b"GMAP",
+            Self::HdrMetadata => *b"HDRM",
             Self::ImageData => *b"IDAT",
             Self::ImageDataLossy => *b"IDLS",
             Self::FrameData => *b"FRMD",
+            Self::ImageDataProgressive => *b"IDPS",
+            Self::TileIndex => *b"TIDX",
+            Self::TileData => *b"TILE",
+            Self::FrameIndex => *b"FIDX",
             Self::Custom => *b"CUST",
             Self::End => *b"IEND",
         }
@@ -65,9 +83,15 @@ impl ChunkType {
             b"XMP\x00" => Ok(Self::Xmp),
             b"THUM" => Ok(Self::Thumbnail),
             b"ANIM" => Ok(Self::Animation),
+            b"GMAP" => Ok(Self::GainMap),
+            b"HDRM" => Ok(Self::HdrMetadata),
             b"IDAT" => Ok(Self::ImageData),
             b"IDLS" => Ok(Self::ImageDataLossy),
             b"FRMD" => Ok(Self::FrameData),
+            b"IDPS" => Ok(Self::ImageDataProgressive),
+            b"TIDX" => Ok(Self::TileIndex),
+            b"TILE" => Ok(Self::TileData),
+            b"FIDX" => Ok(Self::FrameIndex),
             b"CUST" => Ok(Self::Custom),
             b"IEND" => Ok(Self::End),
             _ => Err(WkError::InvalidChunk(format!(
@@ -76,6 +100,11 @@ impl ChunkType {
             ))),
         }
     }
+
+    // As in PNG, a lowercase first letter marks a chunk readers may ignore.
+    pub fn is_ancillary(bytes: &[u8; 4]) -> bool {
+        bytes[0].is_ascii_lowercase()
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -96,21 +125,72 @@ impl Chunk {
     }
 
     fn compute_crc(chunk_type: &ChunkType, data: &[u8]) -> u32 {
-        let mut hasher = crc32fast::Hasher::new();
-        hasher.update(&chunk_type.as_bytes());
-        hasher.update(data);
-        hasher.finalize()
+        raw_crc(&chunk_type.as_bytes(), data)
+    }
+
+    pub fn encoded_len(&self) -> usize {
+        8 + self.data.len() + 4
     }
 
     pub fn verify_crc(&self) -> bool {
         let computed = Self::compute_crc(&self.chunk_type, &self.data);
         computed == self.crc
     }
+
+    // Returns `None` until a whole chunk is buffered; unknown ancillary chunks are consumed without a value.
+    pub fn parse(data: &[u8]) -> WkResult<Option<(Option<Chunk>, usize)>> {
+        if data.len() < 8 {
+            return Ok(None);
+        }
+        let size = u32::from_le_bytes([data[4], data[5], data[6], data[7]]) as usize;
+        let total = 8 + size + 4;
+        if data.len() < total {
+            return Ok(None);
+        }
+
+        let type_bytes = [data[0], data[1], data[2], data[3]];
+        let crc_bytes = &data[8 + size..total];
+        let crc = u32::from_le_bytes([crc_bytes[0], crc_bytes[1], crc_bytes[2], crc_bytes[3]]);
+        let chunk_type = match ChunkType::from_bytes(&type_bytes) {
+            Ok(chunk_type) => chunk_type,
+            Err(e) if !ChunkType::is_ancillary(&type_bytes) => return Err(e),
+            Err(_) => {
+                let actual = raw_crc(&type_bytes, &data[8..8 + size]);
+                if actual != crc {
+                    return Err(WkError::CrcMismatch {
+                        expected: crc,
+                        actual,
+                    });
+                }
+                return Ok(Some((None, total)));
+            }
+        };
+        let chunk = Chunk {
+            chunk_type,
+            data: data[8..8 + size].to_vec(),
+            crc,
+        };
+        if !chunk.verify_crc() {
+            return Err(WkError::CrcMismatch {
+                expected: chunk.crc,
+                actual: Chunk::compute_crc(&chunk.chunk_type, &chunk.data),
+            });
+        }
+        Ok(Some((Some(chunk), total)))
+    }
+}
+
+fn raw_crc(type_bytes: &[u8; 4], data: &[u8]) -> u32 {
+    let mut hasher = crc32fast::Hasher::new();
+    hasher.update(type_bytes);
+    hasher.update(data);
+    hasher.finalize()
 }
 
 pub struct ChunkReader<R: Read> {
     reader: R,
     magic_verified: bool,
+    strict: bool,
 }
 
 impl<R: Read> ChunkReader<R> {
@@ -118,9 +198,16 @@ impl<R: Read> ChunkReader<R> {
         Self {
             reader,
             magic_verified: false,
+            strict: true,
         }
     }
 
+    // Lenient readers keep chunks with bad CRCs and return whatever a truncated chunk holds.
+    pub fn with_strict(mut self, strict: bool) -> Self {
+        self.strict = strict;
+        self
+    }
+
     pub fn verify_magic(&mut self) -> WkResult<()> {
         let mut magic = [0u8; 8];
         self.reader.read_exact(&mut magic)?;
@@ -138,11 +225,35 @@ impl<R: Read> ChunkReader<R> {
             self.verify_magic()?;
         }
 
-        let mut type_bytes = [0u8; 4];
-        self.reader.read_exact(&mut type_bytes)?;
-        let chunk_type = ChunkType::from_bytes(&type_bytes)?;
+        let (chunk_type, size) = loop {
+            let mut type_bytes = [0u8; 4];
+            self.reader.read_exact(&mut type_bytes)?;
+            let size = self.reader.read_u32::<LittleEndian>()? as usize;
+            match ChunkType::from_bytes(&type_bytes) {
+                Ok(chunk_type) => break (chunk_type, size),
+                // Ancillary chunks from newer writers are skipped so this reader
+                // still opens newer files; an unknown critical chunk is an error.
+                Err(e) if !ChunkType::is_ancillary(&type_bytes) => return Err(e),
+                Err(_) => self.skip_chunk(&type_bytes, size as u64)?,
+            }
+        };
 
-        let size = self.reader.read_u32::<LittleEndian>()? as usize;
+        if !self.strict {
+            let mut data = Vec::new();
+            (&mut self.reader)
+                .take(size as u64)
+                .read_to_end(&mut data)?;
+            let crc = if data.len() == size {
+                self.reader.read_u32::<LittleEndian>().unwrap_or(0)
+            } else {
+                0
+            };
+            return Ok(Chunk {
+                chunk_type,
+                data,
+                crc,
+            });
+        }
 
         let mut data = vec![0u8; size];
         if size > 0 {
@@ -167,6 +278,29 @@ impl<R: Read> ChunkReader<R> {
         Ok(chunk)
     }
 
+    fn skip_chunk(&mut self, type_bytes: &[u8; 4], size: u64) -> WkResult<()> {
+        let mut hasher = crc32fast::Hasher::new();
+        hasher.update(type_bytes);
+        let mut buf = [0u8; 8192];
+        let mut remaining = size;
+        while remaining > 0 {
+            let want = remaining.min(buf.len() as u64) as usize;
+            let n = self.reader.read(&mut buf[..want])?;
+            if n == 0 {
+                return Err(WkError::Io(std::io::ErrorKind::UnexpectedEof.into()));
+            }
+            hasher.update(&buf[..n]);
+            remaining -= n as u64;
+        }
+
+        let expected = self.reader.read_u32::<LittleEndian>()?;
+        let actual = hasher.finalize();
+        if self.strict && actual != expected {
+            return Err(WkError::CrcMismatch { expected, actual });
+        }
+        Ok(())
+    }
+
     pub fn read_all_chunks(&mut self) -> WkResult<Vec<Chunk>> {
         let mut chunks = Vec::new();
         loop {
@@ -181,6 +315,17 @@ impl<R: Read> ChunkReader<R> {
     }
 }
 
+impl<R: Read + Seek> ChunkReader<R> {
+    pub fn position(&mut self) -> WkResult<u64> {
+        Ok(self.reader.stream_position()?)
+    }
+
+    pub fn seek_to(&mut self, position: u64) -> WkResult<()> {
+        self.reader.seek(SeekFrom::Start(position))?;
+        Ok(())
+    }
+}
+
 pub struct ChunkWriter<W: Write> {
     writer: W,
     magic_written: bool,
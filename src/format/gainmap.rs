@@ -0,0 +1,390 @@
+use super::hdr::srgb_eotf;
+use crate::compression::{CompressionConfig, CompressionEngine};
+use crate::error::{WkError, WkResult};
+use crate::format::header::CompressionMode;
+use image::{Rgb, Rgb32FImage, RgbImage};
+use serde::{Deserialize, Serialize};
+
+#[derive(Debug, Clone)]
+pub struct GainMapConfig {
+    pub scale: u32,
+    pub quality: u8,
+    pub multichannel: bool,
+    pub gamma: f32,
+    pub offset_sdr: f32,
+    pub offset_hdr: f32,
+}
+
+impl Default for GainMapConfig {
+    fn default() -> Self {
+        Self {
+            scale: 4,
+            quality: 85,
+            multichannel: false,
+            gamma: 1.0,
+            offset_sdr: 1.0 / 64.0,
+            offset_hdr: 1.0 / 64.0,
+        }
+    }
+}
+
+impl GainMapConfig {
+    pub fn with_scale(mut self, scale: u32) -> Self {
+        self.scale = scale.max(1);
+        self
+    }
+
+    pub fn with_quality(mut self, quality: u8) -> Self {
+        self.quality = quality.clamp(1, 100);
+        self
+    }
+
+    pub fn with_multichannel(mut self, multichannel: bool) -> Self {
+        self.multichannel = multichannel;
+        self
+    }
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct GainMapMetadata {
+    pub width: u32,
+    pub height: u32,
+    pub channels: u8,
+    pub quality: u8,
+    pub min_log2_boost: f32,
+    pub max_log2_boost: f32,
+    pub gamma: f32,
+    pub offset_sdr: f32,
+    pub offset_hdr: f32,
+    pub hdr_capacity_min: f32,
+    pub hdr_capacity_max: f32,
+}
+
+impl GainMapMetadata {
+    pub fn min_boost(&self) -> f32 {
+        self.min_log2_boost.exp2()
+    }
+
+    pub fn max_boost(&self) -> f32 {
+        self.max_log2_boost.exp2()
+    }
+
+    pub fn weight(&self, display_headroom: f32) -> f32 {
+        let headroom = display_headroom.max(1.0).log2();
+        let span = self.hdr_capacity_max - self.hdr_capacity_min;
+        if span <= f32::EPSILON {
+            return if headroom >= self.hdr_capacity_max {
+                1.0
+            } else {
+                0.0
+            };
+        }
+        ((headroom - self.hdr_capacity_min) / span).clamp(0.0, 1.0)
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct GainMap {
+    pub metadata: GainMapMetadata,
+    pub data: Vec<u8>,
+}
+
+fn luminance(rgb: [f32; 3]) -> f32 {
+    0.2126 * rgb[0] + 0.7152 * rgb[1] + 0.0722 * rgb[2]
+}
+
+impl GainMap {
+    pub fn compute(sdr: &RgbImage, hdr: &Rgb32FImage, config: &GainMapConfig) -> WkResult<Self> {
+        if sdr.dimensions() != hdr.dimensions() {
+            return Err(WkError::EncodingError(format!(
+                "Gain map needs matching renditions: SDR is {}x{}, HDR is {}x{}",
+                sdr.width(),
+                sdr.height(),
+                hdr.width(),
+                hdr.height()
+            )));
+        }
+
+        let (width, height) = sdr.dimensions();
+        let scale = config.scale.max(1);
+        let gw = width.div_ceil(scale).max(1);
+        let gh = height.div_ceil(scale).max(1);
+        let channels = if config.multichannel { 3 } else { 1 };
+        let linear: Vec<f32> = (0..256).map(|v| srgb_eotf(v as f32 / 255.0)).collect();
+
+        let mut log_gain = vec![0.0f32; (gw * gh) as usize * channels];
+        for gy in 0..gh {
+            for gx in 0..gw {
+                let mut sum = [0.0f32; 3];
+                let mut count = 0u32;
+                for y in gy * scale..((gy + 1) * scale).min(height) {
+                    for x in gx * scale..((gx + 1) * scale).min(width) {
+                        let s = sdr.get_pixel(x, y).0;
+                        let h = hdr.get_pixel(x, y).0;
+                        let s = [
+                            linear[s[0] as usize],
+                            linear[s[1] as usize],
+                            linear[s[2] as usize],
+                        ];
+                        let h = [h[0].max(0.0), h[1].max(0.0), h[2].max(0.0)];
+                        if channels == 1 {
+                            sum[0] += ((luminance(h) + config.offset_hdr)
+                                / (luminance(s) + config.offset_sdr))
+                                .log2();
+                        } else {
+                            for c in 0..3 {
+                                sum[c] += ((h[c] + config.offset_hdr) / (s[c] + config.offset_sdr))
+                                    .log2();
+                            }
+                        }
+                        count += 1;
+                    }
+                }
+                let idx = (gy * gw + gx) as usize * channels;
+                for c in 0..channels {
+                    log_gain[idx + c] = sum[c] / count.max(1) as f32;
+                }
+            }
+        }
+
+        let min_log2 = log_gain.iter().copied().fold(f32::INFINITY, f32::min);
+        let max_log2 = log_gain.iter().copied().fold(f32::NEG_INFINITY, f32::max);
+        let span = (max_log2 - min_log2).max(f32::EPSILON);
+        let gamma = if config.gamma > 0.0 {
+            config.gamma
+        } else {
+            1.0
+        };
+
+        let data = log_gain
+            .iter()
+            .map(|&l| {
+                let norm = ((l - min_log2) / span).clamp(0.0, 1.0).powf(1.0 / gamma);
+                (norm * 255.0).round() as u8
+            })
+            .collect();
+
+        Ok(Self {
+            metadata: GainMapMetadata {
+                width: gw,
+                height: gh,
+                channels: channels as u8,
+                quality: config.quality.clamp(1, 100),
+                min_log2_boost: min_log2,
+                max_log2_boost: max_log2,
+                gamma,
+                offset_sdr: config.offset_sdr,
+                offset_hdr: config.offset_hdr,
+                hdr_capacity_min: 0.0,
+                hdr_capacity_max: max_log2.max(0.0),
+            },
+            data,
+        })
+    }
+
+    fn engine_config(quality: u8) -> CompressionConfig {
+        if quality >= 100 {
+            CompressionConfig::lossless()
+        } else {
+            CompressionConfig::lossy(quality)
+        }
+    }
+
+    pub fn encode(&self) -> WkResult<Vec<u8>> {
+        let meta = &self.metadata;
+        let meta_bytes =
+            bincode::serialize(meta).map_err(|e| WkError::MetadataError(e.to_string()))?;
+        let engine = CompressionEngine::new(Self::engine_config(meta.quality));
+        let compressed = engine.compress(
+            &self.data,
+            meta.width as usize,
+            meta.height as usize,
+            meta.channels as usize,
+        )?;
+
+        let mut output = Vec::with_capacity(4 + meta_bytes.len() + compressed.len());
+        output.extend(&(meta_bytes.len() as u32).to_le_bytes());
+        output.extend(&meta_bytes);
+        output.extend(compressed);
+        Ok(output)
+    }
+
+    pub fn decode(data: &[u8]) -> WkResult<Self> {
+        if data.len() < 4 {
+            return Err(WkError::DecodingError("Gain map chunk too short".into()));
+        }
+        let meta_len = u32::from_le_bytes([data[0], data[1], data[2], data[3]]) as usize;
+        if data.len() < 4 + meta_len {
+            return Err(WkError::DecodingError("Truncated gain map metadata".into()));
+        }
+        let metadata: GainMapMetadata = bincode::deserialize(&data[4..4 + meta_len])
+            .map_err(|e| WkError::MetadataError(e.to_string()))?;
+        if metadata.channels != 1 && metadata.channels != 3 {
+            return Err(WkError::DecodingError(format!(
+                "Unsupported gain map channel count: {}",
+                metadata.channels
+            )));
+        }
+        if metadata.width == 0 || metadata.height == 0 {
+            return Err(WkError::DecodingError("Empty gain map".into()));
+        }
+
+        let mode = if metadata.quality >= 100 {
+            CompressionMode::Lossless
+        } else {
+            CompressionMode::Lossy
+        };
+        let engine = CompressionEngine::new(Self::engine_config(metadata.quality));
+        let plane = engine.decompress(
+            &data[4 + meta_len..],
+            metadata.width as usize,
+            metadata.height as usize,
+            metadata.channels as usize,
+            mode,
+        )?;
+
+        Ok(Self {
+            metadata,
+            data: plane,
+        })
+    }
+
+    fn sample(&self, fx: f32, fy: f32, channel: usize) -> f32 {
+        let meta = &self.metadata;
+        let (w, h, ch) = (
+            meta.width as usize,
+            meta.height as usize,
+            meta.channels as usize,
+        );
+        let fx = fx.clamp(0.0, (w - 1) as f32);
+        let fy = fy.clamp(0.0, (h - 1) as f32);
+        let x0 = fx.floor() as usize;
+        let y0 = fy.floor() as usize;
+        let x1 = (x0 + 1).min(w - 1);
+        let y1 = (y0 + 1).min(h - 1);
+        let tx = fx - x0 as f32;
+        let ty = fy - y0 as f32;
+        let at = |x: usize, y: usize| self.data[(y * w + x) * ch + channel] as f32;
+        let top = at(x0, y0) * (1.0 - tx) + at(x1, y0) * tx;
+        let bottom = at(x0, y1) * (1.0 - tx) + at(x1, y1) * tx;
+        top * (1.0 - ty) + bottom * ty
+    }
+
+    pub fn apply(&self, sdr: &RgbImage, display_headroom: f32) -> WkResult<Rgb32FImage> {
+        let meta = &self.metadata;
+        let plane_len = (meta.width as usize)
+            .checked_mul(meta.height as usize)
+            .and_then(|n| n.checked_mul(meta.channels as usize))
+            .filter(|&n| n > 0)
+            .ok_or_else(|| WkError::DecodingError("Invalid gain map dimensions".into()))?;
+        if self.data.len() < plane_len {
+            return Err(WkError::DecodingError("Gain map plane too small".into()));
+        }
+
+        let (width, height) = sdr.dimensions();
+        let weight = meta.weight(display_headroom);
+        let linear: Vec<f32> = (0..256).map(|v| srgb_eotf(v as f32 / 255.0)).collect();
+        let sx = meta.width as f32 / width as f32;
+        let sy = meta.height as f32 / height as f32;
+
+        let log_boost = |stored: f32| {
+            let norm = (stored / 255.0).powf(meta.gamma);
+            meta.min_log2_boost + (meta.max_log2_boost - meta.min_log2_boost) * norm
+        };
+
+        Ok(Rgb32FImage::from_fn(width, height, |x, y| {
+            let s = sdr.get_pixel(x, y).0;
+            let gx = (x as f32 + 0.5) * sx - 0.5;
+            let gy = (y as f32 + 0.5) * sy - 0.5;
+            let mut out = [0.0f32; 3];
+            for c in 0..3 {
+                let gc = if meta.channels == 3 { c } else { 0 };
+                let gain = (log_boost(self.sample(gx, gy, gc)) * weight).exp2();
+                out[c] =
+                    ((linear[s[c] as usize] + meta.offset_sdr) * gain - meta.offset_hdr).max(0.0);
+            }
+            Rgb(out)
+        }))
+    }
+}
+
+pub fn sdr_to_linear(sdr: &RgbImage) -> Rgb32FImage {
+    let linear: Vec<f32> = (0..256).map(|v| srgb_eotf(v as f32 / 255.0)).collect();
+    Rgb32FImage::from_fn(sdr.width(), sdr.height(), |x, y| {
+        let p = sdr.get_pixel(x, y).0;
+        Rgb([
+            linear[p[0] as usize],
+            linear[p[1] as usize],
+            linear[p[2] as usize],
+        ])
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn renditions() -> (RgbImage, Rgb32FImage) {
+        let sdr = RgbImage::from_fn(32, 32, |x, _| {
+            let v = (x * 8).min(255) as u8;
+            Rgb([v, v, v])
+        });
+        let hdr = Rgb32FImage::from_fn(32, 32, |x, _| {
+            let base = srgb_eotf((x * 8).min(255) as f32 / 255.0);
+            let boost = if x >= 16 { 4.0 } else { 1.0 };
+            Rgb([base * boost, base * boost, base * boost])
+        });
+        (sdr, hdr)
+    }
+
+    #[test]
+    fn test_gain_map_reconstructs_hdr() {
+        let (sdr, hdr) = renditions();
+        let config = GainMapConfig::default().with_scale(1).with_quality(100);
+        let gain_map = GainMap::compute(&sdr, &hdr, &config).unwrap();
+        assert!(gain_map.metadata.max_boost() > 3.5);
+
+        let decoded = GainMap::decode(&gain_map.encode().unwrap()).unwrap();
+        let out = decoded.apply(&sdr, decoded.metadata.max_boost()).unwrap();
+        for x in [4u32, 12, 20, 28] {
+            let expected = hdr.get_pixel(x, 8).0[0];
+            let got = out.get_pixel(x, 8).0[0];
+            assert!(
+                (got - expected).abs() <= expected * 0.1 + 0.01,
+                "x={} expected {} got {}",
+                x,
+                expected,
+                got
+            );
+        }
+    }
+
+    #[test]
+    fn test_sdr_display_ignores_gain_map() {
+        let (sdr, hdr) = renditions();
+        let gain_map = GainMap::compute(&sdr, &hdr, &GainMapConfig::default()).unwrap();
+        assert_eq!(gain_map.metadata.weight(1.0), 0.0);
+
+        let out = gain_map.apply(&sdr, 1.0).unwrap();
+        let base = sdr_to_linear(&sdr);
+        for (a, b) in out.pixels().zip(base.pixels()) {
+            assert!((a.0[1] - b.0[1]).abs() < 1e-4);
+        }
+    }
+
+    #[test]
+    fn test_rejects_empty_or_oversized_gain_maps() {
+        let (sdr, hdr) = renditions();
+        let mut gain_map = GainMap::compute(&sdr, &hdr, &GainMapConfig::default()).unwrap();
+        gain_map.metadata.width = 0;
+        let meta = bincode::serialize(&gain_map.metadata).unwrap();
+        let mut chunk = (meta.len() as u32).to_le_bytes().to_vec();
+        chunk.extend(meta);
+        assert!(GainMap::decode(&chunk).is_err());
+        assert!(gain_map.apply(&sdr, 4.0).is_err());
+
+        gain_map.metadata.width = u32::MAX;
+        gain_map.metadata.height = u32::MAX;
+        assert!(gain_map.apply(&sdr, 4.0).is_err());
+    }
+}
@@ -0,0 +1,367 @@
+use super::hdr::{srgb_eotf, srgb_oetf, ColorGamut};
+use crate::metadata::icc::ColorSpace;
+
+pub type Matrix3 = [[f32; 3]; 3];
+
+const D65: (f32, f32) = (0.3127, 0.3290);
+const D50: (f32, f32) = (0.3457, 0.3585);
+
+const BRADFORD: Matrix3 = [
+    [0.8951, 0.2664, -0.1614],
+    [-0.7502, 1.7135, 0.0367],
+    [0.0389, -0.0685, 1.0296],
+];
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum GamutMapping {
+    Clip,
+    Perceptual,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Primaries {
+    pub red: (f32, f32),
+    pub green: (f32, f32),
+    pub blue: (f32, f32),
+    pub white: (f32, f32),
+}
+
+impl ColorGamut {
+    pub fn primaries(&self) -> Primaries {
+        match self {
+            Self::SRGB => Primaries {
+                red: (0.640, 0.330),
+                green: (0.300, 0.600),
+                blue: (0.150, 0.060),
+                white: D65,
+            },
+            Self::AdobeRGB => Primaries {
+                red: (0.640, 0.330),
+                green: (0.210, 0.710),
+                blue: (0.150, 0.060),
+                white: D65,
+            },
+            Self::DisplayP3 => Primaries {
+                red: (0.680, 0.320),
+                green: (0.265, 0.690),
+                blue: (0.150, 0.060),
+                white: D65,
+            },
+            Self::Rec2020 => Primaries {
+                red: (0.708, 0.292),
+                green: (0.170, 0.797),
+                blue: (0.131, 0.046),
+                white: D65,
+            },
+            Self::ProPhotoRGB => Primaries {
+                red: (0.7347, 0.2653),
+                green: (0.1596, 0.8404),
+                blue: (0.0366, 0.0001),
+                white: D50,
+            },
+        }
+    }
+
+    pub fn from_color_space(space: ColorSpace) -> Option<Self> {
+        match space {
+            ColorSpace::SRGB | ColorSpace::Rec709 => Some(Self::SRGB),
+            ColorSpace::AdobeRGB => Some(Self::AdobeRGB),
+            ColorSpace::DisplayP3 => Some(Self::DisplayP3),
+            ColorSpace::Rec2020 => Some(Self::Rec2020),
+            ColorSpace::ProPhotoRGB => Some(Self::ProPhotoRGB),
+            _ => None,
+        }
+    }
+
+    pub fn linearize(&self, v: f32) -> f32 {
+        let v = v.clamp(0.0, 1.0);
+        match self {
+            Self::SRGB | Self::DisplayP3 => srgb_eotf(v),
+            Self::AdobeRGB => v.powf(563.0 / 256.0),
+            Self::Rec2020 => {
+                if v < 0.081 {
+                    v / 4.5
+                } else {
+                    ((v + 0.099) / 1.099).powf(1.0 / 0.45)
+                }
+            }
+            Self::ProPhotoRGB => {
+                if v < 16.0 / 512.0 {
+                    v / 16.0
+                } else {
+                    v.powf(1.8)
+                }
+            }
+        }
+    }
+
+    pub fn encode_linear(&self, l: f32) -> f32 {
+        let l = l.clamp(0.0, 1.0);
+        match self {
+            Self::SRGB | Self::DisplayP3 => srgb_oetf(l),
+            Self::AdobeRGB => l.powf(256.0 / 563.0),
+            Self::Rec2020 => {
+                if l < 0.018 {
+                    l * 4.5
+                } else {
+                    1.099 * l.powf(0.45) - 0.099
+                }
+            }
+            Self::ProPhotoRGB => {
+                if l < 1.0 / 512.0 {
+                    l * 16.0
+                } else {
+                    l.powf(1.0 / 1.8)
+                }
+            }
+        }
+    }
+
+    pub fn luma_weights(&self) -> [f32; 3] {
+        rgb_to_xyz_matrix(&self.primaries())[1]
+    }
+}
+
+fn xy_to_xyz(xy: (f32, f32)) -> [f32; 3] {
+    [xy.0 / xy.1, 1.0, (1.0 - xy.0 - xy.1) / xy.1]
+}
+
+pub fn multiply(a: &Matrix3, b: &Matrix3) -> Matrix3 {
+    let mut out = [[0.0; 3]; 3];
+    for i in 0..3 {
+        for j in 0..3 {
+            out[i][j] = a[i][0] * b[0][j] + a[i][1] * b[1][j] + a[i][2] * b[2][j];
+        }
+    }
+    out
+}
+
+pub fn invert(m: &Matrix3) -> Matrix3 {
+    let det = m[0][0] * (m[1][1] * m[2][2] - m[1][2] * m[2][1])
+        - m[0][1] * (m[1][0] * m[2][2] - m[1][2] * m[2][0])
+        + m[0][2] * (m[1][0] * m[2][1] - m[1][1] * m[2][0]);
+    let inv_det = 1.0 / det;
+    [
+        [
+            (m[1][1] * m[2][2] - m[1][2] * m[2][1]) * inv_det,
+            (m[0][2] * m[2][1] - m[0][1] * m[2][2]) * inv_det,
+            (m[0][1] * m[1][2] - m[0][2] * m[1][1]) * inv_det,
+        ],
+        [
+            (m[1][2] * m[2][0] - m[1][0] * m[2][2]) * inv_det,
+            (m[0][0] * m[2][2] - m[0][2] * m[2][0]) * inv_det,
+            (m[0][2] * m[1][0] - m[0][0] * m[1][2]) * inv_det,
+        ],
+        [
+            (m[1][0] * m[2][1] - m[1][1] * m[2][0]) * inv_det,
+            (m[0][1] * m[2][0] - m[0][0] * m[2][1]) * inv_det,
+            (m[0][0] * m[1][1] - m[0][1] * m[1][0]) * inv_det,
+        ],
+    ]
+}
+
+pub fn apply(m: &Matrix3, v: [f32; 3]) -> [f32; 3] {
+    [
+        m[0][0] * v[0] + m[0][1] * v[1] + m[0][2] * v[2],
+        m[1][0] * v[0] + m[1][1] * v[1] + m[1][2] * v[2],
+        m[2][0] * v[0] + m[2][1] * v[1] + m[2][2] * v[2],
+    ]
+}
+
+pub fn rgb_to_xyz_matrix(primaries: &Primaries) -> Matrix3 {
+    let r = xy_to_xyz(primaries.red);
+    let g = xy_to_xyz(primaries.green);
+    let b = xy_to_xyz(primaries.blue);
+    let m = [[r[0], g[0], b[0]], [r[1], g[1], b[1]], [r[2], g[2], b[2]]];
+    let s = apply(&invert(&m), xy_to_xyz(primaries.white));
+    [
+        [m[0][0] * s[0], m[0][1] * s[1], m[0][2] * s[2]],
+        [m[1][0] * s[0], m[1][1] * s[1], m[1][2] * s[2]],
+        [m[2][0] * s[0], m[2][1] * s[1], m[2][2] * s[2]],
+    ]
+}
+
+pub fn bradford_adaptation(from_white: (f32, f32), to_white: (f32, f32)) -> Matrix3 {
+    let src = apply(&BRADFORD, xy_to_xyz(from_white));
+    let dst = apply(&BRADFORD, xy_to_xyz(to_white));
+    let scale = [
+        [dst[0] / src[0], 0.0, 0.0],
+        [0.0, dst[1] / src[1], 0.0],
+        [0.0, 0.0, dst[2] / src[2]],
+    ];
+    multiply(&invert(&BRADFORD), &multiply(&scale, &BRADFORD))
+}
+
+pub fn conversion_matrix(from: ColorGamut, to: ColorGamut) -> Matrix3 {
+    let src = from.primaries();
+    let dst = to.primaries();
+    let mut to_xyz = rgb_to_xyz_matrix(&src);
+    if src.white != dst.white {
+        to_xyz = multiply(&bradford_adaptation(src.white, dst.white), &to_xyz);
+    }
+    multiply(&invert(&rgb_to_xyz_matrix(&dst)), &to_xyz)
+}
+
+pub fn map_gamut(rgb: [f32; 3], mapping: GamutMapping, luma_weights: [f32; 3]) -> [f32; 3] {
+    match mapping {
+        GamutMapping::Clip => [
+            rgb[0].clamp(0.0, 1.0),
+            rgb[1].clamp(0.0, 1.0),
+            rgb[2].clamp(0.0, 1.0),
+        ],
+        GamutMapping::Perceptual => {
+            // Desaturate toward the pixel's luminance until every channel fits.
+            let luma =
+                (luma_weights[0] * rgb[0] + luma_weights[1] * rgb[1] + luma_weights[2] * rgb[2])
+                    .clamp(0.0, 1.0);
+            let mut t = 1.0f32;
+            for &c in &rgb {
+                if c > 1.0 {
+                    t = t.min((1.0 - luma) / (c - luma));
+                } else if c < 0.0 {
+                    t = t.min(luma / (luma - c));
+                }
+            }
+            [
+                (luma + t * (rgb[0] - luma)).clamp(0.0, 1.0),
+                (luma + t * (rgb[1] - luma)).clamp(0.0, 1.0),
+                (luma + t * (rgb[2] - luma)).clamp(0.0, 1.0),
+            ]
+        }
+    }
+}
+
+pub struct GamutConverter {
+    from: ColorGamut,
+    to: ColorGamut,
+    matrix: Matrix3,
+    mapping: GamutMapping,
+    linear_lut: [f32; 256],
+}
+
+impl GamutConverter {
+    pub fn new(from: ColorGamut, to: ColorGamut, mapping: GamutMapping) -> Self {
+        let mut linear_lut = [0.0; 256];
+        for (i, v) in linear_lut.iter_mut().enumerate() {
+            *v = from.linearize(i as f32 / 255.0);
+        }
+        Self {
+            from,
+            to,
+            matrix: conversion_matrix(from, to),
+            mapping,
+            linear_lut,
+        }
+    }
+
+    pub fn is_identity(&self) -> bool {
+        self.from == self.to
+    }
+
+    pub fn convert_linear(&self, rgb: [f32; 3]) -> [f32; 3] {
+        map_gamut(
+            apply(&self.matrix, rgb),
+            self.mapping,
+            self.to.luma_weights(),
+        )
+    }
+
+    pub fn convert_pixel(&self, rgb: [u8; 3]) -> [u8; 3] {
+        let linear = [
+            self.linear_lut[rgb[0] as usize],
+            self.linear_lut[rgb[1] as usize],
+            self.linear_lut[rgb[2] as usize],
+        ];
+        let out = self.convert_linear(linear);
+        [
+            (self.to.encode_linear(out[0]) * 255.0).round() as u8,
+            (self.to.encode_linear(out[1]) * 255.0).round() as u8,
+            (self.to.encode_linear(out[2]) * 255.0).round() as u8,
+        ]
+    }
+
+    pub fn convert_rgb8(&self, data: &mut [u8], channels: usize) {
+        if self.is_identity() || channels < 3 {
+            return;
+        }
+        for pixel in data.chunks_exact_mut(channels) {
+            let out = self.convert_pixel([pixel[0], pixel[1], pixel[2]]);
+            pixel[..3].copy_from_slice(&out);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_srgb_matrix_matches_reference() {
+        let m = rgb_to_xyz_matrix(&ColorGamut::SRGB.primaries());
+        let expected = [
+            [0.4124, 0.3576, 0.1805],
+            [0.2126, 0.7152, 0.0722],
+            [0.0193, 0.1192, 0.9505],
+        ];
+        for i in 0..3 {
+            for j in 0..3 {
+                assert!((m[i][j] - expected[i][j]).abs() < 1e-3);
+            }
+        }
+    }
+
+    #[test]
+    fn test_white_is_preserved_across_gamuts() {
+        for gamut in [
+            ColorGamut::AdobeRGB,
+            ColorGamut::DisplayP3,
+            ColorGamut::Rec2020,
+            ColorGamut::ProPhotoRGB,
+        ] {
+            let white = apply(&conversion_matrix(gamut, ColorGamut::SRGB), [1.0, 1.0, 1.0]);
+            for c in white {
+                assert!(
+                    (c - 1.0).abs() < 2e-3,
+                    "{:?} white drifted: {:?}",
+                    gamut,
+                    white
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_in_gamut_roundtrip_and_mapping() {
+        let to_p3 =
+            GamutConverter::new(ColorGamut::SRGB, ColorGamut::DisplayP3, GamutMapping::Clip);
+        let to_srgb =
+            GamutConverter::new(ColorGamut::DisplayP3, ColorGamut::SRGB, GamutMapping::Clip);
+        for rgb in [[200u8, 40, 30], [60, 180, 90], [128, 128, 128]] {
+            let back = to_srgb.convert_pixel(to_p3.convert_pixel(rgb));
+            for c in 0..3 {
+                assert!((back[c] as i32 - rgb[c] as i32).abs() <= 2);
+            }
+        }
+
+        let p3_green = [0.0, 1.0, 0.0];
+        let clipped = to_srgb.convert_linear(p3_green);
+        let perceptual = GamutConverter::new(
+            ColorGamut::DisplayP3,
+            ColorGamut::SRGB,
+            GamutMapping::Perceptual,
+        )
+        .convert_linear(p3_green);
+        assert!(clipped
+            .iter()
+            .chain(&perceptual)
+            .all(|&c| (0.0..=1.0).contains(&c)));
+
+        let weights = ColorGamut::SRGB.luma_weights();
+        let luma = |rgb: [f32; 3]| weights[0] * rgb[0] + weights[1] * rgb[1] + weights[2] * rgb[2];
+        let target = luma(apply(
+            &conversion_matrix(ColorGamut::DisplayP3, ColorGamut::SRGB),
+            p3_green,
+        ));
+        assert!((luma(perceptual) - target).abs() < (luma(clipped) - target).abs());
+    }
+}
@@ -128,6 +128,22 @@ pub fn hlg_oetf(l: f32) -> f32 {
     }
 }
 
+pub fn srgb_eotf(v: f32) -> f32 {
+    if v <= 0.04045 {
+        v / 12.92
+    } else {
+        ((v + 0.055) / 1.055).powf(2.4)
+    }
+}
+
+pub fn srgb_oetf(l: f32) -> f32 {
+    if l <= 0.0031308 {
+        l * 12.92
+    } else {
+        1.055 * l.powf(1.0 / 2.4) - 0.055
+    }
+}
+
 pub fn convert_bit_depth(value: u16, from_bits: u8, to_bits: u8) -> u16 {
     if from_bits == to_bits {
         return value;
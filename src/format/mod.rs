@@ -1,9 +1,15 @@
 pub mod chunk;
+pub mod gainmap;
+pub mod gamut;
 pub mod hdr;
 pub mod header;
 pub mod progressive;
+pub mod tonemap;
 
 pub use chunk::{Chunk, ChunkReader, ChunkType, ChunkWriter};
+pub use gainmap::{GainMap, GainMapConfig, GainMapMetadata};
+pub use gamut::{GamutConverter, GamutMapping, Primaries};
 pub use hdr::{ColorGamut, HDRMetadata, MasteringDisplay, TransferFunction};
 pub use header::WkHeader;
-pub use progressive::{ScanOrder, ScanPass, Tile, TileGrid};
+pub use progressive::{DamagedRegion, ScanOrder, ScanPass, Tile, TileGrid};
+pub use tonemap::{ToneMapConfig, ToneMapOperator, ToneMapper};
@@ -1,3 +1,6 @@
+use crate::error::{WkError, WkResult};
+use byteorder::{ByteOrder, LittleEndian};
+
 #[derive(Debug, Clone)]
 pub struct Tile {
     pub x: u32,
@@ -19,9 +22,9 @@ pub struct TileGrid {
 
 impl TileGrid {
     pub fn new(image_width: u32, image_height: u32, tile_size: u32) -> Self {
-        let cols = (image_width + tile_size - 1) / tile_size;
-        let rows = (image_height + tile_size - 1) / tile_size;
-        let mut tiles = Vec::with_capacity((cols * rows) as usize);
+        let cols = image_width.div_ceil(tile_size);
+        let rows = image_height.div_ceil(tile_size);
+        let mut tiles = Vec::with_capacity(cols as usize * rows as usize);
 
         for row in 0..rows {
             for col in 0..cols {
@@ -62,6 +65,60 @@ impl TileGrid {
     pub fn tile_count(&self) -> usize {
         self.tiles.len()
     }
+
+    pub fn tiles_in_region(&self, x: u32, y: u32, width: u32, height: u32) -> Vec<usize> {
+        if width == 0 || height == 0 || self.tiles.is_empty() {
+            return Vec::new();
+        }
+        let first_col = x / self.tile_width;
+        let first_row = y / self.tile_height;
+        let last_col = ((x + width - 1) / self.tile_width).min(self.cols - 1);
+        let last_row = ((y + height - 1) / self.tile_height).min(self.rows - 1);
+        (first_row..=last_row)
+            .flat_map(|row| (first_col..=last_col).map(move |col| (row * self.cols + col) as usize))
+            .collect()
+    }
+
+    pub fn encode_index(&self) -> Vec<u8> {
+        let mut data = vec![0u8; 12 + self.tiles.len() * 12];
+        LittleEndian::write_u32(&mut data[0..4], self.tile_width);
+        LittleEndian::write_u32(&mut data[4..8], self.tile_height);
+        LittleEndian::write_u32(&mut data[8..12], self.tiles.len() as u32);
+        for (entry, tile) in data[12..].chunks_exact_mut(12).zip(&self.tiles) {
+            LittleEndian::write_u64(&mut entry[0..8], tile.data_offset);
+            LittleEndian::write_u32(&mut entry[8..12], tile.data_size);
+        }
+        data
+    }
+
+    pub fn decode_index(data: &[u8], image_width: u32, image_height: u32) -> WkResult<Self> {
+        if data.len() < 12 {
+            return Err(WkError::InvalidChunk("Tile index too short".into()));
+        }
+        let tile_width = LittleEndian::read_u32(&data[0..4]);
+        let tile_height = LittleEndian::read_u32(&data[4..8]);
+        let count = LittleEndian::read_u32(&data[8..12]) as usize;
+        if tile_width == 0 || tile_width != tile_height {
+            return Err(WkError::InvalidChunk("Invalid tile size".into()));
+        }
+
+        // Everything is checked against the bytes present before the grid is allocated.
+        let expected = (image_width.div_ceil(tile_width) as usize)
+            .checked_mul(image_height.div_ceil(tile_width) as usize);
+        let entries_len = count.checked_mul(12).and_then(|n| n.checked_add(12));
+        if expected != Some(count) || entries_len != Some(data.len()) {
+            return Err(WkError::InvalidChunk(
+                "Tile index does not match image size".into(),
+            ));
+        }
+
+        let mut grid = Self::new(image_width, image_height, tile_width);
+        for (entry, tile) in data[12..].chunks_exact(12).zip(&mut grid.tiles) {
+            tile.data_offset = LittleEndian::read_u64(&entry[0..8]);
+            tile.data_size = LittleEndian::read_u32(&entry[8..12]);
+        }
+        Ok(grid)
+    }
 }
 
 pub struct ScanOrder {
@@ -111,6 +168,36 @@ pub enum ScanPass {
     All,
 }
 
+impl ScanPass {
+    pub fn id(&self) -> u8 {
+        match self {
+            Self::DC => 0,
+            Self::ACLow => 1,
+            Self::ACHigh => 2,
+            Self::All => 3,
+        }
+    }
+
+    pub fn from_u8(v: u8) -> Option<Self> {
+        match v {
+            0 => Some(Self::DC),
+            1 => Some(Self::ACLow),
+            2 => Some(Self::ACHigh),
+            3 => Some(Self::All),
+            _ => None,
+        }
+    }
+
+    pub fn coefficient_count(&self) -> usize {
+        match self {
+            Self::DC => 1,
+            Self::ACLow => 15,
+            Self::ACHigh => 48,
+            Self::All => 64,
+        }
+    }
+}
+
 pub fn reorder_coefficients(coeffs: &[i16; 64], pass: ScanPass) -> Vec<i16> {
     match pass {
         ScanPass::DC => vec![coeffs[0]],
@@ -153,6 +240,14 @@ pub fn merge_progressive_coefficients(dc: &[i16], ac_low: &[i16], ac_high: &[i16
     coeffs
 }
 
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct DamagedRegion {
+    pub x: u32,
+    pub y: u32,
+    pub width: u32,
+    pub height: u32,
+}
+
 pub const RESYNC_MARKER: [u8; 4] = [0xFF, 0xD0, 0x00, 0x00];
 
 pub fn insert_resync_marker(data: &mut Vec<u8>, interval: usize) {
@@ -0,0 +1,301 @@
+use super::gamut::{apply, conversion_matrix, map_gamut, GamutMapping, Matrix3};
+use super::hdr::{
+    compress_to_8bit, hlg_eotf, pq_eotf, pq_oetf, srgb_eotf, srgb_oetf, ColorGamut, HDRMetadata,
+    TransferFunction,
+};
+use image::{Rgb, Rgb32FImage, RgbImage};
+
+pub const PQ_PEAK_NITS: f32 = 10000.0;
+pub const REFERENCE_WHITE_NITS: f32 = 203.0;
+pub const HLG_NOMINAL_PEAK_NITS: f32 = 1000.0;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ToneMapOperator {
+    Bt2390,
+    Reinhard,
+    AcesFilmic,
+    Hable,
+}
+
+#[derive(Debug, Clone)]
+pub struct ToneMapConfig {
+    pub operator: ToneMapOperator,
+    pub source_peak_nits: f32,
+    pub target_peak_nits: f32,
+    pub reference_white_nits: f32,
+    pub gamut_mapping: GamutMapping,
+}
+
+impl Default for ToneMapConfig {
+    fn default() -> Self {
+        Self {
+            operator: ToneMapOperator::Bt2390,
+            source_peak_nits: HLG_NOMINAL_PEAK_NITS,
+            target_peak_nits: REFERENCE_WHITE_NITS,
+            reference_white_nits: REFERENCE_WHITE_NITS,
+            gamut_mapping: GamutMapping::Perceptual,
+        }
+    }
+}
+
+impl ToneMapConfig {
+    pub fn with_operator(mut self, operator: ToneMapOperator) -> Self {
+        self.operator = operator;
+        self
+    }
+
+    pub fn with_source_peak(mut self, nits: f32) -> Self {
+        self.source_peak_nits = nits.max(1.0);
+        self
+    }
+
+    pub fn with_target_peak(mut self, nits: f32) -> Self {
+        self.target_peak_nits = nits.max(1.0);
+        self
+    }
+
+    pub fn with_gamut_mapping(mut self, mapping: GamutMapping) -> Self {
+        self.gamut_mapping = mapping;
+        self
+    }
+
+    pub fn with_metadata(mut self, hdr: &HDRMetadata) -> Self {
+        let peak = hdr
+            .max_cll
+            .map(|cll| cll as f32)
+            .or_else(|| hdr.mastering_display.as_ref().map(|m| m.max_luminance));
+        if let Some(peak) = peak.filter(|&p| p > 0.0) {
+            self.source_peak_nits = peak;
+        } else if hdr.transfer == TransferFunction::PQ {
+            self.source_peak_nits = PQ_PEAK_NITS;
+        }
+        self
+    }
+}
+
+pub struct ToneMapper {
+    config: ToneMapConfig,
+    luma_weights: [f32; 3],
+    to_srgb: Matrix3,
+    pq_source_peak: f32,
+    pq_target_peak: f32,
+}
+
+impl ToneMapper {
+    pub fn new(config: ToneMapConfig, source_gamut: ColorGamut) -> Self {
+        let pq_source_peak = pq_oetf(config.source_peak_nits / PQ_PEAK_NITS);
+        let pq_target_peak = pq_oetf(config.target_peak_nits / PQ_PEAK_NITS);
+        Self {
+            config,
+            luma_weights: source_gamut.luma_weights(),
+            to_srgb: conversion_matrix(source_gamut, ColorGamut::SRGB),
+            pq_source_peak,
+            pq_target_peak,
+        }
+    }
+
+    pub fn config(&self) -> &ToneMapConfig {
+        &self.config
+    }
+
+    pub fn map_luminance(&self, nits: f32) -> f32 {
+        let nits = nits.max(0.0);
+        let target = self.config.target_peak_nits;
+        let source = self.config.source_peak_nits;
+        if source <= target {
+            return nits.min(target);
+        }
+
+        let x = nits / target;
+        let w = source / target;
+        match self.config.operator {
+            ToneMapOperator::Bt2390 => self.bt2390(nits),
+            ToneMapOperator::Reinhard => (x * (1.0 + x / (w * w)) / (1.0 + x)).min(1.0) * target,
+            ToneMapOperator::AcesFilmic => (aces(x) / aces(w)).min(1.0) * target,
+            ToneMapOperator::Hable => (hable(2.0 * x) / hable(2.0 * w)).min(1.0) * target,
+        }
+    }
+
+    fn bt2390(&self, nits: f32) -> f32 {
+        let range = self.pq_source_peak;
+        let e1 = (pq_oetf(nits / PQ_PEAK_NITS) / range).min(1.0);
+        let max_lum = self.pq_target_peak / range;
+        let ks = 1.5 * max_lum - 0.5;
+
+        let e2 = if e1 < ks {
+            e1
+        } else {
+            let t = (e1 - ks) / (1.0 - ks);
+            let t2 = t * t;
+            let t3 = t2 * t;
+            (2.0 * t3 - 3.0 * t2 + 1.0) * ks
+                + (t3 - 2.0 * t2 + t) * (1.0 - ks)
+                + (-2.0 * t3 + 3.0 * t2) * max_lum
+        };
+
+        (pq_eotf(e2 * range) * PQ_PEAK_NITS).min(self.config.target_peak_nits)
+    }
+
+    pub fn map_pixel(&self, nits: [f32; 3]) -> [f32; 3] {
+        let weights = self.luma_weights;
+        let luma = nits[0] * weights[0] + nits[1] * weights[1] + nits[2] * weights[2];
+        let scale = if luma > 0.0 {
+            self.map_luminance(luma) / luma
+        } else {
+            0.0
+        };
+
+        let scale = scale / self.config.target_peak_nits;
+        let rgb = apply(
+            &self.to_srgb,
+            [nits[0] * scale, nits[1] * scale, nits[2] * scale],
+        );
+        map_gamut(
+            rgb,
+            self.config.gamut_mapping,
+            ColorGamut::SRGB.luma_weights(),
+        )
+    }
+
+    pub fn map_to_srgb8(&self, nits: [f32; 3]) -> [u8; 3] {
+        let rgb = self.map_pixel(nits);
+        [
+            (srgb_oetf(rgb[0]) * 255.0).round() as u8,
+            (srgb_oetf(rgb[1]) * 255.0).round() as u8,
+            (srgb_oetf(rgb[2]) * 255.0).round() as u8,
+        ]
+    }
+}
+
+fn aces(x: f32) -> f32 {
+    (x * (2.51 * x + 0.03)) / (x * (2.43 * x + 0.59) + 0.14)
+}
+
+fn hable(x: f32) -> f32 {
+    let (a, b, c, d, e, f) = (0.15, 0.50, 0.10, 0.20, 0.02, 0.30);
+    ((x * (a * x + c * b) + d * e) / (x * (a * x + b) + d * f)) - e / f
+}
+
+fn decode_to_nits(
+    rgb: [f32; 3],
+    transfer: TransferFunction,
+    gamut: ColorGamut,
+    config: &ToneMapConfig,
+) -> [f32; 3] {
+    match transfer {
+        TransferFunction::PQ => rgb.map(|v| pq_eotf(v) * PQ_PEAK_NITS),
+        TransferFunction::HLG => {
+            let scene = rgb.map(hlg_eotf);
+            let weights = gamut.luma_weights();
+            let ys = scene[0] * weights[0] + scene[1] * weights[1] + scene[2] * weights[2];
+            let ootf = config.source_peak_nits * ys.max(1e-6).powf(0.2);
+            scene.map(|v| v * ootf)
+        }
+        TransferFunction::Linear => rgb.map(|v| v * config.source_peak_nits),
+        TransferFunction::SDR => rgb.map(|v| srgb_eotf(v) * config.reference_white_nits),
+    }
+}
+
+pub fn tone_map_to_8bit(
+    data: &[u16],
+    channels: usize,
+    hdr: &HDRMetadata,
+    config: &ToneMapConfig,
+) -> Vec<u8> {
+    if hdr.transfer == TransferFunction::SDR || channels == 0 {
+        return compress_to_8bit(data, hdr.bit_depth);
+    }
+
+    let mapper = ToneMapper::new(config.clone(), hdr.gamut);
+    let color_channels = if channels >= 3 { 3 } else { 1 };
+    let mut out = Vec::with_capacity(data.len());
+
+    for pixel in data.chunks(channels) {
+        let norm = |v: u16| v as f32 / 65535.0;
+        let rgb = if color_channels == 3 && pixel.len() >= 3 {
+            [norm(pixel[0]), norm(pixel[1]), norm(pixel[2])]
+        } else {
+            [norm(pixel[0]); 3]
+        };
+
+        let nits = decode_to_nits(rgb, hdr.transfer, hdr.gamut, config);
+        let mapped = mapper.map_to_srgb8(nits);
+        if color_channels == 3 {
+            out.extend_from_slice(&mapped);
+        } else {
+            out.push(mapped[1]);
+        }
+        out.extend(pixel.iter().skip(color_channels).map(|&v| (v >> 8) as u8));
+    }
+
+    out
+}
+
+pub fn tone_map_linear(image: &Rgb32FImage, config: &ToneMapConfig) -> RgbImage {
+    let mapper = ToneMapper::new(config.clone(), ColorGamut::SRGB);
+    let white = config.reference_white_nits;
+    let mut out = RgbImage::new(image.width(), image.height());
+    for (x, y, p) in image.enumerate_pixels() {
+        let nits = [p[0] * white, p[1] * white, p[2] * white];
+        out.put_pixel(x, y, Rgb(mapper.map_to_srgb8(nits)));
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_operators_monotonic_and_bounded() {
+        for operator in [
+            ToneMapOperator::Bt2390,
+            ToneMapOperator::Reinhard,
+            ToneMapOperator::AcesFilmic,
+            ToneMapOperator::Hable,
+        ] {
+            let config = ToneMapConfig::default()
+                .with_operator(operator)
+                .with_source_peak(4000.0);
+            let mapper = ToneMapper::new(config, ColorGamut::SRGB);
+            let mut prev = 0.0;
+            for i in 0..=400 {
+                let out = mapper.map_luminance(i as f32 * 10.0);
+                assert!(out + 1e-3 >= prev, "{:?} not monotonic at {}", operator, i);
+                assert!(out <= REFERENCE_WHITE_NITS + 1e-3);
+                prev = out;
+            }
+            assert!(
+                prev > REFERENCE_WHITE_NITS * 0.9,
+                "{:?} peak too dark",
+                operator
+            );
+        }
+    }
+
+    #[test]
+    fn test_bt2390_preserves_shadows() {
+        let mapper = ToneMapper::new(ToneMapConfig::default(), ColorGamut::SRGB);
+        for nits in [0.5f32, 5.0, 20.0] {
+            assert!((mapper.map_luminance(nits) - nits).abs() < nits * 0.02);
+        }
+    }
+
+    #[test]
+    fn test_pq_highlights_not_clipped() {
+        let hdr = HDRMetadata::hdr10();
+        let config = ToneMapConfig::default().with_metadata(&hdr);
+        let samples: Vec<u16> = [100.0f32, 400.0, 1000.0]
+            .iter()
+            .flat_map(|&nits| {
+                let v = (pq_oetf(nits / PQ_PEAK_NITS) * 65535.0) as u16;
+                [v, v, v]
+            })
+            .collect();
+
+        let mapped = tone_map_to_8bit(&samples, 3, &hdr, &config);
+        assert!(mapped[0] > 150);
+        assert!(mapped[0] < mapped[3] && mapped[3] < mapped[6]);
+        assert!(mapped[3] < 255);
+    }
+}
@@ -11,18 +11,28 @@ pub mod encoder;
 pub mod error;
 pub mod format;
 pub mod metadata;
+pub mod streaming;
 
 #[cfg(all(target_arch = "wasm32", feature = "wasm"))]
 pub mod wasm;
 
-pub use compression::{CompressionConfig, CompressionEngine};
+pub use animation::{
+    Animation, AnimationFrame, BlendMode, Compositor, DecodedFrame, DisposeMode, FrameIndex,
+    KeyframePlacement, KeyframePolicy, RateControl, RateTarget, WkAnimationDecoder,
+    WkAnimationEncoder,
+};
+pub use compression::{CompressionConfig, CompressionEngine, LosslessEngine};
 pub use converter::WkConverter;
-pub use decoder::{DecodedImage, WkDecoder};
+pub use decoder::{DecodeOptions, DecodeScale, DecodedImage, ProgressiveDecoder, WkDecoder};
 pub use encoder::WkEncoder;
 pub use error::{WkError, WkResult};
 pub use format::header::{ColorType, CompressionMode, WkHeader};
-pub use format::{Chunk, ChunkType};
+pub use format::{
+    Chunk, ChunkType, GainMap, GainMapConfig, GamutMapping, ScanPass, ToneMapConfig,
+    ToneMapOperator,
+};
 pub use metadata::{CustomMetadata, ExifData, ExifTag, IccProfile, WkMetadata, XmpData};
+pub use streaming::{StreamEvent, WkStreamingDecoder};
 
 pub const VERSION: &str = "3.1.1";
 pub const MAGIC: &[u8; 8] = b"WK3.0\x00\x00\x00";
@@ -126,6 +136,1413 @@ mod tests {
         assert_eq!(exif.iso(), Some(800));
     }
 
+    #[test]
+    fn test_gain_map_roundtrip() {
+        let sdr = DynamicImage::ImageRgb8(RgbImage::from_fn(48, 48, |x, y| {
+            image::Rgb([(x * 5) as u8, (y * 5) as u8, 90])
+        }));
+        let hdr = image::Rgb32FImage::from_fn(48, 48, |x, y| {
+            let boost = if x > 24 { 3.0 } else { 1.0 };
+            let v = (x + y) as f32 / 96.0;
+            image::Rgb([v * boost, v * boost, v * boost])
+        });
+
+        let mut encoded = Vec::new();
+        WkEncoder::lossy(90)
+            .encode_with_gain_map(&sdr, &hdr, &mut encoded)
+            .unwrap();
+
+        let decoded = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        assert!(decoded.has_gain_map());
+        assert_eq!(decoded.image.width(), 48);
+
+        let sdr_out = decoded.to_hdr(1.0).unwrap();
+        let hdr_out = decoded.to_hdr(8.0).unwrap();
+        let bright = |img: &image::Rgb32FImage| img.get_pixel(40, 40).0[1];
+        assert!(bright(&hdr_out) > bright(&sdr_out) * 2.0);
+    }
+
+    #[test]
+    fn test_unknown_chunks_skip_only_when_ancillary() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, y| {
+            image::Rgb([(x * 30) as u8, (y * 30) as u8, 60])
+        }));
+        let encoded = WkEncoder::lossless().encode_to_vec(&img).unwrap();
+        let with_chunk = |type_bytes: &[u8; 4], corrupt: bool| {
+            let payload = b"from a newer writer";
+            let mut hasher = crc32fast::Hasher::new();
+            hasher.update(type_bytes);
+            hasher.update(payload);
+            let crc = hasher.finalize() ^ corrupt as u32;
+
+            // The new chunk goes just before the 12-byte IEND chunk.
+            let split = encoded.len() - 12;
+            let mut file = encoded[..split].to_vec();
+            file.extend(type_bytes);
+            file.extend(&(payload.len() as u32).to_le_bytes());
+            file.extend(payload);
+            file.extend(&crc.to_le_bytes());
+            file.extend(&encoded[split..]);
+            file
+        };
+
+        let ancillary = with_chunk(b"xNEW", false);
+        let decoded = WkDecoder::new().decode(ancillary.as_slice()).unwrap();
+        assert_eq!(decoded.image.to_rgb8(), img.to_rgb8());
+        let mut streaming = WkStreamingDecoder::new();
+        streaming.push(&ancillary).unwrap();
+        assert!(streaming.is_done());
+
+        for file in [with_chunk(b"xNEW", true), with_chunk(b"XNEW", false)] {
+            assert!(WkDecoder::new().decode(file.as_slice()).is_err());
+            assert!(WkStreamingDecoder::new().push(&file).is_err());
+        }
+    }
+
+    #[test]
+    fn test_corrupt_gain_map_chunk_is_an_error() {
+        let chunk = Chunk::new(ChunkType::GainMap, vec![3, 0, 0, 0, 1, 2, 3]);
+        let mut metadata = WkMetadata::new();
+        let mut gain_map = None;
+        assert!(decoder::read_metadata_chunk(&chunk, &mut metadata, &mut gain_map).is_err());
+    }
+
+    #[test]
+    fn test_decode_sdr_tone_maps_pq() {
+        let pq_100_nits = 130u8;
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, _| {
+            let v = if x < 8 { pq_100_nits } else { 230 };
+            image::Rgb([v, v, v])
+        }));
+        let metadata = WkMetadata::new().with_hdr(format::HDRMetadata::hdr10());
+        let encoded = WkEncoder::lossless()
+            .with_metadata(metadata)
+            .encode_to_vec(&img)
+            .unwrap();
+
+        let decoded = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        assert!(decoded.is_hdr());
+        assert_eq!(
+            decoded.metadata.encode().unwrap(),
+            WkMetadata::new().encode().unwrap()
+        );
+
+        let sdr = WkDecoder::new()
+            .decode_sdr(encoded.as_slice(), &ToneMapConfig::default())
+            .unwrap()
+            .image
+            .to_rgb8();
+        let mid = sdr.get_pixel(2, 2).0[0];
+        let peak = sdr.get_pixel(12, 2).0[0];
+        assert!(mid > pq_100_nits);
+        assert!(peak > mid);
+    }
+
+    #[test]
+    fn test_decode_srgb_converts_wide_gamut() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(8, 8, |x, _| {
+            if x < 4 {
+                image::Rgb([128, 128, 128])
+            } else {
+                image::Rgb([200, 120, 60])
+            }
+        }));
+        let metadata = WkMetadata::new().with_icc(IccProfile::display_p3());
+        let encoded = WkEncoder::lossless()
+            .with_metadata(metadata)
+            .encode_to_vec(&img)
+            .unwrap();
+
+        let decoded = WkDecoder::new()
+            .decode_srgb(encoded.as_slice(), GamutMapping::Perceptual)
+            .unwrap();
+        let rgb = decoded.image.to_rgb8();
+        assert_eq!(
+            decoded.metadata.icc_profile.unwrap().color_space,
+            metadata::icc::ColorSpace::SRGB
+        );
+        assert_eq!(rgb.get_pixel(1, 1).0, [128, 128, 128]);
+        let orange = rgb.get_pixel(6, 1).0;
+        assert!(orange[0] > 200 && orange[2] < 60);
+    }
+
+    #[test]
+    fn test_lossy_color_matrix_roundtrip() {
+        use compression::{ColorMatrix, ColorRange};
+
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(32, 32, |x, y| {
+            image::Rgb([(x * 8) as u8, (y * 8) as u8, 200 - (x * 4) as u8])
+        }));
+        let source = img.to_rgb8();
+
+        for matrix in [
+            ColorMatrix::Identity,
+            ColorMatrix::Bt601,
+            ColorMatrix::Bt709,
+            ColorMatrix::Bt2020,
+        ] {
+            for range in [ColorRange::Full, ColorRange::Limited] {
+                let encoded = WkEncoder::lossy(95)
+                    .with_color_matrix(matrix)
+                    .with_color_range(range)
+                    .encode_to_vec(&img)
+                    .unwrap();
+                let decoded = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+                let out = decoded.image.to_rgb8();
+                let mean_err = source
+                    .as_raw()
+                    .iter()
+                    .zip(out.as_raw())
+                    .map(|(&a, &b)| (a as i32 - b as i32).abs())
+                    .sum::<i32>() as f32
+                    / source.as_raw().len() as f32;
+                assert!(mean_err < 4.0, "{:?} {:?}: {}", matrix, range, mean_err);
+            }
+        }
+    }
+
+    #[test]
+    fn test_color_matrix_follows_gamut() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(16, 16, |x, y| {
+            image::Rgb([(x * 16) as u8, (y * 16) as u8, 64])
+        }));
+        let encoded = WkEncoder::lossy(80)
+            .with_metadata(WkMetadata::new().with_icc(IccProfile::rec2020()))
+            .encode_to_vec(&img)
+            .unwrap();
+
+        let chunks = format::ChunkReader::new(encoded.as_slice())
+            .read_all_chunks()
+            .unwrap();
+        let stream = &chunks
+            .iter()
+            .find(|c| c.chunk_type == ChunkType::ImageDataLossy)
+            .unwrap()
+            .data;
+        let engine = CompressionEngine::new(CompressionConfig::default());
+        assert_eq!(
+            engine.stream_color_matrix(stream),
+            Some(compression::ColorMatrix::Bt2020)
+        );
+    }
+
+    #[test]
+    fn test_legacy_lossless_payload_decodes() {
+        let data: Vec<u8> = (0..16 * 16 * 3).map(|i| (i * 7 % 251) as u8).collect();
+        let filtered = compression::predictor::apply_optimal_predictor(&data, 16, 16, 3);
+        let legacy = compression::EntropyEncoder::new().encode_with_huffman(&filtered);
+
+        let engine = CompressionEngine::new(CompressionConfig::lossless());
+        assert_eq!(
+            engine.decompress_lossless(&legacy, 16, 16, 3).unwrap(),
+            data
+        );
+    }
+
+    #[test]
+    fn test_lossless_engines_roundtrip() {
+        let img = DynamicImage::ImageRgba8(image::RgbaImage::from_fn(61, 47, |x, y| {
+            image::Rgba([
+                (x * 4 + y) as u8,
+                (x * 2 + y * 3) as u8,
+                ((x ^ y) * 5) as u8,
+                if (x + y) % 9 == 0 { 0 } else { 255 },
+            ])
+        }));
+
+        let mut sizes = Vec::new();
+        for engine in [
+            LosslessEngine::Huffman,
+            LosslessEngine::Loco,
+            LosslessEngine::Lz77,
+        ] {
+            let encoded = WkEncoder::lossless()
+                .with_lossless_engine(engine)
+                .encode_to_vec(&img)
+                .unwrap();
+            let decoded = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+            assert_eq!(decoded.image.to_rgba8(), img.to_rgba8());
+            sizes.push(encoded.len());
+        }
+        assert!(sizes[1] < sizes[0]);
+
+        // The effort 9 engine search leaves an explicit choice alone.
+        let effort_9 = |encoder: WkEncoder| encoder.with_effort(9).encode_to_vec(&img).unwrap();
+        let chosen = effort_9(WkEncoder::lossless().with_lossless_engine(LosslessEngine::Huffman));
+        let searched = effort_9(WkEncoder::lossless());
+        assert!(searched.len() < chosen.len());
+    }
+
+    #[test]
+    fn test_progressive_passes_refine() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(96, 64, |x, y| {
+            let stripe = if (x / 3 + y / 5) % 2 == 0 { 40 } else { 0 };
+            image::Rgb([
+                (x * 2 + stripe) as u8,
+                (y * 3) as u8,
+                (200 - x + stripe) as u8,
+            ])
+        }));
+        let encoded = WkEncoder::lossy(85)
+            .with_progressive(true)
+            .encode_to_vec(&img)
+            .unwrap();
+
+        let error = |decoded: &DecodedImage| -> u64 {
+            let out = decoded.image.to_rgb8();
+            out.as_raw()
+                .iter()
+                .zip(img.to_rgb8().as_raw())
+                .map(|(&a, &b)| (a as i64 - b as i64).pow(2) as u64)
+                .sum()
+        };
+
+        let previews: Vec<_> = WkDecoder::new()
+            .decode_progressive(encoded.as_slice())
+            .collect::<WkResult<_>>()
+            .unwrap();
+        let passes: Vec<ScanPass> = previews.iter().map(|(pass, _)| *pass).collect();
+        assert_eq!(passes, [ScanPass::DC, ScanPass::ACLow, ScanPass::ACHigh]);
+
+        let errors: Vec<u64> = previews.iter().map(|(_, d)| error(d)).collect();
+        assert!(errors[0] > errors[1] && errors[1] > errors[2]);
+
+        let full = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        assert_eq!(full.image.to_rgb8(), previews[2].1.image.to_rgb8());
+
+        let plain = WkEncoder::lossy(85).encode_to_vec(&img).unwrap();
+        let single: Vec<_> = WkDecoder::new()
+            .decode_progressive(plain.as_slice())
+            .collect::<WkResult<_>>()
+            .unwrap();
+        assert_eq!(single.len(), 1);
+        assert_eq!(single[0].0, ScanPass::All);
+
+        assert!(WkEncoder::lossy(85)
+            .with_progressive(true)
+            .with_resync_interval(2)
+            .encode_to_vec(&img)
+            .is_err());
+
+        // Without its IDPS chunks the stream would silently decode DC-only.
+        let mut stripped = format::chunk::WK_MAGIC.to_vec();
+        let mut pos = stripped.len();
+        while pos < encoded.len() {
+            let size = u32::from_le_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            if &encoded[pos..pos + 4] != b"IDPS" {
+                stripped.extend(&encoded[pos..pos + 8 + size + 4]);
+            }
+            pos += 8 + size + 4;
+        }
+        assert!(matches!(
+            WkDecoder::new().decode(stripped.as_slice()),
+            Err(WkError::InvalidFormat(_))
+        ));
+    }
+
+    #[test]
+    fn test_resilient_decode_conceals_damaged_segment() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 3 + y) as u8, (y * 3) as u8, (128 + x - y / 2) as u8])
+        }));
+        let encoded = WkEncoder::lossy(85)
+            .with_resync_interval(2)
+            .encode_to_vec(&img)
+            .unwrap();
+        let clean = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        let (_, damaged) = WkDecoder::new()
+            .decode_resilient(encoded.as_slice())
+            .unwrap();
+        assert!(damaged.is_empty());
+
+        let first = format::progressive::find_resync_marker(&encoded, 0).unwrap();
+        let second = format::progressive::find_resync_marker(&encoded, first + 1).unwrap();
+        let mut corrupted = encoded.clone();
+        for byte in &mut corrupted[second + 20..second + 24] {
+            *byte ^= 0x5A;
+        }
+        assert!(WkDecoder::new().decode(corrupted.as_slice()).is_err());
+
+        let (decoded, damaged) = WkDecoder::new()
+            .decode_resilient(corrupted.as_slice())
+            .unwrap();
+        assert_eq!(
+            damaged,
+            [format::DamagedRegion {
+                x: 0,
+                y: 16,
+                width: 64,
+                height: 16
+            }]
+        );
+
+        let (clean, concealed) = (clean.image.to_rgb8(), decoded.image.to_rgb8());
+        for y in (0..8).chain(40..64) {
+            for x in 0..64 {
+                assert_eq!(clean.get_pixel(x, y), concealed.get_pixel(x, y));
+            }
+        }
+        let original = img.to_rgb8();
+        let error: u32 = (16..32)
+            .flat_map(|y| (0..64).map(move |x| (x, y)))
+            .map(|(x, y)| {
+                let (a, b) = (original.get_pixel(x, y), concealed.get_pixel(x, y));
+                (0..3).map(|c| a[c].abs_diff(b[c]) as u32).sum::<u32>()
+            })
+            .sum();
+        assert!(error / (16 * 64 * 3) < 8);
+
+        // A damaged index is caught too, rather than moving the rows elsewhere.
+        let mut misplaced = encoded.clone();
+        misplaced[second + 4] = 0;
+        let (decoded, damaged) = WkDecoder::new()
+            .decode_resilient(misplaced.as_slice())
+            .unwrap();
+        assert_eq!(damaged.len(), 1);
+        assert_eq!(damaged[0].y, 16);
+        let moved = decoded.image.to_rgb8();
+        for y in 0..8 {
+            for x in 0..64 {
+                assert_eq!(clean.get_pixel(x, y), moved.get_pixel(x, y));
+            }
+        }
+    }
+
+    #[test]
+    fn test_hostile_tile_index_is_rejected() {
+        use format::progressive::TileGrid;
+
+        let index = |tile_size: u32, count: u32, entries: usize| {
+            let mut data = tile_size.to_le_bytes().to_vec();
+            data.extend(tile_size.to_le_bytes());
+            data.extend(count.to_le_bytes());
+            data.resize(12 + entries * 12, 0);
+            data
+        };
+        assert!(TileGrid::decode_index(&index(0, 0, 0), 64, 64).is_err());
+        assert!(TileGrid::decode_index(&index(u32::MAX, 1, 1), u32::MAX, u32::MAX).is_ok());
+        assert!(TileGrid::decode_index(&index(1, u32::MAX, 0), u32::MAX, u32::MAX).is_err());
+        assert!(TileGrid::decode_index(&index(32, 4, 3), 64, 64).is_err());
+
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(64, 64, |x, y| {
+            image::Rgb([(x * 4) as u8, (y * 4) as u8, 30])
+        }));
+        let mut encoded = WkEncoder::lossless()
+            .with_tile_size(32)
+            .encode_to_vec(&img)
+            .unwrap();
+        let mut pos = format::chunk::WK_MAGIC.len();
+        while &encoded[pos..pos + 4] != b"TIDX" {
+            let size = u32::from_le_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            pos += 8 + size + 4;
+        }
+        let size = u32::from_le_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+        // The first entry's offset now wraps when added to the base position.
+        encoded[pos + 20..pos + 28].copy_from_slice(&(u64::MAX - 4).to_le_bytes());
+        let chunk = Chunk::new(
+            ChunkType::TileIndex,
+            encoded[pos + 8..pos + 8 + size].to_vec(),
+        );
+        encoded[pos + 8 + size..pos + 12 + size].copy_from_slice(&chunk.crc.to_le_bytes());
+        assert!(WkDecoder::new()
+            .decode_region(std::io::Cursor::new(&encoded), 0, 0, 16, 16)
+            .is_err());
+    }
+
+    #[test]
+    fn test_tiled_region_decode() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(100, 70, |x, y| {
+            image::Rgb([(x * 2) as u8, (y * 3) as u8, ((x ^ y) * 5) as u8])
+        }));
+        let (x, y, w, h) = (40, 20, 30, 25);
+
+        let mut encoded = WkEncoder::lossless()
+            .with_tile_size(32)
+            .encode_to_vec(&img)
+            .unwrap();
+        let full = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        assert_eq!(full.image.to_rgb8(), img.to_rgb8());
+
+        // Damage the bottom-right tile, which the region does not touch.
+        let mut pos = format::chunk::WK_MAGIC.len();
+        let mut last_tile = None;
+        while pos + 8 <= encoded.len() {
+            let size = u32::from_le_bytes(encoded[pos + 4..pos + 8].try_into().unwrap()) as usize;
+            if &encoded[pos..pos + 4] == b"TILE" {
+                last_tile = Some(pos + 8);
+            }
+            pos += 8 + size + 4;
+        }
+        encoded[last_tile.unwrap()] ^= 0xFF;
+        assert!(WkDecoder::new().decode(encoded.as_slice()).is_err());
+        let region = WkDecoder::new()
+            .decode_region(std::io::Cursor::new(&encoded), x, y, w, h)
+            .unwrap();
+        assert_eq!((region.header.width, region.header.height), (w, h));
+        assert_eq!(region.image.to_rgb8(), img.crop_imm(x, y, w, h).to_rgb8());
+        assert!(WkDecoder::new()
+            .decode_region(std::io::Cursor::new(&encoded), 90, 60, 20, 5)
+            .is_err());
+
+        for encoder in [
+            WkEncoder::lossy(80).with_tile_size(32),
+            WkEncoder::lossy(80),
+        ] {
+            let encoded = encoder.encode_to_vec(&img).unwrap();
+            let full = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+            let region = WkDecoder::new()
+                .decode_region(std::io::Cursor::new(&encoded), x, y, w, h)
+                .unwrap();
+            assert_eq!(
+                region.image.to_rgb8(),
+                full.image.crop_imm(x, y, w, h).to_rgb8()
+            );
+        }
+
+        // Resync segments restart inside each tile; progressive passes are refused.
+        let encoded = WkEncoder::lossy(80)
+            .with_tile_size(32)
+            .with_resync_interval(1)
+            .encode_to_vec(&img)
+            .unwrap();
+        assert!(format::progressive::find_resync_marker(&encoded, 0).is_some());
+        let full = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        let region = WkDecoder::new()
+            .decode_region(std::io::Cursor::new(&encoded), x, y, w, h)
+            .unwrap();
+        assert_eq!(
+            region.image.to_rgb8(),
+            full.image.crop_imm(x, y, w, h).to_rgb8()
+        );
+        assert!(WkEncoder::lossy(80)
+            .with_tile_size(32)
+            .with_progressive(true)
+            .encode_to_vec(&img)
+            .is_err());
+
+        // Chunks written after the tiles still reach the region.
+        let hdr = image::Rgb32FImage::from_fn(100, 70, |x, _| {
+            let v = x as f32 / 50.0;
+            image::Rgb([v, v, v])
+        });
+        let mut encoded = Vec::new();
+        WkEncoder::lossless()
+            .with_tile_size(32)
+            .encode_with_gain_map(&img, &hdr, &mut encoded)
+            .unwrap();
+        let region = WkDecoder::new()
+            .decode_region(std::io::Cursor::new(&encoded), x, y, w, h)
+            .unwrap();
+        assert!(region.has_gain_map());
+    }
+
+    #[test]
+    fn test_scaled_decode() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(75, 50, |x, y| {
+            let ripple = ((x as f32 * 0.3).sin() * 40.0) as i32;
+            image::Rgb([
+                (x * 3) as u8,
+                (100 + ripple + y as i32) as u8,
+                ((x + y) * 2) as u8,
+            ])
+        }));
+        let box_filter = |image: &DynamicImage, s: u32| {
+            let rgb = image.to_rgb8();
+            RgbImage::from_fn(
+                image.width().div_ceil(s),
+                image.height().div_ceil(s),
+                |ox, oy| {
+                    let (mut sum, mut n) = ([0u32; 3], 0);
+                    for y in oy * s..((oy + 1) * s).min(rgb.height()) {
+                        for x in ox * s..((ox + 1) * s).min(rgb.width()) {
+                            let p = rgb.get_pixel(x, y);
+                            (0..3).for_each(|c| sum[c] += p[c] as u32);
+                            n += 1;
+                        }
+                    }
+                    image::Rgb(sum.map(|v| ((v + n / 2) / n) as u8))
+                },
+            )
+        };
+
+        let psnr = |a: &RgbImage, b: &RgbImage| {
+            assert_eq!(a.dimensions(), b.dimensions());
+            let mse = a
+                .as_raw()
+                .iter()
+                .zip(b.as_raw())
+                .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+                .sum::<f64>()
+                / a.as_raw().len() as f64;
+            10.0 * (255.0 * 255.0 / mse).log10()
+        };
+
+        let lossy = WkEncoder::lossy(85).encode_to_vec(&img).unwrap();
+        let lossless = WkEncoder::lossless().encode_to_vec(&img).unwrap();
+        let full = WkDecoder::new().decode(lossy.as_slice()).unwrap();
+        let tiled_lossy = WkEncoder::lossy(85)
+            .with_tile_size(32)
+            .encode_to_vec(&img)
+            .unwrap();
+        let tiled_full = WkDecoder::new().decode(tiled_lossy.as_slice()).unwrap();
+        let mut animation = Animation::new();
+        animation.add_frame(AnimationFrame::new(75, 50, img.to_rgba8().into_raw()));
+        let animated = WkAnimationEncoder::lossless()
+            .encode_to_vec(&animation)
+            .unwrap();
+        for scale in [DecodeScale::Half, DecodeScale::Quarter, DecodeScale::Eighth] {
+            let options = DecodeOptions::new().with_scale(scale);
+            let s = scale.denominator();
+
+            let decoded = WkDecoder::new()
+                .decode_with_options(lossy.as_slice(), &options)
+                .unwrap();
+            assert_eq!(
+                (decoded.header.width, decoded.header.height),
+                (75u32.div_ceil(s), 50u32.div_ceil(s))
+            );
+            assert!(psnr(&decoded.image.to_rgb8(), &box_filter(&full.image, s)) > 30.0);
+
+            let decoded = WkDecoder::new()
+                .decode_with_options(lossless.as_slice(), &options)
+                .unwrap();
+            assert_eq!(decoded.image.to_rgb8(), box_filter(&img, s));
+
+            // Tiles on the scaled grid decode at that scale; 20-pixel tiles
+            // do not fit the 1/8 grid and are filtered after decoding.
+            for tile_size in [32, 20] {
+                let tiled = WkEncoder::lossless()
+                    .with_tile_size(tile_size)
+                    .encode_to_vec(&img)
+                    .unwrap();
+                let decoded = WkDecoder::new()
+                    .decode_with_options(tiled.as_slice(), &options)
+                    .unwrap();
+                assert_eq!(decoded.image.to_rgb8(), box_filter(&img, s));
+            }
+            let decoded = WkDecoder::new()
+                .decode_with_options(tiled_lossy.as_slice(), &options)
+                .unwrap();
+            assert!(psnr(&decoded.image.to_rgb8(), &box_filter(&tiled_full.image, s)) > 30.0);
+
+            let decoded = WkDecoder::new()
+                .decode_with_options(animated.as_slice(), &options)
+                .unwrap();
+            assert_eq!(decoded.image.to_rgb8(), box_filter(&img, s));
+        }
+    }
+
+    #[test]
+    fn test_lossy_decode_independent_of_simd() {
+        let (w, h) = (64, 40);
+        let mut seed = 11u32;
+        let data: Vec<u8> = (0..w * h * 3)
+            .map(|i| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                ((i / 3 % w) * 3 + (seed >> 28) as usize) as u8
+            })
+            .collect();
+
+        for quality in [40, 95] {
+            let encoded = CompressionEngine::new(CompressionConfig::lossy(quality))
+                .compress(&data, w, h, 3)
+                .unwrap();
+            let decode = |use_simd| {
+                let config = CompressionConfig {
+                    use_simd,
+                    ..CompressionConfig::lossy(quality)
+                };
+                CompressionEngine::new(config)
+                    .decompress(&encoded, w, h, 3, CompressionMode::Lossy)
+                    .unwrap()
+            };
+            assert_eq!(decode(true), decode(false));
+        }
+    }
+
+    #[test]
+    fn test_size_shrinks_with_effort() {
+        let mut seed = 5u32;
+        let corpus = [
+            RgbImage::from_fn(64, 48, |x, y| {
+                image::Rgb([(x * 4) as u8, (y * 5) as u8, ((x + y) * 2) as u8])
+            }),
+            RgbImage::from_fn(64, 48, |x, y| {
+                seed = seed.wrapping_mul(1_103_515_245).wrapping_add(12345);
+                let noise = (seed >> 27) as u8;
+                image::Rgb([(x * 3) as u8 ^ noise, (y * 3) as u8, noise * 8])
+            }),
+            RgbImage::from_fn(64, 48, |x, y| {
+                let on = (x / 6 + y / 4) % 3 == 0;
+                image::Rgb(if on { [240, 40, 40] } else { [20, 20, 90] })
+            }),
+        ];
+
+        let total = |encoder: &dyn Fn() -> WkEncoder| -> usize {
+            corpus
+                .iter()
+                .map(|rgb| {
+                    let img = DynamicImage::ImageRgb8(rgb.clone());
+                    encoder().encode_to_vec(&img).unwrap().len()
+                })
+                .sum()
+        };
+        for quality in [None, Some(75)] {
+            let sizes: Vec<usize> = (0..=compression::MAX_EFFORT)
+                .map(|effort| {
+                    total(&|| match quality {
+                        None => WkEncoder::lossless().with_effort(effort),
+                        Some(q) => WkEncoder::lossy(q).with_effort(effort),
+                    })
+                })
+                .collect();
+            assert!(sizes.windows(2).all(|w| w[1] <= w[0]), "{sizes:?}");
+            assert!(sizes[9] < sizes[0]);
+        }
+
+        for effort in [0, 9] {
+            let img = DynamicImage::ImageRgb8(corpus[1].clone());
+            let encoded = WkEncoder::lossless()
+                .with_effort(effort)
+                .encode_to_vec(&img)
+                .unwrap();
+            let decoded = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+            assert_eq!(decoded.image.to_rgb8(), corpus[1]);
+        }
+    }
+
+    #[test]
+    fn test_animation_roundtrip() {
+        let frame = |shift: u32, alpha: u8| {
+            let pixels = RgbaImage::from_fn(24, 16, |x, y| {
+                image::Rgba([(x * 10 + shift) as u8, (y * 15) as u8, shift as u8, alpha])
+            });
+            AnimationFrame::new(24, 16, pixels.into_raw())
+        };
+        let mut animation = Animation::new().with_loop_count(3);
+        animation.add_keyframe(frame(0, 255).with_delay(40));
+        animation.add_frame(frame(60, 200).with_delay(80));
+        let patch = RgbaImage::from_pixel(8, 4, image::Rgba([9, 8, 7, 255]));
+        animation.add_frame(AnimationFrame::new(8, 4, patch.into_raw()).with_offset(10, 6));
+
+        let encoded = WkAnimationEncoder::lossless()
+            .encode_to_vec(&animation)
+            .unwrap();
+        let decoder = WkAnimationDecoder::new(encoded.as_slice()).unwrap();
+        assert_eq!(decoder.loop_count(), 3);
+        assert_eq!(decoder.frame_count(), 3);
+        assert_eq!((decoder.header().width, decoder.header().height), (24, 16));
+        let frames: Vec<DecodedFrame> = decoder.collect::<WkResult<_>>().unwrap();
+
+        assert_eq!(frames[0].image.as_raw(), &animation.frames[0].data);
+        assert_eq!(frames[1].image.as_raw(), &animation.frames[1].data);
+        assert_eq!(
+            frames.iter().map(|f| f.delay_ms).collect::<Vec<_>>(),
+            [40, 80, 100]
+        );
+        for (x, y, pixel) in frames[2].image.enumerate_pixels() {
+            let inside = (10..18).contains(&x) && (6..10).contains(&y);
+            let expected = if inside {
+                image::Rgba([9, 8, 7, 255])
+            } else {
+                *frames[1].image.get_pixel(x, y)
+            };
+            assert_eq!(*pixel, expected);
+        }
+
+        let still = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        assert_eq!(still.image.to_rgba8(), frames[0].image);
+
+        let mut opaque = Animation::new();
+        opaque.add_frame(frame(0, 255));
+        opaque.add_frame(frame(30, 255));
+        let encoded = WkAnimationEncoder::lossy(90)
+            .encode_to_vec(&opaque)
+            .unwrap();
+        let decoder = WkAnimationDecoder::new(encoded.as_slice()).unwrap();
+        assert_eq!(decoder.header().color_type, ColorType::Rgb);
+        for (decoded, source) in decoder.zip(&opaque.frames) {
+            let decoded = decoded.unwrap();
+            let max_diff = decoded
+                .image
+                .as_raw()
+                .iter()
+                .zip(&source.data)
+                .map(|(a, b)| a.abs_diff(*b))
+                .max()
+                .unwrap();
+            assert!(max_diff <= 16, "max diff {max_diff}");
+        }
+    }
+
+    #[test]
+    fn test_decoder_applies_blend_and_dispose() {
+        let sprite = |alpha: u8| {
+            let pixels = RgbaImage::from_fn(6, 6, |x, y| {
+                let inside = (x as i32 - 3).pow(2) + (y as i32 - 3).pow(2) < 8;
+                image::Rgba([220, 60, 20, if inside { alpha } else { 0 }])
+            });
+            pixels.into_raw()
+        };
+        let mut animation = Animation::new();
+        animation.config.background_color = [10, 20, 30, 255];
+        let backdrop = RgbaImage::from_fn(16, 12, |x, y| {
+            image::Rgba([x as u8 * 16, y as u8 * 20, 90, 255])
+        });
+        animation.add_frame(AnimationFrame::new(16, 12, backdrop.into_raw()));
+        let modes = [
+            (BlendMode::Over, DisposeMode::Previous, 255),
+            (BlendMode::Over, DisposeMode::Background, 128),
+            (BlendMode::Source, DisposeMode::None, 200),
+            (BlendMode::Over, DisposeMode::None, 64),
+        ];
+        for (i, (blend, dispose, alpha)) in modes.into_iter().enumerate() {
+            let mut frame = AnimationFrame::new(6, 6, sprite(alpha)).with_offset(i as u32 * 3, 4);
+            frame.blend_mode = blend;
+            frame.dispose_mode = dispose;
+            animation.add_delta_frame(frame);
+        }
+
+        let mut compositor = Compositor::for_animation(&animation).unwrap();
+        let expected: Vec<RgbaImage> = animation
+            .frames
+            .iter()
+            .map(|f| compositor.compose(f).unwrap())
+            .collect();
+        assert_eq!(expected[2].get_pixel(1, 7), expected[0].get_pixel(1, 7));
+        assert_eq!(expected[3].get_pixel(4, 5).0, [10, 20, 30, 255]);
+
+        let encoded = WkAnimationEncoder::lossless()
+            .encode_to_vec(&animation)
+            .unwrap();
+        let decoder = WkAnimationDecoder::new(encoded.as_slice()).unwrap();
+        for (decoded, expected) in decoder.zip(&expected) {
+            assert_eq!(&decoded.unwrap().image, expected);
+        }
+    }
+
+    #[test]
+    fn test_seek_matches_sequential_decode() {
+        let mut animation = Animation::new();
+        for i in 0..12u32 {
+            let pixels = RgbaImage::from_fn(16, 12, |x, y| {
+                image::Rgba([(x * 12 + i * 9) as u8, (y * 15) as u8, (i * 20) as u8, 255])
+            });
+            let frame = AnimationFrame::new(16, 12, pixels.into_raw()).with_delay(30 + i);
+            if i % 5 == 0 {
+                animation.add_keyframe(frame);
+            } else {
+                animation.add_delta_frame(frame);
+            }
+        }
+
+        let encoded = WkAnimationEncoder::lossy(80)
+            .with_keyframe_placement(KeyframePlacement::Manual)
+            .encode_to_vec(&animation)
+            .unwrap();
+        let sequential: Vec<DecodedFrame> = WkAnimationDecoder::new(encoded.as_slice())
+            .unwrap()
+            .collect::<WkResult<_>>()
+            .unwrap();
+
+        let mut decoder = WkAnimationDecoder::new(encoded.as_slice()).unwrap();
+        let keyframes: Vec<usize> = (0..12)
+            .filter(|&i| decoder.frame_index().entries[i].is_keyframe)
+            .collect();
+        assert_eq!(keyframes, [0, 5, 10]);
+        assert_eq!(decoder.duration_ms(), animation.total_duration_ms());
+        for n in [7, 3, 11, 8, 0, 9] {
+            let frame = decoder.seek_to_frame(n).unwrap();
+            assert_eq!(frame.index, n);
+            assert_eq!(frame.image, sequential[n].image, "frame {n}");
+            assert_eq!(frame.timestamp_ms, sequential[n].timestamp_ms);
+        }
+        let next = decoder.next().unwrap().unwrap();
+        assert_eq!(next.image, sequential[10].image);
+
+        let frame = decoder
+            .seek_to_time(sequential[6].timestamp_ms + 5)
+            .unwrap();
+        assert_eq!(frame.index, 6);
+        assert_eq!(frame.image, sequential[6].image);
+        let last = decoder.seek_to_time(u32::MAX).unwrap();
+        assert_eq!(last.index, 11);
+        assert!(decoder.seek_to_frame(12).is_err());
+    }
+
+    #[test]
+    fn test_gif_and_apng_roundtrip() {
+        let palette = [[250, 250, 240, 255], [20, 40, 200, 255], [200, 30, 30, 255]];
+        let mut animation = Animation::new().with_loop_count(2);
+        let base = RgbaImage::from_fn(20, 14, |x, y| {
+            image::Rgba(palette[((x / 5 + y / 7) % 2) as usize])
+        });
+        animation.add_frame(AnimationFrame::new(20, 14, base.into_raw()).with_delay(100));
+        for i in 0..3 {
+            let patch = RgbaImage::from_pixel(4, 4, image::Rgba(palette[2]));
+            animation.add_delta_frame(
+                AnimationFrame::new(4, 4, patch.into_raw())
+                    .with_offset(i * 5, 5)
+                    .with_delay(50 + i * 20),
+            );
+        }
+        let composited = |animation: &Animation| -> Vec<RgbaImage> {
+            let mut compositor = Compositor::for_animation(animation).unwrap();
+            animation
+                .frames
+                .iter()
+                .map(|f| compositor.compose(f).unwrap())
+                .collect()
+        };
+        let expected = composited(&animation);
+
+        let dir = std::env::temp_dir().join(format!("wk_anim_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for name in ["clip.gif", "clip.png"] {
+            let path = dir.join(name);
+            if name.ends_with("gif") {
+                converter::write_gif(&animation, &path).unwrap();
+            } else {
+                converter::write_apng(&animation, &path).unwrap();
+            }
+            let imported = converter::read_animation(&path).unwrap().unwrap();
+            assert_eq!(imported.config.loop_count, 2, "{name}");
+            assert_eq!(
+                imported
+                    .frames
+                    .iter()
+                    .map(|f| f.delay_ms)
+                    .collect::<Vec<_>>(),
+                [100, 50, 70, 90],
+                "{name}"
+            );
+            assert_eq!(composited(&imported), expected, "{name}");
+        }
+
+        let converter = WkConverter::lossless();
+        let wk_path = dir.join("clip.wk");
+        converter.to_wk(dir.join("clip.gif"), &wk_path).unwrap();
+        let decoded = converter.wk_to_animation(&wk_path).unwrap().unwrap();
+        assert_eq!(composited(&decoded), expected);
+        converter.from_wk(&wk_path, dir.join("back.png")).unwrap();
+        let back = converter::read_animation(dir.join("back.png"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(composited(&back), expected);
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_gif_loop_count_counts_plays() {
+        let dir = std::env::temp_dir().join(format!("wk_gif_loops_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("loops.gif");
+        let write = |repeat: Option<gif::Repeat>| {
+            let mut file = std::fs::File::create(&path).unwrap();
+            let mut encoder =
+                gif::Encoder::new(&mut file, 4, 4, &[0, 0, 0, 255, 255, 255]).unwrap();
+            if let Some(repeat) = repeat {
+                encoder.set_repeat(repeat).unwrap();
+            }
+            for color in [0, 1] {
+                let mut frame = gif::Frame::default();
+                frame.width = 4;
+                frame.height = 4;
+                frame.buffer = vec![color; 16].into();
+                encoder.write_frame(&frame).unwrap();
+            }
+        };
+
+        // No NETSCAPE extension: played once.
+        write(None);
+        let imported = converter::read_animation(&path).unwrap().unwrap();
+        assert_eq!(imported.config.loop_count, 1);
+        write(Some(gif::Repeat::Finite(2)));
+        let imported = converter::read_animation(&path).unwrap().unwrap();
+        assert_eq!(imported.config.loop_count, 3);
+        write(Some(gif::Repeat::Infinite));
+        let imported = converter::read_animation(&path).unwrap().unwrap();
+        assert_eq!(imported.config.loop_count, 0);
+
+        let mut once = imported.clone();
+        once.config.loop_count = 1;
+        converter::write_gif(&once, &path).unwrap();
+        let bytes = std::fs::read(&path).unwrap();
+        assert!(!bytes.windows(11).any(|w| w == b"NETSCAPE2.0"));
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_imports_keep_sub_frame_offsets_and_disposal() {
+        // A background, a sprite cleared after showing, one restored after
+        // showing, and a frame with a transparent hole over the canvas.
+        let solid = |w, h, c: [u8; 4]| RgbaImage::from_pixel(w, h, image::Rgba(c)).into_raw();
+        let mut holed = RgbaImage::from_pixel(6, 4, image::Rgba([20, 160, 40, 255]));
+        for x in 2..4 {
+            holed.put_pixel(x, 1, image::Rgba([0, 0, 0, 0]));
+        }
+        let mut animation = Animation::new().with_loop_count(3);
+        animation.add_frame(AnimationFrame::new(
+            16,
+            10,
+            solid(16, 10, [240, 230, 200, 255]),
+        ));
+        animation.add_frame(
+            AnimationFrame::new(4, 3, solid(4, 3, [200, 20, 20, 255]))
+                .with_offset(2, 1)
+                .with_dispose_mode(DisposeMode::Background),
+        );
+        animation.add_frame(
+            AnimationFrame::new(5, 4, solid(5, 4, [20, 20, 200, 255]))
+                .with_offset(9, 5)
+                .with_dispose_mode(DisposeMode::Previous),
+        );
+        animation.add_frame(
+            AnimationFrame::new(6, 4, holed.into_raw())
+                .with_offset(5, 3)
+                .with_blend_mode(BlendMode::Over),
+        );
+        let composited = |animation: &Animation| -> Vec<RgbaImage> {
+            let mut compositor = Compositor::for_animation(animation).unwrap();
+            animation
+                .frames
+                .iter()
+                .map(|f| compositor.compose(f).unwrap())
+                .collect()
+        };
+        let expected = composited(&animation);
+
+        let dir = std::env::temp_dir().join(format!("wk_subframes_{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let gif_path = dir.join("sprites.gif");
+        {
+            let file = std::fs::File::create(&gif_path).unwrap();
+            let mut encoder = gif::Encoder::new(file, 16, 10, &[]).unwrap();
+            encoder.set_repeat(gif::Repeat::Finite(2)).unwrap();
+            for frame in &animation.frames {
+                let mut pixels = frame.data.clone();
+                let mut out = gif::Frame::from_rgba_speed(
+                    frame.width as u16,
+                    frame.height as u16,
+                    &mut pixels,
+                    10,
+                );
+                out.left = frame.x_offset as u16;
+                out.top = frame.y_offset as u16;
+                out.delay = 10;
+                out.dispose = match frame.dispose_mode {
+                    DisposeMode::None => gif::DisposalMethod::Keep,
+                    DisposeMode::Background => gif::DisposalMethod::Background,
+                    DisposeMode::Previous => gif::DisposalMethod::Previous,
+                };
+                encoder.write_frame(&out).unwrap();
+            }
+        }
+        let png_path = dir.join("sprites.png");
+        {
+            let file = std::fs::File::create(&png_path).unwrap();
+            let mut encoder = png::Encoder::new(file, 16, 10);
+            encoder.set_color(png::ColorType::Rgba);
+            encoder.set_depth(png::BitDepth::Eight);
+            encoder.set_animated(4, 3).unwrap();
+            let mut writer = encoder.write_header().unwrap();
+            for frame in &animation.frames {
+                writer
+                    .set_frame_dimension(frame.width, frame.height)
+                    .unwrap();
+                writer
+                    .set_frame_position(frame.x_offset, frame.y_offset)
+                    .unwrap();
+                writer.set_frame_delay(1, 10).unwrap();
+                writer
+                    .set_dispose_op(match frame.dispose_mode {
+                        DisposeMode::None => png::DisposeOp::None,
+                        DisposeMode::Background => png::DisposeOp::Background,
+                        DisposeMode::Previous => png::DisposeOp::Previous,
+                    })
+                    .unwrap();
+                writer
+                    .set_blend_op(match frame.blend_mode {
+                        BlendMode::Source => png::BlendOp::Source,
+                        BlendMode::Over => png::BlendOp::Over,
+                    })
+                    .unwrap();
+                writer.write_image_data(&frame.data).unwrap();
+            }
+            writer.finish().unwrap();
+        }
+
+        for path in [&gif_path, &png_path] {
+            let imported = converter::read_animation(path).unwrap().unwrap();
+            assert_eq!(imported.config.loop_count, 3, "{path:?}");
+            let frames: Vec<_> = imported
+                .frames
+                .iter()
+                .map(|f| (f.x_offset, f.y_offset, f.width, f.dispose_mode, f.delay_ms))
+                .collect();
+            assert_eq!(
+                frames[1..],
+                [
+                    (2, 1, 4, DisposeMode::Background, 100),
+                    (9, 5, 5, DisposeMode::Previous, 100),
+                    (5, 3, 6, DisposeMode::None, 100),
+                ],
+                "{path:?}"
+            );
+            assert_eq!(composited(&imported), expected, "{path:?}");
+
+            let wk_path = dir.join("sprites.wk");
+            WkConverter::lossless().to_wk(path, &wk_path).unwrap();
+            let decoded = WkConverter::lossless()
+                .wk_to_animation(&wk_path)
+                .unwrap()
+                .unwrap();
+            assert_eq!(composited(&decoded), expected, "{path:?}");
+        }
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn test_pframes_on_scrolling_screen() {
+        let screen = |scroll: u32, cursor: u32| {
+            RgbaImage::from_fn(64, 48, |x, y| {
+                let row = y + scroll;
+                let glyph = (x / 3 + row / 5 * 7) % 5 < 2 && row % 5 < 3;
+                let on_cursor = (cursor..cursor + 4).contains(&x) && (30..38).contains(&y);
+                match (on_cursor, glyph) {
+                    (true, _) => image::Rgba([255, 40, 40, 255]),
+                    (false, true) => image::Rgba([30, 30, 60, 255]),
+                    (false, false) => image::Rgba([240, 240, 235, 255]),
+                }
+            })
+            .into_raw()
+        };
+        let build = |delta: bool| {
+            let mut animation = Animation::new();
+            for i in 0..6 {
+                let frame = AnimationFrame::new(64, 48, screen(i * 2, 8 + i * 5));
+                if i == 0 || !delta {
+                    animation.add_keyframe(frame);
+                } else {
+                    animation.add_delta_frame(frame);
+                }
+            }
+            animation
+        };
+        let (intra, inter) = (build(false), build(true));
+
+        let lossless =
+            WkAnimationEncoder::lossless().with_keyframe_placement(KeyframePlacement::Manual);
+        let intra_size = lossless.encode_to_vec(&intra).unwrap().len();
+        let encoded = lossless.encode_to_vec(&inter).unwrap();
+        assert!(
+            encoded.len() < intra_size,
+            "{} vs {}",
+            encoded.len(),
+            intra_size
+        );
+        let frames: Vec<DecodedFrame> = WkAnimationDecoder::new(encoded.as_slice())
+            .unwrap()
+            .collect::<WkResult<_>>()
+            .unwrap();
+        assert!(frames[0].is_keyframe && !frames[1].is_keyframe);
+        for (decoded, source) in frames.iter().zip(&inter.frames) {
+            assert_eq!(decoded.image.as_raw(), &source.data);
+        }
+
+        let lossy =
+            WkAnimationEncoder::lossy(85).with_keyframe_placement(KeyframePlacement::Manual);
+        let intra_size = lossy.encode_to_vec(&intra).unwrap().len();
+        let encoded = lossy.encode_to_vec(&inter).unwrap();
+        assert!(
+            encoded.len() < intra_size,
+            "{} vs {}",
+            encoded.len(),
+            intra_size
+        );
+        let decoder = WkAnimationDecoder::new(encoded.as_slice()).unwrap();
+        for (decoded, source) in decoder.zip(&inter.frames) {
+            let decoded = decoded.unwrap();
+            let sse: f64 = decoded
+                .image
+                .as_raw()
+                .iter()
+                .zip(&source.data)
+                .map(|(&a, &b)| (a as f64 - b as f64).powi(2))
+                .sum();
+            let psnr = 10.0 * (255.0f64.powi(2) * source.data.len() as f64 / sse.max(1.0)).log10();
+            assert!(psnr > 30.0, "frame {} psnr {psnr:.1}", decoded.index);
+        }
+    }
+
+    #[test]
+    fn test_automatic_keyframes_follow_scene_cuts() {
+        let scene = |seed: u32, pan: u32| {
+            RgbaImage::from_fn(48, 32, |x, y| {
+                let (x, y) = ((x + pan) as f32, y as f32);
+                let f = 0.15 + seed as f32 * 0.11;
+                let v = 128.0 + 60.0 * (x * f).sin() + 50.0 * (y * f * 1.7 + x * 0.2).cos();
+                image::Rgba([v as u8, (255.0 - v) as u8, (seed * 70) as u8, 255])
+            })
+            .into_raw()
+        };
+        let mut animation = Animation::new();
+        for i in 0..14 {
+            let seed = if i < 5 { 0 } else { 3 };
+            animation.add_frame(AnimationFrame::new(48, 32, scene(seed, i * 2)));
+        }
+
+        let encoder = WkAnimationEncoder::lossy(80).with_keyframe_placement(
+            KeyframePlacement::Auto(KeyframePolicy {
+                max_interval: 6,
+                ..KeyframePolicy::default()
+            }),
+        );
+        let mut encoded = Vec::new();
+        let stats = encoder.encode_with_stats(&animation, &mut encoded).unwrap();
+        assert_eq!(stats.keyframes(), [0, 5, 11]);
+        let reasons: Vec<_> = stats.frames.iter().map(|f| f.keyframe_reason).collect();
+        assert_eq!(reasons[5], Some(animation::KeyframeReason::SceneCut));
+        assert_eq!(reasons[11], Some(animation::KeyframeReason::MaxInterval));
+        assert!(stats.frames[3].scene.unwrap().sad_ratio < 0.2);
+        assert_eq!(
+            encoded.len(),
+            encoder.encode_to_vec(&animation).unwrap().len()
+        );
+
+        let decoder = WkAnimationDecoder::new(encoded.as_slice()).unwrap();
+        let keyframes: Vec<usize> = (0..14)
+            .filter(|&i| decoder.frame_index().entries[i].is_keyframe)
+            .collect();
+        assert_eq!(keyframes, [0, 5, 11]);
+
+        // Frames marked as keyframes stay keyframes under automatic placement.
+        animation.frames[8].is_keyframe = true;
+        let stats = encoder
+            .encode_with_stats(&animation, &mut Vec::new())
+            .unwrap();
+        assert_eq!(stats.keyframes(), [0, 5, 8]);
+        assert_eq!(
+            stats.frames[8].keyframe_reason,
+            Some(animation::KeyframeReason::Requested)
+        );
+    }
+
+    #[test]
+    fn test_frame_optimization_crops_and_merges() {
+        // A UI recording: a static window, a blinking caret and repeated frames.
+        let window = |caret: bool, typed: u32| {
+            RgbaImage::from_fn(80, 60, |x, y| {
+                let text = y / 6 == 4 && x >= 6 && x < 6 + typed * 4 && x % 4 != 3;
+                let on_caret = caret && x == 6 + typed * 4 && (24..30).contains(&y);
+                let chrome = y < 8 || (x / 10 + y / 10) % 7 == 0;
+                image::Rgba(match (text || on_caret, chrome) {
+                    (true, _) => [20, 20, 20, 255],
+                    (false, true) => [70, 110, 200, 255],
+                    (false, false) => [250, 250, 250, 255],
+                })
+            })
+            .into_raw()
+        };
+        let states = [
+            (true, 0),
+            (true, 0),
+            (false, 0),
+            (true, 1),
+            (true, 1),
+            (true, 2),
+        ];
+        let mut animation = Animation::new();
+        for (caret, typed) in states {
+            animation.add_frame(AnimationFrame::new(80, 60, window(caret, typed)).with_delay(40));
+        }
+
+        let encoder = WkAnimationEncoder::lossless();
+        let mut encoded = Vec::new();
+        let stats = encoder.encode_with_stats(&animation, &mut encoded).unwrap();
+        let merged: Vec<usize> = stats.frames.iter().map(|f| f.merged_frames).collect();
+        assert_eq!(merged, [2, 1, 2, 1]);
+        for frame in &stats.frames[1..] {
+            let (_, _, w, h) = frame.rect;
+            assert!(w * h <= 8 * 6, "{:?}", frame.rect);
+        }
+        let unoptimized = encoder
+            .with_frame_optimization(false)
+            .encode_to_vec(&animation)
+            .unwrap();
+        assert!(encoded.len() < unoptimized.len());
+
+        let frames: Vec<DecodedFrame> = WkAnimationDecoder::new(encoded.as_slice())
+            .unwrap()
+            .collect::<WkResult<_>>()
+            .unwrap();
+        let shown: Vec<(&[u8], u32)> = frames
+            .iter()
+            .map(|f| (f.image.as_raw().as_slice(), f.delay_ms))
+            .collect();
+        let source = [0, 2, 3, 5].map(|i| animation.frames[i].data.as_slice());
+        assert_eq!(
+            shown,
+            [
+                (source[0], 80),
+                (source[1], 40),
+                (source[2], 80),
+                (source[3], 40)
+            ]
+        );
+    }
+
+    #[test]
+    fn test_frame_optimization_blends_scattered_changes() {
+        // Glyphs pop up at opposite corners of a translucent, textured
+        // overlay, so the changed box is mostly unchanged texture.
+        let overlay = RgbaImage::from_fn(64, 48, |x, y| {
+            let v = ((x * 37 + y * 91) % 97 + (x * y) % 13) as u8;
+            image::Rgba([v, 255 - v, v / 2, 160])
+        });
+        let glyph = |image: &mut RgbaImage, x0: u32, y0: u32, seed: u32| {
+            for y in y0..y0 + 6 {
+                for x in x0..x0 + 6 {
+                    let v = ((x * 13 + y * 7 + seed * 31) % 5 * 60) as u8;
+                    image.put_pixel(x, y, image::Rgba([v, v, 255 - v, 255]));
+                }
+            }
+        };
+        let mut animation = Animation::new();
+        let mut canvas = overlay.clone();
+        animation.add_frame(AnimationFrame::new(64, 48, canvas.clone().into_raw()));
+        for i in 0..3 {
+            glyph(&mut canvas, 2 + i * 7, 2, i);
+            glyph(&mut canvas, 56 - i * 7, 40, i + 5);
+            animation.add_frame(AnimationFrame::new(64, 48, canvas.clone().into_raw()));
+        }
+
+        let encoder = WkAnimationEncoder::lossless().with_lossless_engine(LosslessEngine::Loco);
+        let mut encoded = Vec::new();
+        let stats = encoder.encode_with_stats(&animation, &mut encoded).unwrap();
+        let blended = stats.frames[1..]
+            .iter()
+            .filter(|f| f.frame_type == animation::FrameType::IFrame)
+            .count();
+        assert!(blended > 0, "{:?}", stats.frames);
+        let frames: Vec<DecodedFrame> = WkAnimationDecoder::new(encoded.as_slice())
+            .unwrap()
+            .collect::<WkResult<_>>()
+            .unwrap();
+        for (decoded, source) in frames.iter().zip(&animation.frames) {
+            assert_eq!(decoded.image.as_raw(), &source.data);
+        }
+        let keyframes: Vec<bool> = WkAnimationDecoder::new(encoded.as_slice())
+            .unwrap()
+            .frame_index()
+            .entries
+            .iter()
+            .map(|e| e.is_keyframe)
+            .collect();
+        assert_eq!(keyframes, [true, false, false, false]);
+    }
+
+    #[test]
+    fn test_rate_control_meets_size_targets() {
+        // A textured scene panning right, so every frame carries real detail.
+        let mut animation = Animation::new();
+        for t in 0..12u32 {
+            let frame = RgbaImage::from_fn(96, 64, |x, y| {
+                let u = (x + t * 3) as f32;
+                let v = (128.0 + 60.0 * (u * 0.3).sin() + 50.0 * (y as f32 * 0.2 + u * 0.05).cos())
+                    as u8;
+                image::Rgba([v, v / 2 + (y as u8), 255 - v, 255])
+            });
+            animation.add_frame(AnimationFrame::new(96, 64, frame.into_raw()).with_delay(100));
+        }
+
+        let encode = |control: RateControl| {
+            let mut encoded = Vec::new();
+            let stats = WkAnimationEncoder::lossy(80)
+                .with_rate_control(control)
+                .encode_with_stats(&animation, &mut encoded)
+                .unwrap();
+            (encoded, stats)
+        };
+        let mean_quality = |stats: &animation::AnimationEncodeStats| {
+            stats.frames.iter().map(|f| f.quality as f32).sum::<f32>() / stats.frames.len() as f32
+        };
+
+        let (small, small_stats) = encode(RateControl::total_size(6_000));
+        let (large, large_stats) = encode(RateControl::total_size(24_000));
+        assert!(small.len() <= 6_000, "{}", small.len());
+        assert!(large.len() <= 24_000, "{}", large.len());
+        assert!(small.len() < large.len());
+        assert!(mean_quality(&small_stats) < mean_quality(&large_stats));
+
+        // 1.2 s at 40 kbit/s is 6000 bytes, with no frame over 1200.
+        let (capped, capped_stats) =
+            encode(RateControl::bitrate(40_000).with_max_frame_bytes(1_200));
+        assert!(capped.len() <= 6_000, "{}", capped.len());
+        for frame in &capped_stats.frames {
+            assert!(frame.bytes <= 1_200, "{:?}", (frame.bytes, frame.quality));
+        }
+
+        for encoded in [&small, &large, &capped] {
+            let frames: Vec<DecodedFrame> = WkAnimationDecoder::new(encoded.as_slice())
+                .unwrap()
+                .collect::<WkResult<_>>()
+                .unwrap();
+            assert_eq!(frames.len(), 12);
+        }
+        assert!(WkAnimationEncoder::lossless()
+            .with_rate_control(RateControl::total_size(6_000))
+            .encode_to_vec(&animation)
+            .is_err());
+        // Every frame stays under a cap far below its budgeted share, starting
+        // from a quality that needs many steps down.
+        let capped_to = |cap: usize| {
+            let control = RateControl::total_size(60_000)
+                .with_max_frame_bytes(cap)
+                .with_quality_range(1, 99);
+            WkAnimationEncoder::lossy(80)
+                .with_rate_control(control)
+                .encode_with_stats(&animation, &mut Vec::new())
+        };
+        for cap in [700, 900] {
+            for frame in &capped_to(cap).unwrap().frames {
+                assert!(frame.bytes <= cap, "{cap}: {:?}", frame);
+            }
+        }
+        // The first frame does not fit under 450 bytes even at quality 1.
+        assert!(capped_to(450).is_err());
+        // Twelve frames cannot fit in 1500 bytes even at the lowest quality.
+        assert!(WkAnimationEncoder::lossy(80)
+            .with_rate_control(RateControl::total_size(1_500))
+            .encode_to_vec(&animation)
+            .is_err());
+
+        // The quality fields are public, so ranges that skip the builder are
+        // checked at encode time.
+        let mut inverted = RateControl::total_size(6_000);
+        inverted.min_quality = 80;
+        inverted.max_quality = 20;
+        assert!(WkAnimationEncoder::lossy(80)
+            .with_rate_control(inverted)
+            .encode_to_vec(&animation)
+            .is_err());
+        // A frame header quality of 0 means the IHDR quality, so no frame is
+        // coded at 0 even when the range allows it.
+        let mut from_zero = RateControl::total_size(4_000);
+        from_zero.min_quality = 0;
+        let mut encoded = Vec::new();
+        let stats = WkAnimationEncoder::lossy(90)
+            .with_rate_control(from_zero)
+            .encode_with_stats(&animation, &mut encoded)
+            .unwrap();
+        assert!(stats.frames.iter().all(|f| f.quality >= 1), "{stats:?}");
+        let decoded: Vec<DecodedFrame> = WkAnimationDecoder::new(encoded.as_slice())
+            .unwrap()
+            .collect::<WkResult<_>>()
+            .unwrap();
+        assert_eq!(decoded.len(), 12);
+    }
+
     #[test]
     fn test_compression_ratio() {
         let img = DynamicImage::ImageRgb8(RgbImage::from_fn(128, 128, |_, _| {
@@ -139,4 +1556,24 @@ mod tests {
         assert!(lossless_enc.len() < raw_size);
         assert!(lossy_enc.len() < lossless_enc.len());
     }
+
+    #[test]
+    fn test_loco_outcodes_huffman_on_flat_image() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(128, 128, |_, _| {
+            image::Rgb([100, 100, 100])
+        }));
+
+        let loco_enc = WkEncoder::lossless()
+            .with_lossless_engine(LosslessEngine::Loco)
+            .encode_to_vec(&img)
+            .unwrap();
+        let huffman_enc = WkEncoder::lossless()
+            .with_lossless_engine(LosslessEngine::Huffman)
+            .encode_to_vec(&img)
+            .unwrap();
+
+        assert!(loco_enc.len() * 10 < huffman_enc.len());
+        let decoded = WkDecoder::new().decode(loco_enc.as_slice()).unwrap();
+        assert_eq!(decoded.image.to_rgb8(), img.to_rgb8());
+    }
 }
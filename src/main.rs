@@ -1,8 +1,11 @@
 use colored::Colorize;
+use wk_format::converter::read_animation;
 use wk_format::metadata::exif::ExifBuilder;
 use wk_format::metadata::icc::IccProfile;
 use wk_format::metadata::xmp::XmpBuilder;
-use wk_format::{WkDecoder, WkEncoder, WkMetadata, WkResult};
+use wk_format::{
+    LosslessEngine, WkAnimationEncoder, WkConverter, WkDecoder, WkEncoder, WkMetadata, WkResult,
+};
 
 fn main() -> WkResult<()> {
     let args: Vec<String> = std::env::args().collect();
@@ -23,7 +26,8 @@ fn main() -> WkResult<()> {
             }
             let output = &args[3];
             let quality = args.get(4).and_then(|s| s.parse().ok()).unwrap_or(85);
-            encode_image(input, output, quality)?;
+            let progressive = args.get(5).is_some_and(|s| s == "progressive");
+            encode_image(input, output, quality, progressive)?;
         }
         "from-wk" | "decode" => {
             if args.len() < 4 {
@@ -42,7 +46,21 @@ fn main() -> WkResult<()> {
                 std::process::exit(1);
             }
             let output = &args[3];
-            encode_lossless(input, output)?;
+            let engine = match args.get(4).map(String::as_str) {
+                None => None,
+                Some("loco") => Some(LosslessEngine::Loco),
+                Some("lz77") => Some(LosslessEngine::Lz77),
+                Some("huffman") => Some(LosslessEngine::Huffman),
+                Some(other) => {
+                    eprintln!(
+                        "{} Unknown lossless engine: {}",
+                        "Error:".red().bold(),
+                        other
+                    );
+                    std::process::exit(1);
+                }
+            };
+            encode_lossless(input, output, engine)?;
         }
         "benchmark" => {
             if args.len() < 4 {
@@ -62,7 +80,7 @@ fn main() -> WkResult<()> {
     Ok(())
 }
 
-fn encode_image(input: &str, output: &str, quality: u8) -> WkResult<()> {
+fn encode_image(input: &str, output: &str, quality: u8, progressive: bool) -> WkResult<()> {
     println!(
         "{} {} → {} (quality: {})",
         "Encoding".cyan().bold(),
@@ -71,7 +89,7 @@ fn encode_image(input: &str, output: &str, quality: u8) -> WkResult<()> {
         quality.to_string().magenta()
     );
 
-    let img = image::open(input)?;
+    let animation = read_animation(input)?;
 
     let exif = ExifBuilder::new()
         .software("WK Image Format v3.1.1")
@@ -86,10 +104,23 @@ fn encode_image(input: &str, output: &str, quality: u8) -> WkResult<()> {
         .with_xmp(xmp)
         .with_icc(IccProfile::srgb());
 
-    let encoder = WkEncoder::lossy(quality).with_metadata(metadata);
-
     let mut file = std::fs::File::create(output)?;
-    encoder.encode(&img, &mut file)?;
+    if let Some(animation) = animation {
+        println!(
+            "  {} {} frames",
+            "Animated:".dimmed(),
+            animation.frame_count().to_string().white()
+        );
+        WkAnimationEncoder::lossy(quality)
+            .with_metadata(metadata)
+            .encode(&animation, &mut file)?;
+    } else {
+        let img = image::open(input)?;
+        let encoder = WkEncoder::lossy(quality)
+            .with_metadata(metadata)
+            .with_progressive(progressive);
+        encoder.encode(&img, &mut file)?;
+    }
 
     let input_size = std::fs::metadata(input)?.len();
     let output_size = std::fs::metadata(output)?.len();
@@ -115,7 +146,7 @@ fn encode_image(input: &str, output: &str, quality: u8) -> WkResult<()> {
     Ok(())
 }
 
-fn encode_lossless(input: &str, output: &str) -> WkResult<()> {
+fn encode_lossless(input: &str, output: &str, engine: Option<LosslessEngine>) -> WkResult<()> {
     println!(
         "{} {} → {} {}",
         "Encoding".cyan().bold(),
@@ -124,11 +155,21 @@ fn encode_lossless(input: &str, output: &str) -> WkResult<()> {
         "(lossless)".magenta()
     );
 
-    let img = image::open(input)?;
-    let encoder = WkEncoder::lossless();
-
     let mut file = std::fs::File::create(output)?;
-    encoder.encode(&img, &mut file)?;
+    if let Some(animation) = read_animation(input)? {
+        let mut encoder = WkAnimationEncoder::lossless();
+        if let Some(engine) = engine {
+            encoder = encoder.with_lossless_engine(engine);
+        }
+        encoder.encode(&animation, &mut file)?;
+    } else {
+        let img = image::open(input)?;
+        let mut encoder = WkEncoder::lossless();
+        if let Some(engine) = engine {
+            encoder = encoder.with_lossless_engine(engine);
+        }
+        encoder.encode(&img, &mut file)?;
+    }
 
     let input_size = std::fs::metadata(input)?.len();
     let output_size = std::fs::metadata(output)?.len();
@@ -160,7 +201,11 @@ fn decode_image(input: &str, output: &str) -> WkResult<()> {
     let decoder = WkDecoder::new();
     let decoded = decoder.decode(std::io::BufReader::new(file))?;
 
-    decoded.image.save(output)?;
+    if decoded.header.has_animation {
+        WkConverter::new().from_wk(input, output)?;
+    } else {
+        decoded.image.save(output)?;
+    }
 
     println!("{}", "✓ Decoded successfully!".green().bold());
     println!(
@@ -226,6 +271,22 @@ fn show_info(input: &str) -> WkResult<()> {
         "Bit Depth:".dimmed(),
         decoded.header.bit_depth.to_string().white()
     );
+    if decoded.header.has_animation {
+        let animation = WkConverter::new()
+            .wk_to_animation(input)?
+            .unwrap_or_default();
+        println!(
+            "{} {} frames, {}ms, loop {}",
+            "Animation:".dimmed(),
+            animation.frame_count().to_string().white(),
+            animation.total_duration_ms().to_string().white(),
+            match animation.config.loop_count {
+                0 => "forever".to_string(),
+                n => n.to_string(),
+            }
+            .white()
+        );
+    }
 
     if let Some(ref icc) = decoded.metadata.icc_profile {
         println!();
@@ -399,12 +460,12 @@ fn print_usage() {
     println!();
     println!("{}", "USAGE:".yellow().bold());
     println!(
-        "  {} {} <input> <output.wk> [quality]",
+        "  {} {} <input> <output.wk> [quality] [progressive]",
         "wkconverter".white(),
         "encode".green()
     );
     println!(
-        "  {} {} <input> <output.wk>",
+        "  {} {} <input> <output.wk> [engine]",
         "wkconverter".white(),
         "lossless".green()
     );
@@ -425,11 +486,30 @@ fn print_usage() {
         "  {} 1-100 (default: 85, 100 = lossless)",
         "Quality:".dimmed()
     );
+    println!(
+        "  {}  huffman (default), loco (continuous tone), lz77 (screenshots, tiled art)",
+        "Engine:".dimmed()
+    );
+    println!(
+        "  {} GIF, APNG and WebP inputs encode every frame; decoding an",
+        "Animated:".dimmed()
+    );
+    println!("            animated file to .gif, .png or .apng keeps every frame");
     println!();
     println!("{}", "EXAMPLES:".yellow().bold());
     println!("  {} photo.jpg photo.wk 85", "wkconverter encode".cyan());
+    println!(
+        "  {} photo.jpg photo.wk 85 progressive",
+        "wkconverter encode".cyan()
+    );
     println!("  {} art.png art.wk", "wkconverter lossless".cyan());
+    println!(
+        "  {} screen.png screen.wk lz77",
+        "wkconverter lossless".cyan()
+    );
     println!("  {} image.wk image.png", "wkconverter decode".cyan());
+    println!("  {} clip.gif clip.wk 80", "wkconverter encode".cyan());
+    println!("  {} clip.wk clip.apng", "wkconverter decode".cyan());
     println!("  {} image.wk", "wkconverter info".cyan());
     println!();
 }
@@ -9,6 +9,7 @@ pub use icc::IccProfile;
 pub use xmp::XmpData;
 
 use crate::error::WkResult;
+use crate::format::hdr::HDRMetadata;
 use serde::{Deserialize, Serialize};
 
 #[derive(Debug, Clone, Default, Serialize, Deserialize)]
@@ -17,6 +18,10 @@ pub struct WkMetadata {
     pub icc_profile: Option<IccProfile>,
     pub xmp: Option<XmpData>,
     pub custom: CustomMetadata,
+    // Written as its own HDRM chunk; skipped here so `encode` keeps the
+    // layout existing metadata blobs were written with.
+    #[serde(skip)]
+    pub hdr: Option<HDRMetadata>,
 }
 
 impl WkMetadata {
@@ -39,6 +44,11 @@ impl WkMetadata {
         self
     }
 
+    pub fn with_hdr(mut self, hdr: HDRMetadata) -> Self {
+        self.hdr = Some(hdr);
+        self
+    }
+
     pub fn encode(&self) -> WkResult<Vec<u8>> {
         bincode::serialize(self).map_err(|e| crate::error::WkError::MetadataError(e.to_string()))
     }
@@ -0,0 +1,291 @@
+use crate::compression::engine::SegmentDecoder;
+use crate::decoder::{DecodeState, DecodedImage};
+use crate::error::{WkError, WkResult};
+use crate::format::chunk::WK_MAGIC;
+use crate::format::header::WkHeader;
+use crate::format::progressive::ScanPass;
+use crate::format::{Chunk, ChunkType};
+use crate::metadata::WkMetadata;
+
+#[derive(Debug, Clone)]
+pub enum StreamEvent {
+    HeaderAvailable(WkHeader),
+    MetadataParsed(ChunkType),
+    PassDecoded(ScanPass),
+    // This many rows from the top are final while the rest of a sequential
+    // image is still arriving. Only lossy images written with resync
+    // segments decode in bands; others arrive whole as `PassDecoded`.
+    RowsDecoded(u32),
+    Done,
+}
+
+pub struct WkStreamingDecoder {
+    buffer: Vec<u8>,
+    magic_verified: bool,
+    state: DecodeState,
+    image: Option<DecodedImage>,
+    finished: bool,
+    // Rows already reported, and the segments of the pending chunk decoded
+    // so far.
+    rows: u32,
+    segments: Option<SegmentDecoder>,
+}
+
+impl WkStreamingDecoder {
+    pub fn new() -> Self {
+        Self {
+            buffer: Vec::new(),
+            magic_verified: false,
+            state: DecodeState::default(),
+            image: None,
+            finished: false,
+            rows: 0,
+            segments: None,
+        }
+    }
+
+    pub fn push(&mut self, data: &[u8]) -> WkResult<Vec<StreamEvent>> {
+        if self.finished {
+            return Ok(Vec::new());
+        }
+        self.buffer.extend_from_slice(data);
+
+        if !self.magic_verified {
+            if self.buffer.len() < WK_MAGIC.len() {
+                return Ok(Vec::new());
+            }
+            if &self.buffer[..WK_MAGIC.len()] != WK_MAGIC {
+                return Err(WkError::InvalidFormat("Invalid magic bytes".into()));
+            }
+            self.buffer.drain(..WK_MAGIC.len());
+            self.magic_verified = true;
+        }
+
+        let mut events = Vec::new();
+        let mut consumed = 0;
+        while let Some((chunk, len)) = Chunk::parse(&self.buffer[consumed..])? {
+            consumed += len;
+            let Some(chunk) = chunk else {
+                continue;
+            };
+            if let Some(event) = self.accept(chunk)? {
+                let done = matches!(event, StreamEvent::Done);
+                events.push(event);
+                if done {
+                    break;
+                }
+            }
+        }
+        if self.finished {
+            self.buffer.clear();
+        } else {
+            self.buffer.drain(..consumed);
+            if consumed > 0 {
+                self.segments = None;
+            }
+            events.extend(self.partial_rows()?);
+        }
+        Ok(events)
+    }
+
+    // Decodes the rows of a lossy data chunk that is still arriving, one
+    // resync segment at a time as each arrives whole.
+    fn partial_rows(&mut self) -> WkResult<Option<StreamEvent>> {
+        let pending = &self.buffer;
+        if pending.len() < 8
+            || ChunkType::from_bytes(&[pending[0], pending[1], pending[2], pending[3]]).ok()
+                != Some(ChunkType::ImageDataLossy)
+        {
+            return Ok(None);
+        }
+        let size = u32::from_le_bytes([pending[4], pending[5], pending[6], pending[7]]) as usize;
+        let body = &pending[8..pending.len().min(8usize.saturating_add(size))];
+        match self.state.render_partial(&mut self.segments, body)? {
+            Some((rows, image)) if rows > self.rows => {
+                self.rows = rows;
+                self.image = Some(image);
+                Ok(Some(StreamEvent::RowsDecoded(rows)))
+            }
+            _ => Ok(None),
+        }
+    }
+
+    fn accept(&mut self, chunk: Chunk) -> WkResult<Option<StreamEvent>> {
+        let chunk_type = chunk.chunk_type;
+        if chunk_type == ChunkType::End {
+            self.finished = true;
+            return Ok(Some(StreamEvent::Done));
+        }
+
+        match self.state.accept(chunk)? {
+            Some(pass) => {
+                self.image = Some(self.state.render()?);
+                Ok(Some(StreamEvent::PassDecoded(pass)))
+            }
+            None if chunk_type == ChunkType::ImageHeader => {
+                Ok(self.state.header.clone().map(StreamEvent::HeaderAvailable))
+            }
+            None if matches!(chunk_type, ChunkType::TileIndex | ChunkType::TileData) => Ok(None),
+            None => Ok(Some(StreamEvent::MetadataParsed(chunk_type))),
+        }
+    }
+
+    pub fn header(&self) -> Option<&WkHeader> {
+        self.state.header.as_ref()
+    }
+
+    pub fn metadata(&self) -> &WkMetadata {
+        &self.state.metadata
+    }
+
+    pub fn image(&self) -> Option<&DecodedImage> {
+        self.image.as_ref()
+    }
+
+    pub fn into_image(self) -> Option<DecodedImage> {
+        self.image
+    }
+
+    pub fn is_done(&self) -> bool {
+        self.finished
+    }
+}
+
+impl Default for WkStreamingDecoder {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::metadata::IccProfile;
+    use crate::{WkDecoder, WkEncoder};
+    use image::{DynamicImage, RgbImage};
+
+    fn sample() -> DynamicImage {
+        DynamicImage::ImageRgb8(RgbImage::from_fn(40, 24, |x, y| {
+            image::Rgb([(x * 6) as u8, (y * 10) as u8, ((x + y) * 3) as u8])
+        }))
+    }
+
+    #[test]
+    fn test_streaming_progressive_events() {
+        let encoded = WkEncoder::lossy(80)
+            .with_progressive(true)
+            .with_metadata(WkMetadata::new().with_icc(IccProfile::srgb()))
+            .encode_to_vec(&sample())
+            .unwrap();
+
+        let mut decoder = WkStreamingDecoder::new();
+        let mut events = Vec::new();
+        for slice in encoded.chunks(37) {
+            events.extend(decoder.push(slice).unwrap());
+        }
+
+        assert!(matches!(events[0], StreamEvent::HeaderAvailable(ref h) if h.width == 40));
+        assert!(matches!(
+            events[1],
+            StreamEvent::MetadataParsed(ChunkType::IccProfile)
+        ));
+        let passes: Vec<ScanPass> = events
+            .iter()
+            .filter_map(|e| match e {
+                StreamEvent::PassDecoded(pass) => Some(*pass),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(passes, [ScanPass::DC, ScanPass::ACLow, ScanPass::ACHigh]);
+        assert!(matches!(events.last(), Some(StreamEvent::Done)));
+        assert!(decoder.is_done());
+
+        let full = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        let streamed = decoder.into_image().unwrap();
+        assert_eq!(streamed.image.to_rgb8(), full.image.to_rgb8());
+    }
+
+    #[test]
+    fn test_partial_data_waits_for_chunk() {
+        let encoded = WkEncoder::lossless().encode_to_vec(&sample()).unwrap();
+        let mut decoder = WkStreamingDecoder::new();
+
+        let events = decoder.push(&encoded[..encoded.len() - 20]).unwrap();
+        assert!(matches!(events[..], [StreamEvent::HeaderAvailable(_)]));
+        assert!(decoder.image().is_none());
+
+        let events = decoder.push(&encoded[encoded.len() - 20..]).unwrap();
+        assert!(matches!(
+            events[..],
+            [StreamEvent::PassDecoded(ScanPass::All), StreamEvent::Done]
+        ));
+        assert_eq!(decoder.image().unwrap().image.to_rgb8(), sample().to_rgb8());
+    }
+
+    #[test]
+    fn test_resync_segments_stream_in_row_bands() {
+        let img = DynamicImage::ImageRgb8(RgbImage::from_fn(40, 64, |x, y| {
+            image::Rgb([(x * 6) as u8, (y * 4) as u8, ((x * y) % 251) as u8])
+        }));
+        let encoded = WkEncoder::lossy(80)
+            .with_resync_interval(2)
+            .encode_to_vec(&img)
+            .unwrap();
+        let full = WkDecoder::new().decode(encoded.as_slice()).unwrap();
+        let full = full.image.to_rgb8();
+
+        let mut decoder = WkStreamingDecoder::new();
+        let mut events = Vec::new();
+        for slice in encoded.chunks(64) {
+            for event in decoder.push(slice).unwrap() {
+                if let StreamEvent::RowsDecoded(rows) = event {
+                    // Rows reported as final already match the full decode.
+                    let partial = decoder.image().unwrap().image.to_rgb8();
+                    let row_bytes = 40 * 3;
+                    assert_eq!(
+                        partial.as_raw()[..rows as usize * row_bytes],
+                        full.as_raw()[..rows as usize * row_bytes]
+                    );
+                }
+                events.push(event);
+            }
+        }
+
+        let bands: Vec<u32> = events
+            .iter()
+            .filter_map(|e| match e {
+                StreamEvent::RowsDecoded(rows) => Some(*rows),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(bands, [8, 24, 40]);
+        assert!(matches!(
+            events[events.len() - 2..],
+            [StreamEvent::PassDecoded(ScanPass::All), StreamEvent::Done]
+        ));
+        assert_eq!(decoder.image().unwrap().image.to_rgb8(), full);
+    }
+
+    #[test]
+    fn test_tiled_stream_renders_once_complete() {
+        let encoded = WkEncoder::lossless()
+            .with_tile_size(16)
+            .encode_to_vec(&sample())
+            .unwrap();
+        let mut decoder = WkStreamingDecoder::new();
+        let mut events = Vec::new();
+        for slice in encoded.chunks(29) {
+            events.extend(decoder.push(slice).unwrap());
+        }
+
+        assert!(matches!(
+            events[..],
+            [
+                StreamEvent::HeaderAvailable(_),
+                StreamEvent::PassDecoded(ScanPass::All),
+                StreamEvent::Done
+            ]
+        ));
+        assert_eq!(decoder.image().unwrap().image.to_rgb8(), sample().to_rgb8());
+    }
+}